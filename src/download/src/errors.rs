@@ -16,6 +16,13 @@ error_chain! {
         FileNotFound {
             description("file not found")
         }
+        DownloadTimeout {
+            description("download timed out")
+        }
+        ChunkFailed(e: String) {
+            description("chunked download failed")
+            display("chunked download failed: {}", e)
+        }
         BackendUnavailable(be: &'static str) {
             description("download backend unavailable")
             display("download backend '{}' unavailable", be)
@@ -12,6 +12,8 @@ extern crate lazy_static;
 
 use url::Url;
 use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
 
 mod errors;
 pub use errors::*;
@@ -28,6 +30,115 @@ pub enum Event<'a> {
     DownloadDataReceived(&'a [u8]),
 }
 
+/// How requests identify and authenticate themselves
+#[derive(Clone, Debug, Default)]
+pub struct RequestConfig {
+    /// Sent as `User-Agent` with every request
+    pub user_agent: Option<String>,
+    /// Sent as a bearer token with requests to GitHub only, to raise its
+    /// rate limits
+    pub github_token: Option<String>,
+}
+
+static REQUEST_CONFIG: Mutex<Option<RequestConfig>> = Mutex::new(None);
+
+/// Sets the configuration used by all later requests
+pub fn configure_requests(config: RequestConfig) {
+    *REQUEST_CONFIG.lock().unwrap() = Some(config);
+}
+
+fn request_config() -> RequestConfig {
+    REQUEST_CONFIG.lock().unwrap().clone().unwrap_or_default()
+}
+
+/// How long requests wait to connect, and for more data once connected,
+/// unless configured otherwise
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(300);
+
+static TIMEOUTS: Mutex<Option<(Duration, Duration)>> = Mutex::new(None);
+
+/// Sets how long later requests wait to connect, and how long they wait
+/// for more data before giving up with `ErrorKind::DownloadTimeout`
+pub fn configure_timeouts(connect: Duration, read: Duration) {
+    *TIMEOUTS.lock().unwrap() = Some((connect, read));
+}
+
+/// The timeouts set by `configure_timeouts`, or the defaults
+pub fn timeouts() -> (Duration, Duration) {
+    TIMEOUTS.lock().unwrap().unwrap_or((DEFAULT_CONNECT_TIMEOUT, DEFAULT_READ_TIMEOUT))
+}
+
+fn is_github(url: &Url) -> bool {
+    let host = url.host_str().unwrap_or("");
+    url.scheme() == "https" &&
+        ["github.com", "githubusercontent.com"].iter()
+            .any(|domain| host == *domain || host.ends_with(&format!(".{}", domain)))
+}
+
+/// The headers to send with a request to `url`, as names and values
+pub fn request_headers(url: &Url) -> Vec<(&'static str, String)> {
+    let config = request_config();
+    let mut headers = vec![];
+    if let Some(user_agent) = config.user_agent {
+        headers.push(("User-Agent", user_agent));
+    }
+    match config.github_token {
+        Some(ref token) if !token.is_empty() && is_github(url) => {
+            headers.push(("Authorization", format!("Bearer {}", token)));
+        }
+        _ => {}
+    }
+    headers
+}
+
+/// `text` with any secret from the request configuration and the
+/// credentials of urls in it masked, for showing messages that may include
+/// request details
+pub fn redact(text: &str) -> String {
+    let text = redact_userinfo(text);
+    match request_config().github_token {
+        Some(ref token) if !token.is_empty() => text.replace(&**token, "<redacted>"),
+        _ => text,
+    }
+}
+
+/// `url` with any credentials in it replaced, for showing it to the user
+pub fn redact_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut parsed) => {
+            if parsed.password().is_some() {
+                let _ = parsed.set_password(Some("***"));
+            }
+            if !parsed.username().is_empty() {
+                let _ = parsed.set_username("***");
+            }
+            parsed.to_string()
+        }
+        Err(_) => redact_userinfo(url),
+    }
+}
+
+// Masks the `user:password@` part of anything that looks like a url
+fn redact_userinfo(text: &str) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(i) = rest.find("://") {
+        let (before, after) = rest.split_at(i + 3);
+        result.push_str(before);
+        let end = after.find(|c: char| "/?#'\"".contains(c) || c.is_whitespace()).unwrap_or(after.len());
+        rest = match after[..end].rfind('@') {
+            Some(at) => {
+                result.push_str("***:***");
+                &after[at..]
+            }
+            None => after,
+        };
+    }
+    result.push_str(rest);
+    result
+}
+
 fn download_with_backend(backend: Backend,
                          url: &Url,
                          resume_from: u64,
@@ -123,6 +234,132 @@ pub fn download_to_path_with_backend(
     })
 }
 
+/// Downloads `url` to `path` using `chunks` concurrent range requests.
+///
+/// Each chunk is stored next to `path` as `<path>.partN` so that an
+/// interrupted download can be resumed chunk by chunk. The chunks are only
+/// assembled into `path` once all of them have their expected length, and
+/// the assembled data is passed through `callback` in order so that callers
+/// hashing the download see the same byte stream as for a single-stream
+/// download. Returns `Ok(false)` without downloading anything if the server
+/// does not support range requests.
+pub fn download_to_path_parallel(
+    url: &Url,
+    path: &Path,
+    chunks: u64,
+    callback: Option<&Fn(Event) -> Result<()>>)
+    -> Result<bool>
+{
+    use std::fs::{self, OpenOptions};
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+    use std::thread;
+
+    let len = match try!(curl::range_support(url)) {
+        Some(len) if len > 0 && chunks > 1 => len,
+        _ => return Ok(false),
+    };
+    let ranges = chunk_ranges(len, chunks);
+
+    let part_path = |i: u64| -> PathBuf {
+        let mut name = path.file_name().map(|s| s.to_owned()).unwrap_or_default();
+        name.push(format!(".part{}", i));
+        path.with_file_name(name)
+    };
+
+    if let Some(cb) = callback {
+        try!(cb(Event::DownloadContentLengthReceived(len)));
+    }
+
+    let workers: Vec<_> = ranges.iter().enumerate().map(|(i, &(from, to))| {
+        let url = url.clone();
+        let part = part_path(i as u64);
+        thread::spawn(move || -> ::std::result::Result<(), String> {
+            || -> Result<()> {
+                let existing = fs::metadata(&part).map(|m| m.len()).unwrap_or(0);
+                let expected = to - from + 1;
+                if existing == expected {
+                    return Ok(());
+                }
+                let mut file = if existing > expected {
+                    try!(fs::File::create(&part))
+                } else {
+                    try!(OpenOptions::new().append(true).create(true).open(&part))
+                };
+                let start = if existing > expected { from } else { from + existing };
+                try!(curl::download_range(&url, start, to, &mut |data| {
+                    file.write_all(data).chain_err(|| "unable to write download chunk to disk")
+                }));
+                try!(file.sync_data().chain_err(|| "unable to sync download chunk to disk"));
+                Ok(())
+            }().map_err(|e| e.to_string())
+        })
+    }).collect();
+
+    let mut failure = None;
+    for worker in workers {
+        match worker.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => { failure = failure.or(Some(e)); }
+            Err(_) => { failure = failure.or(Some("download thread panicked".to_owned())); }
+        }
+    }
+    if let Some(e) = failure {
+        return Err(ErrorKind::ChunkFailed(e).into());
+    }
+
+    // Assemble the chunks, checking that each was completely downloaded
+    let mut file = try!(fs::File::create(path).chain_err(|| "error creating file for download"));
+    let mut buf = vec![0; 0x10000];
+    let mut total = 0;
+    for (i, &(_, to)) in ranges.iter().enumerate() {
+        let part = part_path(i as u64);
+        let mut src = try!(fs::File::open(&part).chain_err(|| "unable to open download chunk"));
+        loop {
+            let n = try!(src.read(&mut buf));
+            if n == 0 {
+                break;
+            }
+            total += n as u64;
+            try!(file.write_all(&buf[..n]).chain_err(|| "unable to write download to disk"));
+            if let Some(cb) = callback {
+                try!(cb(Event::DownloadDataReceived(&buf[..n])));
+            }
+        }
+        if total != to + 1 {
+            return Err(ErrorKind::ChunkFailed(format!("chunk {} is truncated", i)).into());
+        }
+    }
+    try!(file.sync_data().chain_err(|| "unable to sync download to disk"));
+
+    for i in 0..ranges.len() {
+        let _ = fs::remove_file(part_path(i as u64));
+    }
+
+    Ok(true)
+}
+
+// Splits `len` bytes into at most `chunks` inclusive byte ranges of equal
+// length, except for the last one. Fewer ranges are used if some would be
+// empty, e.g. for downloads shorter than `chunks` bytes.
+fn chunk_ranges(len: u64, chunks: u64) -> Vec<(u64, u64)> {
+    if len == 0 || chunks == 0 {
+        return vec![];
+    }
+    let chunk_len = (len + chunks - 1) / chunks;
+    (0..chunks)
+        .map(|i| i * chunk_len)
+        .take_while(|&from| from < len)
+        .map(|from| (from, (from + chunk_len).min(len) - 1))
+        .collect()
+}
+
+/// Asks the server for the size of `url` without downloading it. Always
+/// goes through curl.
+pub fn content_length(url: &Url) -> Result<Option<u64>> {
+    curl::content_length(url)
+}
+
 /// Download via libcurl; encrypt with the native (or OpenSSl) TLS
 /// stack via libcurl
 #[cfg(feature = "curl-backend")]
@@ -130,17 +367,48 @@ pub mod curl {
 
     extern crate curl;
 
-    use self::curl::easy::Easy;
+    use self::curl::easy::{Easy, List};
     use errors::*;
     use std::cell::RefCell;
     use std::str;
-    use std::time::Duration;
     use url::Url;
     use super::Event;
 
 
     thread_local!(pub static EASY: RefCell<Easy> = RefCell::new(Easy::new()));
 
+    /// Sets the headers from `request_headers` for a request to `url`,
+    /// followed by `extra` ones, replacing those of earlier requests
+    pub fn set_request_headers(handle: &mut Easy, url: &Url, extra: &[String]) -> Result<()> {
+        let mut headers = List::new();
+        for (name, value) in super::request_headers(url) {
+            try!(headers.append(&format!("{}: {}", name, value)).chain_err(|| "failed to set header"));
+        }
+        for header in extra {
+            try!(headers.append(header).chain_err(|| "failed to set header"));
+        }
+        handle.http_headers(headers).chain_err(|| "failed to set headers")
+    }
+
+    // Gives up on connections that take too long to open, or that stop
+    // sending data
+    fn set_timeouts(handle: &mut Easy) -> Result<()> {
+        let (connect, read) = super::timeouts();
+        try!(handle.connect_timeout(connect).chain_err(|| "failed to set connect timeout"));
+        try!(handle.low_speed_limit(1).chain_err(|| "failed to set read timeout"));
+        handle.low_speed_time(read).chain_err(|| "failed to set read timeout")
+    }
+
+    fn transfer_error(e: self::curl::Error) -> Error {
+        if e.is_operation_timedout() {
+            Error::with_chain(e, ErrorKind::DownloadTimeout)
+        } else if e.is_file_couldnt_read_file() {
+            Error::with_chain(e, ErrorKind::FileNotFound)
+        } else {
+            Error::with_chain(e, "error during download")
+        }
+    }
+
     pub fn download(url: &Url,
                     resume_from: u64,
                     callback: &Fn(Event) -> Result<()> )
@@ -155,6 +423,7 @@ pub mod curl {
 
             try!(handle.url(&url.to_string()).chain_err(|| "failed to set url"));
             try!(handle.follow_location(true).chain_err(|| "failed to set follow redirects"));
+            try!(set_request_headers(&mut handle, url, &[]));
 
             if resume_from > 0 {
                 try!(handle.resume_from(resume_from)
@@ -165,8 +434,7 @@ pub mod curl {
                 let _ = handle.resume_from(0);
             }
 
-            // Take at most 30s to connect
-            try!(handle.connect_timeout(Duration::new(30, 0)).chain_err(|| "failed to set connect timeout"));
+            try!(set_timeouts(&mut handle));
 
             {
                 let cberr = RefCell::new(None);
@@ -213,14 +481,8 @@ pub mod curl {
                     // callbacks, return it.
                     match cberr.borrow_mut().take() {
                         Some(cberr) => Err(cberr),
-                        None => {
-                            // Otherwise, return the error from curl
-                            if e.is_file_couldnt_read_file() {
-                                Err(e).chain_err(|| ErrorKind::FileNotFound)
-                            } else {
-                                Err(e).chain_err(|| "error during download")
-                            }
-                        }
+                        // Otherwise, return the error from curl
+                        None => Err(transfer_error(e)),
                     }
                 }));
             }
@@ -235,6 +497,103 @@ pub mod curl {
             Ok(())
         })
     }
+
+    /// Returns the content length of `url` if the server advertises support
+    /// for byte range requests.
+    pub fn range_support(url: &Url) -> Result<Option<u64>> {
+        let (accepts_ranges, content_len) = try!(head(url));
+        Ok(if accepts_ranges { content_len } else { None })
+    }
+
+    /// Returns the content length of `url`, if the server sends one
+    pub fn content_length(url: &Url) -> Result<Option<u64>> {
+        Ok(try!(head(url)).1)
+    }
+
+    // Whether the server accepts byte range requests, and the content length
+    fn head(url: &Url) -> Result<(bool, Option<u64>)> {
+        let mut handle = Easy::new();
+        try!(handle.url(&url.to_string()).chain_err(|| "failed to set url"));
+        try!(handle.follow_location(true).chain_err(|| "failed to set follow redirects"));
+        try!(handle.nobody(true).chain_err(|| "failed to set HEAD request"));
+        try!(set_request_headers(&mut handle, url, &[]));
+        try!(set_timeouts(&mut handle));
+
+        let accepts_ranges = RefCell::new(false);
+        let content_len = RefCell::new(None);
+        {
+            let mut transfer = handle.transfer();
+            try!(transfer.header_function(|header| {
+                if let Ok(data) = str::from_utf8(header) {
+                    let data = data.trim().to_lowercase();
+                    if data.starts_with("http/") {
+                        // A new response in the redirect chain
+                        *accepts_ranges.borrow_mut() = false;
+                        *content_len.borrow_mut() = None;
+                    } else if data.starts_with("accept-ranges:") {
+                        *accepts_ranges.borrow_mut() = data["accept-ranges:".len()..].trim() == "bytes";
+                    } else if data.starts_with("content-length:") {
+                        *content_len.borrow_mut() = data["content-length:".len()..].trim().parse::<u64>().ok();
+                    }
+                }
+                true
+            }).chain_err(|| "failed to set header"));
+            try!(transfer.perform().map_err(transfer_error));
+        }
+
+        let code = try!(handle.response_code().chain_err(|| "failed to get response code"));
+        let result = match code {
+            // Urls like `file://` have no response code, and no range requests
+            0 => (false, *content_len.borrow()),
+            200 ... 299 => (*accepts_ranges.borrow(), *content_len.borrow()),
+            _ => { return Err(ErrorKind::HttpStatus(code).into()); }
+        };
+        Ok(result)
+    }
+
+    /// Downloads the inclusive byte range `from..=to` of `url` using a fresh
+    /// handle, so that it may be called from multiple threads at once.
+    pub fn download_range(url: &Url,
+                          from: u64,
+                          to: u64,
+                          callback: &mut FnMut(&[u8]) -> Result<()>)
+                          -> Result<()> {
+        let mut handle = Easy::new();
+        try!(handle.url(&url.to_string()).chain_err(|| "failed to set url"));
+        try!(handle.follow_location(true).chain_err(|| "failed to set follow redirects"));
+        try!(handle.range(&format!("{}-{}", from, to))
+            .chain_err(|| "setting the range header for chunked download"));
+        try!(set_request_headers(&mut handle, url, &[]));
+        try!(set_timeouts(&mut handle));
+
+        {
+            let cberr = RefCell::new(None);
+            let mut transfer = handle.transfer();
+            try!(transfer.write_function(|data| {
+                match callback(data) {
+                    Ok(()) => Ok(data.len()),
+                    Err(e) => {
+                        *cberr.borrow_mut() = Some(e);
+                        Ok(0)
+                    }
+                }
+            }).chain_err(|| "failed to set write"));
+            try!(transfer.perform().or_else(|e| {
+                match cberr.borrow_mut().take() {
+                    Some(cberr) => Err(cberr),
+                    None => Err(transfer_error(e)),
+                }
+            }));
+        }
+
+        // Anything but "Partial Content" means the range was not honored
+        let code = try!(handle.response_code().chain_err(|| "failed to get response code"));
+        match code {
+            206 => Ok(()),
+            200 ... 299 => Err(ErrorKind::ChunkFailed("server ignored range request".to_owned()).into()),
+            _ => Err(ErrorKind::HttpStatus(code).into()),
+        }
+    }
 }
 
 #[cfg(feature = "reqwest-backend")]
@@ -242,7 +601,8 @@ pub mod reqwest_be {
     extern crate env_proxy;
 
     use std::io;
-    use std::time::Duration;
+    use std::sync::mpsc;
+    use std::thread;
     use errors::*;
     use url::Url;
     use super::Event;
@@ -266,21 +626,32 @@ pub mod reqwest_be {
             return Err(ErrorKind::HttpStatus(code as u32).into());
         }
 
-        let buffer_size = 0x10000;
-        let mut buffer = vec![0u8; buffer_size];
-
         if let Some(len) = res.headers().get::<header::ContentLength>() {
             callback(Event::DownloadContentLengthReceived(len.0 + resume_from))?;
         }
 
-        loop {
-            let bytes_read = io::Read::read(&mut res, &mut buffer)
-                .chain_err(|| "error reading from socket")?;
+        // reqwest only supports a deadline for the whole request, so the
+        // body is read on another thread and given up on once it stops
+        // sending data for the read timeout, like curl's low speed limit
+        let (_, read_timeout) = super::timeouts();
+        let (tx, rx) = mpsc::sync_channel::<io::Result<Vec<u8>>>(4);
+        thread::spawn(move || {
+            let mut buffer = vec![0u8; 0x10000];
+            loop {
+                let data = io::Read::read(&mut res, &mut buffer).map(|n| buffer[..n].to_vec());
+                let done = data.as_ref().map(|d| d.is_empty()).unwrap_or(true);
+                if tx.send(data).is_err() || done {
+                    return;
+                }
+            }
+        });
 
-            if bytes_read != 0 {
-                callback(Event::DownloadDataReceived(&buffer[0..bytes_read]))?;
-            } else {
-                return Ok(());
+        loop {
+            match rx.recv_timeout(read_timeout) {
+                Ok(Ok(ref data)) if data.is_empty() => return Ok(()),
+                Ok(Ok(data)) => callback(Event::DownloadDataReceived(&data))?,
+                Ok(Err(e)) => return Err(e).chain_err(|| "error reading from socket"),
+                Err(_) => return Err(ErrorKind::DownloadTimeout.into()),
             }
         }
     }
@@ -291,7 +662,9 @@ pub mod reqwest_be {
                 Client::builder()
                     .gzip(false)
                     .proxy(Proxy::custom(env_proxy))
-                    .timeout(Duration::from_secs(30))
+                    .connect_timeout(super::timeouts().0)
+                    // See `download` for the read timeout
+                    .timeout(None)
                     .build()
             };
 
@@ -312,6 +685,12 @@ pub mod reqwest_be {
     fn request(url: &Url, resume_from: u64) -> ::reqwest::Result<Response> {
         let mut req = CLIENT.get(url.clone());
 
+        let mut headers = header::Headers::new();
+        for (name, value) in super::request_headers(url) {
+            headers.set_raw(name, value);
+        }
+        req.headers(headers);
+
         if resume_from != 0 {
             req.header(header::Range::Bytes(
                 vec![header::ByteRangeSpec::AllFrom(resume_from)]
@@ -373,6 +752,22 @@ pub mod curl {
                     -> Result<()> {
         Err(ErrorKind::BackendUnavailable("curl").into())
     }
+
+    pub fn range_support(_url: &Url) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    pub fn content_length(_url: &Url) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    pub fn download_range(_url: &Url,
+                          _from: u64,
+                          _to: u64,
+                          _callback: &mut FnMut(&[u8]) -> Result<()>)
+                          -> Result<()> {
+        Err(ErrorKind::BackendUnavailable("curl").into())
+    }
 }
 
 #[cfg(not(feature = "reqwest-backend"))]
@@ -389,3 +784,19 @@ pub mod reqwest_be {
         Err(ErrorKind::BackendUnavailable("reqwest").into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::chunk_ranges;
+
+    #[test]
+    fn test_chunk_ranges() {
+        assert_eq!(chunk_ranges(10, 4), vec![(0, 2), (3, 5), (6, 8), (9, 9)]);
+        assert_eq!(chunk_ranges(8, 4), vec![(0, 1), (2, 3), (4, 5), (6, 7)]);
+        // Rounding up the chunk length would leave the last chunks empty
+        assert_eq!(chunk_ranges(5, 4), vec![(0, 1), (2, 3), (4, 4)]);
+        assert_eq!(chunk_ranges(3, 8), vec![(0, 0), (1, 1), (2, 2)]);
+        assert_eq!(chunk_ranges(1, 4), vec![(0, 0)]);
+        assert_eq!(chunk_ranges(0, 4), vec![]);
+    }
+}
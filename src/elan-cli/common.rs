@@ -1,7 +1,8 @@
 //! Just a dumping ground for cli stuff
 
-use elan::{self, Cfg, Notification, Toolchain, UpdateStatus};
+use elan::{self, Cfg, Notification, Toolchain, ToolchainDirReport, UpdateStatus};
 use elan::telemetry_analysis::TelemetryAnalysis;
+use elan_dist::dist::{self, ToolchainDesc, ToolchainKind};
 use errors::*;
 use elan_utils::utils;
 use elan_utils::notify::NotificationLevel;
@@ -110,20 +111,21 @@ pub fn set_globals(verbose: bool) -> Result<Cfg> {
             return;
         }
 
+        let message = utils::redact(&n.to_string());
         match n.level() {
             NotificationLevel::Verbose => {
                 if verbose {
-                    verbose!("{}", n);
+                    verbose!("{}", message);
                 }
             }
             NotificationLevel::Info => {
-                info!("{}", n);
+                info!("{}", message);
             }
             NotificationLevel::Warn => {
-                warn!("{}", n);
+                warn!("{}", message);
             }
             NotificationLevel::Error => {
-                err!("{}", n);
+                err!("{}", message);
             }
         }
     }))))
@@ -138,7 +140,10 @@ pub fn show_channel_update(cfg: &Cfg, name: &str,
 fn show_channel_updates(cfg: &Cfg, toolchains: Vec<(String, elan::Result<UpdateStatus>)>) -> Result<()> {
     let data = toolchains.into_iter().map(|(name, result)| {
         let ref toolchain = cfg.get_toolchain(&name, false).expect("");
-        let version = lean_version(toolchain);
+        let version = match result {
+            Err(ref e) => e.to_string(),
+            Ok(_) => lean_version(toolchain),
+        };
 
         let banner;
         let color;
@@ -192,11 +197,15 @@ fn show_channel_updates(cfg: &Cfg, toolchains: Vec<(String, elan::Result<UpdateS
 pub fn update_all_channels(cfg: &Cfg, self_update: bool, force_update: bool) -> Result<()> {
 
     let toolchains = try!(cfg.update_all_channels(force_update));
+    let failed = toolchains.iter().filter(|&&(_, ref r)| r.is_err()).count();
 
     if toolchains.is_empty() {
         info!("no updatable toolchains installed");
     }
 
+    // Don't replace elan while some toolchains are in an unknown state
+    let self_update = self_update && failed == 0;
+
     let setup_path = if self_update {
         try!(self_update::prepare_update())
     } else {
@@ -209,6 +218,10 @@ pub fn update_all_channels(cfg: &Cfg, self_update: bool, force_update: bool) ->
         try!(show_channel_updates(cfg, toolchains));
     }
 
+    if failed > 0 {
+        return Err(ErrorKind::ToolchainUpdatesFailed(failed).into());
+    }
+
     if let Some(ref setup_path) = setup_path {
         try!(self_update::run_update(setup_path));
 
@@ -230,7 +243,9 @@ pub fn lean_version(toolchain: &Toolchain) -> String {
             cmd.stdin(Stdio::null());
             cmd.stdout(Stdio::piped());
             cmd.stderr(Stdio::piped());
-            toolchain.set_ldpath(&mut cmd);
+            if let Err(e) = toolchain.set_ldpath(&mut cmd) {
+                return format!("(error reading lean version: {})", e);
+            }
 
             // some toolchains are faulty with some combinations of platforms and
             // may fail to launch but also to timely terminate.
@@ -270,51 +285,87 @@ pub fn lean_version(toolchain: &Toolchain) -> String {
     }
 }
 
-pub fn list_toolchains(cfg: &Cfg) -> Result<()> {
-    let toolchains = try!(cfg.list_toolchains());
+/// Where a linked toolchain points, for appending to its name
+pub fn link_suffix(cfg: &Cfg, toolchain: &str) -> String {
+    match cfg.toolchain_link_target(toolchain) {
+        Ok(Some(ref target)) if target.broken => format!(" -> {} (broken)", target.path.display()),
+        Ok(Some(target)) => format!(" -> {}", target.path.display()),
+        _ => String::new(),
+    }
+}
 
-    if toolchains.is_empty() {
-        println!("no installed toolchains");
-    } else {
-        if let Ok(Some(def_toolchain)) = cfg.find_default() {
-            for toolchain in toolchains {
-                let if_default = if def_toolchain.name() == &*toolchain {
-                    " (default)"
-                } else {
-                    ""
-                };
-                println!("{}{}", &toolchain, if_default);
-            }
+/// Lists the installed toolchains, or only those matching `pattern`
+pub fn list_toolchains(cfg: &Cfg, pattern: Option<&str>, exact: bool, by_version: bool) -> Result<()> {
+    let mut toolchains = try!(cfg.search_toolchains(pattern.unwrap_or(""), exact));
+    if by_version {
+        dist::sort_by_version(&mut toolchains, |t| &t.name, |t| match t.kind {
+            ToolchainKind::Custom => None,
+            _ => ToolchainDesc::from_str(&t.name).ok(),
+        });
+    }
+    let aliases = try!(cfg.list_aliases());
+
+    fn alias_suffix(aliases: &[(String, String)], toolchain: &str) -> String {
+        let names: Vec<_> = aliases.iter()
+            .filter(|&&(_, ref target)| target == toolchain)
+            .map(|&(ref alias, _)| &**alias)
+            .collect();
+        if names.is_empty() {
+            String::new()
+        } else {
+            format!(" (alias: {})", names.join(", "))
+        }
+    }
 
+    if toolchains.is_empty() {
+        if pattern.is_some() {
+            println!("no installed toolchains match");
         } else {
-            for toolchain in toolchains {
-                println!("{}", &toolchain);
-            }
+            println!("no installed toolchains");
+        }
+    } else {
+        // Shortened among all installed toolchains, not just the matches
+        let all = try!(cfg.list_toolchains());
+        let short_names = dist::short_toolchain_names(&all);
+        for toolchain in toolchains {
+            let short_name = all.iter().position(|n| *n == toolchain.name)
+                .map(|i| short_names[i].clone())
+                .unwrap_or_else(|| toolchain.name.clone());
+            let if_default = if toolchain.is_default { " (default)" } else { "" };
+            let if_read_only = if toolchain.read_only { " (read-only)" } else { "" };
+            // Other toolchains already name their version
+            let version = match (&toolchain.kind, &toolchain.version) {
+                (&ToolchainKind::Custom, &Some(ref v)) => format!(" ({})", v),
+                _ => String::new(),
+            };
+            println!("{}{}{}{}{}{}", short_name, version, if_default, if_read_only,
+                     alias_suffix(&aliases, &toolchain.name), link_suffix(cfg, &toolchain.name));
         }
     }
     Ok(())
 }
 
 pub fn list_overrides(cfg: &Cfg) -> Result<()> {
-    let overrides = try!(cfg.settings_file.with(|s| Ok(s.overrides.clone())));
+    let overrides = try!(cfg.list_overrides());
 
     if overrides.is_empty() {
         println!("no overrides");
     } else {
         let mut any_not_exist = false;
-        for (k, v) in overrides {
-            let dir_exists = Path::new(&k).is_dir();
+        for (path, toolchain, dir_exists) in overrides {
             if !dir_exists {
                 any_not_exist = true;
             }
+            // Overrides may name toolchains that can't even be looked up
+            let installed = cfg.get_toolchain(&toolchain, false).map(|t| t.exists()).unwrap_or(false);
             println!("{:<40}\t{:<20}",
-                     utils::format_path_for_display(&k) +
+                     utils::format_path_for_display(&path.to_string_lossy()) +
                      if dir_exists {
                          ""
                      } else {
                          " (not a directory)"
                      },
-                     v)
+                     toolchain + if installed { "" } else { " (not installed)" })
         }
         if any_not_exist {
             println!("");
@@ -325,6 +376,21 @@ pub fn list_overrides(cfg: &Cfg) -> Result<()> {
     Ok(())
 }
 
+pub fn show_toolchain_dir_report(report: &ToolchainDirReport) -> Result<()> {
+    for path in &report.found {
+        println!("found    {}", path.display());
+    }
+    for path in &report.missing {
+        println!("missing  {}", path.display());
+    }
+    for path in &report.missing_optional {
+        println!("missing  {} (optional)", path.display());
+    }
+    if !report.is_valid() {
+        return Err(ErrorKind::InvalidToolchainDir(report.path.clone()).into());
+    }
+    Ok(())
+}
 
 pub fn version() -> &'static str {
     concat!(env!("CARGO_PKG_VERSION"), include_str!(concat!(env!("OUT_DIR"), "/commit-info.txt")))
@@ -332,10 +398,10 @@ pub fn version() -> &'static str {
 
 
 pub fn report_error(e: &Error) {
-    err!("{}", e);
+    err!("{}", utils::redact(&e.to_string()));
 
     for e in e.iter().skip(1) {
-        info!("caused by: {}", e);
+        info!("caused by: {}", utils::redact(&e.to_string()));
     }
 
     if show_backtrace() {
@@ -1,12 +1,19 @@
 use clap::{App, Arg, AppSettings, SubCommand, ArgMatches, Shell};
 use common;
-use elan::{Cfg, Toolchain, command};
-use elan::settings::TelemetryMode;
+use elan::{Cfg, NonInteractivePolicy, Toolchain, UpdateAvailability, command, validate_toolchain_dir};
+use elan::settings::{TelemetryMode, ToolchainLayout};
+use elan::update_check;
+use elan_dist::dist;
+use elan_dist::manifest::Profile;
 use errors::*;
 use elan_utils::utils;
 use self_update;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+use std::thread;
+use std::process;
 use std::iter;
 use std::error::Error;
 use term2;
@@ -18,7 +25,23 @@ pub fn main() -> Result<()> {
 
     let ref matches = cli().get_matches();
     let verbose = matches.is_present("verbose");
-    let ref cfg = try!(common::set_globals(verbose));
+    let mut cfg = try!(common::set_globals(verbose));
+    if let Some(profile) = try!(profile_arg(matches)) {
+        cfg.profile_override = Some(profile);
+    }
+    if matches.subcommand_matches("update").map_or(false, |m| m.is_present("locked")) {
+        cfg.locked = true;
+    }
+    if matches.subcommand_name() == Some("doctor") {
+        // Fixes may remove toolchains, so they are only made when asked
+        cfg.confirm_handler = Arc::new(|action| {
+            common::confirm(&format!("{}? (y/N)", action), false).unwrap_or(false)
+        });
+    }
+    if matches.is_present("force-non-interactive") && cfg.non_interactive.is_none() {
+        cfg.non_interactive = Some(NonInteractivePolicy::Deny);
+    }
+    let ref cfg = cfg;
 
     match matches.subcommand() {
         ("show", Some(_)) => try!(show(cfg)),
@@ -29,8 +52,14 @@ pub fn main() -> Result<()> {
         ("toolchain", Some(c)) => {
             match c.subcommand() {
                 ("install", Some(m)) => try!(update(cfg, m)),
-                ("list", Some(_)) => try!(common::list_toolchains(cfg)),
+                ("list", Some(m)) => try!(common::list_toolchains(cfg, m.value_of("pattern"),
+                                                                  m.is_present("exact"),
+                                                                  m.value_of("sort") == Some("version"))),
                 ("link", Some(m)) => try!(toolchain_link(cfg, m)),
+                ("export", Some(m)) => try!(toolchain_export(cfg, m)),
+                ("import", Some(m)) => try!(toolchain_import(cfg, m)),
+                ("readonly", Some(m)) => try!(toolchain_readonly(cfg, m)),
+                ("layout", Some(m)) => try!(toolchain_layout(cfg, m)),
                 ("uninstall", Some(m)) => try!(toolchain_remove(cfg, m)),
                 (_, _) => unreachable!(),
             }
@@ -47,10 +76,16 @@ pub fn main() -> Result<()> {
         ("which", Some(m)) => try!(which(cfg, m)),
         ("doc", Some(m)) => try!(doc(cfg, m)),
         ("man", Some(m)) => try!(man(cfg,m)),
+        ("clean", Some(m)) => try!(clean(cfg, m)),
+        ("doctor", Some(m)) => try!(doctor(cfg, m)),
+        ("check-update", Some(m)) => try!(check_update(cfg, m)),
+        ("check", Some(m)) => try!(check(cfg, m)),
+        ("lock", Some(_)) => try!(lock(cfg)),
         ("self", Some(c)) => {
             match c.subcommand() {
                 ("update", Some(_)) => try!(self_update::update()),
-                ("uninstall", Some(m)) => try!(self_uninstall(m)),
+                ("uninstall", Some(m)) => try!(self_uninstall(cfg, m)),
+                ("move-home", Some(m)) => try!(self_move_home(cfg, m)),
                 (_ ,_) => unreachable!(),
             }
         }
@@ -85,6 +120,10 @@ pub fn cli() -> App<'static, 'static> {
             .help("Enable verbose output")
             .short("v")
             .long("verbose"))
+        .arg(Arg::with_name("force-non-interactive")
+            .help("Never ask for confirmation and refuse actions that need it, \
+                   unless ELAN_NON_INTERACTIVE=approve")
+            .long("force-non-interactive"))
         .subcommand(SubCommand::with_name("show")
             .about("Show the active and installed toolchains")
             .after_help(SHOW_HELP))
@@ -95,7 +134,16 @@ pub fn cli() -> App<'static, 'static> {
             .arg(Arg::with_name("toolchain")
                 .help(TOOLCHAIN_ARG_HELP)
                 .required(true)
-                .multiple(true)))
+                .multiple(true))
+            .arg(Arg::with_name("profile")
+                .help("Which components to install, overriding the configured profile")
+                .long("profile")
+                .takes_value(true)
+                .possible_values(Profile::names()))
+            .arg(Arg::with_name("default")
+                .help("Also set the toolchain as the default")
+                .long("default")
+                .takes_value(false)))
         .subcommand(SubCommand::with_name("uninstall")
             .about("Uninstall Lean toolchains")
             .setting(AppSettings::Hidden) // synonym for 'toolchain uninstall'
@@ -118,7 +166,21 @@ pub fn cli() -> App<'static, 'static> {
             .arg(Arg::with_name("force")
                 .help("Force an update, even if some components are missing")
                 .long("force")
-                .takes_value(false)))
+                .takes_value(false))
+            .arg(Arg::with_name("locked")
+                .help("Fail instead of updating elan.lock if the project toolchain resolves differently")
+                .long("locked")
+                .takes_value(false))
+            .arg(Arg::with_name("dry-run")
+                .help("Only report whether the toolchains would be installed or updated")
+                .long("dry-run")
+                .takes_value(false)
+                .requires("toolchain"))
+            .arg(Arg::with_name("profile")
+                .help("Which components to install, overriding the configured profile")
+                .long("profile")
+                .takes_value(true)
+                .possible_values(Profile::names())))
         .subcommand(SubCommand::with_name("default")
             .about("Set the default toolchain")
             .after_help(DEFAULT_HELP)
@@ -132,21 +194,61 @@ pub fn cli() -> App<'static, 'static> {
             .setting(AppSettings::DeriveDisplayOrder)
             .setting(AppSettings::SubcommandRequiredElseHelp)
             .subcommand(SubCommand::with_name("list")
-                .about("List installed toolchains"))
+                .about("List installed toolchains")
+                .arg(Arg::with_name("pattern")
+                    .help("Only list toolchains whose name contains this, or matches it as a glob")
+                    .required(false))
+                .arg(Arg::with_name("exact")
+                    .help("Only list the toolchain named exactly like the pattern")
+                    .long("exact")
+                    .takes_value(false))
+                .arg(Arg::with_name("sort")
+                    .help("Sort by name, or by version with the newest first")
+                    .long("sort")
+                    .takes_value(true)
+                    .possible_values(&["name", "version"])
+                    .default_value("name")))
             .subcommand(SubCommand::with_name("install")
                 .about("Install or update a given toolchain")
                 .aliases(&["update", "add"])
                 .arg(Arg::with_name("toolchain")
                      .help(TOOLCHAIN_ARG_HELP)
                      .required(true)
-                     .multiple(true)))
+                     .multiple(true))
+                .arg(Arg::with_name("profile")
+                     .help("Which components to install, overriding the configured profile")
+                     .long("profile")
+                     .takes_value(true)
+                     .possible_values(Profile::names()))
+                .arg(Arg::with_name("default")
+                     .help("Also set the toolchain as the default")
+                     .long("default")
+                     .takes_value(false)))
             .subcommand(SubCommand::with_name("uninstall")
                 .about("Uninstall a toolchain")
                 .alias("remove")
                 .arg(Arg::with_name("toolchain")
                      .help(TOOLCHAIN_ARG_HELP)
                      .required(true)
-                     .multiple(true)))
+                     .multiple(true))
+                .arg(Arg::with_name("force")
+                     .help("Also uninstall read-only toolchains")
+                     .long("force")
+                     .takes_value(false)))
+            .subcommand(SubCommand::with_name("readonly")
+                .about("Protect a toolchain from updates and removal")
+                .arg(Arg::with_name("toolchain")
+                     .help(TOOLCHAIN_ARG_HELP)
+                     .required(true))
+                .arg(Arg::with_name("clear")
+                     .help("Make the toolchain writable again")
+                     .long("clear")
+                     .takes_value(false)))
+            .subcommand(SubCommand::with_name("layout")
+                .about("Switch how toolchain directories are named, moving installed toolchains")
+                .arg(Arg::with_name("layout")
+                     .required(true)
+                     .possible_values(&["flat", "nested"])))
             .subcommand(SubCommand::with_name("link")
                 .about("Create a custom toolchain by symlinking to a directory")
                 .after_help(TOOLCHAIN_LINK_HELP)
@@ -154,6 +256,20 @@ pub fn cli() -> App<'static, 'static> {
                     .help(TOOLCHAIN_ARG_HELP)
                     .required(true))
                 .arg(Arg::with_name("path")
+                    .required(true))
+                .arg(Arg::with_name("dry-run")
+                    .help("Only check that the directory contains a toolchain")
+                    .long("dry-run")))
+            .subcommand(SubCommand::with_name("export")
+                .about("Download a toolchain into a bundle for installing it offline")
+                .arg(Arg::with_name("toolchain")
+                    .help(TOOLCHAIN_ARG_HELP)
+                    .required(true))
+                .arg(Arg::with_name("dir")
+                    .required(true)))
+            .subcommand(SubCommand::with_name("import")
+                .about("Install a toolchain from a bundle created by `elan toolchain export`")
+                .arg(Arg::with_name("dir")
                     .required(true))))
         .subcommand(SubCommand::with_name("override")
             .about("Modify directory toolchain overrides")
@@ -188,6 +304,14 @@ pub fn cli() -> App<'static, 'static> {
             .arg(Arg::with_name("install")
                 .help("Install the requested toolchain if needed")
                 .long("install"))
+            .arg(Arg::with_name("ephemeral")
+                .help("Install the requested toolchain if needed, and remove it again afterwards")
+                .long("ephemeral")
+                .conflicts_with("install"))
+            .arg(Arg::with_name("cwd")
+                .help("Run the command in this directory")
+                .long("cwd")
+                .takes_value(true))
             .arg(Arg::with_name("toolchain")
                 .help(TOOLCHAIN_ARG_HELP)
                 .required(true))
@@ -196,19 +320,58 @@ pub fn cli() -> App<'static, 'static> {
         .subcommand(SubCommand::with_name("which")
             .about("Display which binary will be run for a given command")
             .arg(Arg::with_name("command")
-                .required(true)))
-        /*.subcommand(SubCommand::with_name("doc")
+                .required(true))
+            .arg(Arg::with_name("all")
+                .help("List all installed toolchains that provide the command")
+                .long("all")))
+        .subcommand(SubCommand::with_name("check")
+            .about("Check whether the active toolchain is at the newest release of its channel")
+            .after_help(CHECK_HELP)
+            .arg(Arg::with_name("toolchain")
+                .help(TOOLCHAIN_ARG_HELP)
+                .conflicts_with("frozen"))
+            .arg(Arg::with_name("frozen")
+                .help("Instead check that all referenced toolchains are installed, without installing any")
+                .long("frozen")))
+        .subcommand(SubCommand::with_name("check-update")
+            .about("Print the newer release of the active toolchain, if any")
+            .after_help(CHECK_UPDATE_HELP)
+            .arg(Arg::with_name("max-age")
+                .help("Refresh results older than this many minutes (default: 60)")
+                .long("max-age")
+                .takes_value(true))
+            .arg(Arg::with_name("refresh")
+                .long("refresh")
+                .hidden(true))
+            .arg(Arg::with_name("toolchain")
+                .help(TOOLCHAIN_ARG_HELP)))
+        .subcommand(SubCommand::with_name("lock")
+            .about("Pin the release the project toolchain resolves to in elan.lock")
+            .after_help(LOCK_HELP))
+        .subcommand(SubCommand::with_name("doctor")
+            .about("Check the elan installation for problems")
+            .arg(Arg::with_name("fix")
+                .help("Try to fix the problems found")
+                .long("fix")))
+        .subcommand(SubCommand::with_name("clean")
+            .about("Remove cached downloads")
+            .after_help(CLEAN_HELP)
+            .arg(Arg::with_name("older-than")
+                .help("Only remove downloads older than this many days")
+                .long("older-than")
+                .takes_value(true))
+            .arg(Arg::with_name("max-size")
+                .help("Remove the oldest downloads until at most this many megabytes remain")
+                .long("max-size")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("doc")
             .alias("docs")
             .about("Open the documentation for the current toolchain")
             .after_help(DOC_HELP)
-            .arg(Arg::with_name("book")
-                 .long("book")
-                 .help("The Rust Programming Language book"))
-            .arg(Arg::with_name("std")
-                 .long("std")
-                 .help("Standard library API documentation"))
-            .group(ArgGroup::with_name("page")
-                 .args(&["book", "std"])))*/;
+            .arg(Arg::with_name("component")
+                 .long("component")
+                 .takes_value(true)
+                 .help("Open the documentation of a bundled library instead of Lean's")));
     
     /*if cfg!(not(target_os = "windows")) {
         app = app
@@ -232,7 +395,15 @@ pub fn cli() -> App<'static, 'static> {
         .subcommand(SubCommand::with_name("uninstall")
             .about("Uninstall elan.")
             .arg(Arg::with_name("no-prompt")
-                    .short("y"))))
+                    .short("y")))
+        .subcommand(SubCommand::with_name("move-home")
+            .about("Move the elan home with all toolchains to another directory")
+            .after_help(SELF_MOVE_HOME_HELP)
+            .arg(Arg::with_name("destination")
+                .required(true))
+            .arg(Arg::with_name("keep")
+                .help("Leave the old home in place")
+                .long("keep"))))
     /*.subcommand(SubCommand::with_name("telemetry")
         .about("elan telemetry commands")
         .setting(AppSettings::Hidden)
@@ -255,7 +426,7 @@ pub fn cli() -> App<'static, 'static> {
 
 fn default_(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
     let ref toolchain = m.value_of("toolchain").expect("");
-    let ref toolchain = try!(cfg.get_toolchain(toolchain, false));
+    let ref toolchain = try!(cfg.select_toolchain(toolchain));
 
     let status = if !toolchain.exists() || !toolchain.is_custom() {
         Some(try!(toolchain.install_from_dist_if_not_installed()))
@@ -273,29 +444,52 @@ fn default_(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
+// The `--profile` of whichever install command is being run
+fn profile_arg(matches: &ArgMatches) -> Result<Option<Profile>> {
+    let m = match matches.subcommand() {
+        ("install", Some(m)) | ("update", Some(m)) => m,
+        ("toolchain", Some(c)) => match c.subcommand() {
+            ("install", Some(m)) => m,
+            _ => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    match m.value_of("profile") {
+        Some(name) => Ok(Some(try!(Profile::from_str(name)))),
+        None => Ok(None),
+    }
+}
+
 fn update(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
     if let Some(names) = m.values_of("toolchain") {
         for name in names {
-            let toolchain = try!(cfg.get_toolchain(name, false));
-
-            let status = if !toolchain.exists() || !toolchain.is_custom() {
-                Some(try!(toolchain.install_from_dist(m.is_present("force"))))
-            } else {
-                None
-            };
+            // Installed custom toolchains have nothing to update
+            let toolchain = try!(cfg.get_toolchain(name, !m.is_present("dry-run")));
+            if toolchain.exists() && toolchain.is_custom() {
+                continue;
+            }
+            if m.is_present("dry-run") {
+                try!(toolchain.install_from_dist_inner(m.is_present("force"), true));
+                continue;
+            }
 
-            if let Some(status) = status {
-                println!("");
-                try!(common::show_channel_update(cfg, toolchain.name(), Ok(status)));
+            let status = try!(cfg.install_resolved(&toolchain, m.is_present("force")));
+            if m.is_present("default") {
+                try!(toolchain.make_default());
             }
+            println!("");
+            try!(common::show_channel_update(cfg, toolchain.name(), Ok(status)));
         }
     } else {
+        // Keep the lockfile of the current project, if it has one, in sync
+        try!(cfg.update_lockfile(&try!(utils::current_dir()), true));
         try!(common::update_all_channels(
             cfg,
             !m.is_present("no-self-update") && !self_update::NEVER_SELF_UPDATE,
             m.is_present("force"),
         ));
     }
+    try!(cfg.ensure_project_components_for_dir(&try!(utils::current_dir())));
 
     Ok(())
 }
@@ -304,6 +498,22 @@ fn run(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
     let ref toolchain = m.value_of("toolchain").expect("");
     let args = m.values_of("command").unwrap();
     let args: Vec<_> = args.collect();
+    if m.is_present("ephemeral") {
+        let dir = match m.value_of("cwd") {
+            Some(dir) => PathBuf::from(dir),
+            None => try!(utils::current_dir()),
+        };
+        let code = try!(cfg.with_temp_toolchain(toolchain, &dir, |toolchain| {
+            let mut cmd = try!(toolchain.create_command(args[0]));
+            cmd.args(&args[1..]).current_dir(&dir);
+            Ok(try!(utils::run_forwarding_signals(args[0], &mut cmd)))
+        }));
+        process::exit(code);
+    }
+    if let Some(dir) = m.value_of("cwd") {
+        let code = try!(cfg.run_in_dir(toolchain, m.is_present("install"), args[0], &args[1..], Path::new(dir)));
+        process::exit(code);
+    }
     let cmd = try!(cfg.create_command_for_toolchain(toolchain, m.is_present("install"), args[0]));
 
     Ok(try!(command::run_command_for_dir(cmd, args[0], &args[1..], &cfg)))
@@ -312,6 +522,13 @@ fn run(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
 fn which(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
     let binary = m.value_of("command").expect("");
 
+    if m.is_present("all") {
+        for toolchain in try!(cfg.which_toolchain_provides(binary)) {
+            println!("{}", toolchain);
+        }
+        return Ok(());
+    }
+
     let binary_path = try!(cfg.which_binary(&try!(utils::current_dir()), binary))
                           .expect("binary not found");
 
@@ -339,12 +556,10 @@ fn show(cfg: &Cfg) -> Result<()> {
     if show_installed_toolchains {
         if show_headers { print_header("installed toolchains") }
         let default_name = try!(cfg.get_default());
-        for t in installed_toolchains {
-            if default_name.as_ref() == Some(&t) {
-                println!("{} (default)", t);
-            } else {
-                println!("{}", t);
-            }
+        let short_names = dist::short_toolchain_names(&installed_toolchains);
+        for (t, short_name) in installed_toolchains.iter().zip(short_names) {
+            let if_default = if default_name.as_ref() == Some(t) { " (default)" } else { "" };
+            println!("{}{}{}", short_name, if_default, common::link_suffix(cfg, t));
         }
         if show_headers { println!("") };
     }
@@ -356,11 +571,11 @@ fn show(cfg: &Cfg) -> Result<()> {
             Ok(atc) => {
                 match atc {
                     Some((ref toolchain, Some(ref reason))) => {
-                        println!("{} ({})", toolchain.name(), reason);
+                        println!("{} ({}){}", toolchain.name(), reason, common::link_suffix(cfg, toolchain.name()));
                         println!("{}", common::lean_version(toolchain));
                     }
                     Some((ref toolchain, None)) => {
-                        println!("{} (default)", toolchain.name());
+                        println!("{} (default){}", toolchain.name(), common::link_suffix(cfg, toolchain.name()));
                         println!("{}", common::lean_version(toolchain));
                     }
                     None => {
@@ -395,7 +610,7 @@ fn show(cfg: &Cfg) -> Result<()> {
 fn explicit_or_dir_toolchain<'a>(cfg: &'a Cfg, m: &ArgMatches) -> Result<Toolchain<'a>> {
     let toolchain = m.value_of("toolchain");
     if let Some(toolchain) = toolchain {
-        let toolchain = try!(cfg.get_toolchain(toolchain, false));
+        let toolchain = try!(cfg.select_toolchain(toolchain));
         return Ok(toolchain);
     }
 
@@ -408,31 +623,70 @@ fn explicit_or_dir_toolchain<'a>(cfg: &'a Cfg, m: &ArgMatches) -> Result<Toolcha
 fn toolchain_link(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
     let ref toolchain = m.value_of("toolchain").expect("");
     let ref path = m.value_of("path").expect("");
+    if m.is_present("dry-run") {
+        return common::show_toolchain_dir_report(&validate_toolchain_dir(Path::new(path)));
+    }
     let toolchain = try!(cfg.get_toolchain(toolchain, true));
 
     Ok(try!(toolchain.install_from_dir(Path::new(path), true)))
 }
 
+fn toolchain_export(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
+    let toolchain = m.value_of("toolchain").expect("");
+    let dir = m.value_of("dir").expect("");
+    let bundle = try!(cfg.export_manifest_bundle(toolchain, Path::new(dir)));
+    println!("exported {} to {}", bundle.name, dir);
+    Ok(())
+}
+
+fn toolchain_import(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
+    let dir = m.value_of("dir").expect("");
+    let (name, status) = try!(cfg.import_toolchain(Path::new(dir)));
+    common::show_channel_update(cfg, &name, Ok(status))
+}
+
 fn toolchain_remove(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
     for toolchain in m.values_of("toolchain").expect("") {
         let toolchain = try!(cfg.get_toolchain(toolchain, false));
-        try!(toolchain.remove());
+        if m.is_present("force") {
+            try!(toolchain.force_remove());
+        } else {
+            try!(toolchain.remove());
+        }
+    }
+    Ok(())
+}
+
+fn toolchain_layout(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
+    let layout = match m.value_of("layout").expect("") {
+        "nested" => ToolchainLayout::Nested,
+        _ => ToolchainLayout::Flat,
+    };
+    Ok(try!(cfg.set_toolchain_layout(layout)))
+}
+
+fn toolchain_readonly(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
+    let ref toolchain = m.value_of("toolchain").expect("");
+    if m.is_present("clear") {
+        try!(cfg.clear_readonly(toolchain));
+    } else {
+        try!(cfg.mark_readonly(toolchain));
     }
     Ok(())
 }
 
 fn override_add(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
     let ref toolchain = m.value_of("toolchain").expect("");
-    let toolchain = try!(cfg.get_toolchain(toolchain, false));
+    let toolchain = try!(cfg.select_toolchain(toolchain));
 
+    let ref cwd = try!(utils::current_dir());
     let status = if !toolchain.exists() || !toolchain.is_custom() {
-        Some(try!(toolchain.install_from_dist_if_not_installed()))
+        Some(try!(toolchain.install_and_override(cwd)))
     } else {
+        try!(toolchain.make_override(cwd));
         None
     };
 
-    try!(toolchain.make_override(&try!(utils::current_dir())));
-
     if let Some(status) = status {
         println!("");
         try!(common::show_channel_update(cfg, toolchain.name(), Ok(status)));
@@ -442,62 +696,167 @@ fn override_add(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
 }
 
 fn override_remove(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
-    let paths = if m.is_present("nonexistent") {
-        let list: Vec<_> = try!(cfg.settings_file.with(|s| Ok(s.overrides.iter().filter_map(|(k, _)|
-            if Path::new(k).is_dir() {
-                None
-            } else {
-                Some(k.clone())
-            }
-        ).collect())));
-        if list.is_empty() {
+    if m.is_present("nonexistent") {
+        let removed = try!(cfg.prune_overrides());
+        if removed.is_empty() {
             info!("no nonexistent paths detected");
         }
-        list
+        for path in removed {
+            info!("override toolchain for '{}' removed", path);
+        }
+        return Ok(());
+    }
+
+    let path = if m.is_present("path") {
+        m.value_of("path").unwrap().to_string()
     } else {
-        if m.is_present("path") {
-            vec![m.value_of("path").unwrap().to_string()]
-        } else {
-            vec![try!(utils::current_dir()).to_str().unwrap().to_string()]
+        try!(utils::current_dir()).to_str().unwrap().to_string()
+    };
+
+    if try!(cfg.settings_file.with_mut(|s| {
+        Ok(s.remove_override(&Path::new(&path), cfg.notify_handler.as_ref()))
+    })) {
+        info!("override toolchain for '{}' removed", path);
+    } else {
+        info!("no override toolchain for '{}'", path);
+        if !m.is_present("path") {
+            info!("you may use `--path <path>` option to remove override toolchain \
+                   for a specific path");
         }
+    }
+    Ok(())
+}
+
+fn lock(cfg: &Cfg) -> Result<()> {
+    if let Some((path, lock)) = try!(cfg.update_lockfile(&try!(utils::current_dir()), false)) {
+        println!("'{}' is pinned to '{}' in '{}'", lock.requested, lock.resolved, path.display());
+    }
+    Ok(())
+}
+
+fn check_update(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
+    let toolchain = match m.value_of("toolchain") {
+        Some(name) => try!(cfg.select_toolchain(name)),
+        None => try!(cfg.toolchain_for_dir_without_installing(&try!(utils::current_dir()))).0,
     };
 
-    for path in paths {
-        if try!(cfg.settings_file.with_mut(|s| {
-            Ok(s.remove_override(&Path::new(&path), cfg.notify_handler.as_ref()))
-        })) {
-            info!("override toolchain for '{}' removed", path);
+    if m.is_present("refresh") {
+        // This is the detached background process; make sure it never lingers
+        thread::spawn(|| {
+            thread::sleep(Duration::from_secs(update_check::TIMEOUT_SECS));
+            process::exit(1);
+        });
+        try!(cfg.refresh_update_check(&toolchain));
+        return Ok(());
+    }
+
+    let max_age = match m.value_of("max-age") {
+        None => update_check::DEFAULT_MAX_AGE_SECS,
+        Some(s) => try!(s.parse::<u64>().map_err(|_| format!("invalid value for '--max-age': '{}'", s))) * 60,
+    };
+    match cfg.cached_update_check(&toolchain, Duration::from_secs(max_age)) {
+        Some(check) => {
+            if let Some(latest) = check.latest {
+                println!("{}", latest);
+            }
+        }
+        None => {
+            try!(cfg.spawn_update_check(&toolchain));
+        }
+    }
+    Ok(())
+}
+
+fn check(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
+    if m.is_present("frozen") {
+        let missing = try!(cfg.find_missing_toolchains(&try!(utils::current_dir())));
+        if missing.is_empty() {
+            info!("all referenced toolchains are installed");
+            return Ok(());
+        }
+        for &(ref name, ref referrers) in &missing {
+            warn!("'{}' is not installed, but referenced by {}", name, referrers.join(", "));
+        }
+        return Err(ErrorKind::MissingToolchains(missing.into_iter().map(|(name, _)| name).collect()).into());
+    }
+
+    let toolchain = match m.value_of("toolchain") {
+        Some(name) => try!(cfg.select_toolchain(name)),
+        None => try!(cfg.toolchain_for_dir_without_installing(&try!(utils::current_dir()))).0,
+    };
+
+    match try!(toolchain.is_up_to_date()) {
+        UpdateAvailability::UpToDate => println!("{} is up to date", toolchain.name()),
+        UpdateAvailability::UpdateAvailable { latest, behind: Some(1) } => {
+            println!("{} is 1 release behind {}", toolchain.name(), latest)
+        }
+        UpdateAvailability::UpdateAvailable { latest, behind: Some(n) } => {
+            println!("{} is {} releases behind {}", toolchain.name(), n, latest)
+        }
+        UpdateAvailability::UpdateAvailable { latest, behind: None } => {
+            println!("{} can be updated to {}", toolchain.name(), latest)
+        }
+        UpdateAvailability::NotTracking => {
+            println!("{} is not tracking a channel and is never updated", toolchain.name())
+        }
+    }
+    Ok(())
+}
+
+fn doctor(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
+    if m.is_present("fix") {
+        let fixed = try!(cfg.doctor_fix());
+        let remaining = try!(cfg.verify_all());
+        if fixed.is_empty() && remaining.is_empty() {
+            info!("no problems found");
+        }
+        for problem in remaining {
+            warn!("{}", problem);
+        }
+    } else {
+        let problems = try!(cfg.verify_all());
+        if problems.is_empty() {
+            info!("no problems found");
         } else {
-            info!("no override toolchain for '{}'", path);
-            if !m.is_present("path") && !m.is_present("nonexistent") {
-                info!("you may use `--path <path>` option to remove override toolchain \
-                       for a specific path");
+            for problem in &problems {
+                warn!("{}", problem);
+            }
+            if problems.iter().any(|p| p.is_fixable()) {
+                info!("run `elan doctor --fix` to try to fix them");
             }
         }
     }
     Ok(())
 }
 
+fn clean(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
+    fn number(m: &ArgMatches, arg: &str) -> Result<Option<u64>> {
+        match m.value_of(arg) {
+            None => Ok(None),
+            Some(s) => s.parse().map(Some)
+                .map_err(|_| format!("invalid value for '--{}': '{}'", arg, s).into()),
+        }
+    }
+    let max_age = try!(number(m, "older-than")).map(|days| Duration::from_secs(days * 24 * 60 * 60));
+    let max_size = try!(number(m, "max-size")).map(|mb| mb * 1024 * 1024);
+
+    // Without any limits, remove everything
+    let max_size = if max_age.is_none() && max_size.is_none() { Some(0) } else { max_size };
+
+    try!(cfg.prune_download_dir(max_age, max_size));
+    Ok(())
+}
+
 fn doc(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
-    let doc_url = if m.is_present("book") {
-        "book/index.html"
-    } else if m.is_present("std") {
-        "std/index.html"
-    } else {
-        "index.html"
-    };
+    let component = m.value_of("component").unwrap_or("lean");
 
-    Ok(try!(cfg.open_docs_for_dir(&try!(utils::current_dir()), doc_url)))
+    Ok(try!(cfg.open_docs_for_dir(&try!(utils::current_dir()), component, "index.html")))
 }
 
 fn man(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
     let manpage = m.value_of("command").expect("");
     let toolchain = try!(explicit_or_dir_toolchain(cfg, m));
-    let mut man_path = toolchain.path().to_path_buf();
-    man_path.push("share");
-    man_path.push("man");
-    man_path.push("man1");
-    man_path.push(manpage.to_owned() + ".1");
+    let man_path = toolchain.man_dir().join(manpage.to_owned() + ".1");
     try!(utils::assert_is_file(&man_path));
     Command::new("man")
         .arg(man_path)
@@ -506,12 +865,23 @@ fn man(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
-fn self_uninstall(m: &ArgMatches) -> Result<()> {
-    let no_prompt = m.is_present("no-prompt");
+fn self_uninstall(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
+    let mut no_prompt = m.is_present("no-prompt");
+    if !no_prompt && cfg.non_interactive.is_some() {
+        try!(cfg.confirm("uninstall elan and all toolchains"));
+        no_prompt = true;
+    }
 
     self_update::uninstall(no_prompt)
 }
 
+fn self_move_home(cfg: &Cfg, m: &ArgMatches) -> Result<()> {
+    let dest = try!(cfg.move_elan_home(Path::new(m.value_of("destination").unwrap()), m.is_present("keep")));
+    info!("moved elan home to '{}'", dest.display());
+    info!("set ELAN_HOME to '{}' and add '{}' to your PATH", dest.display(), dest.join("bin").display());
+    Ok(())
+}
+
 fn set_telemetry(cfg: &Cfg, t: TelemetryMode) -> Result<()> {
     match t {
         TelemetryMode::On => Ok(try!(cfg.set_telemetry(true))),
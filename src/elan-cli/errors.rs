@@ -37,8 +37,42 @@ error_chain! {
             description("elan is not installed")
             display("elan is not installed at '{}'", p.display())
         }
+        InvalidToolchainDir(p: PathBuf) {
+            description("not a toolchain directory")
+            display("'{}' does not contain a toolchain", p.display())
+        }
+        ToolchainUpdatesFailed(n: usize) {
+            description("some toolchains failed to update")
+            display("{} toolchains failed to update", n)
+        }
+        MissingToolchains(t: Vec<String>) {
+            description("referenced toolchains are not installed")
+            display("these toolchains are missing: {}", t.join(", "))
+        }
         WindowsUninstallMadness {
             description("failure during windows uninstall")
         }
     }
 }
+
+impl Error {
+    /// The exit code for this error, see `elan::codes`
+    pub fn error_code(&self) -> u32 {
+        use std::error::Error as StdError;
+
+        let code = match *self.kind() {
+            ErrorKind::Elan(ref kind) => kind.error_code(),
+            ErrorKind::Dist(ref kind) => kind.error_code(),
+            ErrorKind::Utils(ref kind) => kind.error_code(),
+            ErrorKind::Temp(ref e) => e.error_code(),
+            ErrorKind::ToolchainNotInstalled(_) |
+            ErrorKind::MissingToolchains(_) => elan::codes::NOT_INSTALLED,
+            ErrorKind::InvalidToolchainDir(_) => elan::codes::INVALID_TOOLCHAIN,
+            _ => elan::codes::FAILURE,
+        };
+        match self.source() {
+            Some(cause) if code == elan::codes::FAILURE => elan::error_code(cause),
+            _ => code,
+        }
+    }
+}
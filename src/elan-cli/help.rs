@@ -20,13 +20,22 @@ r"DISCUSSION:
     updates elan itself.
 
     If given a toolchain argument then `update` updates that
-    toolchain, the same as `elan toolchain install`.";
+    toolchain, the same as `elan toolchain install`.
+
+    Without a toolchain argument, an `elan.lock` of the current
+    project is also updated to the release its toolchain now resolves
+    to. With `--locked`, or if `ELAN_LOCKED=1` is set, `update` fails
+    instead if the lockfile is missing or out of date.";
 
 pub static INSTALL_HELP: &'static str =
 r"DISCUSSION:
     Installs a specific lean toolchain.
 
-    The 'install' command is an alias for 'elan update <toolchain>'.";
+    The 'install' command is an alias for 'elan update <toolchain>'.
+
+    If no default toolchain is set yet, the installed toolchain becomes
+    the default. Set ELAN_AUTO_DEFAULT=0 to disable this, and pass
+    `--default` to make any installed toolchain the default.";
 
 pub static DEFAULT_HELP: &'static str =
 r"DISCUSSION:
@@ -52,13 +61,24 @@ r"DISCUSSION:
     number, such as '3.3.0'. Channel names can be optionally appended
     with an archive date, as in 'nightly-2018-04-10', in which case
     the toolchain is downloaded from the archive for that date.
+    A version number without a patch component, such as 'v4.1', tracks
+    the newest release matching it.
     'origin' can be used to refer to custom forks of Lean on Github;
     the default is 'leanprover/lean'. For nightly versions, '-nightly'
     is appended to the value of 'origin'.
 
     elan can also manage symlinked local toolchain builds, which are
     often used to for developing Lean itself. For more information see
-    `elan toolchain help link`.";
+    `elan toolchain help link`.
+
+    Frequently used toolchain names can be abbreviated by aliases in
+    the `[aliases]` table of `settings.toml` in the elan home directory:
+
+        [aliases]
+        lean4 = 'leanprover/lean4:nightly'
+
+    An installed toolchain of the same name takes precedence over an
+    alias.";
 
 pub static TOOLCHAIN_LINK_HELP: &'static str =
 r"DISCUSSION:
@@ -109,6 +129,63 @@ r"DISCUSSION:
     directories. Otherwise, removes the override toolchain for the
     current directory.";
 
+pub static CHECK_HELP: &'static str =
+r"DISCUSSION:
+    With `--frozen`, checks that every toolchain referenced by the
+    default, the overrides and the current project, including its
+    `elan.lock`, is installed, and fails listing the missing ones
+    otherwise. Nothing is installed and the network is never accessed,
+    so CI pipelines can run `elan check --frozen` to fail early.";
+
+pub static CHECK_UPDATE_HELP: &'static str =
+r"DISCUSSION:
+    Meant for shell prompts and editors. Prints the newer release of
+    the active (or given) tracking toolchain if the last check found
+    one, and prints nothing otherwise. This never waits for the
+    network: if the cached result is older than `--max-age`, a new
+    check is started in the background and its result is shown by
+    later invocations.
+
+    Set `ELAN_NO_UPDATE_CHECK=1` to disable background checks.";
+
+pub static LOCK_HELP: &'static str =
+r"DISCUSSION:
+    Resolves the toolchain named by the project's `lean-toolchain` or
+    `leanpkg.toml` file and records the release it stands for in an
+    `elan.lock` file next to it. As long as the file requests the same
+    toolchain, elan then uses the pinned release instead, so that
+    everyone working on the project gets the same toolchain. Commit
+    `elan.lock` alongside the project and run `elan update` to move
+    the pin to a newer release.
+
+    If `ELAN_LOCKED=1` is set, tracking project toolchains must be
+    pinned by a lockfile that matches the requested toolchain.";
+
+pub static CLEAN_HELP: &'static str =
+r"DISCUSSION:
+    Removes downloads kept in the download cache (see
+    `ELAN_KEEP_DOWNLOADS`). Without arguments, all cached downloads
+    are removed. With `--older-than`, only downloads older than the
+    given number of days are removed. With `--max-size`, the oldest
+    downloads are removed until the cache fits into the given number
+    of megabytes.
+
+    Setting `ELAN_DOWNLOAD_CACHE_LIMIT` to a number of megabytes
+    prunes the cache to that size after every install.";
+
+pub static SELF_MOVE_HOME_HELP: &'static str =
+r"DISCUSSION:
+    Moves the elan home, `~/.elan` unless `ELAN_HOME` is set, to the
+    given directory, which must not exist yet. Overrides for
+    directories inside the home and linked toolchains pointing into it
+    are updated to the new location. Nothing is changed if any
+    installed toolchain would stop working.
+
+    With `--keep`, the old home is left in place as a copy.
+
+    Afterwards, set `ELAN_HOME` to the new location and replace the old
+    `bin` directory on your `PATH` with the new one.";
+
 pub static RUN_HELP: &'static str =
 r"DISCUSSION:
     Configures an environment to use the given toolchain and then runs
@@ -123,15 +200,24 @@ r"DISCUSSION:
 
         $ leanpkg +nightly build
 
-        $ elan run nightly leanpkg build";
+        $ elan run nightly leanpkg build
+
+    With `--cwd`, the command is run in the given directory and elan
+    waits for it to exit, passing on Ctrl-C and termination requests,
+    then exits with the command's exit code.
+
+    With `--ephemeral`, a toolchain that isn't installed yet is
+    installed for running the command and removed again once it has
+    exited, like with `--cwd`.";
 
-pub static _DOC_HELP: &'static str =
+pub static DOC_HELP: &'static str =
 r"DISCUSSION:
     Opens the documentation for the currently active toolchain with
     the default browser.
 
-    By default, it opens the documentation index. Use the various
-    flags to open specific pieces of documentation.";
+    By default, it opens the index of Lean's documentation. With
+    `--component`, the documentation of another library shipped with
+    the toolchain is opened instead.";
 
 pub static COMPLETIONS_HELP: &'static str =
 r"DISCUSSION:
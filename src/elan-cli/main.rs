@@ -67,7 +67,7 @@ use elan::env_var::LEAN_RECURSION_COUNT_MAX;
 fn main() {
     if let Err(ref e) = run_elan() {
         common::report_error(e);
-        std::process::exit(1);
+        std::process::exit(e.error_code() as i32);
     }
 }
 
@@ -535,7 +535,7 @@ fn maybe_install_lean(toolchain_str: &str, verbose: bool) -> Result<()> {
     } else if try!(cfg.find_default()).is_none() {
         let toolchain = try!(cfg.get_toolchain(toolchain_str, false));
         let status = try!(toolchain.install_from_dist(false));
-        try!(cfg.set_default(toolchain_str));
+        try!(cfg.set_default_if_unset(toolchain_str));
         println!("");
         try!(common::show_channel_update(cfg, toolchain_str, Ok(status)));
     } else {
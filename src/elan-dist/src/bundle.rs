@@ -0,0 +1,248 @@
+//! Bundles of a toolchain's release archive, for installing it on machines
+//! without network access. A bundle is a directory holding the archive for
+//! one platform next to an `elan-bundle.toml` that describes it:
+//!
+//! ```toml
+//! version = "1"
+//!
+//! [toolchain]
+//! name = "leanprover/lean4:v4.1.0"
+//! url = "https://github.com/leanprover/lean4/releases/tag/v4.1.0"
+//! target = "linux"
+//! archive = "lean-4.1.0-linux.tar.gz"
+//! sha256 = "..."
+//! ```
+//!
+//! `name` is always a pinned release, even if a tracking toolchain like
+//! `stable` was exported, and `url` the release page it was resolved to.
+//! `archive` is relative to the bundle and checked against `sha256` when
+//! it is installed.
+
+use toml;
+use sha2::{Digest, Sha256};
+
+use elan_utils::toml_utils::*;
+use elan_utils::utils;
+use dist::{self, ToolchainDesc};
+use download::DownloadCfg;
+use errors::*;
+use manifestation::{self, Manifestation};
+use prefix::InstallPrefix;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const BUNDLE_MANIFEST_NAME: &'static str = "elan-bundle.toml";
+
+pub const SUPPORTED_BUNDLE_VERSIONS: [&'static str; 1] = ["1"];
+pub const DEFAULT_BUNDLE_VERSION: &'static str = "1";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bundle {
+    pub name: String,
+    pub url: String,
+    pub target: String,
+    pub archive: String,
+    pub sha256: String,
+}
+
+impl Bundle {
+    pub fn from_toml(mut table: toml::value::Table, path: &str) -> Result<Self> {
+        let version = try!(get_string(&mut table, "version", path));
+        if !SUPPORTED_BUNDLE_VERSIONS.contains(&&*version) {
+            return Err(ErrorKind::UnsupportedVersion(version).into());
+        }
+        let mut toolchain = try!(get_table(&mut table, "toolchain", path));
+        let path = format!("{}toolchain.", path);
+        Ok(Bundle {
+            name: try!(get_string(&mut toolchain, "name", &path)),
+            url: try!(get_string(&mut toolchain, "url", &path)),
+            target: try!(get_string(&mut toolchain, "target", &path)),
+            archive: try!(get_string(&mut toolchain, "archive", &path)),
+            sha256: try!(get_string(&mut toolchain, "sha256", &path)),
+        })
+    }
+    pub fn to_toml(self) -> toml::value::Table {
+        let mut toolchain = toml::value::Table::new();
+        toolchain.insert("name".to_owned(), toml::Value::String(self.name));
+        toolchain.insert("url".to_owned(), toml::Value::String(self.url));
+        toolchain.insert("target".to_owned(), toml::Value::String(self.target));
+        toolchain.insert("archive".to_owned(), toml::Value::String(self.archive));
+        toolchain.insert("sha256".to_owned(), toml::Value::String(self.sha256));
+
+        let mut result = toml::value::Table::new();
+        result.insert("version".to_owned(),
+                      toml::Value::String(DEFAULT_BUNDLE_VERSION.to_owned()));
+        result.insert("toolchain".to_owned(), toml::Value::Table(toolchain));
+        result
+    }
+
+    pub fn parse(data: &str) -> Result<Self> {
+        let value = toml::from_str(data).map_err(ErrorKind::Parsing)?;
+        Self::from_toml(value, "")
+    }
+
+    pub fn stringify(self) -> String {
+        toml::Value::Table(self.to_toml()).to_string()
+    }
+
+    /// Reads the manifest of the bundle in `dir`
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(BUNDLE_MANIFEST_NAME);
+        let data = try!(utils::read_file("bundle manifest", &path)
+                        .chain_err(|| ErrorKind::InvalidBundle(dir.to_owned())));
+        Bundle::parse(&data).chain_err(|| ErrorKind::InvalidBundle(dir.to_owned()))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        Ok(try!(utils::write_file_atomic("bundle manifest", &dir.join(BUNDLE_MANIFEST_NAME),
+                                         &self.clone().stringify())))
+    }
+}
+
+fn file_hash(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut file = try!(fs::File::open(path).chain_err(|| "opening bundled archive"));
+    try!(io::copy(&mut file, &mut hasher).chain_err(|| "reading bundled archive"));
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Downloads the archive of `toolchain` for the host platform into `dir`
+/// and describes it there, pinning tracking toolchains to the release they
+/// currently stand for
+pub fn export(download: DownloadCfg, toolchain: &ToolchainDesc, dir: &Path) -> Result<Bundle> {
+    let url = try!(dist::resolve_toolchain_url(toolchain, download.include_prereleases, download.notify_handler));
+    let tag = try!(dist::release_tag(&url)
+                   .ok_or_else(|| format!("unexpected release url '{}'", url)));
+    let origin = dist::build_origin_name(toolchain.origin.as_ref(), &toolchain.channel);
+    let archive_url = try!(manifestation::archive_url(&origin, &url, download));
+    let archive = archive_url.rsplit('/').next().unwrap_or("").to_owned();
+    if archive.is_empty() {
+        return Err(format!("unexpected archive url '{}'", archive_url).into());
+    }
+
+    let file = try!(download.download_artifact(&archive_url, manifestation::archive_ext()));
+    try!(download.verify_artifact(&file, &archive_url));
+    try!(utils::ensure_dir_exists("bundle", dir, &|n| (download.notify_handler)(n.into())));
+    try!(utils::hardlink_or_copy_file(&file, &dir.join(&archive)));
+
+    let bundle = Bundle {
+        name: toolchain.at_release(tag).name(),
+        url: url,
+        target: manifestation::informal_target().to_owned(),
+        archive: archive.clone(),
+        sha256: try!(file_hash(&dir.join(&archive))),
+    };
+    try!(bundle.save(dir));
+    Ok(bundle)
+}
+
+/// Installs the toolchain bundled in `dir` into `prefix` without network
+/// access
+pub fn install(download: DownloadCfg, dir: &Path, prefix: &InstallPrefix) -> Result<Bundle> {
+    let bundle = try!(Bundle::load(dir));
+    if bundle.target != manifestation::informal_target() {
+        return Err(ErrorKind::BundleTargetMismatch(bundle.target, manifestation::informal_target().to_owned()).into());
+    }
+    // Archives must stay inside the bundle
+    if bundle.archive.contains(|c| c == '/' || c == '\\') || bundle.archive.starts_with('.') {
+        return Err(ErrorKind::InvalidBundle(dir.to_owned()).into());
+    }
+    let manifestation = try!(Manifestation::open(prefix.clone()));
+    try!(manifestation.install_from_file(&dir.join(&bundle.archive), &bundle.sha256, &bundle.url, download));
+    Ok(bundle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elan_utils::test_dir::TestDir;
+    use manifest::Profile;
+    use notifications::Notification;
+    use temp;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tar;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn test_bundle_manifest_round_trip() {
+        let bundle = Bundle {
+            name: "leanprover/lean4:v4.1.0".to_owned(),
+            url: "https://github.com/leanprover/lean4/releases/tag/v4.1.0".to_owned(),
+            target: "linux".to_owned(),
+            archive: "lean-4.1.0-linux.tar.gz".to_owned(),
+            sha256: "0".repeat(64),
+        };
+        assert_eq!(Bundle::parse(&bundle.clone().stringify()).unwrap(), bundle);
+        assert!(Bundle::parse("version = \"2\"\n[toolchain]\n").is_err());
+        assert!(Bundle::parse("version = \"1\"\n[toolchain]\nname = \"stable\"\n").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_export_and_install_bundle() {
+        let root = TestDir::new("bundle");
+
+        // A mirror of the release page and its archive
+        let mirror = root.join("mirror");
+        let release = mirror.join("leanprover/lean4/releases");
+        let download = "/leanprover/lean4/releases/download/v4.1.0/lean-4.1.0-linux.tar.gz";
+        fs::create_dir_all(release.join("tag")).unwrap();
+        fs::create_dir_all(release.join("download/v4.1.0")).unwrap();
+        fs::write(release.join("tag/v4.1.0"), format!("<a href=\"{}\">linux</a>", download)).unwrap();
+        {
+            let archive = fs::File::create(mirror.join(&download[1..])).unwrap();
+            let mut builder = tar::Builder::new(GzEncoder::new(archive, Compression::default()));
+            let contents = b"#!/bin/sh\n";
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o755);
+            header.set_cksum();
+            builder.append_data(&mut header, "lean-4.1.0-linux/bin/lean", &contents[..]).unwrap();
+            builder.into_inner().unwrap().finish().unwrap();
+        }
+
+        let temp_cfg = temp::Cfg::new(root.join("tmp"), Box::new(|_| ()));
+        let download_dir = root.join("downloads");
+        let cancel = AtomicBool::new(false);
+        let notify = |_: Notification| ();
+        let dist_servers = vec![format!("file://{}", mirror.display())];
+        let mut cfg = DownloadCfg {
+            temp_cfg: &temp_cfg,
+            download_dir: &download_dir,
+            keep_downloads: false,
+            include_prereleases: false,
+            profile: Profile::Default,
+            cancel: &cancel,
+            dist_servers: &dist_servers,
+            url_rewrites: &[],
+            disk_space_factor: None,
+            delta_updates: false,
+            provenance: None,
+            deterministic_extract: false,
+            check_case: false,
+            notify_handler: &notify,
+        };
+
+        let dir = root.join("bundle");
+        let desc = ToolchainDesc::from_str("leanprover/lean4:v4.1.0").unwrap();
+        let bundle = export(cfg, &desc, &dir).unwrap();
+        assert_eq!(bundle.name, "leanprover/lean4:v4.1.0");
+        assert_eq!(bundle.archive, "lean-4.1.0-linux.tar.gz");
+        assert_eq!(Bundle::load(&dir).unwrap(), bundle);
+
+        // Installing needs nothing but the bundle
+        fs::remove_dir_all(&mirror).unwrap();
+        cfg.dist_servers = &[];
+        let prefix = root.join("toolchain");
+        assert_eq!(install(cfg, &dir, &InstallPrefix::from(prefix.clone())).unwrap(), bundle);
+        assert!(prefix.join("bin/lean").is_file());
+
+        // Corrupted archives are rejected and leave the toolchain alone
+        fs::write(dir.join(&bundle.archive), "corrupt").unwrap();
+        assert!(install(cfg, &dir, &InstallPrefix::from(prefix.clone())).is_err());
+        assert!(prefix.join("bin/lean").is_file());
+    }
+}
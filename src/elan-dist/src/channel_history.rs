@@ -0,0 +1,64 @@
+//! Append-only records of the releases a tracking toolchain like `stable`
+//! resolved to over time, one `<seconds since the Unix epoch> <toolchain>`
+//! line per change.
+
+use elan_utils::utils;
+use dist::ToolchainDesc;
+use errors::*;
+
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Reads the history at `path`, oldest first. A missing file is an empty
+/// history; lines that don't parse, e.g. one cut short by a crash, are
+/// skipped.
+pub fn read(path: &Path) -> Result<Vec<(SystemTime, ToolchainDesc)>> {
+    if !utils::is_file(path) {
+        return Ok(vec![]);
+    }
+    let contents = try!(utils::read_file("channel history", path));
+    Ok(contents.lines().filter_map(|line| {
+        let mut parts = line.splitn(2, ' ');
+        let secs = parts.next().and_then(|s| s.parse::<u64>().ok());
+        let desc = parts.next().and_then(|s| ToolchainDesc::from_str(s).ok());
+        match (secs, desc) {
+            (Some(secs), Some(desc)) => Some((UNIX_EPOCH + Duration::from_secs(secs), desc)),
+            _ => None,
+        }
+    }).collect())
+}
+
+/// Appends `resolved` to the history at `path` unless it is already the
+/// latest entry
+pub fn record(path: &Path, time: SystemTime, resolved: &ToolchainDesc) -> Result<()> {
+    let history = try!(read(path));
+    if history.last().map(|&(_, ref last)| last.name()) == Some(resolved.name()) {
+        return Ok(());
+    }
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    Ok(try!(utils::append_file("channel history", path, &format!("{} {}", secs, resolved.name()))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elan_utils::test_dir::TestDir;
+
+    #[test]
+    fn test_channel_history() {
+        let dir = TestDir::new("channel-history");
+        let path = dir.join("stable");
+        assert!(read(&path).unwrap().is_empty());
+
+        let v1 = ToolchainDesc::from_str("leanprover/lean4:v4.0.0").unwrap();
+        let v2 = ToolchainDesc::from_str("leanprover/lean4:v4.1.0").unwrap();
+        let t = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        record(&path, t(100), &v1).unwrap();
+        record(&path, t(200), &v1).unwrap();
+        record(&path, t(300), &v2).unwrap();
+        utils::append_file("channel history", &path, "400 leanprover/le").unwrap();
+
+        let history: Vec<_> = read(&path).unwrap().into_iter().map(|(time, desc)| (time, desc.name())).collect();
+        assert_eq!(history, vec![(t(100), v1.name()), (t(300), v2.name())]);
+    }
+}
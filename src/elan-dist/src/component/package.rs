@@ -9,34 +9,137 @@ extern crate filetime;
 use errors::*;
 use temp;
 
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::io::{Read,Seek,self};
 use std::fs::{File,self};
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
 use zip::ZipArchive;
 
 #[derive(Debug)]
 pub struct TarPackage<'a>(temp::Dir<'a>);
 
 impl<'a> TarPackage<'a> {
-    pub fn unpack<R: Read>(stream: R, path: &Path) -> Result<()> {
+    pub fn unpack<R: Read>(stream: R, path: &Path, opts: ExtractOptions) -> Result<()> {
         let mut archive = tar::Archive::new(stream);
         // The lean-installer packages unpack to a directory called
         // $pkgname-$version-$target. Skip that directory when
         // unpacking.
-        unpack_without_first_dir(&mut archive, path)
+        unpack_without_first_dir(&mut archive, path, opts)
     }
 }
 
-fn unpack_without_first_dir<R: Read>(archive: &mut tar::Archive<R>, path: &Path) -> Result<()> {
+/// How archives are extracted
+#[derive(Clone, Copy, Debug)]
+pub struct ExtractOptions<'a> {
+    /// Extraction is aborted between entries once this is set
+    pub cancel: &'a AtomicBool,
+    /// Whether extracted trees get a fixed mtime and only keep whether
+    /// files are executable, so that two extractions of the same archive
+    /// are identical, e.g. for deduplicating them by hash
+    pub deterministic: bool,
+    /// Whether entries whose paths only differ in case are rejected, as
+    /// they would overwrite each other on case-insensitive file systems
+    pub check_case: bool,
+}
+
+impl<'a> ExtractOptions<'a> {
+    pub fn new(cancel: &'a AtomicBool) -> Self {
+        ExtractOptions {
+            cancel: cancel,
+            deterministic: false,
+            check_case: cfg!(any(windows, target_os = "macos")),
+        }
+    }
+}
+
+fn check_cancelled(cancel: &AtomicBool) -> Result<()> {
+    if cancel.load(Ordering::SeqCst) {
+        Err(ErrorKind::Cancelled.into())
+    } else {
+        Ok(())
+    }
+}
+
+// See `ExtractOptions::check_case`
+struct CaseCollisions {
+    seen: Option<HashMap<String, PathBuf>>,
+}
+
+impl CaseCollisions {
+    fn new(opts: ExtractOptions) -> Self {
+        CaseCollisions { seen: if opts.check_case { Some(HashMap::new()) } else { None } }
+    }
+
+    // Checks the entry and each of its ancestors, so that `Foo/a` and
+    // `foo/b` collide as well
+    fn check(&mut self, relpath: &Path) -> Result<()> {
+        if let Some(ref mut seen) = self.seen {
+            let mut prefix = PathBuf::new();
+            for component in relpath.components() {
+                prefix.push(component);
+                let key = prefix.to_string_lossy().to_lowercase();
+                if let Some(other) = seen.get(&key) {
+                    if *other != prefix {
+                        return Err(ErrorKind::CaseInsensitiveCollision {
+                            a: other.clone(),
+                            b: prefix,
+                        }.into());
+                    }
+                    continue;
+                }
+                seen.insert(key, prefix.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+// See `ExtractOptions::deterministic`
+const NORMALIZED_MTIME: i64 = 1;
+
+fn normalize_tree(path: &Path, opts: ExtractOptions) -> Result<()> {
+    if !opts.deterministic {
+        return Ok(());
+    }
+    let mtime = filetime::FileTime::from_unix_time(NORMALIZED_MTIME, 0);
+    for entry in WalkDir::new(path).contents_first(true) {
+        let entry = try!(entry.chain_err(|| ErrorKind::ExtractingPackage));
+        if entry.path_is_symlink() {
+            try!(filetime::set_symlink_file_times(entry.path(), mtime, mtime)
+                 .chain_err(|| ErrorKind::ExtractingPackage));
+            continue;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let metadata = try!(entry.metadata().chain_err(|| ErrorKind::ExtractingPackage));
+            let executable = metadata.is_dir() || metadata.permissions().mode() & 0o111 != 0;
+            let mode = if executable { 0o755 } else { 0o644 };
+            try!(fs::set_permissions(entry.path(), fs::Permissions::from_mode(mode))
+                 .chain_err(|| ErrorKind::ExtractingPackage));
+        }
+        try!(filetime::set_file_times(entry.path(), mtime, mtime).chain_err(|| ErrorKind::ExtractingPackage));
+    }
+    Ok(())
+}
+
+fn unpack_without_first_dir<R: Read>(archive: &mut tar::Archive<R>, path: &Path, opts: ExtractOptions) -> Result<()> {
     let entries = try!(archive.entries().chain_err(|| ErrorKind::ExtractingPackage));
+    let mut collisions = CaseCollisions::new(opts);
     for entry in entries {
+        try!(check_cancelled(opts.cancel));
         let mut entry = try!(entry.chain_err(|| ErrorKind::ExtractingPackage));
         let relpath = {
             let path = entry.path();
             let path = try!(path.chain_err(|| ErrorKind::ExtractingPackage));
             path.into_owned()
         };
+        try!(collisions.check(&relpath));
         let mut components = relpath.components();
         // Throw away the first path component
         components.next();
@@ -52,14 +155,14 @@ fn unpack_without_first_dir<R: Read>(archive: &mut tar::Archive<R>, path: &Path)
         try!(entry.unpack(&full_path).chain_err(|| ErrorKind::ExtractingPackage));
     }
 
-    Ok(())
+    normalize_tree(path, opts)
 }
 
 #[derive(Debug)]
 pub struct ZipPackage<'a>(temp::Dir<'a>);
 
 impl<'a> ZipPackage<'a> {
-    pub fn unpack<R: Read + Seek>(stream: R, path: &Path) -> Result<()> {
+    pub fn unpack<R: Read + Seek>(stream: R, path: &Path, opts: ExtractOptions) -> Result<()> {
         let mut archive = ZipArchive::new(stream).chain_err(|| ErrorKind::ExtractingPackage)?;
         /*
                 let mut src = archive.by_name("elan-init.exe").chain_err(|| "failed to extract update")?;
@@ -69,16 +172,19 @@ impl<'a> ZipPackage<'a> {
         // The lean-installer packages unpack to a directory called
         // $pkgname-$version-$target. Skip that directory when
         // unpacking.
-        Self::unpack_without_first_dir(&mut archive, &path)
+        Self::unpack_without_first_dir(&mut archive, &path, opts)
     }
-    pub fn unpack_file(path: &Path, into: &Path) -> Result<()> {
+    pub fn unpack_file(path: &Path, into: &Path, opts: ExtractOptions) -> Result<()> {
         let file = try!(File::open(path).chain_err(|| ErrorKind::ExtractingPackage));
-        Self::unpack(file, into)
+        Self::unpack(file, into, opts)
     }
 
-    fn unpack_without_first_dir<R: Read + Seek>(archive: &mut ZipArchive<R>, path: &Path) -> Result<()> {
+    fn unpack_without_first_dir<R: Read + Seek>(archive: &mut ZipArchive<R>, path: &Path, opts: ExtractOptions) -> Result<()> {
+        let mut collisions = CaseCollisions::new(opts);
         for i in 0..archive.len() {
+            try!(check_cancelled(opts.cancel));
             let mut entry = archive.by_index(i).chain_err(|| ErrorKind::ExtractingPackage)?;
+            try!(collisions.check(Path::new(entry.name())));
             if entry.name().ends_with('/') {
                 continue // skip directories
             }
@@ -114,7 +220,7 @@ impl<'a> ZipPackage<'a> {
             filetime::set_file_times(&full_path, mtime, mtime).unwrap();
         }
 
-        Ok(())
+        normalize_tree(path, opts)
     }
 }
 
@@ -122,13 +228,190 @@ impl<'a> ZipPackage<'a> {
 pub struct TarGzPackage<'a>(TarPackage<'a>);
 
 impl<'a> TarGzPackage<'a> {
-    pub fn unpack<R: Read>(stream: R, path: &Path) -> Result<()> {
+    pub fn unpack<R: Read>(stream: R, path: &Path, opts: ExtractOptions) -> Result<()> {
         let stream = flate2::read::GzDecoder::new(stream);
 
-        TarPackage::unpack(stream, path)
+        TarPackage::unpack(stream, path, opts)
     }
-    pub fn unpack_file(path: &Path, into: &Path) -> Result<()> {
+    pub fn unpack_file(path: &Path, into: &Path, opts: ExtractOptions) -> Result<()> {
         let file = try!(File::open(path).chain_err(|| ErrorKind::ExtractingPackage));
-        Self::unpack(file, into)
+        Self::unpack(file, into, opts)
+    }
+
+    /// Like `unpack_file`, but hashes the archive as it is decompressed so
+    /// that it is read from disk only once. The checksum is compared after
+    /// the last byte has been read; on a mismatch `ChecksumFailed` is
+    /// returned and `into` may hold a partial extraction that the caller
+    /// has to discard.
+    pub fn unpack_file_verified(path: &Path, into: &Path, expected: &str, opts: ExtractOptions) -> Result<()> {
+        let file = try!(File::open(path).chain_err(|| ErrorKind::ExtractingPackage));
+        let mut hasher = Sha256::new();
+        let result = {
+            let mut reader = HashingReader { inner: file, hasher: &mut hasher };
+            let result = Self::unpack(&mut reader, into, opts);
+            // A cancelled extraction says nothing about the archive
+            if let Err(Error(ErrorKind::Cancelled, _)) = result {
+                return result;
+            }
+            // Whatever the decoders didn't consume, like the gzip trailer
+            // or when extraction failed early
+            io::copy(&mut reader, &mut io::sink()).chain_err(|| ErrorKind::ExtractingPackage)
+                .and(result)
+        };
+
+        let calculated = format!("{:x}", hasher.finalize());
+        if calculated != expected {
+            return Err(ErrorKind::ChecksumFailed {
+                url: path.display().to_string(),
+                expected: expected.to_owned(),
+                calculated: calculated,
+            }.into());
+        }
+        result
+    }
+}
+
+struct HashingReader<'a, R> {
+    inner: R,
+    hasher: &'a mut Sha256,
+}
+
+impl<'a, R: Read> Read for HashingReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elan_utils::test_dir::TestDir;
+
+    fn make_archive_with(dir: &Path, files: &[&str]) -> PathBuf {
+        let archive = dir.join("lean.tar.gz");
+        let gz = flate2::write::GzEncoder::new(File::create(&archive).unwrap(),
+                                               flate2::Compression::default());
+        let mut builder = tar::Builder::new(gz);
+        let contents = b"#!/bin/sh\n";
+        for file in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o755);
+            header.set_cksum();
+            builder.append_data(&mut header, file, &contents[..]).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+        archive
+    }
+
+    fn make_archive(dir: &Path) -> PathBuf {
+        make_archive_with(dir, &["lean-3.0.0-linux/bin/lean"])
+    }
+
+    #[test]
+    fn test_unpack_file_verified() {
+        let root = TestDir::new("verified-unpack");
+        let archive = make_archive(&root);
+        let mut hasher = Sha256::new();
+        hasher.update(&fs::read(&archive).unwrap());
+        let hash = format!("{:x}", hasher.finalize());
+
+        let cancel = AtomicBool::new(false);
+
+        let good = root.join("good");
+        TarGzPackage::unpack_file_verified(&archive, &good, &hash, ExtractOptions::new(&cancel)).unwrap();
+        assert!(good.join("bin/lean").is_file());
+
+        let bad = root.join("bad");
+        match TarGzPackage::unpack_file_verified(&archive, &bad, &"0".repeat(64), ExtractOptions::new(&cancel)) {
+            Err(Error(ErrorKind::ChecksumFailed { calculated, .. }, _)) => assert_eq!(calculated, hash),
+            r => panic!("expected checksum failure, got {:?}", r),
+        }
+
+        cancel.store(true, Ordering::SeqCst);
+        let cancelled = root.join("cancelled");
+        match TarGzPackage::unpack_file_verified(&archive, &cancelled, &"0".repeat(64), ExtractOptions::new(&cancel)) {
+            Err(Error(ErrorKind::Cancelled, _)) => {}
+            r => panic!("expected cancellation, got {:?}", r),
+        }
+        assert!(!cancelled.join("bin/lean").exists());
+    }
+
+    #[test]
+    fn test_case_insensitive_collision() {
+        let root = TestDir::new("case-collision");
+        let archive = make_archive_with(&root, &["lean-3.0.0-linux/lib/Init/Data.olean",
+                                                 "lean-3.0.0-linux/lib/Init/data.olean"]);
+        let cancel = AtomicBool::new(false);
+        let opts = ExtractOptions { check_case: true, ..ExtractOptions::new(&cancel) };
+
+        match TarGzPackage::unpack_file(&archive, &root.join("out"), opts) {
+            Err(Error(ErrorKind::CaseInsensitiveCollision { a, b }, _)) => {
+                assert_eq!(a, Path::new("lean-3.0.0-linux/lib/Init/Data.olean"));
+                assert_eq!(b, Path::new("lean-3.0.0-linux/lib/Init/data.olean"));
+            }
+            r => panic!("expected a case collision, got {:?}", r),
+        }
+
+        let archive = make_archive_with(&root, &["lean-3.0.0-linux/lib/Init/Data.olean",
+                                                 "lean-3.0.0-linux/lib/init/Core.olean"]);
+        match TarGzPackage::unpack_file(&archive, &root.join("out-dirs"), opts) {
+            Err(Error(ErrorKind::CaseInsensitiveCollision { a, b }, _)) => {
+                assert_eq!(a, Path::new("lean-3.0.0-linux/lib/Init"));
+                assert_eq!(b, Path::new("lean-3.0.0-linux/lib/init"));
+            }
+            r => panic!("expected a case collision, got {:?}", r),
+        }
+
+        let archive = make_archive_with(&root, &["lean-3.0.0-linux/lib/Init/Data.olean",
+                                                 "lean-3.0.0-linux/lib/Init/Core.olean"]);
+        TarGzPackage::unpack_file(&archive, &root.join("out-ok"), opts).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_deterministic_extraction() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = TestDir::new("deterministic");
+        let archive = root.join("lean.tar.gz");
+        {
+            let gz = flate2::write::GzEncoder::new(File::create(&archive).unwrap(),
+                                                   flate2::Compression::default());
+            let mut builder = tar::Builder::new(gz);
+            for &(file, mode) in &[("lean-3.0.0-linux/bin/lean", 0o775), ("lean-3.0.0-linux/lib/lean.olean", 0o640)] {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(4);
+                header.set_mode(mode);
+                header.set_mtime(1500000000);
+                header.set_cksum();
+                builder.append_data(&mut header, file, &b"data"[..]).unwrap();
+            }
+            builder.into_inner().unwrap().finish().unwrap();
+        }
+        fn tree(dir: &Path) -> Vec<(PathBuf, u32, i64, Vec<u8>)> {
+            WalkDir::new(dir).sort_by(|a, b| a.file_name().cmp(b.file_name())).into_iter().map(|e| {
+                let e = e.unwrap();
+                let metadata = e.metadata().unwrap();
+                let mtime = filetime::FileTime::from_last_modification_time(&metadata).unix_seconds();
+                let contents = if e.file_type().is_file() { fs::read(e.path()).unwrap() } else { vec![] };
+                (e.path().strip_prefix(dir).unwrap().to_owned(), metadata.permissions().mode() & 0o7777, mtime, contents)
+            }).collect()
+        }
+
+        let cancel = AtomicBool::new(false);
+        let opts = ExtractOptions { deterministic: true, ..ExtractOptions::new(&cancel) };
+        TarGzPackage::unpack_file(&archive, &root.join("a"), opts).unwrap();
+        ::std::thread::sleep(::std::time::Duration::from_millis(10));
+        TarGzPackage::unpack_file(&archive, &root.join("b"), opts).unwrap();
+        let a = tree(&root.join("a"));
+        assert_eq!(a, tree(&root.join("b")));
+        assert!(a.iter().all(|&(_, _, mtime, _)| mtime == NORMALIZED_MTIME));
+        let mode = |p: &str| a.iter().find(|e| e.0 == Path::new(p)).unwrap().1;
+        assert_eq!(mode("bin/lean"), 0o755);
+        assert_eq!(mode("lib/lean.olean"), 0o644);
+        assert_eq!(mode("lib"), 0o755);
     }
 }
@@ -0,0 +1,395 @@
+//! Deltas between two releases of a toolchain, for updating an installed
+//! release without downloading the full archive of the next one. A delta is
+//! a `.tar.gz` published next to the archive of the new release and named
+//! after the release it updates from, e.g.
+//! `lean-4.0.0-nightly-2023-01-02-linux.delta-nightly-2023-01-01.tar.gz`.
+//! Inside its top-level directory, an `elan-delta.toml` lists every file and
+//! link of the new release:
+//!
+//! ```toml
+//! version = "1"
+//!
+//! [delta]
+//! from = "nightly-2023-01-01"
+//! to = "nightly-2023-01-02"
+//!
+//! [delta.files]
+//! "bin/lean" = "<sha256>"
+//!
+//! [delta.links]
+//! "lib/libleanshared.so" = "libleanshared.so.1"
+//! ```
+//!
+//! and `files` holds those files that differ from the old release. All
+//! other files are taken from the installed toolchain.
+//!
+//! The result is not checked against the delta's own digests, which would
+//! vouch for nothing, but against the digest list the new release publishes
+//! next to its archive, with `.sha256sums` appended to the archive's url.
+//! It lists every file of the release in the format of `sha256sum`, so that
+//! both local changes to the toolchain and a delta that doesn't fit the
+//! release make the delta fail rather than carry over. Links may only point
+//! inside the toolchain.
+
+use toml;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tar;
+use walkdir::WalkDir;
+
+use elan_utils::toml_utils::*;
+use elan_utils::utils;
+use download::file_hash;
+use errors::*;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub const DELTA_MANIFEST_NAME: &'static str = "elan-delta.toml";
+
+pub const SUPPORTED_DELTA_VERSIONS: [&'static str; 1] = ["1"];
+pub const DEFAULT_DELTA_VERSION: &'static str = "1";
+
+pub const DIGESTS_EXT: &'static str = ".sha256sums";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delta {
+    pub from: String,
+    pub to: String,
+    /// SHA-256 digests of the new release's files by relative path
+    pub files: BTreeMap<String, String>,
+    /// Targets of the new release's links by relative path
+    pub links: BTreeMap<String, String>,
+}
+
+fn get_string_table(table: &mut toml::value::Table, key: &str, path: &str) -> Result<BTreeMap<String, String>> {
+    let mut strings = try!(get_table(table, key, path));
+    let path = format!("{}{}.", path, key);
+    let keys: Vec<String> = strings.keys().cloned().collect();
+    let mut result = BTreeMap::new();
+    for key in keys {
+        let value = try!(get_string(&mut strings, &key, &path));
+        result.insert(key, value);
+    }
+    Ok(result)
+}
+
+fn to_string_table(strings: BTreeMap<String, String>) -> toml::Value {
+    toml::Value::Table(strings.into_iter().map(|(k, v)| (k, toml::Value::String(v))).collect())
+}
+
+impl Delta {
+    pub fn from_toml(mut table: toml::value::Table, path: &str) -> Result<Self> {
+        let version = try!(get_string(&mut table, "version", path));
+        if !SUPPORTED_DELTA_VERSIONS.contains(&&*version) {
+            return Err(ErrorKind::UnsupportedVersion(version).into());
+        }
+        let mut delta = try!(get_table(&mut table, "delta", path));
+        let path = format!("{}delta.", path);
+        Ok(Delta {
+            from: try!(get_string(&mut delta, "from", &path)),
+            to: try!(get_string(&mut delta, "to", &path)),
+            files: try!(get_string_table(&mut delta, "files", &path)),
+            links: try!(get_string_table(&mut delta, "links", &path)),
+        })
+    }
+    pub fn to_toml(self) -> toml::value::Table {
+        let mut delta = toml::value::Table::new();
+        delta.insert("from".to_owned(), toml::Value::String(self.from));
+        delta.insert("to".to_owned(), toml::Value::String(self.to));
+        delta.insert("files".to_owned(), to_string_table(self.files));
+        delta.insert("links".to_owned(), to_string_table(self.links));
+
+        let mut result = toml::value::Table::new();
+        result.insert("version".to_owned(),
+                      toml::Value::String(DEFAULT_DELTA_VERSION.to_owned()));
+        result.insert("delta".to_owned(), toml::Value::Table(delta));
+        result
+    }
+
+    pub fn parse(data: &str) -> Result<Self> {
+        let value = toml::from_str(data).map_err(ErrorKind::Parsing)?;
+        Self::from_toml(value, "")
+    }
+
+    pub fn stringify(self) -> String {
+        toml::Value::Table(self.to_toml()).to_string()
+    }
+
+    /// Reads the manifest of the unpacked delta in `dir`
+    pub fn load(dir: &Path) -> Result<Self> {
+        let data = try!(utils::read_file("delta manifest", &dir.join(DELTA_MANIFEST_NAME))
+                        .chain_err(|| ErrorKind::InvalidDelta(dir.to_owned())));
+        Delta::parse(&data).chain_err(|| ErrorKind::InvalidDelta(dir.to_owned()))
+    }
+}
+
+/// The url of the delta from the release `from` to the one whose archive
+/// is at `archive_url`
+pub fn delta_url(archive_url: &str, from: &str) -> String {
+    let stem = archive_url.trim_end_matches(".tar.gz").trim_end_matches(".zip");
+    format!("{}.delta-{}.tar.gz", stem, from)
+}
+
+/// The url of the digest list of the release whose archive is at
+/// `archive_url`
+pub fn digests_url(archive_url: &str) -> String {
+    format!("{}{}", archive_url, DIGESTS_EXT)
+}
+
+/// Parses a digest list downloaded from `url` into digests by relative path
+pub fn parse_digests(data: &str, url: &str) -> Result<BTreeMap<String, String>> {
+    let mut digests = BTreeMap::new();
+    for line in data.lines().filter(|line| !line.trim().is_empty()) {
+        let mut parts = line.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some(digest), Some(path)) if digest.len() == 64 => {
+                // `sha256sum` marks files read in binary mode with `*`
+                let path = path.trim_start_matches(' ').trim_start_matches('*');
+                digests.insert(path.to_owned(), digest.to_lowercase());
+            }
+            _ => return Err(ErrorKind::InvalidDigests(url.to_owned()).into()),
+        }
+    }
+    Ok(digests)
+}
+
+/// The digest list of the release at `release`, as published next to its
+/// archive
+pub fn write_digests(release: &Path) -> Result<String> {
+    let mut list = String::new();
+    for entry in WalkDir::new(release).min_depth(1).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+        let entry = try!(entry.chain_err(|| "reading release"));
+        if entry.file_type().is_file() {
+            let relative = entry.path().strip_prefix(release).unwrap().to_string_lossy().replace('\\', "/");
+            list += &format!("{}  {}\n", try!(file_hash(entry.path())), relative);
+        }
+    }
+    Ok(list)
+}
+
+// Paths in a delta must stay inside the toolchain
+fn relative_path(path: &str, delta_dir: &Path) -> Result<PathBuf> {
+    let relative = PathBuf::from(path);
+    if relative.components().all(|c| match c { Component::Normal(_) => true, _ => false }) {
+        Ok(relative)
+    } else {
+        Err(ErrorKind::InvalidDelta(delta_dir.to_owned()).into())
+    }
+}
+
+// Link targets as well, relative to the link at `path`
+fn check_link(path: &Path, link: &str, delta_dir: &Path) -> Result<()> {
+    let mut depth = path.components().count() as isize - 1;
+    for c in Path::new(link).components() {
+        depth += match c {
+            Component::Normal(_) => 1,
+            Component::CurDir => 0,
+            Component::ParentDir => -1,
+            _ => return Err(ErrorKind::InvalidDelta(delta_dir.to_owned()).into()),
+        };
+        if depth < 0 {
+            return Err(ErrorKind::InvalidDelta(delta_dir.to_owned()).into());
+        }
+    }
+    Ok(())
+}
+
+/// Writes a delta from the release `from` installed at `old` to the
+/// release `to` installed at `new` to the archive `dest`
+pub fn create(old: &Path, new: &Path, from: &str, to: &str, dest: &Path) -> Result<Delta> {
+    let mut delta = Delta {
+        from: from.to_owned(),
+        to: to.to_owned(),
+        files: BTreeMap::new(),
+        links: BTreeMap::new(),
+    };
+    let archive = try!(fs::File::create(dest).chain_err(|| "creating delta archive"));
+    let mut builder = tar::Builder::new(GzEncoder::new(archive, Compression::default()));
+    for entry in WalkDir::new(new).min_depth(1) {
+        let entry = try!(entry.chain_err(|| "reading new release"));
+        let relative = entry.path().strip_prefix(new).unwrap().to_string_lossy().replace('\\', "/");
+        if entry.path_is_symlink() {
+            let target = try!(fs::read_link(entry.path()).chain_err(|| "reading new release"));
+            delta.links.insert(relative, target.to_string_lossy().into_owned());
+        } else if entry.file_type().is_file() {
+            let digest = try!(file_hash(entry.path()));
+            let old_file = old.join(&relative);
+            let unchanged = utils::is_file(&old_file) && try!(file_hash(&old_file)) == digest;
+            if !unchanged {
+                try!(builder.append_path_with_name(entry.path(), format!("delta/files/{}", relative))
+                     .chain_err(|| "writing delta archive"));
+            }
+            delta.files.insert(relative, digest);
+        }
+    }
+
+    let manifest = delta.clone().stringify();
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    try!(builder.append_data(&mut header, format!("delta/{}", DELTA_MANIFEST_NAME), manifest.as_bytes())
+         .chain_err(|| "writing delta archive"));
+    try!(builder.into_inner().and_then(|gz| gz.finish()).chain_err(|| "writing delta archive"));
+    Ok(delta)
+}
+
+/// Builds the new release described by the unpacked delta in `delta_dir`
+/// in `dest`, taking unchanged files from the old release at `old`. Files
+/// below the directories `excluded` are left out. The files built must be
+/// exactly those in `digests`, the release's published digest list.
+pub fn apply(old: &Path,
+             delta_dir: &Path,
+             dest: &Path,
+             excluded: &[&str],
+             digests: &BTreeMap<String, String>,
+             cancel: &AtomicBool) -> Result<Delta> {
+    let delta = try!(Delta::load(delta_dir));
+    let is_excluded = |path: &str| excluded.iter().any(|dir| path == *dir || path.starts_with(&format!("{}/", dir)));
+
+    // Files of the release the delta doesn't build
+    if let Some(path) = digests.keys().find(|path| !is_excluded(path) && !delta.files.contains_key(*path)) {
+        return Err(ErrorKind::DeltaMismatch(path.clone()).into());
+    }
+    for path in delta.files.keys() {
+        if cancel.load(Ordering::SeqCst) {
+            return Err(ErrorKind::Cancelled.into());
+        }
+        if is_excluded(path) {
+            continue;
+        }
+        let relative = try!(relative_path(path, delta_dir));
+        let patched = delta_dir.join("files").join(&relative);
+        let src = if utils::is_file(&patched) { patched } else { old.join(&relative) };
+        let target = dest.join(&relative);
+        let digest = try!(digests.get(path).ok_or_else(|| Error::from(ErrorKind::DeltaMismatch(path.clone()))));
+        try!(utils::ensure_dir_exists("toolchain", target.parent().unwrap(), &|_| ()));
+        try!(utils::copy_file(&src, &target).chain_err(|| ErrorKind::DeltaMismatch(path.clone())));
+        if try!(file_hash(&target)) != *digest {
+            return Err(ErrorKind::DeltaMismatch(path.clone()).into());
+        }
+    }
+    for (path, link) in &delta.links {
+        if is_excluded(path) {
+            continue;
+        }
+        let relative = try!(relative_path(path, delta_dir));
+        try!(check_link(&relative, link, delta_dir));
+        let target = dest.join(relative);
+        try!(utils::ensure_dir_exists("toolchain", target.parent().unwrap(), &|_| ()));
+        try!(utils::symlink_file(Path::new(link), &target));
+    }
+    Ok(delta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elan_utils::test_dir::TestDir;
+    use component::{ExtractOptions, TarGzPackage};
+
+    #[test]
+    fn test_delta_url() {
+        assert_eq!(delta_url("https://github.com/leanprover/lean4/releases/download/v4.2.0/lean-4.2.0-linux.tar.gz", "v4.1.0"),
+                   "https://github.com/leanprover/lean4/releases/download/v4.2.0/lean-4.2.0-linux.delta-v4.1.0.tar.gz");
+        assert_eq!(delta_url("https://example.com/lean-4.2.0-darwin.zip", "v4.1.0"),
+                   "https://example.com/lean-4.2.0-darwin.delta-v4.1.0.tar.gz");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_and_apply_delta() {
+        let root = TestDir::new("delta");
+        let (old, new) = (root.join("old"), root.join("new"));
+        for dir in &[&old, &new] {
+            fs::create_dir_all(dir.join("bin")).unwrap();
+            fs::create_dir_all(dir.join("share/doc")).unwrap();
+            fs::write(dir.join("bin/lake"), "lake").unwrap();
+        }
+        fs::write(old.join("bin/lean"), "lean 1").unwrap();
+        fs::write(old.join("bin/removed"), "gone").unwrap();
+        fs::write(new.join("bin/lean"), "lean 2").unwrap();
+        fs::write(new.join("bin/added"), "new").unwrap();
+        fs::write(new.join("share/doc/README"), "docs").unwrap();
+        ::std::os::unix::fs::symlink("lean", new.join("bin/lean-link")).unwrap();
+
+        let archive = root.join("delta.tar.gz");
+        let delta = create(&old, &new, "v1", "v2", &archive).unwrap();
+        assert_eq!(delta.files.len(), 4);
+        assert_eq!(delta.links.get("bin/lean-link").map(|s| &**s), Some("lean"));
+        assert_eq!(Delta::parse(&delta.clone().stringify()).unwrap(), delta);
+
+        // Only changed files are shipped
+        let unpacked = root.join("unpacked");
+        TarGzPackage::unpack_file(&archive, &unpacked, ExtractOptions::new(&AtomicBool::new(false))).unwrap();
+        assert!(!unpacked.join("files/bin/lake").exists());
+        assert_eq!(Delta::load(&unpacked).unwrap(), delta);
+
+        let digests = parse_digests(&write_digests(&new).unwrap(), "digests").unwrap();
+        assert_eq!(digests, delta.files);
+        let cancel = AtomicBool::new(false);
+        let dest = root.join("dest");
+        assert_eq!(apply(&old, &unpacked, &dest, &["share/doc"], &digests, &cancel).unwrap(), delta);
+        assert_eq!(fs::read_to_string(dest.join("bin/lean")).unwrap(), "lean 2");
+        assert_eq!(fs::read_to_string(dest.join("bin/lake")).unwrap(), "lake");
+        assert_eq!(fs::read_to_string(dest.join("bin/added")).unwrap(), "new");
+        assert_eq!(fs::read_link(dest.join("bin/lean-link")).unwrap(), Path::new("lean"));
+        assert!(!dest.join("bin/removed").exists());
+        assert!(!dest.join("share/doc/README").exists());
+
+        // Changes to the old release are caught
+        fs::write(old.join("bin/lake"), "modified").unwrap();
+        match apply(&old, &unpacked, &root.join("dest2"), &[], &digests, &cancel) {
+            Err(Error(ErrorKind::DeltaMismatch(path), _)) => assert_eq!(path, "bin/lake"),
+            r => panic!("expected a mismatch, got {:?}", r),
+        }
+        fs::write(old.join("bin/lake"), "lake").unwrap();
+
+        // The delta's own digests don't count, only the published ones
+        let mut forged = delta.clone();
+        fs::write(unpacked.join("files/bin/lean"), "forged").unwrap();
+        forged.files.insert("bin/lean".to_owned(), file_hash(&unpacked.join("files/bin/lean")).unwrap());
+        fs::write(unpacked.join(DELTA_MANIFEST_NAME), forged.stringify()).unwrap();
+        match apply(&old, &unpacked, &root.join("dest4"), &[], &digests, &cancel) {
+            Err(Error(ErrorKind::DeltaMismatch(path), _)) => assert_eq!(path, "bin/lean"),
+            r => panic!("expected a mismatch, got {:?}", r),
+        }
+        fs::write(unpacked.join("files/bin/lean"), "lean 2").unwrap();
+
+        // Nor may a delta leave out files of the release
+        let mut incomplete = delta.clone();
+        incomplete.files.remove("bin/added");
+        fs::write(unpacked.join(DELTA_MANIFEST_NAME), incomplete.stringify()).unwrap();
+        match apply(&old, &unpacked, &root.join("dest5"), &[], &digests, &cancel) {
+            Err(Error(ErrorKind::DeltaMismatch(path), _)) => assert_eq!(path, "bin/added"),
+            r => panic!("expected a mismatch, got {:?}", r),
+        }
+
+        // Paths may not leave the toolchain
+        let mut escaping = delta.clone();
+        escaping.files.insert("../outside".to_owned(), "0".repeat(64));
+        fs::write(unpacked.join(DELTA_MANIFEST_NAME), escaping.stringify()).unwrap();
+        match apply(&old, &unpacked, &root.join("dest3"), &[], &digests, &cancel) {
+            Err(Error(ErrorKind::InvalidDelta(_), _)) => {}
+            r => panic!("expected an invalid delta, got {:?}", r),
+        }
+        for &link in &["/etc/passwd", "../../outside", "../lib/../../outside"] {
+            let mut escaping = delta.clone();
+            escaping.links.insert("bin/lean-link".to_owned(), link.to_owned());
+            fs::write(unpacked.join(DELTA_MANIFEST_NAME), escaping.stringify()).unwrap();
+            match apply(&old, &unpacked, &root.join("dest6"), &[], &digests, &cancel) {
+                Err(Error(ErrorKind::InvalidDelta(_), _)) => {}
+                r => panic!("expected an invalid delta for '{}', got {:?}", link, r),
+            }
+        }
+        assert!(check_link(Path::new("bin/lean-link"), "../lib/libleanshared.so", &unpacked).is_ok());
+
+        // Digest lists are in the format of `sha256sum`
+        let digest = "a".repeat(64);
+        assert_eq!(parse_digests(&format!("{}  bin/lean\n{} *bin/lake\n", digest, digest), "url").unwrap().len(), 2);
+        assert!(parse_digests("not a digest list", "url").is_err());
+    }
+}
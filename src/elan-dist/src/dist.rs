@@ -4,23 +4,32 @@ use errors::*;
 use elan_utils::{self, utils};
 use prefix::InstallPrefix;
 use manifest::Component;
-use manifestation::{Manifestation};
+use manifestation::{apply_profile, is_incomplete, profile_needs_reinstall, record_metadata, Manifestation,
+                    METADATA_FILE};
 use download::{DownloadCfg};
 use notifications::Notification;
+use version::{PartialVersion, Version};
 
 use std::path::Path;
 use std::fmt;
+use std::cmp::Ordering;
 
+use json::{self, JsonValue};
 use regex::Regex;
 
 // fetch latest versions from leanprover-community, but don't break pinned origin-less versions
 const DEFAULT_ORIGIN: &str = "leanprover/lean";
 const DEFAULT_CHANNEL_ORIGIN: &str = "leanprover-community/lean";
+// Omitted from names shown in listings
+const DISPLAY_ORIGIN: &str = "leanprover/lean4";
+
+/// The server hosting the release pages toolchains are downloaded from
+pub const DIST_SERVER: &str = "https://github.com";
 
 // Fully-resolved toolchain descriptors. These always have full target
 // triples attached to them and are used for canonical identification,
 // such as naming their installation directory.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ToolchainDesc {
     // The GitHub source repository to use (if "nightly" is specified, we append "-nightly" to this)
     // If None, we default to "leanprover/lean"
@@ -32,6 +41,9 @@ pub struct ToolchainDesc {
 
 impl ToolchainDesc {
     pub fn from_str(name: &str) -> Result<Self> {
+        if name.contains("://") {
+            return ToolchainDesc::from_release_url(name);
+        }
         let pattern = r"^(?:([a-zA-Z0-9-]+[/][a-zA-Z0-9-]+)[:])?(?:(nightly|stable)(?:-(\d{4}-\d{2}-\d{2}))?|([a-zA-Z0-9-.]+))$";
 
         let re = Regex::new(&pattern).unwrap();
@@ -54,6 +66,65 @@ impl ToolchainDesc {
             .ok_or(ErrorKind::InvalidToolchainName(name.to_string()).into())
     }
 
+    /// The toolchain of a release page as copied from the browser, e.g.
+    /// `https://github.com/leanprover/lean4/releases/tag/v4.0.0`
+    pub fn from_release_url(url: &str) -> Result<Self> {
+        let invalid = || Error::from(ErrorKind::InvalidReleaseUrl(url.to_owned()));
+        let re = Regex::new(r"^https?://github\.com/([a-zA-Z0-9-]+/[a-zA-Z0-9-]+)/releases/tag/([a-zA-Z0-9-.]+)/?$").unwrap();
+        let c = try!(re.captures(url).ok_or_else(invalid));
+        let (repo, tag) = (&c[1], &c[2]);
+        let desc = match (repo.ends_with("-nightly"), tag.starts_with("nightly-")) {
+            (true, true) => ToolchainDesc {
+                origin: Some(repo[..repo.len() - "-nightly".len()].to_owned()),
+                channel: "nightly".to_owned(),
+                date: Some(tag["nightly-".len()..].to_owned()),
+            },
+            _ => ToolchainDesc { origin: Some(repo.to_owned()), channel: tag.to_owned(), date: None },
+        };
+        // Only accept descriptors that lead back to the same release page,
+        // e.g. not tags that `stable` or a bare version would be mistaken for
+        let page = format!("{}/{}/releases/tag/{}", DIST_SERVER, repo, tag);
+        if desc.is_tracking() || resolve_toolchain_url(&desc, false, &|_| ()).ok() != Some(page) {
+            return Err(invalid());
+        }
+        Ok(desc)
+    }
+
+    /// Resolves bare channels like `stable` and versions against `origin`
+    /// instead of the official repositories. Explicit origins are kept, and
+    /// so is the official nightly repository, as `nightly` would otherwise
+    /// look for a `-nightly` companion of `origin` that forks don't have.
+    pub fn with_default_origin(mut self, origin: Option<&str>) -> Self {
+        if self.origin.is_none() && self.channel != "nightly" {
+            self.origin = origin.map(|o| o.to_owned());
+        }
+        self
+    }
+
+    /// Whether `origin` is of the form `owner/repo`
+    pub fn is_valid_origin(origin: &str) -> bool {
+        ToolchainDesc::from_str(&format!("{}:stable", origin)).is_ok()
+    }
+
+    /// The name the toolchain can be referred to by, which parses back to
+    /// the same descriptor, e.g. `leanprover/lean4:nightly-2021-06-01`
+    pub fn name(&self) -> String {
+        match self.origin {
+            Some(ref origin) => format!("{}:{}", origin, self.manifest_name()),
+            None => self.manifest_name(),
+        }
+    }
+
+    /// Like `name`, but without the origin if it is `leanprover/lean4`,
+    /// e.g. `v4.1.0`. Only for display, as the result may resolve to a
+    /// different toolchain.
+    pub fn short_name(&self) -> String {
+        match self.origin {
+            Some(ref origin) if origin == DISPLAY_ORIGIN => self.manifest_name(),
+            _ => self.name(),
+        }
+    }
+
     /// Either "$channel" or "channel-$date"
     pub fn manifest_name(&self) -> String {
         match self.date {
@@ -79,8 +150,422 @@ impl ToolchainDesc {
 
     pub fn is_tracking(&self) -> bool {
         let channels = ["nightly", "stable"];
-        channels.iter().any(|x| *x == self.channel) && self.date.is_none()
+        (channels.iter().any(|x| *x == self.channel) || self.is_partial_version()) && self.date.is_none()
     }
+
+    /// The toolchain that names the release `tag` of this one directly,
+    /// for nightlies by their date so that the nightly repository is still
+    /// used. The origin is always explicit since bare channels and bare
+    /// tags don't default to the same repository.
+    pub fn at_release(&self, tag: &str) -> ToolchainDesc {
+        let origin = Some(self.origin.clone()
+                          .unwrap_or_else(|| default_origin_for(&self.channel).to_owned()));
+        if self.channel == "nightly" && tag.starts_with("nightly-") {
+            ToolchainDesc {
+                origin: origin,
+                channel: "nightly".to_owned(),
+                date: Some(tag["nightly-".len()..].to_owned()),
+            }
+        } else {
+            ToolchainDesc {
+                origin: origin,
+                channel: tag.to_owned(),
+                date: None,
+            }
+        }
+    }
+
+    /// Whether the channel is a version without a patch (`4.1`, `v4`)
+    /// component, which tracks the newest matching release
+    pub fn is_partial_version(&self) -> bool {
+        PartialVersion::parse(&self.channel).is_some()
+    }
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum SortKey<'a> {
+    Version(Version),
+    Channel(&'a str, Option<&'a str>),
+}
+
+impl ToolchainDesc {
+    /// Whether the channel is a version with a pre-release suffix such as
+    /// `v4.0.0-rc1`
+    pub fn is_prerelease(&self) -> bool {
+        Version::parse(&self.channel).map_or(false, |v| v.is_prerelease())
+    }
+
+    /// Orders releases of the same origin, comparing versions numerically
+    /// so that `4.0.0` and `v4.0.0` are the same release
+    pub fn cmp_version(&self, other: &ToolchainDesc) -> Ordering {
+        self.sort_key().cmp(&other.sort_key())
+    }
+
+    fn sort_key(&self) -> (Option<&str>, SortKey) {
+        let key = match Version::parse(&self.channel) {
+            Some(version) => SortKey::Version(version),
+            None => SortKey::Channel(&self.channel, self.date.as_ref().map(|d| &**d)),
+        };
+        (self.origin.as_ref().map(|o| &**o), key)
+    }
+}
+
+/// Sorts toolchains by origin and newest first within each origin, using
+/// `desc` to look up what they stand for. Toolchains without a description,
+/// like custom ones, go last in the alphabetical order of their `name`.
+pub fn sort_by_version<T, N, D>(toolchains: &mut Vec<T>, name: N, desc: D)
+    where N: Fn(&T) -> &str, D: Fn(&T) -> Option<ToolchainDesc>
+{
+    let mut keyed: Vec<_> = toolchains.drain(..).map(|t| (desc(&t), t)).collect();
+    keyed.sort_by(|&(ref a_desc, ref a), &(ref b_desc, ref b)| match (a_desc, b_desc) {
+        (&Some(ref a_desc), &Some(ref b_desc)) => {
+            let ((a_origin, a_key), (b_origin, b_key)) = (a_desc.sort_key(), b_desc.sort_key());
+            a_origin.cmp(&b_origin).then_with(|| b_key.cmp(&a_key))
+        }
+        (&Some(_), &None) => Ordering::Less,
+        (&None, &Some(_)) => Ordering::Greater,
+        (&None, &None) => name(a).cmp(name(b)),
+    });
+    toolchains.extend(keyed.into_iter().map(|(_, t)| t));
+}
+
+/// Picks the newest release tag of the form `vX.Y.Z`, optionally
+/// considering pre-releases such as `v4.0.0-rc1`
+pub fn latest_release<S: AsRef<str>>(tags: &[S], include_prereleases: bool) -> Option<String> {
+    tags.iter()
+        .filter(|tag| tag.as_ref().starts_with('v'))
+        .filter_map(|tag| Version::parse(tag.as_ref()).map(|v| (v, tag.as_ref().to_owned())))
+        .filter(|&(ref v, _)| include_prereleases || !v.is_prerelease())
+        .max_by(|a, b| a.0.cmp(&b.0))
+        .map(|(_, tag)| tag)
+}
+
+// Nightlies order by date, before all versions
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum ReleaseKey {
+    Nightly(i64),
+    Version(Version),
+}
+
+fn release_key(tag: &str) -> Option<ReleaseKey> {
+    if tag.starts_with("nightly-") {
+        parse_date(&tag["nightly-".len()..]).map(ReleaseKey::Nightly)
+    } else {
+        Version::parse(tag).map(ReleaseKey::Version)
+    }
+}
+
+/// How many of `tags` are newer than `installed` and at most `latest`,
+/// not counting pre-releases unless `include_prereleases` is set
+pub fn releases_between<S: AsRef<str>>(tags: &[S], installed: &str, latest: &str,
+                                       include_prereleases: bool) -> usize {
+    let (installed, latest) = match (release_key(installed), release_key(latest)) {
+        (Some(installed), Some(latest)) => (installed, latest),
+        _ => return 0,
+    };
+    tags.iter()
+        .filter_map(|tag| release_key(tag.as_ref()))
+        .filter(|key| match *key {
+            ReleaseKey::Version(ref v) => include_prereleases || !v.is_prerelease(),
+            ReleaseKey::Nightly(_) => true,
+        })
+        .filter(|key| *key > installed && *key <= latest)
+        .count()
+}
+
+/// How many releases the release page `installed` is behind `latest`,
+/// both as returned by `resolve_toolchain_url`, where `fetch_tags` lists
+/// the release tags of a repository like `utils::fetch_release_tags`
+pub fn releases_behind(installed: &str, latest: &str, include_prereleases: bool,
+                       fetch_tags: &Fn(&str) -> Result<Vec<String>>) -> Result<usize> {
+    let repo = latest.trim_start_matches(DIST_SERVER).trim_start_matches('/')
+        .splitn(2, "/releases/tag/").next().unwrap_or("");
+    match (release_tag(installed), release_tag(latest)) {
+        (Some(installed), Some(latest)) => {
+            let tags = try!(fetch_tags(repo));
+            Ok(releases_between(&tags, installed, latest, include_prereleases))
+        }
+        _ => Ok(0),
+    }
+}
+
+/// Whether a toolchain is at the newest release of its channel, see
+/// `update_availability`
+#[derive(Clone, Debug, PartialEq)]
+pub enum UpdateAvailability {
+    UpToDate,
+    /// `latest` is the newer release's tag, `behind` how many releases the
+    /// installed one is behind it, if that could be determined
+    UpdateAvailable { latest: String, behind: Option<usize> },
+    /// A pinned release or custom toolchain, which is never updated
+    NotTracking,
+}
+
+/// Compares the release page a tracking toolchain was `installed` from,
+/// if known, with the `latest` one of its channel, see `releases_behind`
+pub fn update_availability(installed: Option<&str>, latest: &str, include_prereleases: bool,
+                           fetch_tags: &Fn(&str) -> Result<Vec<String>>) -> UpdateAvailability {
+    if installed == Some(latest) {
+        return UpdateAvailability::UpToDate;
+    }
+    let behind = installed.and_then(|installed| {
+        releases_behind(installed, latest, include_prereleases, fetch_tags).ok()
+    });
+    UpdateAvailability::UpdateAvailable {
+        latest: release_tag(latest).unwrap_or(latest).to_owned(),
+        behind: behind.filter(|&n| n > 0),
+    }
+}
+
+/// What a toolchain name refers to
+#[derive(Debug, Clone, PartialEq)]
+pub enum ToolchainKind {
+    /// A linked toolchain, or one whose name is not a toolchain descriptor
+    Custom,
+    /// A channel or partial version that is updated to its newest release
+    TrackingChannel { channel: String },
+    /// A fixed release or nightly
+    PinnedVersion { version: String },
+}
+
+impl ToolchainKind {
+    /// Classifies the toolchain called `name`, where `linked` says whether
+    /// it is installed as a link to a local directory
+    pub fn of(name: &str, linked: bool) -> ToolchainKind {
+        if linked {
+            return ToolchainKind::Custom;
+        }
+        match ToolchainDesc::from_str(name) {
+            Ok(ref desc) if desc.is_tracking() => {
+                ToolchainKind::TrackingChannel { channel: desc.channel.clone() }
+            }
+            Ok(desc) => ToolchainKind::PinnedVersion { version: desc.manifest_name() },
+            Err(_) => ToolchainKind::Custom,
+        }
+    }
+}
+
+/// Picks the newest release tag of the form `vX.Y.Z` matching the given
+/// partial version, ignoring pre-releases.
+pub fn latest_matching_version<S: AsRef<str>>(tags: &[S], partial: &str) -> Option<String> {
+    let partial = match PartialVersion::parse(partial) {
+        Some(partial) => partial,
+        None => return None,
+    };
+    tags.iter()
+        .filter(|tag| tag.as_ref().starts_with('v'))
+        .filter_map(|tag| Version::parse(tag.as_ref()).map(|v| (v, tag.as_ref().to_owned())))
+        .filter(|&(ref v, _)| !v.is_prerelease() && partial.matches(v))
+        .max_by(|a, b| a.0.cmp(&b.0))
+        .map(|(_, tag)| tag)
+}
+
+// Days since 1970-01-01 of a `YYYY-MM-DD` date, so that nightlies can be
+// compared by distance
+fn parse_date(s: &str) -> Option<i64> {
+    let re = Regex::new(r"^(\d{4})-(\d{2})-(\d{2})$").unwrap();
+    let c = re.captures(s)?;
+    let (y, m, d): (i64, i64, i64) = (c[1].parse().ok()?, c[2].parse().ok()?, c[3].parse().ok()?);
+    if m < 1 || m > 12 || d < 1 || d > 31 {
+        return None;
+    }
+    // https://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some(era * 146097 + doe - 719468)
+}
+
+// The dates of all `nightly-YYYY-MM-DD` tags, oldest first
+fn nightly_dates<S: AsRef<str>>(tags: &[S]) -> Vec<(i64, String)> {
+    let mut dates: Vec<_> = tags.iter()
+        .filter_map(|tag| {
+            let date = tag.as_ref().trim_start_matches("nightly-");
+            if date.len() == tag.as_ref().len() {
+                return None;
+            }
+            parse_date(date).map(|day| (day, date.to_owned()))
+        })
+        .collect();
+    dates.sort();
+    dates
+}
+
+/// Picks the date of the nightly tag closest to `date`, preferring the
+/// earlier one on ties, if it is at most `tolerance_days` away.
+pub fn nearest_nightly<S: AsRef<str>>(tags: &[S], date: &str, tolerance_days: u32) -> Result<String> {
+    let day = parse_date(date).ok_or_else(|| ErrorKind::InvalidDate(date.to_owned()))?;
+    nightly_dates(tags).into_iter()
+        .filter(|&(d, _)| (d - day).abs() <= tolerance_days as i64)
+        .min_by_key(|&(d, _)| ((d - day).abs(), d))
+        .map(|(_, date)| date)
+        .ok_or_else(|| ErrorKind::NoNightlyNearDate(date.to_owned(), tolerance_days).into())
+}
+
+/// Lists the dates of all nightly tags between `from` and `to` (inclusive),
+/// oldest first.
+pub fn nightlies_in_range<S: AsRef<str>>(tags: &[S], from: &str, to: &str) -> Result<Vec<String>> {
+    let from_day = parse_date(from).ok_or_else(|| ErrorKind::InvalidDate(from.to_owned()))?;
+    let to_day = parse_date(to).ok_or_else(|| ErrorKind::InvalidDate(to.to_owned()))?;
+    Ok(nightly_dates(tags).into_iter()
+        .filter(|&(d, _)| from_day <= d && d <= to_day)
+        .map(|(_, date)| date)
+        .collect())
+}
+
+/// Name of the asset an origin attaches to a release it deprecates. The
+/// asset's label gives the reason, so that it is part of the release list
+/// and nothing needs to be downloaded.
+pub const DEPRECATED_ASSET: &'static str = "DEPRECATED";
+/// Like `DEPRECATED_ASSET`, for releases that are broken
+pub const YANKED_ASSET: &'static str = "YANKED";
+
+/// Why an origin advises against a release, see `DEPRECATED_ASSET`.
+/// Yanked releases are broken: tracking toolchains skip them and they are
+/// only installed when forced.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Deprecation {
+    pub yanked: bool,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Release {
+    pub tag: String,
+    pub deprecation: Option<Deprecation>,
+}
+
+fn parse_deprecation(release: &JsonValue) -> Option<Deprecation> {
+    release["assets"].members().filter_map(|asset| {
+        let yanked = match asset["name"].as_str()? {
+            YANKED_ASSET => true,
+            DEPRECATED_ASSET => false,
+            _ => return None,
+        };
+        let reason = match asset["label"].as_str().map(|l| l.trim()) {
+            Some(label) if !label.is_empty() => label.to_owned(),
+            _ => "no reason given".to_owned(),
+        };
+        Some(Deprecation { yanked: yanked, reason: reason })
+    }).max_by_key(|d| d.yanked)
+}
+
+/// Parses a page of the release list as sent by the GitHub API
+pub fn parse_releases(data: &str) -> Result<Vec<Release>> {
+    Ok(releases_from_list(&try!(utils::parse_release_list(200, data.as_bytes()))))
+}
+
+fn releases_from_list(list: &JsonValue) -> Vec<Release> {
+    list.members().filter_map(|release| {
+        Some(Release {
+            tag: release["tag_name"].as_str()?.to_owned(),
+            deprecation: parse_deprecation(release),
+        })
+    }).collect()
+}
+
+pub fn fetch_releases(origin: &str) -> Result<Vec<Release>> {
+    Ok(releases_from_list(&try!(utils::fetch_release_list(origin))))
+}
+
+fn deprecation_of<'a>(releases: &'a [Release], tag: &str) -> Option<&'a Deprecation> {
+    releases.iter().find(|r| r.tag == tag).and_then(|r| r.deprecation.as_ref())
+}
+
+/// Looks up whether the origin advises against the release `url` points
+/// to, unless `releases` resolution already fetched tells. A tracking
+/// toolchain whose newest release turns out to be yanked falls back to the
+/// newest unyanked one; yanked fixed releases are refused unless `force`
+/// is set. Returns the release to install and its deprecation.
+///
+/// The list counts against the GitHub API's rate limit, so it is only
+/// fetched once a new release is about to be installed, and deprecations
+/// are not checked if the API is unavailable.
+pub fn check_release(toolchain: &ToolchainDesc,
+                     url: String,
+                     releases: Option<Vec<Release>>,
+                     force: bool,
+                     include_prereleases: bool,
+                     notify_handler: &Fn(Notification)) -> Result<(String, Option<Deprecation>)> {
+    let tag = match release_tag(&url) {
+        Some(tag) => tag.to_owned(),
+        None => return Ok((url, None)),
+    };
+    let origin = build_origin_name(toolchain.origin.as_ref(), &toolchain.channel);
+    let known = releases.is_some();
+    let releases = match releases {
+        Some(releases) => releases,
+        None => fetch_releases(&origin).unwrap_or_default(),
+    };
+    let mut url = url;
+    let mut deprecation = deprecation_of(&releases, &tag).cloned();
+    if deprecation.as_ref().map_or(false, |d| d.yanked) {
+        if toolchain.is_tracking() {
+            let release = latest_unyanked(&releases, &toolchain.channel, include_prereleases)
+                .ok_or_else(|| format!("no release found for '{}'", origin))?;
+            deprecation = deprecation_of(&releases, &release).cloned();
+            url = release_url(&origin, &release);
+        } else if !force {
+            let reason = deprecation.map(|d| d.reason).unwrap_or_default();
+            return Err(ErrorKind::YankedRelease(toolchain.name(), reason).into());
+        }
+    }
+    // Resolution already reported deprecations it knew about
+    if let (false, Some(d)) = (known, deprecation.as_ref()) {
+        let release = release_tag(&url).unwrap_or(&tag);
+        notify_handler(Notification::ToolchainDeprecated {
+            name: &toolchain.at_release(release).name(),
+            reason: &d.reason,
+        });
+    }
+    Ok((url, deprecation))
+}
+
+/// Picks the newest of `releases` that the tracking toolchain `version`,
+/// i.e. `stable`, `nightly` or a partial version, may resolve to, skipping
+/// yanked ones
+pub fn latest_unyanked(releases: &[Release], version: &str, include_prereleases: bool) -> Option<String> {
+    let tags: Vec<&str> = releases.iter()
+        .filter(|r| !r.deprecation.as_ref().map_or(false, |d| d.yanked))
+        .map(|r| &*r.tag)
+        .collect();
+    match version {
+        "stable" => latest_release(&tags, include_prereleases),
+        "nightly" => nightly_dates(&tags).pop().map(|(_, date)| format!("nightly-{}", date)),
+        partial => latest_matching_version(&tags, partial),
+    }
+}
+
+fn nightly_desc(origin: Option<&str>, date: String) -> ToolchainDesc {
+    ToolchainDesc {
+        origin: origin.map(|o| o.to_owned()),
+        channel: "nightly".to_owned(),
+        date: Some(date),
+    }
+}
+
+/// Resolves the nightly of `origin` released closest to `date`, e.g. for
+/// bisecting regressions. Returns the toolchain together with its actual
+/// release date. Only the most recent releases are taken into account.
+pub fn resolve_nearest_nightly(origin: Option<&str>, date: &str, tolerance_days: u32)
+                               -> Result<(ToolchainDesc, String)> {
+    let origin_name = build_origin_name(origin.map(|o| o.to_owned()).as_ref(), "nightly");
+    let tags = utils::fetch_release_tags(&origin_name)?;
+    let date = nearest_nightly(&tags, date, tolerance_days)?;
+    Ok((nightly_desc(origin, date.clone()), date))
+}
+
+/// Resolves all nightlies of `origin` released between `from` and `to`
+/// (inclusive), oldest first
+pub fn resolve_nightlies_in_range(origin: Option<&str>, from: &str, to: &str)
+                                  -> Result<Vec<(ToolchainDesc, String)>> {
+    let origin_name = build_origin_name(origin.map(|o| o.to_owned()).as_ref(), "nightly");
+    let tags = utils::fetch_release_tags(&origin_name)?;
+    Ok(nightlies_in_range(&tags, from, to)?.into_iter()
+        .map(|date| (nightly_desc(origin, date.clone()), date))
+        .collect())
 }
 
 #[derive(Debug)]
@@ -137,30 +622,205 @@ pub fn update_from_dist<'a>(download: DownloadCfg<'a>,
     res
 }
 
+/// The name the toolchain `name` is installed from a dist server under:
+/// release pages are named like the toolchain they stand for, other names
+/// are kept. Names that don't describe a release at all, including bare
+/// tags that are neither a channel nor a version, can only be custom
+/// toolchains.
+pub fn dist_toolchain_name(name: &str) -> Result<String> {
+    let is_version = |tag: &str| tag.trim_start_matches('v').starts_with(|c: char| c.is_ascii_digit());
+    match ToolchainDesc::from_str(name) {
+        Ok(ref desc) if name.contains("://") => Ok(desc.name()),
+        Ok(ref desc) if desc.origin.is_none() && !desc.is_tracking() && desc.date.is_none() &&
+                        !is_version(&desc.channel) => {
+            Err(ErrorKind::CustomToolchainName(name.to_owned()).into())
+        }
+        Ok(_) => Ok(name.to_owned()),
+        Err(Error(ErrorKind::InvalidToolchainName(_), _)) => {
+            Err(ErrorKind::CustomToolchainName(name.to_owned()).into())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// `ToolchainDesc::short_name` of the toolchain `name`, which is kept as
+/// is for custom toolchains
+pub fn short_toolchain_name(name: &str) -> String {
+    ToolchainDesc::from_str(name).map(|desc| desc.short_name()).unwrap_or_else(|_| name.to_owned())
+}
+
+/// `short_toolchain_name` of each of `names`, listed together, except that
+/// the full name is kept where the short one wouldn't tell them apart, e.g.
+/// for `leanprover/lean4:v4.1.0` next to a custom toolchain `v4.1.0`
+pub fn short_toolchain_names<S: AsRef<str>>(names: &[S]) -> Vec<String> {
+    let short: Vec<String> = names.iter().map(|n| short_toolchain_name(n.as_ref())).collect();
+    names.iter().zip(&short).map(|(name, s)| {
+        let name = name.as_ref();
+        let ambiguous = names.iter().zip(&short)
+            .any(|(other, other_short)| other.as_ref() != name && (other_short == s || other.as_ref() == s));
+        if ambiguous { name.to_owned() } else { s.clone() }
+    }).collect()
+}
+
+/// The repository toolchains without an explicit origin are resolved
+/// against
+pub fn default_origin_for(version: &str) -> &'static str {
+    if version == "stable" || version == "nightly" { DEFAULT_CHANNEL_ORIGIN } else { DEFAULT_ORIGIN }
+}
+
 //Append "-nightly" to the origin if version == "nightly" was specified.
 //If origin is None use DEFAULT_ORIGIN.
-fn build_origin_name(origin: Option<&String>, version: &str) -> String {
+pub(crate) fn build_origin_name(origin: Option<&String>, version: &str) -> String {
     let repo = match origin {
-        None => if version == "stable" || version == "nightly" { DEFAULT_CHANNEL_ORIGIN } else { DEFAULT_ORIGIN },
+        None => default_origin_for(version),
         Some (repo) => repo
     };
     format!("{}{}", repo, if version == "nightly" { "-nightly" } else { "" })
 }
 
+/// What the toolchain `name` installed in `dir` stands for. Names without
+/// an origin keep resolving against the origin they were installed from,
+/// as recorded by `update_from_dist`, so that changing `default_origin`
+/// doesn't switch installed toolchains to another repository. Toolchains
+/// installed before elan recorded it are from the official repositories.
+pub fn installed_desc(name: &str, dir: &Path, default_origin: Option<&str>) -> Result<ToolchainDesc> {
+    let desc = try!(ToolchainDesc::from_str(name));
+    if !utils::is_directory(dir) || is_incomplete(dir) {
+        return Ok(desc.with_default_origin(default_origin));
+    }
+    let installed = utils::read_file("toolchain metadata", &dir.join(METADATA_FILE)).ok()
+        .and_then(|contents| json::parse(&contents).ok())
+        .and_then(|metadata| metadata["installed_origin"].as_str().map(|o| o.to_owned()));
+    Ok(desc.with_default_origin(installed.as_ref().map(|o| &**o)))
+}
+
+// What `installed_desc` reads back
+fn record_installed_origin(dir: &Path, toolchain: &ToolchainDesc) -> Result<()> {
+    let origin = toolchain.origin.as_ref().map_or(JsonValue::Null, |o| o.clone().into());
+    record_metadata(dir, "installed_origin", origin)
+}
+
+/// Reads the release URL recorded in an update hash file. A missing,
+/// unreadable or corrupt file (e.g. one truncated by a crash while it was
+/// written) counts as no hash at all, so that the toolchain is reinstalled.
+pub fn read_update_hash(hash_file: &Path) -> Option<String> {
+    let contents = match utils::read_file("update hash", hash_file) {
+        Ok(contents) => contents,
+        Err(_) => return None,
+    };
+    let valid = (contents.starts_with("https://") || contents.starts_with("http://")) &&
+        !contents.chars().any(|c| c.is_whitespace() || c.is_control());
+    if valid { Some(contents) } else { None }
+}
+
+pub fn update_hash_matches(hash_file: &Path, url: &str) -> bool {
+    read_update_hash(hash_file).as_ref().map(|h| &**h) == Some(url)
+}
+
+/// Removes the update hash file, so that the next update reinstalls even
+/// an unchanged release. Returns whether there was one.
+pub fn reset_update_hash(hash_file: &Path) -> Result<bool> {
+    if !utils::is_file(hash_file) {
+        return Ok(false);
+    }
+    try!(utils::remove_file("update hash", hash_file));
+    Ok(true)
+}
+
 pub fn update_from_dist_<'a>(download: DownloadCfg<'a>,
                              update_hash: Option<&Path>,
                              toolchain: &ToolchainDesc,
                              prefix: &InstallPrefix,
                              _add: &[Component],
                              _remove: &[Component],
-                             _force_update: bool)
+                             force_update: bool)
                              -> Result<Option<String>> {
 
     let toolchain_str = toolchain.to_string();
     let manifestation = try!(Manifestation::open(prefix.clone()));
 
-    let url = match toolchain_url(download, toolchain) {
-        Ok(url) => url,
+    // A profile change is applied to the installed release in place unless
+    // components have to be added
+    let installed = |url: &str| -> Result<bool> {
+        match update_hash {
+            Some(hash_file) if update_hash_matches(hash_file, url) => {
+                apply_profile(prefix.path(), download.profile, download.notify_handler)
+            }
+            _ => Ok(false),
+        }
+    };
+    let (url, deprecation) = match try!(resolve_update(download, toolchain, force_update, &installed)) {
+        Some(resolved) => resolved,
+        // Skip download, url matches
+        None => return Ok(None),
+    };
+
+    let origin = build_origin_name(toolchain.origin.as_ref(), &toolchain.channel);
+
+    // The release currently installed, which a delta may update from
+    let delta_from = match update_hash {
+        Some(hash_file) if download.delta_updates && utils::is_directory(prefix.path()) &&
+                           !is_incomplete(prefix.path()) => {
+            read_update_hash(hash_file).filter(|old| *old != url)
+                .and_then(|old| release_tag(&old).map(|tag| tag.to_owned()))
+        }
+        _ => None,
+    };
+
+    match manifestation.update(&origin,
+                               &url,
+                               delta_from.as_ref().map(|s| &**s),
+                               download) {
+        Ok(()) => {}
+        e @ Err(Error(ErrorKind::Utils(elan_utils::ErrorKind::DownloadNotExists { .. }), _)) => {
+            return e.chain_err(|| {
+                format!("could not download nonexistent lean version `{}`",
+                        toolchain_str)
+            }).map(|()| None);
+        }
+        Err(e) => return Err(e),
+    }
+    try!(record_installed_origin(prefix.path(), toolchain));
+    // Running the toolchain warns about it as well
+    if let Some(d) = deprecation {
+        let mut recorded = JsonValue::new_object();
+        recorded["yanked"] = d.yanked.into();
+        recorded["reason"] = d.reason.into();
+        try!(record_metadata(prefix.path(), "deprecation", recorded));
+    }
+    Ok(Some(url))
+}
+
+/// Whether `update_from_dist` would install anything, without writing
+/// anything. Releases are resolved and refused the same way.
+pub fn update_available<'a>(download: DownloadCfg<'a>,
+                            update_hash: Option<&Path>,
+                            toolchain: &ToolchainDesc,
+                            prefix: &InstallPrefix,
+                            force_update: bool)
+                            -> Result<bool> {
+    let installed = |url: &str| -> Result<bool> {
+        Ok(match update_hash {
+            Some(hash_file) if update_hash_matches(hash_file, url) => {
+                !profile_needs_reinstall(prefix.path(), download.profile)
+            }
+            _ => false,
+        })
+    };
+    Ok(try!(resolve_update(download, toolchain, force_update, &installed)).is_some())
+}
+
+// The release to update `toolchain` to and what the origin says about it,
+// or `None` if `installed` says the release is installed already, which
+// is checked before as well as after the release is refused or replaced
+// for being yanked
+fn resolve_update<'a>(download: DownloadCfg<'a>,
+                      toolchain: &ToolchainDesc,
+                      force_update: bool,
+                      installed: &Fn(&str) -> Result<bool>)
+                      -> Result<Option<(String, Option<Deprecation>)>> {
+    let (url, releases) = match toolchain_url(download, toolchain) {
+        Ok(resolved) => resolved,
         Err(Error(ErrorKind::Utils(elan_utils::ErrorKind::DownloadNotExists { .. }), _)) => {
             return Err(format!("no release found for '{}'", toolchain.manifest_name()).into());
         }
@@ -174,56 +834,536 @@ pub fn update_from_dist_<'a>(download: DownloadCfg<'a>,
             });
         }
     };
+    if try!(installed(&url)) {
+        return Ok(None);
+    }
 
-    if let Some(hash_file) = update_hash {
-        if utils::is_file(hash_file) {
-            if let Ok(contents) = utils::read_file("update hash", hash_file) {
-                if contents == url {
-                    // Skip download, url matches
-                    return Ok(None);
-                }
-            } /*else {
-                (self.notify_handler)(Notification::CantReadUpdateHash(hash_file));
-            }*/
-        } /*else {
-            (self.notify_handler)(Notification::NoUpdateHash(hash_file));
-        }*/
+    let resolved = url.clone();
+    let (url, deprecation) = try!(check_release(toolchain, url, releases, force_update,
+                                                download.include_prereleases, download.notify_handler));
+    if url != resolved && try!(installed(&url)) {
+        return Ok(None);
     }
+    Ok(Some((url, deprecation)))
+}
 
-    match manifestation.update(&build_origin_name(toolchain.origin.as_ref(), &toolchain.channel),
-                               &url,
-                               &download.temp_cfg,
-                               download.notify_handler.clone()) {
-        Ok(()) => Ok(()),
-        e @ Err(Error(ErrorKind::Utils(elan_utils::ErrorKind::DownloadNotExists { .. }), _)) => {
-            e.chain_err(|| {
-                format!("could not download nonexistent lean version `{}`",
-                        toolchain_str)
-            })
-        }
-        Err(e) => Err(e),
-    }.map(|()| Some(url))
+fn toolchain_url<'a>(download: DownloadCfg<'a>, toolchain: &ToolchainDesc) -> Result<(String, Option<Vec<Release>>)> {
+    resolve_toolchain_release(toolchain, download.include_prereleases, download.notify_handler)
+}
+
+/// The release tag of a release page as returned by
+/// `resolve_toolchain_url`, e.g. `v4.1.0`
+pub fn release_tag(url: &str) -> Option<&str> {
+    let tag = url.splitn(2, "/releases/tag/").nth(1)?;
+    if tag.is_empty() || tag.contains('/') { None } else { Some(tag) }
+}
+
+/// Determines the release page of `toolchain`, looking up the newest
+/// matching release for tracking toolchains. `stable` only resolves to
+/// pre-releases if `include_prereleases` is set.
+pub fn resolve_toolchain_url(toolchain: &ToolchainDesc,
+                             include_prereleases: bool,
+                             notify_handler: &Fn(Notification)) -> Result<String> {
+    resolve_toolchain_release(toolchain, include_prereleases, notify_handler).map(|(url, _)| url)
 }
 
-fn toolchain_url<'a>(download: DownloadCfg<'a>, toolchain: &ToolchainDesc) -> Result<String> {
+// Like `resolve_toolchain_url`, also returning the release list if it was
+// needed for the resolution. Otherwise only the latest release tag is
+// looked up, which isn't subject to the GitHub API's rate limit, and
+// deprecations are left to `check_release`.
+fn resolve_toolchain_release(toolchain: &ToolchainDesc,
+                             include_prereleases: bool,
+                             notify_handler: &Fn(Notification)) -> Result<(String, Option<Vec<Release>>)> {
     let origin = build_origin_name(toolchain.origin.as_ref(), toolchain.channel.as_ref());
-    Ok(match (toolchain.date.as_ref(), toolchain.channel.as_str()) {
+    let (release, releases) = match (toolchain.date.as_ref(), toolchain.channel.as_str()) {
         (None, version) if version == "stable" || version == "nightly" => {
-            (download.notify_handler)(Notification::DownloadingManifest(version));
-            let release = utils::fetch_latest_release_tag(&origin)?;
-            (download.notify_handler)(Notification::DownloadedManifest(version, Some(&release)));
-            format!("https://github.com/{}/releases/tag/{}", origin, release)
-        }
-        (Some(date), "nightly") =>
-            format!("https://github.com/{}/releases/tag/nightly-{}", origin, date),
-        (None, version) if version.starts_with(|c: char| c.is_numeric()) =>
-            format!("https://github.com/{}/releases/tag/v{}", origin, version),
-        (None, tag) =>
-            format!("https://github.com/{}/releases/tag/{}", origin, tag),
+            notify_handler(Notification::DownloadingManifest(version));
+            let latest = utils::fetch_latest_release_tag(&origin)?;
+            // GitHub's "latest" release is not necessarily a final one
+            let needs_list = version == "stable" &&
+                (include_prereleases || Version::parse(&latest).map_or(false, |v| v.is_prerelease()));
+            let (release, releases) = if needs_list {
+                let releases = fetch_releases(&origin)?;
+                let release = latest_unyanked(&releases, version, include_prereleases)
+                    .ok_or_else(|| format!("no release found for '{}'", origin))?;
+                (release, Some(releases))
+            } else {
+                (latest, None)
+            };
+            notify_handler(Notification::DownloadedManifest(version, Some(&release)));
+            (release, releases)
+        }
+        (None, version) if PartialVersion::parse(version).is_some() => {
+            notify_handler(Notification::DownloadingManifest(version));
+            let releases = fetch_releases(&origin)?;
+            let release = latest_unyanked(&releases, version, false)
+                .ok_or_else(|| format!("no release found matching '{}'", version))?;
+            notify_handler(Notification::DownloadedManifest(version, Some(&release)));
+            (release, Some(releases))
+        }
+        (Some(date), "nightly") => (format!("nightly-{}", date), None),
+        (None, version) if version.starts_with(|c: char| c.is_numeric()) => (format!("v{}", version), None),
+        (None, tag) => (tag.to_owned(), None),
         _ => panic!("wat"),
-    })
+    };
+    if let Some(deprecation) = releases.as_ref().and_then(|r| deprecation_of(r, &release)) {
+        notify_handler(Notification::ToolchainDeprecated {
+            name: &toolchain.at_release(&release).name(),
+            reason: &deprecation.reason,
+        });
+    }
+    Ok((release_url(&origin, &release), releases))
+}
+
+fn release_url(origin: &str, release: &str) -> String {
+    format!("{}/{}/releases/tag/{}", DIST_SERVER, origin, release)
 }
 
 pub fn host_triple() -> &'static str {
     include_str!(concat!(env!("OUT_DIR"), "/target.txt"))
-}
\ No newline at end of file
+}
+
+/// Where toolchains are resolved and downloaded from, with all
+/// configuration applied, see `Cfg::dist_config`
+#[derive(Clone, Debug, PartialEq)]
+pub struct DistConfig {
+    /// The configured default origin, if any
+    pub default_origin: Option<String>,
+    /// Repositories that `stable`, `nightly` and bare versions like
+    /// `3.4.2` are resolved against
+    pub stable_origin: String,
+    pub nightly_origin: String,
+    pub release_origin: String,
+    pub dist_server: String,
+    /// Servers release artifacts are downloaded from instead, in order
+    pub mirrors: Vec<String>,
+    pub host_triple: String,
+    pub include_prereleases: bool,
+    /// Whether `ELAN_GITHUB_TOKEN` is set. It is sent with requests to
+    /// GitHub only, never to mirrors.
+    pub has_auth_token: bool,
+}
+
+impl DistConfig {
+    pub fn new(default_origin: Option<&str>, include_prereleases: bool, has_auth_token: bool) -> Self {
+        let origin = |channel: &str| {
+            let desc = ToolchainDesc { origin: None, channel: channel.to_owned(), date: None }
+                .with_default_origin(default_origin);
+            build_origin_name(desc.origin.as_ref(), channel)
+        };
+        DistConfig {
+            default_origin: default_origin.map(|o| o.to_owned()),
+            stable_origin: origin("stable"),
+            nightly_origin: origin("nightly"),
+            release_origin: origin("3.4.2"),
+            dist_server: DIST_SERVER.to_owned(),
+            mirrors: vec![],
+            host_triple: host_triple().trim().to_owned(),
+            include_prereleases: include_prereleases,
+            has_auth_token: has_auth_token,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elan_utils::test_dir::TestDir;
+    use std::fs;
+
+    // Bare names resolve against the default origin until installed, and
+    // against the origin they were installed from afterwards
+    #[test]
+    fn test_installed_origin_is_pinned() {
+        use std::fs;
+
+        let root = TestDir::new("installed-origin");
+        let url = |name: &str, dir: &Path, default_origin| {
+            resolve_toolchain_url(&installed_desc(name, dir, default_origin).unwrap(), false, &|_| ()).unwrap()
+        };
+
+        let fork = root.join("4.2.0");
+        assert_eq!(url("4.2.0", &fork, Some("myorg/lean4")), "https://github.com/myorg/lean4/releases/tag/v4.2.0");
+        let desc = installed_desc("4.2.0", &fork, Some("myorg/lean4")).unwrap();
+        fs::create_dir_all(&fork).unwrap();
+        record_installed_origin(&fork, &desc).unwrap();
+        assert_eq!(url("4.2.0", &fork, Some("other/lean4")), "https://github.com/myorg/lean4/releases/tag/v4.2.0");
+        assert_eq!(url("4.2.0", &fork, None), "https://github.com/myorg/lean4/releases/tag/v4.2.0");
+
+        // Installed from the official repositories, before or after elan
+        // recorded the origin
+        let official = root.join("stable");
+        fs::create_dir_all(&official).unwrap();
+        let stable = |default_origin| installed_desc("stable", &official, default_origin).unwrap();
+        assert_eq!(stable(Some("myorg/lean4")).origin, None);
+        record_installed_origin(&official, &ToolchainDesc::from_str("stable").unwrap()).unwrap();
+        assert_eq!(stable(Some("myorg/lean4")).origin, None);
+        assert_eq!(build_origin_name(stable(Some("myorg/lean4")).origin.as_ref(), "stable"), DEFAULT_CHANNEL_ORIGIN);
+
+        // Explicit origins always win
+        let qualified = installed_desc("leanprover/lean4:4.2.0", &fork, Some("other/lean4")).unwrap();
+        assert_eq!(qualified.origin, Some("leanprover/lean4".to_owned()));
+    }
+
+    #[test]
+    fn test_dist_config() {
+        let official = DistConfig::new(None, false, false);
+        assert_eq!(official.stable_origin, DEFAULT_CHANNEL_ORIGIN);
+        assert_eq!(official.nightly_origin, format!("{}-nightly", DEFAULT_CHANNEL_ORIGIN));
+        assert_eq!(official.release_origin, DEFAULT_ORIGIN);
+        assert_eq!(official.dist_server, DIST_SERVER);
+
+        let overridden = DistConfig::new(Some("leanprover/lean4"), true, true);
+        assert_eq!(overridden.default_origin, Some("leanprover/lean4".to_owned()));
+        assert_eq!(overridden.stable_origin, "leanprover/lean4");
+        assert_eq!(overridden.nightly_origin, official.nightly_origin);
+        assert_eq!(overridden.release_origin, "leanprover/lean4");
+        assert!(overridden.include_prereleases && overridden.has_auth_token);
+
+        // Agrees with what the resolver downloads
+        let desc = ToolchainDesc::from_str("4.0.0").unwrap().with_default_origin(Some("leanprover/lean4"));
+        let url = resolve_toolchain_url(&desc, false, &|_| ()).unwrap();
+        assert_eq!(url, format!("{}/{}/releases/tag/v4.0.0", overridden.dist_server, overridden.release_origin));
+    }
+
+    #[test]
+    fn test_yanked_releases_are_skipped() {
+        let releases = parse_releases(r#"[
+            {"tag_name": "v4.2.0", "assets": [{"name": "YANKED", "label": "breaks `lake build`"},
+                                              {"name": "DEPRECATED", "label": "superseded"}]},
+            {"tag_name": "v4.1.1", "assets": [{"name": "DEPRECATED", "label": " use v4.1.2 instead "}]},
+            {"tag_name": "v4.1.0", "body": "Yanked: mentioned in the notes only", "assets": []},
+            {"tag_name": "v4.0.0", "assets": [{"name": "lean-4.0.0-linux.tar.zst", "label": "YANKED"}]},
+            {"tag_name": "nightly-2021-06-02", "assets": [{"name": "YANKED", "label": null}]},
+            {"tag_name": "nightly-2021-06-01"}
+        ]"#).unwrap();
+        assert_eq!(releases.len(), 6);
+        assert_eq!(releases[0].deprecation, Some(Deprecation { yanked: true, reason: "breaks `lake build`".to_owned() }));
+        assert_eq!(releases[1].deprecation, Some(Deprecation { yanked: false, reason: "use v4.1.2 instead".to_owned() }));
+        assert_eq!(releases[2].deprecation, None);
+        assert_eq!(releases[3].deprecation, None);
+        assert_eq!(releases[4].deprecation, Some(Deprecation { yanked: true, reason: "no reason given".to_owned() }));
+
+        // Deprecated releases are still picked, yanked ones aren't
+        assert_eq!(latest_unyanked(&releases, "stable", false), Some("v4.1.1".to_owned()));
+        assert_eq!(latest_unyanked(&releases, "4", false), Some("v4.1.1".to_owned()));
+        assert_eq!(latest_unyanked(&releases, "4.2", false), None);
+        assert_eq!(latest_unyanked(&releases, "nightly", false), Some("nightly-2021-06-01".to_owned()));
+        assert_eq!(deprecation_of(&releases, "v4.1.1").map(|d| d.yanked), Some(false));
+
+        // Releases resolution already picked are checked against the list
+        let desc = |name: &str| ToolchainDesc::from_str(name).unwrap();
+        let check = |name: &str, tag: &str, force: bool| {
+            let url = release_url(&build_origin_name(desc(name).origin.as_ref(), &desc(name).channel), tag);
+            check_release(&desc(name), url, Some(releases.clone()), force, false, &|_| ())
+                .map(|(url, d)| (release_tag(&url).unwrap().to_owned(), d.map(|d| d.yanked)))
+        };
+        assert!(check("leanprover/lean4:v4.2.0", "v4.2.0", false).is_err());
+        assert_eq!(check("leanprover/lean4:v4.2.0", "v4.2.0", true).unwrap(), ("v4.2.0".to_owned(), Some(true)));
+        assert_eq!(check("leanprover/lean4:v4.1.1", "v4.1.1", false).unwrap(), ("v4.1.1".to_owned(), Some(false)));
+        assert_eq!(check("leanprover/lean4:stable", "v4.2.0", false).unwrap(), ("v4.1.1".to_owned(), Some(false)));
+        assert_eq!(check("leanprover/lean4:v4.1.0", "v4.1.0", false).unwrap(), ("v4.1.0".to_owned(), None));
+
+        assert!(parse_releases("{\"message\": \"API rate limit exceeded\"}").is_err());
+        assert!(parse_releases("[{\"tag_name\": ").is_err());
+    }
+
+    #[test]
+    fn test_release_tag() {
+        assert_eq!(release_tag("https://github.com/leanprover/lean4/releases/tag/v4.1.0"), Some("v4.1.0"));
+        assert_eq!(release_tag("https://github.com/leanprover/lean4-nightly/releases/tag/nightly-2021-06-01"),
+                   Some("nightly-2021-06-01"));
+        assert_eq!(release_tag("https://github.com/leanprover/lean4/releases"), None);
+        assert_eq!(release_tag("https://github.com/leanprover/lean4/releases/tag/"), None);
+    }
+
+    #[test]
+    fn test_default_origin() {
+        let stable = ToolchainDesc::from_str("stable").unwrap().with_default_origin(Some("myorg/lean4"));
+        assert_eq!(stable.origin, Some("myorg/lean4".to_owned()));
+        assert_eq!(build_origin_name(stable.origin.as_ref(), &stable.channel), "myorg/lean4");
+
+        // Forks have no nightly repository
+        let nightly = ToolchainDesc::from_str("nightly-2021-06-01").unwrap().with_default_origin(Some("myorg/lean4"));
+        assert_eq!(nightly.origin, None);
+        assert_eq!(build_origin_name(nightly.origin.as_ref(), &nightly.channel),
+                   format!("{}-nightly", DEFAULT_CHANNEL_ORIGIN));
+        let nightly = ToolchainDesc::from_str("myorg/lean4:nightly").unwrap().with_default_origin(Some("other/lean4"));
+        assert_eq!(build_origin_name(nightly.origin.as_ref(), &nightly.channel), "myorg/lean4-nightly");
+
+        let qualified = ToolchainDesc::from_str("leanprover/lean4:stable").unwrap().with_default_origin(Some("myorg/lean4"));
+        assert_eq!(qualified.origin, Some("leanprover/lean4".to_owned()));
+
+        let unset = ToolchainDesc::from_str("stable").unwrap().with_default_origin(None);
+        assert_eq!(build_origin_name(unset.origin.as_ref(), &unset.channel), DEFAULT_CHANNEL_ORIGIN);
+
+        assert!(ToolchainDesc::is_valid_origin("myorg/lean4"));
+        assert!(!ToolchainDesc::is_valid_origin("myorg"));
+        assert!(!ToolchainDesc::is_valid_origin("myorg/lean4:stable"));
+    }
+
+    #[test]
+    fn test_corrupt_update_hash_forces_reinstall() {
+        let dir = TestDir::new("hash");
+        let hash_file = dir.join("stable");
+        let url = "https://github.com/leanprover/lean4/releases/expanded_assets/v4.0.0";
+
+        assert!(!update_hash_matches(&hash_file, url));
+
+        utils::write_file_atomic("update hash", &hash_file, url).unwrap();
+        assert!(update_hash_matches(&hash_file, url));
+
+        // A crash before or while the contents were flushed
+        for len in &[0, 8, url.len() / 2] {
+            fs::write(&hash_file, &url[..*len]).unwrap();
+            assert!(!update_hash_matches(&hash_file, url));
+        }
+        fs::write(&hash_file, &[0xff, 0xfe, 0x00]).unwrap();
+        assert_eq!(read_update_hash(&hash_file), None);
+    }
+
+    #[test]
+    fn test_reset_update_hash() {
+        let dir = TestDir::new("reset-hash");
+        let hash_file = dir.join("stable");
+        let url = "https://github.com/leanprover/lean4/releases/expanded_assets/v4.0.0";
+
+        utils::write_file_atomic("update hash", &hash_file, url).unwrap();
+        assert!(reset_update_hash(&hash_file).unwrap());
+        assert!(!update_hash_matches(&hash_file, url));
+        // Nothing to reset is fine
+        assert!(!reset_update_hash(&hash_file).unwrap());
+    }
+
+    #[test]
+    fn test_latest_matching_version() {
+        let tags = vec!["v4.0.0", "v4.1.0", "v4.1.2", "v4.1.10", "v4.2.0-rc1",
+                        "v4.2.0", "v3.9.9", "nightly-2021-05-01"];
+        assert_eq!(latest_matching_version(&tags, "v4.1"), Some("v4.1.10".to_owned()));
+        assert_eq!(latest_matching_version(&tags, "4.1"), Some("v4.1.10".to_owned()));
+        assert_eq!(latest_matching_version(&tags, "v4"), Some("v4.2.0".to_owned()));
+        assert_eq!(latest_matching_version(&tags, "v3"), Some("v3.9.9".to_owned()));
+        assert_eq!(latest_matching_version(&tags, "v5"), None);
+        assert_eq!(latest_matching_version(&tags, "v4.1.2"), None);
+
+        let prereleases_only = vec!["v5.0.0-rc1", "v5.0.0-rc2"];
+        assert_eq!(latest_matching_version(&prereleases_only, "v5"), None);
+
+        let empty: Vec<String> = vec![];
+        assert_eq!(latest_matching_version(&empty, "v4.1"), None);
+
+        // Too large for a version number, but not a reason to panic
+        assert_eq!(latest_matching_version(&tags, "v99999999999999999999"), None);
+        assert!(ToolchainDesc::from_str("4.99999999999999999999").map(|d| !d.is_partial_version()).unwrap_or(true));
+    }
+
+    #[test]
+    fn test_nightly_date_queries() {
+        let tags = vec!["nightly-2021-05-01", "nightly-2021-05-04", "nightly-2021-06-30",
+                        "v4.0.0", "nightly-bogus"];
+        assert_eq!(nearest_nightly(&tags, "2021-05-01", 0).unwrap(), "2021-05-01");
+        assert_eq!(nearest_nightly(&tags, "2021-05-03", 3).unwrap(), "2021-05-04");
+        // ties prefer the earlier nightly
+        assert_eq!(nearest_nightly(&tags, "2021-05-02", 3).unwrap(), "2021-05-01");
+        assert_eq!(nearest_nightly(&tags, "2021-06-20", 30).unwrap(), "2021-06-30");
+        assert!(nearest_nightly(&tags, "2021-06-01", 7).is_err());
+        assert!(nearest_nightly(&tags, "2021-13-01", 7).is_err());
+
+        assert_eq!(nightlies_in_range(&tags, "2021-04-30", "2021-05-31").unwrap(),
+                   vec!["2021-05-01", "2021-05-04"]);
+        assert!(nightlies_in_range(&tags, "2022-01-01", "2022-12-31").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_prerelease_parsing() {
+        for name in &["v4.0.0-rc1", "leanprover/lean4:v4.0.0-beta.2", "4.0.0-alpha"] {
+            let desc = ToolchainDesc::from_str(name).unwrap();
+            assert!(desc.is_prerelease(), "{}", name);
+            assert!(!desc.is_tracking(), "{}", name);
+        }
+        assert_eq!(ToolchainDesc::from_str("leanprover/lean4:v4.0.0-rc1").unwrap().channel, "v4.0.0-rc1");
+        assert!(!ToolchainDesc::from_str("v4.0.0").unwrap().is_prerelease());
+        assert!(!ToolchainDesc::from_str("nightly").unwrap().is_prerelease());
+    }
+
+    #[test]
+    fn test_version_ordering() {
+        let desc = |s| ToolchainDesc::from_str(s).unwrap();
+        let ordered = ["v4.0.0-foo", "v4.0.0-alpha", "v4.0.0-alpha2", "v4.0.0-beta.1",
+                       "v4.0.0-rc1", "v4.0.0-rc2", "v4.0.0-rc10", "v4.0.0", "v4.0.1", "v4.10.0"];
+        for w in ordered.windows(2) {
+            assert_eq!(desc(w[0]).cmp_version(&desc(w[1])), Ordering::Less, "{} < {}", w[0], w[1]);
+        }
+        assert_eq!(desc("4.0.0").cmp_version(&desc("v4.0.0")), Ordering::Equal);
+        assert!(desc("4.0.0") != desc("v4.0.0"));
+        assert!(desc("v4.0.0-rc1").cmp_version(&desc("v4.0.0-rc2")) != Ordering::Equal);
+        assert_eq!(desc("nightly-2021-05-01").cmp_version(&desc("nightly-2021-05-02")), Ordering::Less);
+
+        let tags = vec!["v4.0.0-rc2", "v4.0.0-rc1", "v3.9.0", "nightly-2021-05-01"];
+        assert_eq!(latest_release(&tags, false), Some("v3.9.0".to_owned()));
+        assert_eq!(latest_release(&tags, true), Some("v4.0.0-rc2".to_owned()));
+    }
+
+    #[test]
+    fn test_sort_by_version() {
+        let mut names = vec![
+            "leanprover/lean4:v4.2.0",
+            "my-lean",
+            "leanprover/lean4:nightly-2021-06-01",
+            "leanprover/lean4:v4.10.0",
+            "leanprover-community/lean:v3.30.0",
+            "leanprover/lean4:v4.10.0-rc1",
+            "leanprover/lean4:nightly-2021-07-01",
+            "a-custom-lean",
+            "leanprover/lean4:v4.1.0",
+        ];
+        sort_by_version(&mut names, |n| n, |n| if n.contains(':') { ToolchainDesc::from_str(n).ok() } else { None });
+        assert_eq!(names, vec![
+            "leanprover-community/lean:v3.30.0",
+            "leanprover/lean4:nightly-2021-07-01",
+            "leanprover/lean4:nightly-2021-06-01",
+            "leanprover/lean4:v4.10.0",
+            "leanprover/lean4:v4.10.0-rc1",
+            "leanprover/lean4:v4.2.0",
+            "leanprover/lean4:v4.1.0",
+            "a-custom-lean",
+            "my-lean",
+        ]);
+    }
+
+    #[test]
+    fn test_dist_toolchain_name() {
+        for &(name, expected) in &[
+            ("stable", "stable"),
+            ("leanprover/lean4:nightly", "leanprover/lean4:nightly"),
+            ("nightly-2021-06-01", "nightly-2021-06-01"),
+            ("4.0.0", "4.0.0"),
+            ("v4.1", "v4.1"),
+            ("v4.0.0-rc1", "v4.0.0-rc1"),
+            ("nightly", "nightly"),
+            ("myorg/lean4:my-branch", "myorg/lean4:my-branch"),
+            ("https://github.com/leanprover/lean4/releases/tag/v4.0.0", "leanprover/lean4:v4.0.0"),
+            ("https://github.com/leanprover/lean4-nightly/releases/tag/nightly-2021-06-01/",
+             "leanprover/lean4:nightly-2021-06-01"),
+        ] {
+            assert_eq!(dist_toolchain_name(name).unwrap(), expected);
+        }
+
+        for name in &["my lean", "my_lean", "leanprover/lean4", "my-lean", "lean4", "v", "release-1"] {
+            match dist_toolchain_name(name) {
+                Err(Error(ErrorKind::CustomToolchainName(ref n), _)) if n == name => {}
+                r => panic!("expected '{}' to be custom, got {:?}", name, r),
+            }
+        }
+        match dist_toolchain_name("https://github.com/leanprover/lean4/releases") {
+            Err(Error(ErrorKind::InvalidReleaseUrl(_), _)) => {}
+            r => panic!("expected an invalid release url, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_toolchain_kind() {
+        assert_eq!(ToolchainKind::of("my-lean", true), ToolchainKind::Custom);
+        assert_eq!(ToolchainKind::of("nightly", true), ToolchainKind::Custom);
+        assert_eq!(ToolchainKind::of("weird name!", false), ToolchainKind::Custom);
+        assert_eq!(ToolchainKind::of("leanprover/lean4:nightly", false),
+                   ToolchainKind::TrackingChannel { channel: "nightly".to_owned() });
+        assert_eq!(ToolchainKind::of("v4.1", false),
+                   ToolchainKind::TrackingChannel { channel: "v4.1".to_owned() });
+        assert_eq!(ToolchainKind::of("nightly-2021-05-01", false),
+                   ToolchainKind::PinnedVersion { version: "nightly-2021-05-01".to_owned() });
+        assert_eq!(ToolchainKind::of("leanprover/lean4:v4.0.0", false),
+                   ToolchainKind::PinnedVersion { version: "v4.0.0".to_owned() });
+    }
+
+    #[test]
+    fn test_partial_version_is_tracking() {
+        assert!(ToolchainDesc::from_str("leanprover/lean4:v4.1").unwrap().is_tracking());
+        assert!(ToolchainDesc::from_str("v4").unwrap().is_tracking());
+        assert!(!ToolchainDesc::from_str("leanprover/lean4:v4.1.0").unwrap().is_tracking());
+        assert!(!ToolchainDesc::from_str("3.4.2").unwrap().is_tracking());
+    }
+
+    #[test]
+    fn test_short_name() {
+        for &(name, short) in &[
+            ("leanprover/lean4:v4.1.0", "v4.1.0"),
+            ("leanprover/lean4:stable", "stable"),
+            ("leanprover/lean4:nightly-2021-06-01", "nightly-2021-06-01"),
+            ("leanprover/lean4-nightly:nightly-2021-06-01", "leanprover/lean4-nightly:nightly-2021-06-01"),
+            ("leanprover-community/lean:v3.30.0", "leanprover-community/lean:v3.30.0"),
+            ("stable", "stable"),
+            ("v4.1.0", "v4.1.0"),
+        ] {
+            assert_eq!(ToolchainDesc::from_str(name).unwrap().short_name(), short);
+            assert_eq!(short_toolchain_name(name), short);
+        }
+        assert_eq!(short_toolchain_name("my custom"), "my custom");
+
+        let names = ["leanprover/lean4:v4.1.0", "v4.1.0", "leanprover/lean4:v4.2.0",
+                     "leanprover/lean4:stable", "stable-backup"];
+        assert_eq!(short_toolchain_names(&names),
+                   vec!["leanprover/lean4:v4.1.0", "v4.1.0", "v4.2.0", "stable", "stable-backup"]);
+    }
+
+    #[test]
+    fn test_releases_between() {
+        let tags = ["v4.0.0", "v4.1.0-rc1", "v4.1.0", "v4.2.0", "nightly-2021-06-01", "nightly-2021-06-03", "latest"];
+        assert_eq!(releases_between(&tags, "v4.0.0", "v4.2.0", false), 2);
+        assert_eq!(releases_between(&tags, "v4.0.0", "v4.2.0", true), 3);
+        assert_eq!(releases_between(&tags, "4.1.0", "v4.2.0", false), 1);
+        assert_eq!(releases_between(&tags, "v4.2.0", "v4.2.0", false), 0);
+        assert_eq!(releases_between(&tags, "nightly-2021-05-30", "nightly-2021-06-03", false), 2);
+        assert_eq!(releases_between(&tags, "custom", "v4.2.0", false), 0);
+    }
+
+    // What `Toolchain::is_up_to_date` reports
+    #[test]
+    fn test_update_availability() {
+        let page = |tag| format!("https://github.com/leanprover/lean4/releases/tag/{}", tag);
+        let fetch_tags = |repo: &str| -> Result<Vec<String>> {
+            assert_eq!(repo, "leanprover/lean4");
+            Ok(vec!["v4.0.0".to_owned(), "v4.1.0".to_owned(), "v4.2.0".to_owned()])
+        };
+        let offline = |_: &str| -> Result<Vec<String>> { Err("offline".into()) };
+
+        assert_eq!(update_availability(Some(&page("v4.2.0")), &page("v4.2.0"), false, &fetch_tags),
+                   UpdateAvailability::UpToDate);
+        assert_eq!(update_availability(Some(&page("v4.0.0")), &page("v4.2.0"), false, &fetch_tags),
+                   UpdateAvailability::UpdateAvailable { latest: "v4.2.0".to_owned(), behind: Some(2) });
+        // How far behind is unknown without the tags or the installed release
+        assert_eq!(update_availability(Some(&page("v4.0.0")), &page("v4.2.0"), false, &offline),
+                   UpdateAvailability::UpdateAvailable { latest: "v4.2.0".to_owned(), behind: None });
+        assert_eq!(update_availability(None, &page("v4.2.0"), false, &fetch_tags),
+                   UpdateAvailability::UpdateAvailable { latest: "v4.2.0".to_owned(), behind: None });
+    }
+
+    #[test]
+    fn test_release_url_parsing() {
+        for &(url, name) in &[
+            ("https://github.com/leanprover/lean4/releases/tag/v4.0.0", "leanprover/lean4:v4.0.0"),
+            ("https://github.com/leanprover/lean4/releases/tag/v4.1.0-rc1/", "leanprover/lean4:v4.1.0-rc1"),
+            ("https://github.com/leanprover/lean4-nightly/releases/tag/nightly-2021-06-01",
+             "leanprover/lean4:nightly-2021-06-01"),
+            ("https://github.com/leanprover-community/lean/releases/tag/v3.30.0",
+             "leanprover-community/lean:v3.30.0"),
+        ] {
+            let desc = ToolchainDesc::from_str(url).unwrap();
+            assert_eq!(desc.name(), name);
+            assert_eq!(ToolchainDesc::from_str(&desc.name()).unwrap().name(), name);
+        }
+
+        for url in &[
+            "https://github.com/leanprover/lean4/releases",
+            "https://github.com/leanprover/lean4/releases/download/v4.0.0/lean.zip",
+            "https://gitlab.com/leanprover/lean4/releases/tag/v4.0.0",
+            "https://github.com/leanprover/lean4/releases/tag/stable",
+            "https://github.com/leanprover/lean4/releases/tag/4.0.0",
+        ] {
+            match ToolchainDesc::from_str(url) {
+                Err(Error(ErrorKind::InvalidReleaseUrl(_), _)) => {}
+                r => panic!("expected '{}' to be rejected, got {:?}", url, r),
+            }
+        }
+    }
+}
@@ -2,7 +2,7 @@ use std::path::PathBuf;
 use std::io::{self, Write};
 use temp;
 use toml;
-use elan_utils;
+use elan_utils::{self, codes};
 use manifest::Component;
 
 error_chain! {
@@ -16,10 +16,117 @@ error_chain! {
     }
 
     errors {
+        Cancelled {
+            description("installation cancelled")
+        }
+        CaseInsensitiveCollision {
+            a: PathBuf,
+            b: PathBuf,
+        } {
+            description("archive contains paths that differ only in case")
+            display("'{}' and '{}' differ only in case and can't both be extracted on this file system",
+                    a.display(), b.display())
+        }
+        InvalidProfile(p: String) {
+            description("invalid profile")
+            display("invalid profile '{}', expected one of: minimal, default, complete", p)
+        }
+        InvalidToolchainConfig(p: PathBuf) {
+            description("invalid toolchain config")
+            display("invalid toolchain config '{}'", p.display())
+        }
+        InvalidLeanpkgFile(path: PathBuf, error: toml::de::Error) {
+            description("couldn't parse 'leanpkg.toml'")
+            display("couldn't parse '{}': '{}'", path.display(), error)
+        }
+        InvalidLeanVersion(path: PathBuf, t: &'static str) {
+            description("invalid 'package.lean_version' value")
+            display("invalid 'package.lean_version' value in '{}': expected string instead of {}", path.display(), t)
+        }
+        EmptyDownload(url: String) {
+            description("downloaded archive is empty")
+            display("the archive downloaded from '{}' is empty, a proxy may be interfering", url)
+        }
+        TruncatedDownload {
+            url: String,
+            expected: u64,
+            got: u64,
+        } {
+            description("downloaded archive is incomplete")
+            display("the archive downloaded from '{}' is incomplete: expected {} bytes, got {}", url, expected, got)
+        }
+        InsufficientDiskSpace {
+            required: u64,
+            available: u64,
+        } {
+            description("insufficient disk space")
+            display("not enough disk space to install the toolchain: needs {:.1} MiB, {:.1} MiB available",
+                    *required as f64 / (1024.0 * 1024.0), *available as f64 / (1024.0 * 1024.0))
+        }
+        InvalidUrlRewrite(pattern: String, m: String) {
+            description("invalid url rewrite rule")
+            display("invalid url rewrite pattern '{}': {}", pattern, m)
+        }
+        InvalidBundle(p: PathBuf) {
+            description("invalid toolchain bundle")
+            display("'{}' is not a valid toolchain bundle", p.display())
+        }
+        InvalidDelta(p: PathBuf) {
+            description("invalid toolchain delta")
+            display("'{}' is not a valid toolchain delta", p.display())
+        }
+        InvalidDigests(url: String) {
+            description("invalid digest list")
+            display("'{}' is not a valid digest list", url)
+        }
+        DeltaMismatch(path: String) {
+            description("patched file does not match the new release")
+            display("patched file '{}' does not match the new release", path)
+        }
+        YankedRelease(name: String, reason: String) {
+            description("release has been yanked")
+            display("toolchain '{}' has been yanked: {} (use --force to install it anyway)", name, reason)
+        }
+        MissingProvenance(url: String) {
+            description("release archive has no provenance attestation")
+            display("no provenance attestation found at '{}'", url)
+        }
+        InvalidProvenanceKey(p: PathBuf) {
+            description("invalid provenance key")
+            display("'{}' is not a PEM public key", p.display())
+        }
+        ProvenanceMismatch(m: String) {
+            description("provenance attestation does not match the release archive")
+            display("provenance attestation does not match: {}", m)
+        }
+        BundleTargetMismatch(bundle: String, host: String) {
+            description("toolchain bundle is for a different platform")
+            display("the toolchain bundle is for '{}', but this is '{}'", bundle, host)
+        }
+        InvalidLockfile(m: String) {
+            description("invalid lockfile")
+            display("invalid lockfile: {}", m)
+        }
         InvalidToolchainName(t: String) {
             description("invalid toolchain name")
             display("invalid toolchain name: '{}'", t)
         }
+        CustomToolchainName(t: String) {
+            description("not the name of a release")
+            display("'{}' is not the name of a release and can only be a custom toolchain, see `elan toolchain link`", t)
+        }
+        InvalidReleaseUrl(u: String) {
+            description("invalid release url")
+            display("'{}' is not a release page, expected a url of the form 'https://github.com/<owner>/<repo>/releases/tag/<tag>'", u)
+        }
+        InvalidDate(d: String) {
+            description("invalid date")
+            display("invalid date: '{}', expected YYYY-MM-DD", d)
+        }
+        NoNightlyNearDate(d: String, tolerance: u32) {
+            description("no nightly found near the requested date")
+            display("no nightly found within {} days of {}", tolerance, d)
+        }
         ChecksumFailed {
             url: String,
             expected: String,
@@ -104,6 +211,60 @@ error_chain! {
     }
 }
 
+impl ErrorKind {
+    /// The exit code for this kind of error, see `elan_utils::codes`
+    pub fn error_code(&self) -> u32 {
+        match *self {
+            ErrorKind::Utils(ref kind) => kind.error_code(),
+            ErrorKind::Temp(ref e) => e.error_code(),
+            ErrorKind::InvalidToolchainName(_) |
+            ErrorKind::InvalidReleaseUrl(_) |
+            ErrorKind::InvalidDate(_) |
+            ErrorKind::CustomToolchainName(_) |
+            ErrorKind::YankedRelease(_, _) => codes::INVALID_TOOLCHAIN,
+            ErrorKind::NoNightlyNearDate(_, _) => codes::NOT_FOUND,
+            ErrorKind::ChecksumFailed { .. } |
+            ErrorKind::DeltaMismatch(_) |
+            ErrorKind::MissingProvenance(_) |
+            ErrorKind::ProvenanceMismatch(_) => codes::CHECKSUM,
+            ErrorKind::EmptyDownload(_) |
+            ErrorKind::TruncatedDownload { .. } => codes::NETWORK,
+            ErrorKind::InvalidProfile(_) |
+            ErrorKind::InvalidToolchainConfig(_) |
+            ErrorKind::InvalidLeanpkgFile(_, _) |
+            ErrorKind::InvalidLeanVersion(_, _) |
+            ErrorKind::InvalidUrlRewrite(_, _) |
+            ErrorKind::Parsing(_) |
+            ErrorKind::UnsupportedVersion(_) |
+            ErrorKind::InvalidBundle(_) |
+            ErrorKind::InvalidProvenanceKey(_) |
+            ErrorKind::InvalidDelta(_) |
+            ErrorKind::InvalidDigests(_) => codes::INVALID_CONFIG,
+            ErrorKind::BundleTargetMismatch(_, _) => codes::INCOMPATIBLE,
+            ErrorKind::InvalidLockfile(_) => codes::LOCKFILE,
+            ErrorKind::InsufficientDiskSpace { .. } => codes::NO_SPACE,
+            ErrorKind::Cancelled => codes::CANCELLED,
+            ErrorKind::Msg(_) |
+            ErrorKind::Io(_) |
+            ErrorKind::CaseInsensitiveCollision { .. } |
+            ErrorKind::ComponentConflict { .. } |
+            ErrorKind::ComponentMissingFile { .. } |
+            ErrorKind::ComponentMissingDir { .. } |
+            ErrorKind::CorruptComponent(_) |
+            ErrorKind::ExtractingPackage |
+            ErrorKind::BadInstallerVersion(_) |
+            ErrorKind::BadInstalledMetadataVersion(_) |
+            ErrorKind::ComponentDirPermissionsFailed |
+            ErrorKind::ComponentFilePermissionsFailed |
+            ErrorKind::ComponentDownloadFailed(_) |
+            ErrorKind::MissingPackageForComponent(_) |
+            ErrorKind::MissingPackageForRename(_) |
+            ErrorKind::RequestedComponentsUnavailable(_) |
+            ErrorKind::__Nonexhaustive { .. } => codes::FAILURE,
+        }
+    }
+}
+
 fn component_unavailable_msg(cs: &[Component]) -> String {
     assert!(!cs.is_empty());
 
@@ -125,3 +286,73 @@ fn component_unavailable_msg(cs: &[Component]) -> String {
 
     String::from_utf8(buf).expect("")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_error_codes() {
+        let path = || Path::new("lean.tar.gz").to_owned();
+        let s = || "lean".to_owned();
+        let component = || Component { pkg: s() };
+        let parse_error = toml::from_str::<toml::Value>("=").unwrap_err();
+        let temp_error = temp::Error::NotWritable {
+            var: "ELAN_TEMP_DIR",
+            path: path(),
+            error: io::Error::new(io::ErrorKind::PermissionDenied, "read-only"),
+        };
+        let kinds = vec![
+            (ErrorKind::Utils(elan_utils::ErrorKind::ExpectedType("string", s())), codes::INVALID_CONFIG),
+            (ErrorKind::Temp(temp_error), codes::INVALID_CONFIG),
+            (ErrorKind::InvalidToolchainName(s()), codes::INVALID_TOOLCHAIN),
+            (ErrorKind::InvalidReleaseUrl(s()), codes::INVALID_TOOLCHAIN),
+            (ErrorKind::InvalidDate(s()), codes::INVALID_TOOLCHAIN),
+            (ErrorKind::CustomToolchainName(s()), codes::INVALID_TOOLCHAIN),
+            (ErrorKind::YankedRelease(s(), s()), codes::INVALID_TOOLCHAIN),
+            (ErrorKind::NoNightlyNearDate(s(), 7), codes::NOT_FOUND),
+            (ErrorKind::ChecksumFailed { url: s(), expected: s(), calculated: s() }, codes::CHECKSUM),
+            (ErrorKind::DeltaMismatch(s()), codes::CHECKSUM),
+            (ErrorKind::MissingProvenance(s()), codes::CHECKSUM),
+            (ErrorKind::ProvenanceMismatch(s()), codes::CHECKSUM),
+            (ErrorKind::EmptyDownload(s()), codes::NETWORK),
+            (ErrorKind::TruncatedDownload { url: s(), expected: 2, got: 1 }, codes::NETWORK),
+            (ErrorKind::InvalidProfile(s()), codes::INVALID_CONFIG),
+            (ErrorKind::InvalidToolchainConfig(path()), codes::INVALID_CONFIG),
+            (ErrorKind::InvalidLeanpkgFile(path(), toml::from_str::<toml::Value>("=").unwrap_err()),
+             codes::INVALID_CONFIG),
+            (ErrorKind::InvalidLeanVersion(path(), "integer"), codes::INVALID_CONFIG),
+            (ErrorKind::InvalidUrlRewrite(s(), s()), codes::INVALID_CONFIG),
+            (ErrorKind::Parsing(parse_error), codes::INVALID_CONFIG),
+            (ErrorKind::UnsupportedVersion(s()), codes::INVALID_CONFIG),
+            (ErrorKind::InvalidBundle(path()), codes::INVALID_CONFIG),
+            (ErrorKind::InvalidProvenanceKey(path()), codes::INVALID_CONFIG),
+            (ErrorKind::InvalidDelta(path()), codes::INVALID_CONFIG),
+            (ErrorKind::InvalidDigests(s()), codes::INVALID_CONFIG),
+            (ErrorKind::BundleTargetMismatch(s(), s()), codes::INCOMPATIBLE),
+            (ErrorKind::InvalidLockfile(s()), codes::LOCKFILE),
+            (ErrorKind::InsufficientDiskSpace { required: 2, available: 1 }, codes::NO_SPACE),
+            (ErrorKind::Cancelled, codes::CANCELLED),
+            (ErrorKind::Msg(s()), codes::FAILURE),
+            (ErrorKind::Io(io::Error::new(io::ErrorKind::Other, "io")), codes::FAILURE),
+            (ErrorKind::CaseInsensitiveCollision { a: path(), b: path() }, codes::FAILURE),
+            (ErrorKind::ComponentConflict { name: s(), path: path() }, codes::FAILURE),
+            (ErrorKind::ComponentMissingFile { name: s(), path: path() }, codes::FAILURE),
+            (ErrorKind::ComponentMissingDir { name: s(), path: path() }, codes::FAILURE),
+            (ErrorKind::CorruptComponent(s()), codes::FAILURE),
+            (ErrorKind::ExtractingPackage, codes::FAILURE),
+            (ErrorKind::BadInstallerVersion(s()), codes::FAILURE),
+            (ErrorKind::BadInstalledMetadataVersion(s()), codes::FAILURE),
+            (ErrorKind::ComponentDirPermissionsFailed, codes::FAILURE),
+            (ErrorKind::ComponentFilePermissionsFailed, codes::FAILURE),
+            (ErrorKind::ComponentDownloadFailed(component()), codes::FAILURE),
+            (ErrorKind::MissingPackageForComponent(component()), codes::FAILURE),
+            (ErrorKind::MissingPackageForRename(s()), codes::FAILURE),
+            (ErrorKind::RequestedComponentsUnavailable(vec![component()]), codes::FAILURE),
+        ];
+        for (kind, code) in kinds {
+            assert_eq!(kind.error_code(), code, "{}", kind);
+        }
+    }
+}
@@ -13,6 +13,8 @@ extern crate error_chain;
 extern crate sha2;
 extern crate json;
 extern crate zip;
+extern crate openssl;
+extern crate semver;
 
 #[cfg(windows)]
 extern crate winapi;
@@ -23,15 +25,23 @@ extern crate libc;
 
 pub use errors::*;
 pub use notifications::{Notification};
+pub use manifestation::{is_incomplete, record_metadata, METADATA_FILE};
 
 pub mod temp;
+pub mod channel_history;
+pub mod bundle;
+pub mod delta;
 
 pub mod dist;
 pub mod errors;
 pub mod notifications;
 pub mod prefix;
+pub mod provenance;
 mod component;
 mod manifestation;
 pub mod download;
+pub mod lockfile;
 pub mod manifest;
+pub mod toolchain_file;
+pub mod version;
 pub mod config;
@@ -0,0 +1,149 @@
+//! `elan.lock` files, which pin the release a project's toolchain channel
+//! resolved to so that everyone working on it gets the same toolchain.
+//!
+//! ```toml
+//! version = "1"
+//!
+//! [toolchain]
+//! requested = "leanprover/lean4:stable"
+//! resolved = "leanprover/lean4:v4.1.0"
+//! url = "https://github.com/leanprover/lean4/releases/tag/v4.1.0"
+//! ```
+//!
+//! `requested` is the toolchain as named by the project, `resolved` the
+//! toolchain it stands for and `url` the release page it was resolved to.
+
+use toml;
+
+use elan_utils::toml_utils::*;
+use elan_utils::utils;
+use dist::{self, ToolchainDesc};
+use errors::*;
+use notifications::Notification;
+
+use std::path::Path;
+
+pub const LOCKFILE_NAME: &'static str = "elan.lock";
+
+pub const SUPPORTED_LOCKFILE_VERSIONS: [&'static str; 1] = ["1"];
+pub const DEFAULT_LOCKFILE_VERSION: &'static str = "1";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lockfile {
+    pub requested: String,
+    pub resolved: String,
+    pub url: String,
+}
+
+impl Lockfile {
+    /// Looks up the release `requested` currently stands for
+    pub fn resolve(requested: &str,
+                   desc: &ToolchainDesc,
+                   include_prereleases: bool,
+                   notify_handler: &Fn(Notification))
+                   -> Result<Self> {
+        let url = try!(dist::resolve_toolchain_url(desc, include_prereleases, notify_handler));
+        let tag = try!(dist::release_tag(&url)
+                       .ok_or_else(|| ErrorKind::InvalidLockfile(format!("unexpected release url '{}'", url))));
+        let resolved = desc.at_release(tag);
+        Ok(Lockfile {
+            requested: requested.to_owned(),
+            resolved: resolved.name(),
+            url: url,
+        })
+    }
+
+    pub fn from_toml(mut table: toml::value::Table, path: &str) -> Result<Self> {
+        let version = try!(get_string(&mut table, "version", path));
+        if !SUPPORTED_LOCKFILE_VERSIONS.contains(&&*version) {
+            return Err(ErrorKind::UnsupportedVersion(version).into());
+        }
+        let mut toolchain = try!(get_table(&mut table, "toolchain", path));
+        let path = format!("{}toolchain.", path);
+        Ok(Lockfile {
+            requested: try!(get_string(&mut toolchain, "requested", &path)),
+            resolved: try!(get_string(&mut toolchain, "resolved", &path)),
+            url: try!(get_string(&mut toolchain, "url", &path)),
+        })
+    }
+    pub fn to_toml(self) -> toml::value::Table {
+        let mut toolchain = toml::value::Table::new();
+        toolchain.insert("requested".to_owned(), toml::Value::String(self.requested));
+        toolchain.insert("resolved".to_owned(), toml::Value::String(self.resolved));
+        toolchain.insert("url".to_owned(), toml::Value::String(self.url));
+
+        let mut result = toml::value::Table::new();
+        result.insert("version".to_owned(),
+                      toml::Value::String(DEFAULT_LOCKFILE_VERSION.to_owned()));
+        result.insert("toolchain".to_owned(), toml::Value::Table(toolchain));
+        result
+    }
+
+    pub fn parse(data: &str) -> Result<Self> {
+        let value = toml::from_str(data).map_err(ErrorKind::Parsing)?;
+        Self::from_toml(value, "")
+    }
+
+    pub fn stringify(self) -> String {
+        toml::Value::Table(self.to_toml()).to_string()
+    }
+
+    /// Reads the lockfile in `dir`, if there is one
+    pub fn load(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(LOCKFILE_NAME);
+        if !utils::is_file(&path) {
+            return Ok(None);
+        }
+        let data = try!(utils::read_file("lockfile", &path));
+        Ok(Some(try!(Lockfile::parse(&data)
+                      .chain_err(|| ErrorKind::InvalidLockfile(path.display().to_string())))))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        Ok(try!(utils::write_file_atomic("lockfile", &dir.join(LOCKFILE_NAME), &self.clone().stringify())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lockfile_round_trip() {
+        let lock = Lockfile {
+            requested: "leanprover/lean4:stable".to_owned(),
+            resolved: "leanprover/lean4:v4.1.0".to_owned(),
+            url: "https://github.com/leanprover/lean4/releases/tag/v4.1.0".to_owned(),
+        };
+        let data = lock.clone().stringify();
+        assert!(data.contains("version = \"1\""));
+        assert_eq!(Lockfile::parse(&data).unwrap(), lock);
+
+        assert!(Lockfile::parse("version = \"2\"\n[toolchain]\nrequested = \"stable\"\n").is_err());
+        assert!(Lockfile::parse("version = \"1\"\n[toolchain]\nrequested = \"stable\"\n").is_err());
+    }
+
+    #[test]
+    fn test_locked_toolchain_resolves_to_same_release() {
+        let notify = |_: Notification| ();
+        for &(requested, tag, resolved, url) in &[
+            ("leanprover/lean4:stable", "v4.1.0", "leanprover/lean4:v4.1.0",
+             "https://github.com/leanprover/lean4/releases/tag/v4.1.0"),
+            ("leanprover/lean4:nightly", "nightly-2021-06-01", "leanprover/lean4:nightly-2021-06-01",
+             "https://github.com/leanprover/lean4-nightly/releases/tag/nightly-2021-06-01"),
+            ("leanprover/lean4:4.1", "v4.1.2", "leanprover/lean4:v4.1.2",
+             "https://github.com/leanprover/lean4/releases/tag/v4.1.2"),
+            ("stable", "v3.30.0", "leanprover-community/lean:v3.30.0",
+             "https://github.com/leanprover-community/lean/releases/tag/v3.30.0"),
+        ] {
+            let desc = ToolchainDesc::from_str(requested).unwrap();
+            let locked = desc.at_release(tag);
+            assert_eq!(locked.name(), resolved);
+            assert!(!locked.is_tracking());
+
+            // Pinned toolchains are resolved without a lookup
+            let reparsed = ToolchainDesc::from_str(&locked.name()).unwrap();
+            assert_eq!(dist::resolve_toolchain_url(&reparsed, false, &notify).unwrap(), url);
+        }
+    }
+}
@@ -38,3 +38,57 @@ impl Component {
         format!("'{}'", self.pkg)
     }
 }
+
+/// Parts of a release archive besides `lean` itself, with the directory
+/// they occupy in it
+pub const OPTIONAL_COMPONENTS: [(&'static str, &'static str); 2] = [
+    ("src", "src"),
+    ("docs", "share/doc"),
+];
+
+/// How much of a release to install. `minimal` only installs the
+/// binaries and libraries needed to build packages; `default` and
+/// `complete` currently both install everything in the release archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Profile {
+    Minimal,
+    Default,
+    Complete,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::Default
+    }
+}
+
+impl Profile {
+    pub fn names() -> &'static [&'static str] {
+        &["minimal", "default", "complete"]
+    }
+
+    pub fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "minimal" => Ok(Profile::Minimal),
+            "default" => Ok(Profile::Default),
+            "complete" => Ok(Profile::Complete),
+            _ => Err(ErrorKind::InvalidProfile(name.to_owned()).into()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Profile::Minimal => "minimal",
+            Profile::Default => "default",
+            Profile::Complete => "complete",
+        }
+    }
+
+    /// Whether the profile installs the component `pkg`
+    pub fn includes(&self, pkg: &str) -> bool {
+        match *self {
+            Profile::Minimal => !OPTIONAL_COMPONENTS.iter().any(|&(c, _)| c == pkg),
+            Profile::Default | Profile::Complete => true,
+        }
+    }
+}
@@ -1,12 +1,47 @@
 //! Manifest a particular Lean version by installing it from a distribution server.
 
 use component::{TarGzPackage, ZipPackage};
-use temp;
+use delta;
+use provenance::{self, Provenance};
 use errors::*;
 use notifications::*;
-use download::DownloadCfg;
+use download::{self, Artifact, DownloadCfg, File};
 use prefix::InstallPrefix;
-use elan_utils::utils;
+use manifest::{Profile, OPTIONAL_COMPONENTS};
+use elan_utils::{self, utils};
+
+use json::{self, JsonValue};
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The metadata at the root of a toolchain, which elan adds to at install
+/// time
+pub const METADATA_FILE: &'static str = "elan-toolchain.json";
+
+/// Present in a toolchain directory from the start of its extraction until
+/// the installation has completed
+pub const INCOMPLETE_MARKER: &'static str = ".elan-incomplete";
+
+/// Whether the installation at `prefix` was interrupted before it completed
+pub fn is_incomplete(prefix: &Path) -> bool {
+    utils::is_file(&prefix.join(INCOMPLETE_MARKER))
+}
+
+/// Sets `key` in the metadata of the toolchain in `dir`, creating the
+/// metadata if the toolchain doesn't ship any. Everything elan records in
+/// the metadata goes through here.
+pub fn record_metadata(dir: &Path, key: &str, value: JsonValue) -> Result<()> {
+    let path = dir.join(METADATA_FILE);
+    let mut metadata = if utils::is_file(&path) {
+        let contents = try!(utils::read_file("toolchain metadata", &path));
+        try!(json::parse(&contents).chain_err(|| format!("invalid toolchain metadata in '{}'", path.display())))
+    } else {
+        JsonValue::new_object()
+    };
+    metadata[key] = value;
+    Ok(try!(utils::write_file_atomic("toolchain metadata", &path, &metadata.pretty(2))))
+}
 
 #[derive(Debug)]
 pub struct Manifestation {
@@ -18,70 +53,659 @@ impl Manifestation {
         Ok(Manifestation { prefix })
     }
 
-    /// Installation using the legacy v1 manifest format
+    /// Installation using the legacy v1 manifest format. If `delta_from`
+    /// names the release currently installed, a delta from it is tried
+    /// before the full archive, unless provenance is verified, which
+    /// deltas don't have.
     pub fn update(&self,
                   origin: &String,
                   url: &String,
-                  temp_cfg: &temp::Cfg,
-                  notify_handler: &Fn(Notification)) -> Result<()> {
+                  delta_from: Option<&str>,
+                  dlcfg: DownloadCfg) -> Result<()> {
+        let notify_handler = dlcfg.notify_handler;
         notify_handler(Notification::DownloadingComponent("lean"));
 
-        use std::path::PathBuf;
-        let dld_dir = PathBuf::from("bogus");
-        let dlcfg = DownloadCfg {
-            download_dir: &dld_dir,
-            temp_cfg: temp_cfg,
-            notify_handler: notify_handler
+        let url = try!(archive_url(origin, url, dlcfg));
+        if let Some(from) = delta_from.filter(|_| dlcfg.provenance.is_none()) {
+            match self.update_from_delta(&url, from, dlcfg) {
+                Ok(()) => return record_profile(self.prefix.path(), dlcfg.profile),
+                Err(e) => match *e.kind() {
+                    ErrorKind::Cancelled => return Err(e),
+                    ErrorKind::Utils(elan_utils::ErrorKind::DownloadNotExists { .. }) => {
+                        notify_handler(Notification::DeltaUnavailable(from));
+                    }
+                    _ => notify_handler(Notification::DeltaFailed(from, &e)),
+                },
+            }
+        }
+
+        let ext = archive_ext();
+        try!(dlcfg.check_disk_space(&url, ext, self.prefix.path()));
+        let installer_file = try!(dlcfg.download_artifact(&url, ext));
+        let provenance = try!(verify_provenance(&installer_file, &url, dlcfg));
+
+        try!(match self.install_artifact(&installer_file, &url, provenance.as_ref(), dlcfg) {
+            Err(Error(ErrorKind::ChecksumFailed { .. }, _)) if installer_file.expected_hash().is_some() => {
+                // A corrupted download cache, fetch the archive again
+                notify_handler(Notification::CachedFileChecksumFailed);
+                try!(installer_file.discard());
+                let installer_file = try!(dlcfg.download_artifact(&url, ext));
+                let provenance = try!(verify_provenance(&installer_file, &url, dlcfg));
+                self.install_artifact(&installer_file, &url, provenance.as_ref(), dlcfg)
+            }
+            r => r,
+        });
+        record_profile(self.prefix.path(), dlcfg.profile)
+    }
+
+    // Patches the installed release `from` into the one whose archive is
+    // at `url`
+    fn update_from_delta(&self, url: &str, from: &str, dlcfg: DownloadCfg) -> Result<()> {
+        let notify_handler = dlcfg.notify_handler;
+        // What the release itself says its files are
+        let digests_url = delta::digests_url(url);
+        let digests_file = try!(dlcfg.download_and_check(&digests_url, ""));
+        let digests = try!(delta::parse_digests(&try!(utils::read_file("digest list", &digests_file)), &digests_url));
+        let delta_file = try!(dlcfg.download_and_check(&delta::delta_url(url, from), ".tar.gz"));
+        let delta_dir = try!(dlcfg.temp_cfg.new_directory());
+        try!(TarGzPackage::unpack_file(&delta_file, &delta_dir, dlcfg.extract_options()));
+
+        let old = self.prefix.path();
+        let excluded: Vec<&str> = OPTIONAL_COMPONENTS.iter()
+            .filter(|&&(component, _)| !dlcfg.profile.includes(component))
+            .map(|&(_, path)| path)
+            .collect();
+        let apply: Box<Fn(&Path) -> Result<()>> = Box::new(|dir| {
+            let delta = try!(delta::apply(old, &delta_dir, dir, &excluded, &digests, dlcfg.cancel));
+            notify_handler(Notification::AppliedDelta(&delta.from, &delta.to));
+            Ok(())
+        });
+        install_components(old, vec![("lean", apply)], dlcfg.cancel, &*notify_handler)
+    }
+
+    /// Installs from an archive that was downloaded from `url` earlier,
+    /// verifying it against `hash` while it is extracted. If provenance is
+    /// verified, the attestation must have been kept next to the archive.
+    pub fn install_from_file(&self, path: &Path, hash: &str, url: &str, dlcfg: DownloadCfg) -> Result<()> {
+        let provenance = match dlcfg.provenance {
+            Some(policy) => {
+                let attestation_file = PathBuf::from(provenance::attestation_url(&path.to_string_lossy()));
+                if !utils::is_file(&attestation_file) {
+                    return Err(ErrorKind::MissingProvenance(attestation_file.display().to_string()).into());
+                }
+                let attestation = try!(utils::read_file("provenance attestation", &attestation_file));
+                let provenance = try!(provenance::verify(&attestation, hash, policy));
+                (dlcfg.notify_handler)(Notification::ProvenanceVerified(&provenance.builder, provenance.signed));
+                Some(provenance)
+            }
+            None => None,
         };
+        let artifact = Artifact::Reused(File::new(path.to_owned()), hash.to_owned());
+        try!(self.install_artifact(&artifact, url, provenance.as_ref(), dlcfg));
+        record_profile(self.prefix.path(), dlcfg.profile)
+    }
+
+    fn install_artifact(&self,
+                        installer_file: &Artifact,
+                        url: &str,
+                        provenance: Option<&Provenance>,
+                        dlcfg: DownloadCfg) -> Result<()> {
+        let notify_handler = dlcfg.notify_handler;
+        let profile = dlcfg.profile;
+        let extract: Box<Fn(&Path) -> Result<()>> = Box::new(|dir| {
+            if cfg!(target_os = "linux") {
+                // Verified in the same pass as the extraction
+                match installer_file.expected_hash() {
+                    Some(hash) => {
+                        try!(TarGzPackage::unpack_file_verified(installer_file, dir, hash, dlcfg.extract_options()));
+                        notify_handler(Notification::ChecksumValid(url));
+                    }
+                    None => try!(TarGzPackage::unpack_file(installer_file, dir, dlcfg.extract_options())),
+                }
+            } else {
+                // Zip archives are read from the end, so they have to be
+                // verified up front
+                try!(dlcfg.verify_artifact(installer_file, url));
+                try!(ZipPackage::unpack_file(installer_file, dir, dlcfg.extract_options()));
+            }
+            if let Some(provenance) = provenance {
+                try!(provenance::record(dir, provenance));
+            }
+            remove_excluded_components(dir, profile, &*notify_handler)
+        });
+        install_components(self.prefix.path(), vec![("lean", extract)], dlcfg.cancel, &*notify_handler)
+    }
+}
+
+// Checks the attestation published next to the archive at `url` if
+// provenance is to be verified
+fn verify_provenance(installer_file: &Artifact, url: &str, dlcfg: DownloadCfg) -> Result<Option<Provenance>> {
+    let policy = match dlcfg.provenance {
+        Some(policy) => policy,
+        None => return Ok(None),
+    };
+    let attestation_url = provenance::attestation_url(url);
+    let attestation_file = match dlcfg.download_and_check(&attestation_url, ".jsonl") {
+        Err(Error(ErrorKind::Utils(elan_utils::ErrorKind::DownloadNotExists { .. }), _)) => {
+            return Err(ErrorKind::MissingProvenance(attestation_url).into());
+        }
+        r => try!(r),
+    };
+    let attestation = try!(utils::read_file("provenance attestation", &attestation_file));
+    let sha256 = try!(download::file_hash(installer_file));
+    let provenance = try!(provenance::verify(&attestation, &sha256, policy));
+    (dlcfg.notify_handler)(Notification::ProvenanceVerified(&provenance.builder, provenance.signed));
+    Ok(Some(provenance))
+}
+
+/// How release archives name the host platform
+pub fn informal_target() -> &'static str {
+    if cfg!(target_os = "windows") {
+        "windows"
+    } else if cfg!(target_os = "linux") {
+        "linux"
+    } else if cfg!(target_os = "macos") {
+        "darwin"
+    } else {
+        unreachable!()
+    }
+}
 
-        // find correct download on HTML page (AAAAH)
-        use std::fs;
-        use regex::Regex;
-        use std::io::Read;
-        let informal_target = if cfg!(target_os = "windows") {
-            "windows"
-        } else if cfg!(target_os = "linux") {
-            "linux"
-        } else if cfg!(target_os = "macos") {
-            "darwin"
+/// The extension of release archives for the host platform
+pub fn archive_ext() -> &'static str {
+    if cfg!(target_os = "linux") { ".tar.gz" } else { ".zip" }
+}
+
+/// The archive for the host platform listed on the release page `url` of
+/// `origin`
+pub fn archive_url(origin: &str, url: &str, dlcfg: DownloadCfg) -> Result<String> {
+    // find correct download on HTML page (AAAAH)
+    use std::fs;
+    use regex::Regex;
+    use std::io::Read;
+    let informal_target = informal_target();
+    let re = Regex::new(format!(r#"/{}/releases/download/[^"]+"#, origin).as_str()).unwrap();
+    let download_page_file = dlcfg.download_and_check(&url, "")?;
+    let mut html = String::new();
+    fs::File::open(&download_page_file as &::std::path::Path)?.read_to_string(&mut html)?;
+    // The archive is named like `lean-4.1.0-linux.tar.gz`, unlike deltas,
+    // attestations and archives for other architectures next to it
+    let suffix = format!("-{}{}", informal_target, archive_ext());
+    let url = re.find_iter(&html).map(|m| m.as_str().to_string()).find(|m| {
+        let name = m.rsplit('/').next().unwrap();
+        name.starts_with("lean-") && name.ends_with(&suffix)
+    });
+    if url.is_none() {
+        return Err(format!("binary package was not provided for '{}'",
+                           informal_target).into());
+    }
+    Ok(format!("https://github.com/{}", url.unwrap()))
+}
+
+/// Extracts each component into its own staging directory next to
+/// `prefix` and only replaces `prefix` once all of them have succeeded, so
+/// that a failing component leaves the previous installation untouched.
+/// Cancellation is checked between components and before `prefix` is
+/// replaced.
+fn install_components(prefix: &Path,
+                      components: Vec<(&str, Box<Fn(&Path) -> Result<()> + '_>)>,
+                      cancel: &AtomicBool,
+                      notify_handler: &Fn(Notification)) -> Result<()> {
+    let check_cancelled = || -> Result<()> {
+        if cancel.load(Ordering::SeqCst) {
+            Err(ErrorKind::Cancelled.into())
         } else {
-            unreachable!()
-        };
-        let re = Regex::new(format!(r#"/{}/releases/download/[^"]+"#, origin).as_str()).unwrap();
-        let download_page_file = dlcfg.download_and_check(&url, "")?;
-        let mut html = String::new();
-        fs::File::open(&download_page_file as &::std::path::Path)?.read_to_string(&mut html)?;
-        let url = re.find_iter(&html).map(|m| m.as_str().to_string()).find(|m|
-            m.contains(informal_target));
-        if url.is_none() {
-            return Err(format!("binary package was not provided for '{}'",
-                               informal_target).into());
+            Ok(())
+        }
+    };
+
+    let parent = prefix.parent().expect("toolchain directory has no parent");
+    let name = prefix.file_name().expect("toolchain directory has no name").to_string_lossy();
+    let staging = parent.join(format!(".{}.staging", name));
+    let backup = parent.join(format!(".{}.old", name));
+
+    // Leftovers from an interrupted installation
+    for dir in &[&staging, &backup] {
+        if utils::path_exists(dir) {
+            utils::remove_dir("staging directory", dir, &|n| notify_handler(n.into()))?;
+        }
+    }
+
+    let result = (|| -> Result<()> {
+        let merged = staging.join("toolchain");
+        utils::ensure_dir_exists("staging directory", &merged,
+                                 &|n| notify_handler(n.into()))?;
+        utils::write_file("install marker", &merged.join(INCOMPLETE_MARKER), "")?;
+
+        for &(component, ref extract) in &components {
+            check_cancelled()?;
+            notify_handler(Notification::InstallingComponent(component));
+            let dir = staging.join("components").join(component);
+            utils::ensure_dir_exists("staging directory", &dir,
+                                     &|n| notify_handler(n.into()))?;
+            extract(&dir)?;
         }
-        let url = format!("https://github.com/{}", url.unwrap());
 
-        let ext = if cfg!(target_os = "linux") { ".tar.gz" } else { ".zip" };
-        let installer_file = try!(dlcfg.download_and_check(&url, ext));
+        for &(component, _) in &components {
+            merge_dir(component, &staging.join("components").join(component), &merged)?;
+        }
 
-        let prefix = self.prefix.path();
+        check_cancelled()?;
+        if utils::path_exists(prefix) {
+            utils::rename_dir("toolchain directory", prefix, &backup)?;
+        }
+        if let Err(e) = utils::rename_dir("toolchain directory", &merged, prefix) {
+            if utils::path_exists(&backup) {
+                utils::rename_dir("toolchain directory", &backup, prefix)?;
+            }
+            return Err(e.into());
+        }
+        utils::remove_file("install marker", &prefix.join(INCOMPLETE_MARKER))?;
+        Ok(())
+    })();
 
-        notify_handler(Notification::InstallingComponent("lean"));
+    if utils::path_exists(&staging) {
+        let _ = utils::remove_dir("staging directory", &staging, &|n| notify_handler(n.into()));
+    }
+    if result.is_ok() && utils::path_exists(&backup) {
+        utils::remove_dir("toolchain directory", &backup, &|n| notify_handler(n.into()))?;
+    }
+    result
+}
 
-        // Remove old files
-        if utils::is_directory(prefix) {
-            utils::remove_dir("toolchain directory", prefix,
-                              &|n| (notify_handler)(n.into()))?;
+// Release archives contain all components, so the ones the profile
+// doesn't want are removed after extraction
+fn remove_excluded_components(dir: &Path, profile: Profile, notify_handler: &Fn(Notification)) -> Result<()> {
+    for &(component, path) in OPTIONAL_COMPONENTS.iter() {
+        let path = dir.join(path);
+        if !profile.includes(component) && utils::path_exists(&path) {
+            notify_handler(Notification::SkippingComponent(component, profile.name()));
+            utils::remove_dir("component", &path, &|n| notify_handler(n.into()))?;
         }
+    }
+    Ok(())
+}
+
+fn record_profile(dir: &Path, profile: Profile) -> Result<()> {
+    record_metadata(dir, "profile", profile.name().into())
+}
 
-        utils::ensure_dir_exists("toolchain directory", prefix,
-                                 &|n| (notify_handler)(n.into()))?;
+// The profile the toolchain in `dir` was installed with. Toolchains
+// installed before elan recorded it got everything.
+fn installed_profile(dir: &Path) -> Profile {
+    utils::read_file("toolchain metadata", &dir.join(METADATA_FILE)).ok()
+        .and_then(|contents| json::parse(&contents).ok())
+        .and_then(|metadata| metadata["profile"].as_str().and_then(|name| Profile::from_str(name).ok()))
+        .unwrap_or_default()
+}
+
+/// Whether the toolchain installed in `dir` lacks components `profile`
+/// includes, which only reinstalling the release can add
+pub fn profile_needs_reinstall(dir: &Path, profile: Profile) -> bool {
+    let installed = installed_profile(dir);
+    OPTIONAL_COMPONENTS.iter().any(|&(c, _)| profile.includes(c) && !installed.includes(c))
+}
+
+/// Brings the toolchain installed in `dir` in line with `profile` by
+/// removing the components it excludes. Returns `false` without changing
+/// anything if `profile_needs_reinstall`.
+pub fn apply_profile(dir: &Path, profile: Profile, notify_handler: &Fn(Notification)) -> Result<bool> {
+    if installed_profile(dir) == profile {
+        return Ok(true);
+    }
+    if profile_needs_reinstall(dir, profile) {
+        return Ok(false);
+    }
+    try!(remove_excluded_components(dir, profile, notify_handler));
+    try!(record_profile(dir, profile));
+    Ok(true)
+}
 
-        // Extract new files
-        if cfg!(target_os = "linux") {
-            TarGzPackage::unpack_file(&installer_file, prefix)?
+// Moves the contents of `src` into `dest`, descending into directories
+// present in both. Components must not provide the same file.
+fn merge_dir(component: &str, src: &Path, dest: &Path) -> Result<()> {
+    for entry in utils::read_dir("staging directory", src)? {
+        let entry = entry?;
+        let target = dest.join(entry.file_name());
+        if entry.file_type()?.is_dir() && utils::is_directory(&target) {
+            merge_dir(component, &entry.path(), &target)?;
+        } else if utils::path_exists(&target) {
+            return Err(ErrorKind::ComponentConflict {
+                name: component.to_owned(),
+                path: target,
+            }.into());
         } else {
-            ZipPackage::unpack_file(&installer_file, prefix)?
+            utils::rename_file("component file", &entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elan_utils::test_dir::TestDir;
+    use std::fs;
+
+    #[test]
+    fn test_failed_component_preserves_prefix() {
+        let root = TestDir::new("failed-component");
+        let prefix = root.join("toolchain");
+        fs::create_dir_all(prefix.join("bin")).unwrap();
+        fs::write(prefix.join("bin").join("lean"), "old").unwrap();
+
+        let good: Box<Fn(&Path) -> Result<()>> = Box::new(|dir| {
+            fs::create_dir_all(dir.join("bin"))?;
+            fs::write(dir.join("bin").join("lean"), "new")?;
+            Ok(())
+        });
+        let bad: Box<Fn(&Path) -> Result<()>> = Box::new(|dir| {
+            fs::create_dir_all(dir.join("bin"))?;
+            fs::write(dir.join("bin").join("leanpkg"), "partial")?;
+            Err(ErrorKind::ExtractingPackage.into())
+        });
+        let cancel = AtomicBool::new(false);
+        let result = install_components(&prefix, vec![("lean", good), ("leanpkg", bad)], &cancel, &|_| ());
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(prefix.join("bin").join("lean")).unwrap(), "old");
+        assert!(!prefix.join("bin").join("leanpkg").exists());
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_cancelled_install_preserves_prefix() {
+        let root = TestDir::new("cancel");
+        let prefix = root.join("toolchain");
+        fs::create_dir_all(prefix.join("bin")).unwrap();
+        fs::write(prefix.join("bin").join("lean"), "old").unwrap();
+
+        // Cancelled while the last component is being extracted
+        let cancel = AtomicBool::new(false);
+        let extract: Box<Fn(&Path) -> Result<()>> = Box::new(|dir| {
+            fs::create_dir_all(dir.join("bin"))?;
+            fs::write(dir.join("bin").join("lean"), "new")?;
+            cancel.store(true, Ordering::SeqCst);
+            Ok(())
+        });
+        match install_components(&prefix, vec![("lean", extract)], &cancel, &|_| ()) {
+            Err(Error(ErrorKind::Cancelled, _)) => {}
+            r => panic!("expected cancellation, got {:?}", r),
+        }
+        assert_eq!(fs::read_to_string(prefix.join("bin").join("lean")).unwrap(), "old");
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_interrupted_install_is_incomplete() {
+        let root = TestDir::new("marker");
+        let prefix = root.join("toolchain");
+        fs::create_dir_all(&root).unwrap();
+        let extract = |contents: &'static str| -> Box<Fn(&Path) -> Result<()>> {
+            Box::new(move |dir| {
+                fs::create_dir_all(dir.join("bin"))?;
+                fs::write(dir.join("bin").join("lean"), contents)?;
+                Ok(())
+            })
         };
+        let cancel = AtomicBool::new(false);
 
-        Ok(())
+        install_components(&prefix, vec![("lean", extract("old"))], &cancel, &|_| ()).unwrap();
+        assert!(!is_incomplete(&prefix));
+
+        // Crashed after the marker was written, leaving a partial toolchain
+        let crashed = ::std::panic::catch_unwind(|| {
+            let crash: Box<Fn(&Path) -> Result<()>> = Box::new(|dir| {
+                fs::create_dir_all(dir.join("bin")).unwrap();
+                panic!("crashed");
+            });
+            let _ = install_components(&prefix, vec![("lean", crash)], &cancel, &|_| ());
+        });
+        assert!(crashed.is_err());
+        assert!(!is_incomplete(&prefix));
+        assert_eq!(fs::read_to_string(prefix.join("bin").join("lean")).unwrap(), "old");
+        let staging = root.join(".toolchain.staging");
+        assert!(is_incomplete(&staging.join("toolchain")));
+
+        // Moved into place just before the crash
+        fs::remove_dir_all(&prefix).unwrap();
+        fs::rename(staging.join("toolchain"), &prefix).unwrap();
+        assert!(is_incomplete(&prefix));
+
+        // The next attempt replaces it
+        install_components(&prefix, vec![("lean", extract("new"))], &cancel, &|_| ()).unwrap();
+        assert!(!is_incomplete(&prefix));
+        assert_eq!(fs::read_to_string(prefix.join("bin").join("lean")).unwrap(), "new");
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_minimal_profile_removes_optional_components() {
+        let root = TestDir::new("profile");
+        for dir in &["bin", "lib", "src/Init", "share/doc/lean/html"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+
+        remove_excluded_components(&root, Profile::Default, &|_| ()).unwrap();
+        assert!(root.join("src").exists() && root.join("share/doc").exists());
+
+        remove_excluded_components(&root, Profile::Minimal, &|_| ()).unwrap();
+        assert!(root.join("bin").exists() && root.join("lib").exists());
+        assert!(!root.join("src").exists() && !root.join("share/doc").exists());
+        assert!(root.join("share").exists());
+    }
+
+    #[test]
+    fn test_apply_profile() {
+        let root = TestDir::new("apply-profile");
+        for dir in &["bin", "src/Init", "share/doc/lean"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+
+        // Toolchains without a recorded profile have everything
+        assert!(apply_profile(&root, Profile::Complete, &|_| ()).unwrap());
+        assert!(root.join("src").exists());
+        assert!(apply_profile(&root, Profile::Minimal, &|_| ()).unwrap());
+        assert!(!root.join("src").exists() && !root.join("share/doc").exists());
+        assert_eq!(installed_profile(&root), Profile::Minimal);
+
+        // Getting the removed components back needs a reinstall
+        assert!(!apply_profile(&root, Profile::Default, &|_| ()).unwrap());
+        assert_eq!(installed_profile(&root), Profile::Minimal);
+        assert!(apply_profile(&root, Profile::Minimal, &|_| ()).unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_delta_update() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::cell::RefCell;
+        use tar;
+        use temp;
+
+        let root = TestDir::new("delta-update");
+        let write_release = |dir: &Path, version: &str| {
+            let _ = fs::remove_dir_all(dir);
+            fs::create_dir_all(dir.join("bin")).unwrap();
+            fs::write(dir.join("bin/lean"), version).unwrap();
+            fs::write(dir.join("bin/lake"), "lake").unwrap();
+        };
+        let (prefix, new) = (root.join("toolchain"), root.join("new"));
+        write_release(&new, "4.2.0");
+
+        // A mirror of the release page, which also links to the delta
+        let mirror = root.join("mirror");
+        let release = mirror.join("leanprover/lean4/releases");
+        let download = "leanprover/lean4/releases/download/v4.2.0/lean-4.2.0-linux.tar.gz";
+        let delta_path = mirror.join(delta::delta_url(download, "v4.1.0"));
+        fs::create_dir_all(release.join("tag")).unwrap();
+        fs::create_dir_all(release.join("download/v4.2.0")).unwrap();
+        fs::write(release.join("tag/v4.2.0"),
+                  format!("<a href=\"/{}\">delta</a><a href=\"/{}.sha256sums\">digests</a>\
+                           <a href=\"/leanprover/lean4/releases/download/v4.2.0/lean-4.2.0-linux_aarch64.tar.gz\">arm</a>\
+                           <a href=\"/{}\">linux</a>",
+                          delta::delta_url(download, "v4.1.0"), download, download)).unwrap();
+        let write_archive = || {
+            let archive = fs::File::create(mirror.join(download)).unwrap();
+            let mut builder = tar::Builder::new(GzEncoder::new(archive, Compression::default()));
+            builder.append_dir_all("lean-4.2.0-linux", &new).unwrap();
+            builder.into_inner().unwrap().finish().unwrap();
+        };
+
+        let temp_cfg = temp::Cfg::new(root.join("tmp"), Box::new(|_| ()));
+        let download_dir = root.join("downloads");
+        let cancel = AtomicBool::new(false);
+        let events = RefCell::new(vec![]);
+        let notify = |n: Notification| match n {
+            Notification::AppliedDelta(from, to) => events.borrow_mut().push(format!("applied {} {}", from, to)),
+            Notification::DeltaUnavailable(_) => events.borrow_mut().push("unavailable".to_owned()),
+            Notification::DeltaFailed(_, _) => events.borrow_mut().push("failed".to_owned()),
+            _ => {}
+        };
+        let dist_servers = vec![format!("file://{}", mirror.display())];
+        let cfg = DownloadCfg {
+            temp_cfg: &temp_cfg,
+            download_dir: &download_dir,
+            keep_downloads: false,
+            include_prereleases: false,
+            profile: Profile::Default,
+            cancel: &cancel,
+            dist_servers: &dist_servers,
+            url_rewrites: &[],
+            disk_space_factor: None,
+            delta_updates: true,
+            provenance: None,
+            deterministic_extract: false,
+            check_case: false,
+            notify_handler: &notify,
+        };
+        let update = || {
+            let manifestation = Manifestation::open(InstallPrefix::from(prefix.clone())).unwrap();
+            manifestation.update(&"leanprover/lean4".to_owned(),
+                                 &"https://github.com/leanprover/lean4/releases/tag/v4.2.0".to_owned(),
+                                 Some("v4.1.0"), cfg).unwrap();
+            assert_eq!(fs::read_to_string(prefix.join("bin/lean")).unwrap(), "4.2.0");
+            assert_eq!(fs::read_to_string(prefix.join("bin/lake")).unwrap(), "lake");
+            events.borrow_mut().pop().unwrap()
+        };
+
+        // Without a delta, the full archive is downloaded
+        write_release(&prefix, "4.1.0");
+        write_archive();
+        assert_eq!(update(), "unavailable");
+
+        // With one, the full archive isn't needed
+        write_release(&prefix, "4.1.0");
+        delta::create(&prefix, &new, "v4.1.0", "v4.2.0", &delta_path).unwrap();
+        assert_eq!(update(), "unavailable");
+        write_release(&prefix, "4.1.0");
+        write_archive();
+        fs::write(mirror.join(delta::digests_url(download)), delta::write_digests(&new).unwrap()).unwrap();
+        fs::remove_file(mirror.join(download)).unwrap();
+        assert_eq!(update(), "applied v4.1.0 v4.2.0");
+
+        // A delta that doesn't fit the installed files is given up on
+        write_release(&prefix, "4.1.0");
+        fs::write(prefix.join("bin/lake"), "modified").unwrap();
+        write_archive();
+        assert_eq!(update(), "failed");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_provenance_verification() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use tar;
+        use temp;
+
+        let root = TestDir::new("provenance-update");
+        let (prefix, new) = (root.join("toolchain"), root.join("new"));
+        fs::create_dir_all(prefix.join("bin")).unwrap();
+        fs::write(prefix.join("bin/lean"), "4.1.0").unwrap();
+        fs::create_dir_all(new.join("bin")).unwrap();
+        fs::write(new.join("bin/lean"), "4.2.0").unwrap();
+
+        let mirror = root.join("mirror");
+        let release = mirror.join("leanprover/lean4/releases");
+        let download = "leanprover/lean4/releases/download/v4.2.0/lean-4.2.0-linux.tar.gz";
+        fs::create_dir_all(release.join("tag")).unwrap();
+        fs::create_dir_all(release.join("download/v4.2.0")).unwrap();
+        fs::write(release.join("tag/v4.2.0"), format!("<a href=\"/{}\">linux</a>", download)).unwrap();
+        {
+            let archive = fs::File::create(mirror.join(download)).unwrap();
+            let mut builder = tar::Builder::new(GzEncoder::new(archive, Compression::default()));
+            builder.append_dir_all("lean-4.2.0-linux", &new).unwrap();
+            builder.into_inner().unwrap().finish().unwrap();
+        }
+        let sha256 = download::file_hash(&mirror.join(download)).unwrap();
+        let attestation = mirror.join(provenance::attestation_url(download));
+        let write_attestation = |sha256: &str| {
+            fs::write(&attestation, format!(
+                r#"{{"subject": [{{"digest": {{"sha256": "{}"}}}}], "predicate": {{"builder": {{"id": "{}"}}}}}}"#,
+                sha256, "https://github.com/slsa-framework/slsa-github-generator/generic")).unwrap();
+        };
+
+        let temp_cfg = temp::Cfg::new(root.join("tmp"), Box::new(|_| ()));
+        let download_dir = root.join("downloads");
+        let cancel = AtomicBool::new(false);
+        let dist_servers = vec![format!("file://{}", mirror.display())];
+        let policy = provenance::Policy { builder: provenance::DEFAULT_BUILDER.to_owned(), public_key: None };
+        let cfg = DownloadCfg {
+            temp_cfg: &temp_cfg,
+            download_dir: &download_dir,
+            keep_downloads: false,
+            include_prereleases: false,
+            profile: Profile::Default,
+            cancel: &cancel,
+            dist_servers: &dist_servers,
+            url_rewrites: &[],
+            disk_space_factor: None,
+            delta_updates: false,
+            provenance: Some(&policy),
+            deterministic_extract: false,
+            check_case: false,
+            notify_handler: &|_| (),
+        };
+        let update = || {
+            let manifestation = Manifestation::open(InstallPrefix::from(prefix.clone())).unwrap();
+            manifestation.update(&"leanprover/lean4".to_owned(),
+                                 &"https://github.com/leanprover/lean4/releases/tag/v4.2.0".to_owned(),
+                                 None, cfg)
+        };
+
+        // Without an attestation, nothing is installed
+        match update() {
+            Err(Error(ErrorKind::MissingProvenance(_), _)) => {}
+            r => panic!("expected missing provenance, got {:?}", r),
+        }
+        assert_eq!(fs::read_to_string(prefix.join("bin/lean")).unwrap(), "4.1.0");
+
+        // Nor with one for a different archive
+        write_attestation(&"0".repeat(64));
+        match update() {
+            Err(Error(ErrorKind::ProvenanceMismatch(_), _)) => {}
+            r => panic!("expected a provenance mismatch, got {:?}", r),
+        }
+        assert_eq!(fs::read_to_string(prefix.join("bin/lean")).unwrap(), "4.1.0");
+
+        // A matching one is recorded with the toolchain
+        write_attestation(&sha256);
+        update().unwrap();
+        assert_eq!(fs::read_to_string(prefix.join("bin/lean")).unwrap(), "4.2.0");
+        let metadata = fs::read_to_string(prefix.join(METADATA_FILE)).unwrap();
+        assert!(metadata.contains(&sha256));
+
+        // Archives installed from a file need the attestation next to them
+        let manifestation = Manifestation::open(InstallPrefix::from(prefix.clone())).unwrap();
+        let install_from_file = || manifestation.install_from_file(&mirror.join(download), &sha256, download, cfg);
+        fs::remove_file(&attestation).unwrap();
+        match install_from_file() {
+            Err(Error(ErrorKind::MissingProvenance(_), _)) => {}
+            r => panic!("expected missing provenance, got {:?}", r),
+        }
+        write_attestation(&sha256);
+        install_from_file().unwrap();
     }
 }
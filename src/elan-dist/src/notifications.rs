@@ -25,11 +25,20 @@ pub enum Notification<'a> {
     MissingInstalledComponent(&'a str),
     DownloadingComponent(&'a str),
     InstallingComponent(&'a str),
+    SkippingComponent(&'a str, &'a str),
     RemovingComponent(&'a str),
     DownloadingManifest(&'a str),
     DownloadedManifest(&'a str, Option<&'a str>),
     DownloadingLegacyManifest,
     ManifestChecksumFailedHack,
+    MirrorFailover { from: &'a str, to: &'a str },
+    RewroteUrl(&'a str, &'a str),
+    AppliedDelta(&'a str, &'a str),
+    DeltaUnavailable(&'a str),
+    DeltaFailed(&'a str, &'a Error),
+    /// The builder, and whether the attestation's signature was checked
+    ProvenanceVerified(&'a str, bool),
+    ToolchainDeprecated { name: &'a str, reason: &'a str },
 }
 
 impl<'a> From<elan_utils::Notification<'a>> for Notification<'a> {
@@ -45,24 +54,37 @@ impl<'a> From<temp::Notification<'a>> for Notification<'a> {
 }
 
 impl<'a> Notification<'a> {
+    /// See `elan_utils::Notification::is_download_lifecycle`
+    pub fn is_download_lifecycle(&self) -> bool {
+        match *self {
+            Notification::Utils(ref n) => n.is_download_lifecycle(),
+            Notification::DownloadingComponent(_) | Notification::FileAlreadyDownloaded => true,
+            _ => false,
+        }
+    }
+
     pub fn level(&self) -> NotificationLevel {
         use self::Notification::*;
         match *self {
             Temp(ref n) => n.level(),
             Utils(ref n) => n.level(),
-            ChecksumValid(_) | NoUpdateHash(_) |
+            ChecksumValid(_) | NoUpdateHash(_) | RewroteUrl(_, _) |
             FileAlreadyDownloaded |
             DownloadingLegacyManifest  => NotificationLevel::Verbose,
             Extracting(_, _) | SignatureValid(_)  |
             DownloadingComponent(_) |
             InstallingComponent(_) |
+            SkippingComponent(_, _) |
             RemovingComponent(_) |
             ComponentAlreadyInstalled(_)  |
             ManifestChecksumFailedHack |
             RollingBack | DownloadingManifest(_) |
+            AppliedDelta(_, _) | DeltaUnavailable(_) | ProvenanceVerified(_, _) |
             DownloadedManifest(_, _) => NotificationLevel::Info,
             CantReadUpdateHash(_) | ExtensionNotInstalled(_) |
-            MissingInstalledComponent(_) | CachedFileChecksumFailed => NotificationLevel::Warn,
+            MissingInstalledComponent(_) | CachedFileChecksumFailed |
+            MirrorFailover { .. } | DeltaFailed(_, _) |
+            ToolchainDeprecated { .. } => NotificationLevel::Warn,
             NonFatalError(_) => NotificationLevel::Error,
         }
     }
@@ -96,12 +118,27 @@ impl<'a> Display for Notification<'a> {
             MissingInstalledComponent(c) => write!(f, "during uninstall component {} was not found", c),
             DownloadingComponent(c) => write!(f, "downloading component '{}'", c),
             InstallingComponent(c) => write!(f, "installing component '{}'", c),
+            SkippingComponent(c, profile) => {
+                write!(f, "skipping component '{}', which is not part of the '{}' profile", c, profile)
+            }
             RemovingComponent(c) => write!(f, "removing component '{}'", c),
             DownloadingManifest(t) => write!(f, "syncing channel updates for '{}'", t),
             DownloadedManifest(date, Some(version)) => write!(f, "latest update on {}, lean version {}", date, version),
             DownloadedManifest(date, None) => write!(f, "latest update on {}, no lean version", date),
             DownloadingLegacyManifest => write!(f, "manifest not found. trying legacy manifest"),
             ManifestChecksumFailedHack => write!(f, "update not yet available, sorry! try again later"),
+            RewroteUrl(from, to) => write!(f, "rewrote download url '{}' to '{}'", from, to),
+            MirrorFailover { from, to } => write!(f, "download from '{}' failed, trying '{}'", from, to),
+            AppliedDelta(from, to) => write!(f, "updated from {} to {} using a delta", from, to),
+            DeltaUnavailable(from) => write!(f, "no delta from {} available, downloading the full release", from),
+            ProvenanceVerified(builder, true) => write!(f, "verified signed provenance: built by {}", builder),
+            ProvenanceVerified(builder, false) => {
+                write!(f, "attestation lists the archive as built by {} (signature not checked)", builder)
+            }
+            ToolchainDeprecated { name, reason } => write!(f, "toolchain '{}' is deprecated: {}", name, reason),
+            DeltaFailed(from, e) => {
+                write!(f, "could not apply the delta from {} ({}), downloading the full release", from, e)
+            }
         }
     }
 }
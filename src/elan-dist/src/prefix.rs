@@ -1,6 +1,32 @@
-use std::path::{Path, PathBuf};
+use elan_utils::utils;
+
+use std::ffi::OsStr;
+use std::path::{self, Path, PathBuf};
 
 const REL_MANIFEST_DIR: &'static str = ".";
+const REL_BIN_DIR: &'static str = "bin";
+const REL_LIB_DIR: &'static str = "lib";
+const REL_SHARE_DIR: &'static str = "share";
+const REL_LIBRARY_DIR: &'static str = "library";
+
+/// What `InstallPrefix::check_toolchain` found in a toolchain directory,
+/// relative to its root
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolchainDirReport {
+    pub path: PathBuf,
+    pub found: Vec<PathBuf>,
+    /// Required entries that don't exist
+    pub missing: Vec<PathBuf>,
+    /// Entries that are expected but not needed, like `bin/lake` for
+    /// Lean 3 builds
+    pub missing_optional: Vec<PathBuf>,
+}
+
+impl ToolchainDirReport {
+    pub fn is_valid(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct InstallPrefix {
@@ -33,4 +59,141 @@ impl InstallPrefix {
         path.push(name);
         path
     }
+    /// Where executables like `lean` are installed
+    pub fn bin_dir(&self) -> PathBuf {
+        self.path.join(REL_BIN_DIR)
+    }
+    /// The executable `name`, with `exe_suffix` appended
+    pub fn binary_file(&self, name: &str, exe_suffix: &str) -> PathBuf {
+        self.bin_dir().join(format!("{}{}", name, exe_suffix))
+    }
+    /// `bin_dir`, followed by the directories `extra` relative to the
+    /// prefix that also hold executables, like `libexec`. Entries leading
+    /// outside the prefix are ignored.
+    pub fn bin_dirs<S: AsRef<str>>(&self, extra: &[S]) -> Vec<PathBuf> {
+        let mut dirs = vec![self.bin_dir()];
+        for dir in extra {
+            let relative = Path::new(dir.as_ref());
+            let inside = relative.components().all(|c| match c {
+                path::Component::Normal(_) => true,
+                _ => false,
+            });
+            if inside && !dir.as_ref().is_empty() && relative != Path::new(REL_BIN_DIR) {
+                dirs.push(self.path.join(relative));
+            }
+        }
+        dirs
+    }
+    /// Like `binary_file`, but looks through `bin_dirs(extra)` in order,
+    /// see `utils::find_binary_in_dirs`
+    pub fn find_binary<S: AsRef<str>>(&self, name: &OsStr, extra: &[S], exe_suffix: &str,
+                                      scripts: bool) -> PathBuf {
+        utils::find_binary_in_dirs(&self.bin_dirs(extra), name, exe_suffix, scripts)
+    }
+    /// Where shared libraries and, below `lean`, the compiled core library
+    /// are installed
+    pub fn lib_dir(&self) -> PathBuf {
+        self.path.join(REL_LIB_DIR)
+    }
+    /// Where the core library may be found: compiled below `lib/lean` in
+    /// Lean 4 toolchains, as sources in `library` in Lean 3 ones
+    pub fn core_library_dirs(&self) -> Vec<PathBuf> {
+        vec![self.lib_dir().join("lean"), self.path.join(REL_LIBRARY_DIR)]
+    }
+    /// Where documentation is installed, in a directory per component
+    pub fn doc_dir(&self) -> PathBuf {
+        self.path.join(REL_SHARE_DIR).join("doc")
+    }
+    /// Where manual pages for the executables are installed
+    pub fn man_dir(&self) -> PathBuf {
+        self.path.join(REL_SHARE_DIR).join("man").join("man1")
+    }
+    /// Checks that the prefix holds a toolchain, i.e. `bin/lean`, and
+    /// whether it has `bin/lake`, collecting all problems instead of
+    /// stopping at the first
+    pub fn check_toolchain(&self, exe_suffix: &str) -> ToolchainDirReport {
+        let mut report = ToolchainDirReport {
+            path: self.path.clone(),
+            found: vec![],
+            missing: vec![],
+            missing_optional: vec![],
+        };
+        let entries = [(self.bin_dir(), true, true),
+                       (self.binary_file("lean", exe_suffix), false, true),
+                       (self.binary_file("lake", exe_suffix), false, false)];
+        for &(ref path, is_dir, required) in &entries {
+            let relative = path.strip_prefix(&self.path).unwrap().to_owned();
+            let exists = if is_dir { utils::is_directory(path) } else { utils::is_file(path) };
+            if exists {
+                report.found.push(relative);
+            } else if required {
+                report.missing.push(relative);
+            } else {
+                report.missing_optional.push(relative);
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elan_utils::test_dir::TestDir;
+
+    #[test]
+    fn test_prefix_dirs() {
+        let prefix = InstallPrefix::from(PathBuf::from("toolchains").join("stable"));
+        let root = Path::new("toolchains").join("stable");
+        assert_eq!(prefix.bin_dir(), root.join("bin"));
+        assert_eq!(prefix.binary_file("lean", ""), root.join("bin").join("lean"));
+        assert_eq!(prefix.binary_file("lean", ".exe"), root.join("bin").join("lean.exe"));
+        assert_eq!(prefix.lib_dir(), root.join("lib"));
+        assert_eq!(prefix.doc_dir(), root.join("share").join("doc"));
+        assert_eq!(prefix.man_dir(), root.join("share").join("man").join("man1"));
+        assert_eq!(prefix.core_library_dirs(), vec![root.join("lib").join("lean"), root.join("library")]);
+    }
+
+    #[test]
+    fn test_bin_dirs() {
+        let root = Path::new("toolchains").join("stable");
+        let prefix = InstallPrefix::from(root.clone());
+        assert_eq!(prefix.bin_dirs::<&str>(&[]), vec![root.join("bin")]);
+        assert_eq!(prefix.bin_dirs(&["libexec", "tools/bin"]),
+                   vec![root.join("bin"), root.join("libexec"), root.join("tools/bin")]);
+        // `bin` isn't searched twice, and nothing outside the prefix
+        assert_eq!(prefix.bin_dirs(&["bin", "", "../other", "/usr/bin"]), vec![root.join("bin")]);
+    }
+
+    #[test]
+    fn test_check_toolchain() {
+        use std::fs;
+
+        let dir = TestDir::new("check-toolchain");
+        let prefix = InstallPrefix::from(dir.to_path_buf());
+        let bin = Path::new("bin");
+
+        let report = prefix.check_toolchain(".exe");
+        assert!(!report.is_valid());
+        assert!(report.found.is_empty());
+        assert_eq!(report.missing, vec![bin.to_owned(), bin.join("lean.exe")]);
+        assert_eq!(report.missing_optional, vec![bin.join("lake.exe")]);
+
+        // A directory where the binary should be doesn't count
+        fs::create_dir_all(dir.join("bin").join("lean.exe")).unwrap();
+        assert_eq!(prefix.check_toolchain(".exe").missing, vec![bin.join("lean.exe")]);
+
+        // Lean 3 builds have no `lake`
+        fs::write(dir.join("bin").join("lean"), "").unwrap();
+        let report = prefix.check_toolchain("");
+        assert!(report.is_valid());
+        assert_eq!(report.found, vec![bin.to_owned(), bin.join("lean")]);
+        assert_eq!(report.missing_optional, vec![bin.join("lake")]);
+
+        fs::write(dir.join("bin").join("lake"), "").unwrap();
+        let report = prefix.check_toolchain("");
+        assert_eq!(report.found, vec![bin.to_owned(), bin.join("lean"), bin.join("lake")]);
+        assert!(report.missing_optional.is_empty());
+        assert_eq!(report.path, dir.to_path_buf());
+    }
 }
@@ -0,0 +1,309 @@
+//! Provenance attestations published next to release archives, like those
+//! of the SLSA GitHub generator. The attestation of an archive is found at
+//! its url with `.intoto.jsonl` appended and holds one in-toto statement
+//! per line, either as is or wrapped in a DSSE envelope:
+//!
+//! ```json
+//! {"_type": "https://in-toto.io/Statement/v0.1",
+//!  "subject": [{"name": "lean-4.1.0-linux.tar.gz", "digest": {"sha256": "..."}}],
+//!  "predicateType": "https://slsa.dev/provenance/v0.2",
+//!  "predicate": {"builder": {"id": "https://github.com/slsa-framework/..."}}}
+//! ```
+//!
+//! An archive is verified if a statement lists its digest and names a
+//! builder whose id starts with the expected one. If the policy names a
+//! public key, only statements in DSSE envelopes signed with it count, so
+//! that the attestation can't come from whoever serves the archive. Without
+//! a key, signatures aren't checked and the attestation is only trusted as
+//! far as the server it was downloaded from, which is what the
+//! notification for a verified archive then says.
+
+use json::{self, JsonValue};
+use openssl::pkey::{Id, PKey, Public};
+use openssl::sign::Verifier;
+use openssl::hash::MessageDigest;
+
+use elan_utils::utils;
+use errors::*;
+use manifestation::record_metadata;
+
+use std::path::{Path, PathBuf};
+
+pub const ATTESTATION_EXT: &'static str = ".intoto.jsonl";
+
+/// Builders trusted unless configured otherwise
+pub const DEFAULT_BUILDER: &'static str = "https://github.com/slsa-framework/slsa-github-generator/";
+
+/// What release archives must be attested with
+#[derive(Clone, Debug, PartialEq)]
+pub struct Policy {
+    /// The beginning of the builder id statements must name
+    pub builder: String,
+    /// A PEM file with the public key envelopes must be signed with
+    pub public_key: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Provenance {
+    pub builder: String,
+    pub predicate_type: String,
+    pub sha256: String,
+    /// Whether the statement's signature was checked against the key of
+    /// the policy
+    pub signed: bool,
+}
+
+pub fn attestation_url(archive_url: &str) -> String {
+    format!("{}{}", archive_url, ATTESTATION_EXT)
+}
+
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let (mut buf, mut bits) = (0u32, 0);
+    for c in s.bytes().filter(|&c| c != b'=' && !(c as char).is_whitespace()) {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return None,
+        };
+        buf = (buf << 6) | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buf >> bits) as u8);
+            buf &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+// The DSSE pre-authentication encoding that envelope signatures are over
+fn pae(payload_type: &str, payload: &[u8]) -> Vec<u8> {
+    let mut encoded = format!("DSSEv1 {} {} {} ", payload_type.len(), payload_type, payload.len()).into_bytes();
+    encoded.extend_from_slice(payload);
+    encoded
+}
+
+fn verify_signature(key: &PKey<Public>, message: &[u8], signature: &[u8]) -> bool {
+    // Ed25519 hashes the message itself
+    let verifier = if key.id() == Id::ED25519 {
+        Verifier::new_without_digest(key)
+    } else {
+        Verifier::new(MessageDigest::sha256(), key)
+    };
+    verifier.and_then(|mut v| v.verify_oneshot(signature, message)).unwrap_or(false)
+}
+
+fn load_key(path: &Path) -> Result<PKey<Public>> {
+    let pem = try!(utils::read_file("provenance key", path));
+    PKey::public_key_from_pem(pem.as_bytes())
+        .chain_err(|| ErrorKind::InvalidProvenanceKey(path.to_owned()))
+}
+
+// The statement on a line of an attestation, unwrapping envelopes. With a
+// key, only envelopes with a signature by it are accepted.
+fn statement(line: &str, key: Option<&PKey<Public>>) -> Option<JsonValue> {
+    let value = json::parse(line).ok()?;
+    match value["payload"].as_str() {
+        Some(payload) => {
+            let payload = decode_base64(payload)?;
+            if let Some(key) = key {
+                let message = pae(value["payloadType"].as_str().unwrap_or(""), &payload);
+                let signed = value["signatures"].members()
+                    .filter_map(|s| s["sig"].as_str().and_then(decode_base64))
+                    .any(|sig| verify_signature(key, &message, &sig));
+                if !signed {
+                    return None;
+                }
+            }
+            json::parse(&String::from_utf8(payload).ok()?).ok()
+        }
+        None if key.is_none() => Some(value),
+        None => None,
+    }
+}
+
+/// Checks that `attestation` vouches for the archive with the SHA-256
+/// digest `sha256` having been built by the builder of `policy`
+pub fn verify(attestation: &str, sha256: &str, policy: &Policy) -> Result<Provenance> {
+    let key = match policy.public_key {
+        Some(ref path) => Some(try!(load_key(path))),
+        None => None,
+    };
+    let statements: Vec<JsonValue> = attestation.lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| statement(line, key.as_ref()))
+        .collect();
+    if statements.is_empty() {
+        return Err(ErrorKind::ProvenanceMismatch(if key.is_some() {
+            "no statements signed with the configured key found".to_owned()
+        } else {
+            "no statements found".to_owned()
+        }).into());
+    }
+    let builder = &*policy.builder;
+    let mut builders = vec![];
+    for statement in &statements {
+        if !statement["subject"].members().any(|s| s["digest"]["sha256"].as_str() == Some(sha256)) {
+            continue;
+        }
+        // `runDetails` is where SLSA v1 keeps the builder
+        let predicate = &statement["predicate"];
+        let id = predicate["runDetails"]["builder"]["id"].as_str()
+            .or_else(|| predicate["builder"]["id"].as_str())
+            .unwrap_or("");
+        if !id.is_empty() && id.starts_with(builder) {
+            return Ok(Provenance {
+                builder: id.to_owned(),
+                predicate_type: statement["predicateType"].as_str().unwrap_or("").to_owned(),
+                sha256: sha256.to_owned(),
+                signed: key.is_some(),
+            });
+        }
+        builders.push(id.to_owned());
+    }
+    Err(ErrorKind::ProvenanceMismatch(if builders.is_empty() {
+        format!("no statement covers digest {}", sha256)
+    } else {
+        format!("built by '{}', expected '{}'", builders.join("', '"), builder)
+    }).into())
+}
+
+/// Adds `provenance` to the metadata of the toolchain in `dir`
+pub fn record(dir: &Path, provenance: &Provenance) -> Result<()> {
+    let mut recorded = JsonValue::new_object();
+    recorded["builder"] = provenance.builder.clone().into();
+    recorded["predicate_type"] = provenance.predicate_type.clone().into();
+    recorded["sha256"] = provenance.sha256.clone().into();
+    recorded["signed"] = provenance.signed.into();
+    record_metadata(dir, "provenance", recorded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elan_utils::test_dir::TestDir;
+    use manifestation::METADATA_FILE;
+    use std::fs;
+
+    const BUILDER: &'static str = "https://github.com/slsa-framework/slsa-github-generator/.github/workflows/generator_generic_slsa3.yml@refs/tags/v1.9.0";
+
+    fn statement(sha256: &str, builder: &str) -> String {
+        format!(r#"{{"_type": "https://in-toto.io/Statement/v0.1", "subject": [{{"name": "lean.tar.gz", "digest": {{"sha256": "{}"}}}}], "predicateType": "https://slsa.dev/provenance/v0.2", "predicate": {{"builder": {{"id": "{}"}}}}}}"#,
+                sha256, builder)
+    }
+
+    fn policy(builder: &str) -> Policy {
+        Policy { builder: builder.to_owned(), public_key: None }
+    }
+
+    fn encode_base64(bytes: &[u8]) -> String {
+        const CHARS: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut s = String::new();
+        for chunk in bytes.chunks(3) {
+            let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                s.push(if i <= chunk.len() { CHARS[(n >> (18 - 6 * i) & 63) as usize] as char } else { '=' });
+            }
+        }
+        s
+    }
+
+    #[test]
+    fn test_verify_provenance() {
+        let digest = "a".repeat(64);
+        let provenance = verify(&statement(&digest, BUILDER), &digest, &policy(DEFAULT_BUILDER)).unwrap();
+        assert!(!provenance.signed);
+        assert_eq!(provenance.builder, BUILDER);
+        assert_eq!(provenance.predicate_type, "https://slsa.dev/provenance/v0.2");
+
+        // Envelopes are unwrapped, "aGk=" decoding to "hi"
+        assert_eq!(decode_base64("aGk="), Some(b"hi".to_vec()));
+        let payload = "eyJzdWJqZWN0IjogW3siZGlnZXN0IjogeyJzaGEyNTYiOiAiYWJjIn19XSwgInByZWRpY2F0ZSI6IHsicnVuRGV0YWlscyI6IHsiYnVpbGRlciI6IHsiaWQiOiAiaHR0cHM6Ly9leGFtcGxlLmNvbS9idWlsZGVyIn19fX0=";
+        assert_eq!(String::from_utf8(decode_base64(payload).unwrap()).unwrap(),
+                   r#"{"subject": [{"digest": {"sha256": "abc"}}], "predicate": {"runDetails": {"builder": {"id": "https://example.com/builder"}}}}"#);
+        let envelope = format!("\n{{\"payloadType\": \"application/vnd.in-toto+json\", \"payload\": \"{}\"}}\n", payload);
+        assert_eq!(verify(&envelope, "abc", &policy("https://example.com/")).unwrap().builder, "https://example.com/builder");
+
+        // Tampered attestations are rejected
+        for &(attestation, sha256) in &[
+            (&*statement(&"b".repeat(64), BUILDER), &*digest),
+            (&*statement(&digest, "https://evil.example.com/builder"), &*digest),
+            ("not json", &*digest),
+        ] {
+            match verify(attestation, sha256, &policy(DEFAULT_BUILDER)) {
+                Err(Error(ErrorKind::ProvenanceMismatch(_), _)) => {}
+                r => panic!("expected a mismatch, got {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_verify_signed_provenance() {
+        use openssl::ec::{EcGroup, EcKey};
+        use openssl::nid::Nid;
+        use openssl::sign::Signer;
+
+        let dir = TestDir::new("provenance-key");
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let keys: Vec<_> = (0..2).map(|_| PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()).collect();
+        let key_file = dir.join("key.pem");
+        fs::write(&key_file, keys[0].public_key_to_pem().unwrap()).unwrap();
+        let policy = Policy { builder: DEFAULT_BUILDER.to_owned(), public_key: Some(key_file) };
+
+        let digest = "a".repeat(64);
+        let payload_type = "application/vnd.in-toto+json";
+        let payload = statement(&digest, BUILDER);
+        let envelope = |key: &PKey<_>| {
+            let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+            signer.update(&pae(payload_type, payload.as_bytes())).unwrap();
+            format!(r#"{{"payloadType": "{}", "payload": "{}", "signatures": [{{"sig": "{}"}}]}}"#,
+                    payload_type, encode_base64(payload.as_bytes()), encode_base64(&signer.sign_to_vec().unwrap()))
+        };
+        assert_eq!(decode_base64(&encode_base64(b"lean")), Some(b"lean".to_vec()));
+
+        let provenance = verify(&envelope(&keys[0]), &digest, &policy).unwrap();
+        assert!(provenance.signed);
+
+        // Statements signed with another key, unsigned envelopes and bare
+        // statements don't count
+        let unsigned = format!(r#"{{"payloadType": "{}", "payload": "{}"}}"#, payload_type, encode_base64(payload.as_bytes()));
+        for attestation in &[envelope(&keys[1]), unsigned, statement(&digest, BUILDER)] {
+            match verify(attestation, &digest, &policy) {
+                Err(Error(ErrorKind::ProvenanceMismatch(_), _)) => {}
+                r => panic!("expected a mismatch, got {:?}", r),
+            }
+        }
+
+        fs::write(policy.public_key.as_ref().unwrap(), "not a key").unwrap();
+        match verify(&envelope(&keys[0]), &digest, &policy) {
+            Err(Error(ErrorKind::InvalidProvenanceKey(_), _)) => {}
+            r => panic!("expected an invalid key, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_record_provenance() {
+        let dir = TestDir::new("provenance");
+        let provenance = Provenance {
+            builder: BUILDER.to_owned(),
+            predicate_type: "https://slsa.dev/provenance/v0.2".to_owned(),
+            sha256: "a".repeat(64),
+            signed: false,
+        };
+
+        record(&dir, &provenance).unwrap();
+        let metadata = json::parse(&fs::read_to_string(dir.join(METADATA_FILE)).unwrap()).unwrap();
+        assert_eq!(metadata["provenance"]["builder"].as_str(), Some(BUILDER));
+
+        // Shipped metadata is kept
+        fs::write(dir.join(METADATA_FILE), r#"{"version": "4.1.0"}"#).unwrap();
+        record(&dir, &provenance).unwrap();
+        let metadata = json::parse(&fs::read_to_string(dir.join(METADATA_FILE)).unwrap()).unwrap();
+        assert_eq!(metadata["version"].as_str(), Some("4.1.0"));
+        assert_eq!(metadata["provenance"]["sha256"].as_str(), Some(&*"a".repeat(64)));
+    }
+}
@@ -6,6 +6,7 @@ use std::path::{Path, PathBuf};
 use std::io;
 use std::ops;
 use std::fmt::{self, Display};
+use elan_utils::codes;
 use elan_utils::raw;
 
 use elan_utils::notify::NotificationLevel;
@@ -24,6 +25,13 @@ pub enum Error {
         path: PathBuf,
         error: io::Error,
     },
+    /// The root was set through an environment variable and nothing can
+    /// be created in it
+    NotWritable {
+        var: &'static str,
+        path: PathBuf,
+        error: io::Error,
+    },
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
@@ -39,6 +47,7 @@ pub enum Notification<'a> {
 
 pub struct Cfg {
     root_directory: PathBuf,
+    configured_by: Option<&'static str>,
     notify_handler: Box<Fn(Notification)>,
 }
 
@@ -95,6 +104,19 @@ impl<'a> Display for Notification<'a> {
     }
 }
 
+impl Error {
+    /// The exit code for this error, see `elan_utils::codes`
+    pub fn error_code(&self) -> u32 {
+        use self::Error::*;
+        match *self {
+            NotWritable { .. } => codes::INVALID_CONFIG,
+            CreatingRoot { .. } |
+            CreatingFile { .. } |
+            CreatingDirectory { .. } => codes::FAILURE,
+        }
+    }
+}
+
 impl error::Error for Error {
     fn description(&self) -> &str {
         use self::Error::*;
@@ -102,6 +124,7 @@ impl error::Error for Error {
             CreatingRoot {..} => "could not create temp root",
             CreatingFile {..} => "could not create temp file",
             CreatingDirectory {..} => "could not create temp directory",
+            NotWritable {..} => "temp directory is not writable",
         }
     }
 
@@ -110,7 +133,8 @@ impl error::Error for Error {
         match *self {
             CreatingRoot { ref error, .. } |
             CreatingFile { ref error, .. } |
-            CreatingDirectory { ref error, .. } => Some(error),
+            CreatingDirectory { ref error, .. } |
+            NotWritable { ref error, .. } => Some(error),
         }
     }
 }
@@ -128,6 +152,9 @@ impl Display for Error {
             CreatingDirectory { ref path, error: _ } => {
                 write!(f, "could not create temp directory: {}", path.display())
             }
+            NotWritable { var, ref path, error: _ } => {
+                write!(f, "cannot create files in {} '{}'", var, path.display())
+            }
         }
     }
 }
@@ -136,10 +163,28 @@ impl Cfg {
     pub fn new(root_directory: PathBuf, notify_handler: Box<Fn(Notification)>) -> Self {
         Cfg {
             root_directory: root_directory,
+            configured_by: None,
             notify_handler: notify_handler,
         }
     }
 
+    /// Marks the root as set through the environment variable `var`, which
+    /// failures to create anything in it then name
+    pub fn configured_by(self, var: &'static str) -> Self {
+        Cfg { configured_by: Some(var), ..self }
+    }
+
+    fn check_writable<T>(&self, result: Result<T>) -> Result<T> {
+        match (result, self.configured_by) {
+            (Err(Error::CreatingRoot { error, .. }), Some(var)) |
+            (Err(Error::CreatingFile { error, .. }), Some(var)) |
+            (Err(Error::CreatingDirectory { error, .. }), Some(var)) => {
+                Err(Error::NotWritable { var: var, path: self.root_directory.clone(), error: error })
+            }
+            (result, _) => result,
+        }
+    }
+
     pub fn create_root(&self) -> Result<bool> {
         raw::ensure_dir_exists(&self.root_directory, |p| {
             (self.notify_handler)(Notification::CreatingRoot(p));
@@ -153,6 +198,10 @@ impl Cfg {
     }
 
     pub fn new_directory(&self) -> Result<Dir> {
+        self.check_writable(self.create_directory())
+    }
+
+    fn create_directory(&self) -> Result<Dir> {
         try!(self.create_root());
 
         loop {
@@ -183,6 +232,10 @@ impl Cfg {
     }
 
     pub fn new_file_with_ext(&self, prefix: &str, ext: &str) -> Result<File> {
+        self.check_writable(self.create_file(prefix, ext))
+    }
+
+    fn create_file(&self, prefix: &str, ext: &str) -> Result<File> {
         try!(self.create_root());
 
         loop {
@@ -251,3 +304,32 @@ impl<'a> Drop for File<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elan_utils::test_dir::TestDir;
+
+    #[test]
+    fn test_configured_root_is_checked_when_used() {
+        let root = TestDir::new("temp");
+
+        // Below a file, so nothing can be created
+        fs::write(root.join("file"), "").unwrap();
+        let cfg = Cfg::new(root.join("file/tmp"), Box::new(|_| ())).configured_by("ELAN_TEMP_DIR");
+        match cfg.new_file() {
+            Err(e @ Error::NotWritable { .. }) => {
+                assert!(e.to_string().contains("ELAN_TEMP_DIR"));
+                assert_eq!(e.error_code(), codes::INVALID_CONFIG);
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert!(cfg.new_directory().is_err());
+
+        let cfg = Cfg::new(root.join("tmp"), Box::new(|_| ())).configured_by("ELAN_TEMP_DIR");
+        assert!(!root.join("tmp").exists());
+        let dir = cfg.new_directory().unwrap();
+        assert!(dir.starts_with(root.join("tmp")));
+        drop(dir);
+    }
+}
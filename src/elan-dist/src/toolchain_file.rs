@@ -0,0 +1,276 @@
+//! `lean-toolchain.toml` files, which sit next to a project's
+//! `lean-toolchain` and declare what else the project needs from its
+//! toolchain.
+//!
+//! ```toml
+//! [toolchain]
+//! components = ["docs", "src"]
+//! lean_path = ["vendor/batteries/.lake/build/lib"]
+//! ```
+//!
+//! `lean_path` lists library directories relative to the project that are
+//! added to `LEAN_PATH` when the project's toolchain runs.
+
+use toml;
+
+use elan_utils::toml_utils::*;
+use elan_utils::utils;
+use elan_utils::walk_cache::WalkCache;
+use errors::*;
+use notifications::Notification;
+
+use std::path::{Path, PathBuf};
+
+pub const TOOLCHAIN_CONFIG_NAME: &'static str = "lean-toolchain.toml";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ToolchainConfig {
+    /// Components that must be installed, including unknown ones
+    pub components: Vec<String>,
+    /// Library directories relative to the project
+    pub lean_path: Vec<String>,
+}
+
+fn get_strings(table: &mut toml::value::Table, key: &str, path: &str) -> Result<Vec<String>> {
+    let mut strings = vec![];
+    for (i, v) in try!(get_array(table, key, path)).into_iter().enumerate() {
+        match v {
+            toml::Value::String(s) => strings.push(s),
+            _ => {
+                let kind = ::elan_utils::ErrorKind::ExpectedType("string", format!("{}{}[{}]", path, key, i));
+                return Err(::elan_utils::Error::from(kind).into());
+            }
+        }
+    }
+    Ok(strings)
+}
+
+impl ToolchainConfig {
+    pub fn from_toml(mut table: toml::value::Table, path: &str) -> Result<Self> {
+        let mut toolchain = try!(get_table(&mut table, "toolchain", path));
+        let path = format!("{}toolchain.", path);
+        Ok(ToolchainConfig {
+            components: try!(get_strings(&mut toolchain, "components", &path)),
+            lean_path: try!(get_strings(&mut toolchain, "lean_path", &path)),
+        })
+    }
+
+    pub fn parse(data: &str) -> Result<Self> {
+        let value = toml::from_str(data).map_err(ErrorKind::Parsing)?;
+        Self::from_toml(value, "")
+    }
+
+    /// Reads the `lean-toolchain.toml` in `dir`, if there is one
+    pub fn load(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(TOOLCHAIN_CONFIG_NAME);
+        if !utils::is_file(&path) {
+            return Ok(None);
+        }
+        let data = try!(utils::read_file("toolchain config", &path));
+        Ok(Some(try!(ToolchainConfig::parse(&data)
+                      .chain_err(|| ErrorKind::InvalidToolchainConfig(path.clone())))))
+    }
+}
+
+/// Where the toolchain named for a directory comes from, see
+/// `find_toolchain_name`
+#[derive(Clone, Debug, PartialEq)]
+pub enum ToolchainNameSource {
+    /// The override set for the directory
+    Override(PathBuf),
+    /// A `lean-toolchain` file
+    ToolchainFile(PathBuf),
+    /// The `package.lean_version` of a `leanpkg.toml`
+    LeanpkgFile(PathBuf),
+}
+
+/// The toolchain named for `dir` by the override `dir_override` returns
+/// for it or one of its parents, or else by the nearest `lean-toolchain`
+/// or `leanpkg.toml`. Of an override and a file in the same directory,
+/// the override wins.
+pub fn find_toolchain_name(walk_cache: &WalkCache, dir: &Path,
+                           dir_override: &Fn(&Path) -> Option<String>,
+                           notify_handler: &Fn(Notification))
+                           -> Result<Option<(String, ToolchainNameSource)>> {
+    let dir = utils::canonicalize_path(dir, &|n| notify_handler(n.into()));
+
+    // Look for 'lean-toolchain', then for a 'leanpkg.toml' that names a
+    // Lean version. Invalid files are taken so that the error is shown.
+    let accept = |file: &Path, contents: &str| {
+        if file.ends_with("lean-toolchain") {
+            return contents.lines().next().is_some();
+        }
+        match contents.parse::<toml::Value>() {
+            Ok(value) => value.get("package").and_then(|package| package.get("lean_version")).is_some(),
+            Err(_) => true,
+        }
+    };
+    let (dirs, found) = walk_cache.find(&dir, &["lean-toolchain", "leanpkg.toml"], &accept);
+
+    // The override database takes precedence in each directory
+    for d in dirs {
+        if let Some(name) = dir_override(&d) {
+            return Ok(Some((name, ToolchainNameSource::Override(d))));
+        }
+    }
+
+    let (file, contents) = match found {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    if file.ends_with("lean-toolchain") {
+        let toolchain_name = contents.lines().next().unwrap().trim();
+        return Ok(Some((toolchain_name.to_string(), ToolchainNameSource::ToolchainFile(file))));
+    }
+    let value = try!(contents.parse::<toml::Value>()
+                     .map_err(|error| ErrorKind::InvalidLeanpkgFile(file.clone(), error)));
+    match value.get("package").and_then(|package| package.get("lean_version")) {
+        Some(&toml::Value::String(ref s)) => Ok(Some((s.to_string(), ToolchainNameSource::LeanpkgFile(file)))),
+        Some(a) => Err(ErrorKind::InvalidLeanVersion(file, a.type_str()).into()),
+        None => Ok(None),
+    }
+}
+
+/// The name of the toolchain that applies to `dir`, exactly as given by
+/// `env_override` (`ELAN_TOOLCHAIN`), else by `find_toolchain_name`, else
+/// by `default_toolchain`
+pub fn effective_toolchain_name(env_override: Option<&str>, walk_cache: &WalkCache, dir: &Path,
+                                dir_override: &Fn(&Path) -> Option<String>,
+                                default_toolchain: Option<&str>,
+                                notify_handler: &Fn(Notification)) -> Result<Option<String>> {
+    if let Some(name) = env_override {
+        return Ok(Some(name.to_owned()));
+    }
+    if let Some((name, _)) = try!(find_toolchain_name(walk_cache, dir, dir_override, notify_handler)) {
+        return Ok(Some(name));
+    }
+    Ok(default_toolchain.map(|s| s.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elan_utils::test_dir::TestDir;
+    use std::fs;
+
+    // A project with a nested package, below a directory naming no
+    // toolchain, and its canonical path
+    fn project(name: &str) -> (TestDir, PathBuf) {
+        let dir = TestDir::new(&format!("toolchain-name-{}", name));
+        fs::create_dir_all(dir.join("project").join("pkg").join("src")).unwrap();
+        let root = dir.canonicalize().unwrap();
+        (dir, root)
+    }
+
+    fn name_for(dir: &Path, overrides: &[(&Path, &str)], default: Option<&str>) -> Option<String> {
+        let overrides: Vec<(PathBuf, String)> = overrides.iter()
+            .map(|&(d, n)| (d.to_owned(), n.to_owned())).collect();
+        let dir_override = |d: &Path| overrides.iter().find(|&&(ref o, _)| o == d).map(|&(_, ref n)| n.clone());
+        effective_toolchain_name(None, &WalkCache::new(), dir, &dir_override, default, &|_| ()).unwrap()
+    }
+
+    #[test]
+    fn test_name_from_environment() {
+        let (_dir, root) = project("env");
+        fs::write(root.join("project").join("lean-toolchain"), "stable\n").unwrap();
+        let name = effective_toolchain_name(Some("nightly"), &WalkCache::new(), &root.join("project"),
+                                            &|_| Some("v4.0.0".to_owned()), Some("v4.1.0"), &|_| ()).unwrap();
+        assert_eq!(name, Some("nightly".to_owned()));
+    }
+
+    #[test]
+    fn test_name_from_override() {
+        let (_dir, root) = project("override");
+        let project_dir = root.join("project");
+        fs::write(project_dir.join("lean-toolchain"), "stable\n").unwrap();
+        let src = project_dir.join("pkg").join("src");
+        assert_eq!(name_for(&src, &[(&project_dir, "v4.0.0")], Some("v4.1.0")), Some("v4.0.0".to_owned()));
+        assert_eq!(name_for(&src, &[(&src, "v4.0.0")], None), Some("v4.0.0".to_owned()));
+
+        let found = find_toolchain_name(&WalkCache::new(), &src, &|d| if d == &*project_dir {
+            Some("v4.0.0".to_owned())
+        } else {
+            None
+        }, &|_| ()).unwrap();
+        assert_eq!(found, Some(("v4.0.0".to_owned(), ToolchainNameSource::Override(project_dir.clone()))));
+
+        // Like `Toolchain::install_and_override`, which stores overrides
+        // under `override_key`, followed by a run in the project
+        #[cfg(unix)]
+        {
+            let link = root.join("link");
+            ::std::os::unix::fs::symlink(&project_dir, &link).unwrap();
+            let key = utils::override_key(&link, &|_| ());
+            let dir_override = |d: &Path| if utils::override_key(d, &|_| ()) == key {
+                Some("v4.0.0".to_owned())
+            } else {
+                None
+            };
+            let name = effective_toolchain_name(None, &WalkCache::new(), &src, &dir_override, None, &|_| ());
+            assert_eq!(name.unwrap(), Some("v4.0.0".to_owned()));
+        }
+    }
+
+    #[test]
+    fn test_name_from_toolchain_file() {
+        let (_dir, root) = project("toolchain-file");
+        let project_dir = root.join("project");
+        let pkg = project_dir.join("pkg");
+        fs::write(project_dir.join("lean-toolchain"), "leanprover/lean4:stable\n").unwrap();
+        fs::write(pkg.join("lean-toolchain"), " v4.2.0 \n").unwrap();
+        assert_eq!(name_for(&pkg.join("src"), &[], Some("v4.1.0")), Some("v4.2.0".to_owned()));
+        assert_eq!(name_for(&project_dir, &[], None), Some("leanprover/lean4:stable".to_owned()));
+        // Overrides further up don't win over a nearer file
+        assert_eq!(name_for(&pkg, &[(&root, "v4.0.0")], None), Some("v4.2.0".to_owned()));
+
+        let found = find_toolchain_name(&WalkCache::new(), &pkg, &|_| None, &|_| ()).unwrap();
+        assert_eq!(found, Some(("v4.2.0".to_owned(), ToolchainNameSource::ToolchainFile(pkg.join("lean-toolchain")))));
+    }
+
+    #[test]
+    fn test_name_from_leanpkg_file() {
+        let (_dir, root) = project("leanpkg");
+        let project_dir = root.join("project");
+        fs::write(project_dir.join("leanpkg.toml"), "[package]\nlean_version = \"3.4.2\"\n").unwrap();
+        // Files that don't name a version are skipped
+        fs::write(project_dir.join("pkg").join("leanpkg.toml"), "[package]\nname = \"pkg\"\n").unwrap();
+        assert_eq!(name_for(&project_dir.join("pkg"), &[], None), Some("3.4.2".to_owned()));
+
+        let found = find_toolchain_name(&WalkCache::new(), &project_dir, &|_| None, &|_| ()).unwrap();
+        assert_eq!(found, Some(("3.4.2".to_owned(), ToolchainNameSource::LeanpkgFile(project_dir.join("leanpkg.toml")))));
+
+        fs::write(project_dir.join("leanpkg.toml"), "[package]\nlean_version = 3\n").unwrap();
+        match find_toolchain_name(&WalkCache::new(), &project_dir, &|_| None, &|_| ()) {
+            Err(Error(ErrorKind::InvalidLeanVersion(_, "integer"), _)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        fs::write(project_dir.join("leanpkg.toml"), "[package").unwrap();
+        match find_toolchain_name(&WalkCache::new(), &project_dir, &|_| None, &|_| ()) {
+            Err(Error(ErrorKind::InvalidLeanpkgFile(..), _)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_name_from_default() {
+        let (_dir, root) = project("default");
+        assert_eq!(name_for(&root.join("project"), &[], Some("v4.1.0")), Some("v4.1.0".to_owned()));
+        assert_eq!(name_for(&root.join("project"), &[], None), None);
+    }
+
+    #[test]
+    fn test_parse_toolchain_config() {
+        let config = ToolchainConfig::parse("[toolchain]\ncomponents = [\"docs\", \"mathlib\"]\n").unwrap();
+        assert_eq!(config.components, vec!["docs", "mathlib"]);
+        assert!(config.lean_path.is_empty());
+
+        let config = ToolchainConfig::parse("[toolchain]\nlean_path = [\"vendor/lib\"]\n").unwrap();
+        assert_eq!(config.lean_path, vec!["vendor/lib"]);
+        assert!(config.components.is_empty());
+        assert!(ToolchainConfig::parse("[toolchain]\nlean_path = [\"vendor/lib\", 1]\n").is_err());
+
+        assert_eq!(ToolchainConfig::parse("").unwrap(), ToolchainConfig::default());
+        assert!(ToolchainConfig::parse("[toolchain]\ncomponents = \"docs\"\n").is_err());
+        assert!(ToolchainConfig::parse("[toolchain]\ncomponents = [1]\n").is_err());
+    }
+}
@@ -0,0 +1,154 @@
+//! Release versions as they appear in toolchain names and release tags,
+//! like `v4.0.0-rc1`
+
+use semver;
+
+use regex::Regex;
+
+/// Pre-release suffixes order before the final release
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PreRelease {
+    Other(String),
+    Alpha(u64),
+    Beta(u64),
+    Rc(u64),
+    Final,
+}
+
+/// A version `[v]X.Y.Z[-suffix]`. Unlike in semver, `alpha`, `beta` and
+/// `rc` suffixes compare by their number, so that `rc10` follows `rc9`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    pub pre: PreRelease,
+}
+
+// A number without sign or other decoration
+fn number(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+fn strip_v(s: &str) -> &str {
+    if s.starts_with('v') { &s[1..] } else { s }
+}
+
+impl Version {
+    pub fn parse(s: &str) -> Option<Version> {
+        let v = semver::Version::parse(strip_v(s)).ok()?;
+        if !v.build.is_empty() {
+            return None;
+        }
+        let pre = if v.pre.is_empty() {
+            PreRelease::Final
+        } else {
+            let suffix = v.pre.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(".");
+            let pre_re = Regex::new(r"^(alpha|beta|rc)\.?(\d*)$").unwrap();
+            match pre_re.captures(&suffix) {
+                Some(p) => {
+                    let n = p[2].parse().unwrap_or(0);
+                    match &p[1] {
+                        "alpha" => PreRelease::Alpha(n),
+                        "beta" => PreRelease::Beta(n),
+                        _ => PreRelease::Rc(n),
+                    }
+                }
+                None => PreRelease::Other(suffix),
+            }
+        };
+        Some(Version { major: v.major, minor: v.minor, patch: v.patch, pre: pre })
+    }
+
+    /// Like `parse`, but accepts missing minor and patch components and
+    /// ignores suffixes, so that development builds are treated like their
+    /// release. For versions recorded in toolchain metadata.
+    pub fn parse_lenient(s: &str) -> Option<Version> {
+        let s = strip_v(s.trim());
+        let core = s.split(|c| c == '-' || c == '+').next().unwrap_or(s);
+        let padded = match core.matches('.').count() {
+            0 => format!("{}.0.0", core),
+            1 => format!("{}.0", core),
+            _ => core.to_owned(),
+        };
+        Version::parse(&padded)
+    }
+
+    pub fn is_prerelease(&self) -> bool {
+        self.pre != PreRelease::Final
+    }
+}
+
+/// A version without a patch component, `[v]X[.Y]`, which stands for the
+/// newest release matching it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialVersion {
+    pub major: u64,
+    pub minor: Option<u64>,
+}
+
+impl PartialVersion {
+    pub fn parse(s: &str) -> Option<PartialVersion> {
+        let mut parts = strip_v(s).split('.');
+        let major = number(parts.next()?)?;
+        let minor = match parts.next() {
+            Some(minor) => Some(number(minor)?),
+            None => None,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(PartialVersion { major: major, minor: minor })
+    }
+
+    pub fn matches(&self, version: &Version) -> bool {
+        version.major == self.major && self.minor.map_or(true, |minor| version.minor == minor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(Version::parse("v4.1.2"), Some(Version { major: 4, minor: 1, patch: 2, pre: PreRelease::Final }));
+        assert_eq!(Version::parse("4.1.2"), Version::parse("v4.1.2"));
+        assert_eq!(Version::parse("v4.0.0-rc1").unwrap().pre, PreRelease::Rc(1));
+        assert_eq!(Version::parse("v4.0.0-beta.2").unwrap().pre, PreRelease::Beta(2));
+        assert_eq!(Version::parse("v4.0.0-m5").unwrap().pre, PreRelease::Other("m5".to_owned()));
+        assert_eq!(Version::parse("v4.1"), None);
+        assert_eq!(Version::parse("v4.1.2+build"), None);
+        assert_eq!(Version::parse("nightly"), None);
+
+        assert!(Version::parse("v4.0.0-rc10") > Version::parse("v4.0.0-rc9"));
+        assert!(Version::parse("v4.0.0-rc1") > Version::parse("v4.0.0-beta3"));
+        assert!(Version::parse("v4.0.0") > Version::parse("v4.0.0-rc2"));
+        assert!(Version::parse("v4.10.0") > Version::parse("v4.9.0"));
+    }
+
+    #[test]
+    fn test_parse_lenient_version() {
+        assert_eq!(Version::parse_lenient("4"), Version::parse("4.0.0"));
+        assert_eq!(Version::parse_lenient(" v4.1 "), Version::parse("4.1.0"));
+        assert_eq!(Version::parse_lenient("4.1.2-dev+abc"), Version::parse("4.1.2"));
+        assert_eq!(Version::parse_lenient("four"), None);
+    }
+
+    #[test]
+    fn test_partial_version() {
+        assert_eq!(PartialVersion::parse("v4"), Some(PartialVersion { major: 4, minor: None }));
+        assert_eq!(PartialVersion::parse("4.1"), Some(PartialVersion { major: 4, minor: Some(1) }));
+        assert_eq!(PartialVersion::parse("4.1.0"), None);
+        assert_eq!(PartialVersion::parse("+4"), None);
+        assert_eq!(PartialVersion::parse("stable"), None);
+
+        let v4_1_2 = Version::parse("v4.1.2").unwrap();
+        assert!(PartialVersion::parse("4").unwrap().matches(&v4_1_2));
+        assert!(PartialVersion::parse("4.1").unwrap().matches(&v4_1_2));
+        assert!(!PartialVersion::parse("4.2").unwrap().matches(&v4_1_2));
+    }
+}
@@ -0,0 +1,113 @@
+//! Settings supplied through the environment, e.g. on CI runners that
+//! should not keep any state
+
+use std::env;
+
+use raw::if_not_empty;
+
+/// Settings supplied through the environment. They take precedence over
+/// `settings.toml` when reading but are never written back to it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EnvSettings {
+    /// `ELAN_DEFAULT_TOOLCHAIN`
+    pub default_toolchain: Option<String>,
+    /// `ELAN_DEFAULT_ORIGIN`
+    pub default_origin: Option<String>,
+    /// `ELAN_DISABLE_FALLBACK=1`
+    pub disable_fallback: bool,
+    /// `ELAN_EPHEMERAL_SETTINGS=1`: don't read or write `settings.toml` at
+    /// all and only keep changes in memory
+    pub ephemeral: bool,
+}
+
+impl EnvSettings {
+    pub fn from_env() -> Self {
+        EnvSettings::from_vars(&|var| env::var(var).ok())
+    }
+
+    /// Reads the settings from the variables `var` looks up
+    pub fn from_vars(var: &Fn(&str) -> Option<String>) -> Self {
+        let flag = |name| var(name).as_ref().map(|s| &**s) == Some("1");
+        EnvSettings {
+            default_toolchain: var("ELAN_DEFAULT_TOOLCHAIN").and_then(if_not_empty),
+            default_origin: var("ELAN_DEFAULT_ORIGIN").and_then(if_not_empty),
+            disable_fallback: flag("ELAN_DISABLE_FALLBACK"),
+            ephemeral: flag("ELAN_EPHEMERAL_SETTINGS"),
+        }
+    }
+
+    /// Whether `apply` changes anything
+    pub fn overrides_anything(&self) -> bool {
+        self.default_toolchain.is_some() || self.default_origin.is_some() || self.disable_fallback
+    }
+
+    /// Puts the settings in place of those read from `settings.toml`
+    pub fn apply(&self, default_toolchain: &mut Option<String>, default_origin: &mut Option<String>,
+                 disable_fallback: &mut bool) {
+        if let Some(ref t) = self.default_toolchain {
+            *default_toolchain = Some(t.clone());
+        }
+        if let Some(ref o) = self.default_origin {
+            *default_origin = Some(o.clone());
+        }
+        if self.disable_fallback {
+            *disable_fallback = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn from(vars: &[(&str, &str)]) -> EnvSettings {
+        let vars: HashMap<String, String> = vars.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect();
+        EnvSettings::from_vars(&|var| vars.get(var).cloned())
+    }
+
+    #[test]
+    fn test_env_settings() {
+        assert_eq!(from(&[]), EnvSettings::default());
+        assert!(!from(&[]).overrides_anything());
+
+        let settings = from(&[("ELAN_DEFAULT_TOOLCHAIN", "v4.1.0"),
+                              ("ELAN_DEFAULT_ORIGIN", "myorg/lean4"),
+                              ("ELAN_DISABLE_FALLBACK", "1"),
+                              ("ELAN_EPHEMERAL_SETTINGS", "1")]);
+        assert_eq!(settings, EnvSettings {
+            default_toolchain: Some("v4.1.0".to_owned()),
+            default_origin: Some("myorg/lean4".to_owned()),
+            disable_fallback: true,
+            ephemeral: true,
+        });
+
+        // Empty variables and flags other than `1` are ignored
+        let settings = from(&[("ELAN_DEFAULT_TOOLCHAIN", ""),
+                              ("ELAN_DISABLE_FALLBACK", "true"),
+                              ("ELAN_EPHEMERAL_SETTINGS", "0")]);
+        assert_eq!(settings, EnvSettings::default());
+        // Ephemeral settings alone change no setting
+        assert!(!from(&[("ELAN_EPHEMERAL_SETTINGS", "1")]).overrides_anything());
+    }
+
+    #[test]
+    fn test_env_settings_take_precedence() {
+        let mut default_toolchain = Some("stable".to_owned());
+        let mut default_origin = Some("leanprover/lean4".to_owned());
+        let mut disable_fallback = false;
+        from(&[("ELAN_DEFAULT_TOOLCHAIN", "v4.1.0")])
+            .apply(&mut default_toolchain, &mut default_origin, &mut disable_fallback);
+        assert_eq!(default_toolchain, Some("v4.1.0".to_owned()));
+        assert_eq!(default_origin, Some("leanprover/lean4".to_owned()));
+        assert!(!disable_fallback);
+
+        // An unset flag doesn't turn off what `settings.toml` turned on
+        let mut disable_fallback = true;
+        from(&[("ELAN_DEFAULT_ORIGIN", "myorg/lean4")])
+            .apply(&mut default_toolchain, &mut default_origin, &mut disable_fallback);
+        assert_eq!(default_toolchain, Some("v4.1.0".to_owned()));
+        assert_eq!(default_origin, Some("myorg/lean4".to_owned()));
+        assert!(disable_fallback);
+    }
+}
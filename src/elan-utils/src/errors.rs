@@ -35,6 +35,13 @@ error_chain! {
             description("could not write file")
             display("could not write {} file: '{}'", name, path.display())
         }
+        LockingFile {
+            name: &'static str,
+            path: PathBuf,
+        } {
+            description("could not lock file")
+            display("could not take the lock for the {} file: '{}'", name, path.display())
+        }
         CreatingDirectory {
             name: &'static str,
             path: PathBuf,
@@ -75,14 +82,14 @@ error_chain! {
             path: PathBuf,
         } {
             description("could not download file")
-            display("could not download file from '{}' to '{}'", url, path.display())
+            display("could not download file from '{}' to '{}'", download::redact_url(url.as_str()), path.display())
         }
         DownloadNotExists {
             url: Url,
             path: PathBuf,
         } {
             description("could not download file")
-            display("could not download file from '{}' to '{}'", url, path.display())
+            display("could not download file from '{}' to '{}'", download::redact_url(url.as_str()), path.display())
         }
         InvalidUrl {
             url: String,
@@ -129,6 +136,17 @@ error_chain! {
             description("could not copy directory")
             display("could not copy directory from '{}' to '{}'", src.display(), dest.display())
         }
+        RelocatingIntoItself {
+            src: PathBuf,
+            dest: PathBuf,
+        } {
+            description("cannot move a directory into itself")
+            display("cannot move '{}' into itself, to '{}'", src.display(), dest.display())
+        }
+        RelocationTargetExists(path: PathBuf) {
+            description("destination already exists")
+            display("destination '{}' already exists", path.display())
+        }
         CopyingFile {
             src: PathBuf,
             dest: PathBuf,
@@ -161,3 +179,117 @@ error_chain! {
         }
     }
 }
+
+/// Exit codes of the `elan` binaries by kind of failure, for scripts that
+/// wrap them. Codes are never reassigned within a major version; new kinds
+/// of errors are mapped to one of them or get a new one.
+pub mod codes {
+    /// Anything without a more specific code
+    pub const FAILURE: u32 = 1;
+    /// The requested toolchain, or a binary in it, is not installed
+    pub const NOT_INSTALLED: u32 = 10;
+    /// No toolchain was requested and there is no default
+    pub const NO_DEFAULT_TOOLCHAIN: u32 = 11;
+    /// The toolchain name, release url or project toolchain is invalid
+    pub const INVALID_TOOLCHAIN: u32 = 12;
+    /// A download failed, e.g. because there is no network
+    pub const NETWORK: u32 = 20;
+    /// The server has no such release or file
+    pub const NOT_FOUND: u32 = 21;
+    /// A download did not match its checksum
+    pub const CHECKSUM: u32 = 22;
+    /// Settings, toolchain configuration or project files are invalid
+    pub const INVALID_CONFIG: u32 = 30;
+    /// The project's elan.lock is missing or doesn't match
+    pub const LOCKFILE: u32 = 31;
+    /// The toolchain is read-only or in use
+    pub const TOOLCHAIN_BUSY: u32 = 40;
+    /// The toolchain needs a newer elan or a different architecture
+    pub const INCOMPATIBLE: u32 = 41;
+    /// There is not enough disk space, or too many toolchains are installed
+    pub const NO_SPACE: u32 = 50;
+    /// The operation was declined or cancelled
+    pub const CANCELLED: u32 = 130;
+}
+
+impl ErrorKind {
+    /// The exit code for this kind of error, see `codes`
+    pub fn error_code(&self) -> u32 {
+        match *self {
+            ErrorKind::Download(_) |
+            ErrorKind::DownloadingFile { .. } => codes::NETWORK,
+            ErrorKind::DownloadNotExists { .. } => codes::NOT_FOUND,
+            ErrorKind::ExpectedType(_, _) => codes::INVALID_CONFIG,
+            ErrorKind::Msg(_) |
+            ErrorKind::LocatingWorkingDir |
+            ErrorKind::ReadingFile { .. } |
+            ErrorKind::ReadingDirectory { .. } |
+            ErrorKind::WritingFile { .. } |
+            ErrorKind::LockingFile { .. } |
+            ErrorKind::CreatingDirectory { .. } |
+            ErrorKind::FilteringFile { .. } |
+            ErrorKind::RenamingFile { .. } |
+            ErrorKind::RenamingDirectory { .. } |
+            ErrorKind::InvalidUrl { .. } |
+            ErrorKind::RunningCommand { .. } |
+            ErrorKind::NotAFile { .. } |
+            ErrorKind::NotADirectory { .. } |
+            ErrorKind::LinkingFile { .. } |
+            ErrorKind::LinkingDirectory { .. } |
+            ErrorKind::CopyingDirectory { .. } |
+            ErrorKind::RelocatingIntoItself { .. } |
+            ErrorKind::RelocationTargetExists(_) |
+            ErrorKind::CopyingFile { .. } |
+            ErrorKind::RemovingFile { .. } |
+            ErrorKind::RemovingDirectory { .. } |
+            ErrorKind::SettingPermissions { .. } |
+            ErrorKind::ElanHome |
+            ErrorKind::__Nonexhaustive { .. } => codes::FAILURE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_error_codes() {
+        let url = Url::parse("https://example.com/lean.tar.gz").unwrap();
+        let path = || Path::new("lean.tar.gz").to_owned();
+        let kinds = vec![
+            (ErrorKind::Download(download::ErrorKind::Msg("timeout".to_owned())), codes::NETWORK),
+            (ErrorKind::DownloadingFile { url: url.clone(), path: path() }, codes::NETWORK),
+            (ErrorKind::DownloadNotExists { url: url.clone(), path: path() }, codes::NOT_FOUND),
+            (ErrorKind::ExpectedType("string", "version".to_owned()), codes::INVALID_CONFIG),
+            (ErrorKind::Msg("failed".to_owned()), codes::FAILURE),
+            (ErrorKind::LocatingWorkingDir, codes::FAILURE),
+            (ErrorKind::ReadingFile { name: "settings", path: path() }, codes::FAILURE),
+            (ErrorKind::ReadingDirectory { name: "toolchains", path: path() }, codes::FAILURE),
+            (ErrorKind::WritingFile { name: "settings", path: path() }, codes::FAILURE),
+            (ErrorKind::LockingFile { name: "settings", path: path() }, codes::FAILURE),
+            (ErrorKind::CreatingDirectory { name: "toolchains", path: path() }, codes::FAILURE),
+            (ErrorKind::FilteringFile { name: "settings", src: path(), dest: path() }, codes::FAILURE),
+            (ErrorKind::RenamingFile { name: "settings", src: path(), dest: path() }, codes::FAILURE),
+            (ErrorKind::RenamingDirectory { name: "toolchain", src: path(), dest: path() }, codes::FAILURE),
+            (ErrorKind::InvalidUrl { url: "lean".to_owned() }, codes::FAILURE),
+            (ErrorKind::RunningCommand { name: "lean".into() }, codes::FAILURE),
+            (ErrorKind::NotAFile { path: path() }, codes::FAILURE),
+            (ErrorKind::NotADirectory { path: path() }, codes::FAILURE),
+            (ErrorKind::LinkingFile { src: path(), dest: path() }, codes::FAILURE),
+            (ErrorKind::LinkingDirectory { src: path(), dest: path() }, codes::FAILURE),
+            (ErrorKind::CopyingDirectory { src: path(), dest: path() }, codes::FAILURE),
+            (ErrorKind::RelocatingIntoItself { src: path(), dest: path() }, codes::FAILURE),
+            (ErrorKind::RelocationTargetExists(path()), codes::FAILURE),
+            (ErrorKind::CopyingFile { src: path(), dest: path() }, codes::FAILURE),
+            (ErrorKind::RemovingFile { name: "settings", path: path() }, codes::FAILURE),
+            (ErrorKind::RemovingDirectory { name: "toolchain", path: path() }, codes::FAILURE),
+            (ErrorKind::SettingPermissions { path: path() }, codes::FAILURE),
+            (ErrorKind::ElanHome, codes::FAILURE),
+        ];
+        for (kind, code) in kinds {
+            assert_eq!(kind.error_code(), code, "{}", kind);
+        }
+    }
+}
@@ -5,6 +5,7 @@ extern crate scopeguard;
 #[macro_use]
 extern crate error_chain;
 extern crate sha2;
+extern crate json;
 extern crate url;
 extern crate toml;
 extern crate download;
@@ -12,6 +13,7 @@ extern crate semver;
 extern crate curl;
 extern crate regex;
 extern crate dirs;
+extern crate filetime;
 
 #[cfg(windows)]
 extern crate winapi;
@@ -21,12 +23,16 @@ extern crate winreg;
 #[cfg(unix)]
 extern crate libc;
 
+pub mod env_settings;
 pub mod errors;
 pub mod notifications;
 pub mod raw;
 pub mod tty;
 pub mod utils;
 pub mod toml_utils;
+pub mod walk_cache;
+// Used by the tests of this and the depending crates
+pub mod test_dir;
 
 pub use errors::*;
 pub use notifications::{Notification};
@@ -2,6 +2,7 @@ use std::path::Path;
 use std::fmt::{self, Display};
 
 use url::Url;
+use download::redact_url;
 
 use notify::NotificationLevel;
 
@@ -11,6 +12,7 @@ pub enum Notification<'a> {
     LinkingDirectory(&'a Path, &'a Path),
     CopyingDirectory(&'a Path, &'a Path),
     RemovingDirectory(&'a str, &'a Path),
+    ClearedReadOnly(&'a Path),
     DownloadingFile(&'a Url, &'a Path),
     /// Received the Content-Length of the to-be downloaded data.
     DownloadContentLengthReceived(u64),
@@ -22,14 +24,30 @@ pub enum Notification<'a> {
     ResumingPartialDownload,
     UsingCurl,
     UsingReqwest,
+    UsingParallelDownload(u64),
+    ParallelDownloadUnsupported,
     UsingHyperDeprecated,
 }
 
 impl<'a> Notification<'a> {
+    /// Whether this marks a step of a download that progress bars track,
+    /// which must never be dropped or collapsed
+    pub fn is_download_lifecycle(&self) -> bool {
+        use self::Notification::*;
+        match *self {
+            DownloadingFile(_, _) |
+            DownloadContentLengthReceived(_) |
+            DownloadDataReceived(_) |
+            DownloadFinished |
+            ResumingPartialDownload => true,
+            _ => false,
+        }
+    }
+
     pub fn level(&self) -> NotificationLevel {
         use self::Notification::*;
         match *self {
-            CreatingDirectory(_, _) | RemovingDirectory(_, _) => NotificationLevel::Verbose,
+            CreatingDirectory(_, _) | RemovingDirectory(_, _) | ClearedReadOnly(_) => NotificationLevel::Verbose,
             LinkingDirectory(_, _) |
             CopyingDirectory(_, _) |
             DownloadingFile(_, _) |
@@ -37,7 +55,9 @@ impl<'a> Notification<'a> {
             DownloadDataReceived(_) |
             DownloadFinished |
             ResumingPartialDownload |
-            UsingCurl | UsingReqwest => NotificationLevel::Verbose,
+            UsingCurl | UsingReqwest |
+            UsingParallelDownload(_) |
+            ParallelDownloadUnsupported => NotificationLevel::Verbose,
             UsingHyperDeprecated |
             NoCanonicalPath(_) => NotificationLevel::Warn,
         }
@@ -56,7 +76,8 @@ impl<'a> Display for Notification<'a> {
             RemovingDirectory(name, path) => {
                 write!(f, "removing {} directory: '{}'", name, path.display())
             }
-            DownloadingFile(url, _) => write!(f, "downloading file from: '{}'", url),
+            ClearedReadOnly(path) => write!(f, "made read-only files in '{}' writable to remove them", path.display()),
+            DownloadingFile(url, _) => write!(f, "downloading file from: '{}'", redact_url(url.as_str())),
             DownloadContentLengthReceived(len) => write!(f, "download size is: '{}'", len),
             DownloadDataReceived(data) => write!(f, "received some data of size {}", data.len()),
             DownloadFinished => write!(f, "download finished"),
@@ -64,6 +85,10 @@ impl<'a> Display for Notification<'a> {
             ResumingPartialDownload => write!(f, "resuming partial download"),
             UsingCurl => write!(f, "downloading with curl"),
             UsingReqwest => write!(f, "downloading with reqwest"),
+            UsingParallelDownload(chunks) => write!(f, "downloading in {} parallel chunks", chunks),
+            ParallelDownloadUnsupported => {
+                write!(f, "server does not support range requests, downloading in a single stream")
+            }
             UsingHyperDeprecated => f.write_str("ELAN_USE_HYPER environment variable is deprecated, use ELAN_USE_REQWEST instead"),
         }
     }
@@ -1,3 +1,7 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
 #[derive(Debug)]
 pub enum NotificationLevel {
     Verbose,
@@ -5,3 +9,116 @@ pub enum NotificationLevel {
     Warn,
     Error,
 }
+
+/// Decides which notifications are passed on to a frontend. Identical
+/// consecutive messages are collapsed into the first one, and a message that
+/// was passed less than `interval` ago is dropped. Distinct messages and
+/// errors always pass, as does everything while no interval is set.
+pub struct Throttle {
+    state: Mutex<ThrottleState>,
+}
+
+struct ThrottleState {
+    interval: Option<Duration>,
+    last: Option<String>,
+    passed_at: HashMap<String, Instant>,
+}
+
+// Bounds the memory used by long-running installs with many distinct
+// messages
+const MAX_TRACKED_MESSAGES: usize = 256;
+
+impl Throttle {
+    pub fn new(interval: Option<Duration>) -> Self {
+        Throttle {
+            state: Mutex::new(ThrottleState {
+                interval: interval,
+                last: None,
+                passed_at: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Changes the interval, forgetting which messages were passed
+    pub fn set_interval(&self, interval: Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+        state.interval = interval;
+        state.last = None;
+        state.passed_at.clear();
+    }
+
+    pub fn should_pass(&self, level: &NotificationLevel, message: &str) -> bool {
+        if let NotificationLevel::Error = *level {
+            return true;
+        }
+        let mut state = self.state.lock().unwrap();
+        let interval = match state.interval {
+            Some(interval) => interval,
+            None => return true,
+        };
+        if state.last.as_ref().map(|s| &**s) == Some(message) {
+            return false;
+        }
+        state.last = Some(message.to_owned());
+
+        let now = Instant::now();
+        if let Some(&at) = state.passed_at.get(message) {
+            if now.duration_since(at) < interval {
+                return false;
+            }
+        }
+        if state.passed_at.len() >= MAX_TRACKED_MESSAGES {
+            state.passed_at.retain(|_, at| now.duration_since(*at) < interval);
+        }
+        state.passed_at.insert(message.to_owned(), now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let throttle = Throttle::new(Some(Duration::from_secs(3600)));
+        let info = NotificationLevel::Info;
+        assert!(throttle.should_pass(&info, "retrying download"));
+        assert!(!throttle.should_pass(&info, "retrying download"));
+        assert!(throttle.should_pass(&info, "downloading component"));
+        // Not consecutive, but within the interval
+        assert!(!throttle.should_pass(&info, "retrying download"));
+        assert!(throttle.should_pass(&NotificationLevel::Warn, "something else"));
+
+        for _ in 0..3 {
+            assert!(throttle.should_pass(&NotificationLevel::Error, "failed"));
+        }
+
+        let throttle = Throttle::new(Some(Duration::from_secs(0)));
+        assert!(throttle.should_pass(&info, "a"));
+        assert!(!throttle.should_pass(&info, "a"));
+        assert!(throttle.should_pass(&info, "b"));
+        assert!(throttle.should_pass(&info, "a"));
+
+        throttle.set_interval(None);
+        assert!(throttle.should_pass(&info, "a"));
+        assert!(throttle.should_pass(&info, "a"));
+        throttle.set_interval(Some(Duration::from_secs(3600)));
+        assert!(throttle.should_pass(&info, "a"));
+        assert!(!throttle.should_pass(&info, "a"));
+    }
+
+    #[test]
+    fn test_download_lifecycle_notifications() {
+        use notifications::Notification;
+        use std::path::Path;
+
+        let url = ::url::Url::parse("https://example.com/lean.tar.zst").unwrap();
+        assert!(Notification::DownloadingFile(&url, Path::new("lean.tar.zst")).is_download_lifecycle());
+        assert!(Notification::DownloadContentLengthReceived(10).is_download_lifecycle());
+        assert!(Notification::DownloadDataReceived(b"data").is_download_lifecycle());
+        assert!(Notification::DownloadFinished.is_download_lifecycle());
+        assert!(Notification::ResumingPartialDownload.is_download_lifecycle());
+        assert!(!Notification::UsingCurl.is_download_lifecycle());
+    }
+}
@@ -8,9 +8,13 @@ use std::fmt;
 use std::fs;
 use std::io::Write;
 use std::io;
-use std::path::Path;
-use std::process::{Command, ExitStatus};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
 use std::str;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 
 use rand::random;
 
@@ -64,6 +68,94 @@ pub fn write_file(path: &Path, contents: &str) -> io::Result<()> {
     Ok(())
 }
 
+/// Like `write_file`, but writes to a temporary sibling first and renames
+/// it over `path`, so readers never observe a partially written file.
+pub fn write_file_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    write_file_atomic_with(path, contents, &|file, contents| {
+        io::Write::write_all(file, contents.as_bytes())
+    })
+}
+
+pub(crate) fn write_file_atomic_with(path: &Path,
+                                     contents: &str,
+                                     write: &Fn(&mut fs::File, &str) -> io::Result<()>)
+                                     -> io::Result<()> {
+    let file_name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let tmp_path = path.with_file_name(format!(".{}.tmp-{}", file_name, random_string(8)));
+
+    let result = (|| {
+        let mut file = try!(fs::OpenOptions::new()
+                                .write(true)
+                                .create_new(true)
+                                .open(&tmp_path));
+        try!(write(&mut file, contents));
+        try!(file.sync_data());
+        fs::rename(&tmp_path, path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Creates `path` as a lock file, waiting while it exists. Locks older
+/// than `stale_after` are assumed to be left behind by a process that
+/// crashed and are broken. Returns the token identifying the owner, which
+/// `release_lock_file` takes.
+pub fn acquire_lock_file(path: &Path, stale_after: Duration) -> io::Result<String> {
+    let token = random_string(16);
+    let mut delay = Duration::from_millis(5);
+    loop {
+        match fs::OpenOptions::new().write(true).create_new(true).open(path) {
+            Ok(mut file) => {
+                try!(file.write_all(token.as_bytes()));
+                drop(file);
+                // Another process may have broken the lock as stale just
+                // before it was created
+                if fs::read_to_string(path).ok().as_ref() == Some(&token) {
+                    return Ok(token);
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let age = fs::metadata(path).and_then(|m| m.modified()).ok()
+                    .and_then(|t| t.elapsed().ok());
+                if age.map_or(false, |age| age > stale_after) {
+                    break_stale_lock(path);
+                    continue;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+        thread::sleep(delay);
+        delay = ::std::cmp::min(delay * 2, Duration::from_millis(200));
+    }
+}
+
+// Moves the lock aside before removing it, so that a lock another process
+// took in the meantime is put back rather than removed
+fn break_stale_lock(path: &Path) {
+    let owner = fs::read_to_string(path).ok();
+    let moved = path.with_file_name(format!("{}.{}.stale",
+                                            path.file_name().unwrap().to_string_lossy(),
+                                            random_string(8)));
+    if fs::rename(path, &moved).is_err() {
+        return;
+    }
+    if fs::read_to_string(&moved).ok() != owner {
+        let _ = fs::hard_link(&moved, path);
+    }
+    let _ = fs::remove_file(&moved);
+}
+
+/// Removes the lock file at `path` taken by `acquire_lock_file`, unless it
+/// was broken as stale and another process owns it now
+pub fn release_lock_file(path: &Path, token: &str) {
+    if fs::read_to_string(path).ok().as_ref().map(|s| &**s) == Some(token) {
+        let _ = fs::remove_file(path);
+    }
+}
+
 pub fn read_file(path: &Path) -> io::Result<String> {
     let mut file = try!(fs::OpenOptions::new()
                             .read(true)
@@ -303,6 +395,68 @@ pub fn cmd_status(cmd: &mut Command) -> CommandResult<()> {
     })
 }
 
+// The child SIGTERM is passed on to, 0 if none. A signal handler can't
+// carry any state of its own.
+#[cfg(unix)]
+static FORWARD_SIGNALS_TO: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs `cmd` with inherited stdio until it exits and returns its exit
+/// code, or 128 plus the signal number if a signal killed it. On Unix, the
+/// current process ignores SIGINT meanwhile, which the terminal already
+/// sends to the child, and passes SIGTERM on to the child.
+pub fn run_forwarding_signals(cmd: &mut Command) -> io::Result<i32> {
+    cmd.stdin(Stdio::inherit()).stdout(Stdio::inherit()).stderr(Stdio::inherit());
+    return run(cmd);
+
+    #[cfg(unix)]
+    fn run(cmd: &mut Command) -> io::Result<i32> {
+        use std::os::unix::process::ExitStatusExt;
+        use std::mem;
+        use libc;
+
+        extern "C" fn forward(signal: libc::c_int) {
+            let pid = FORWARD_SIGNALS_TO.load(Ordering::SeqCst);
+            if pid != 0 {
+                unsafe { libc::kill(pid as libc::pid_t, signal); }
+            }
+        }
+
+        unsafe fn set_action(signal: libc::c_int, handler: libc::sighandler_t) -> libc::sigaction {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handler;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            let mut old: libc::sigaction = mem::zeroed();
+            libc::sigaction(signal, &action, &mut old);
+            old
+        }
+
+        // The child is spawned first so that it doesn't inherit SIGINT
+        // being ignored
+        let mut child = try!(cmd.spawn());
+        FORWARD_SIGNALS_TO.store(child.id() as usize, Ordering::SeqCst);
+        let handler = forward as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        let (old_int, old_term) = unsafe {
+            (set_action(libc::SIGINT, libc::SIG_IGN), set_action(libc::SIGTERM, handler))
+        };
+        let status = child.wait();
+        unsafe {
+            libc::sigaction(libc::SIGINT, &old_int, ::std::ptr::null_mut());
+            libc::sigaction(libc::SIGTERM, &old_term, ::std::ptr::null_mut());
+        }
+        FORWARD_SIGNALS_TO.store(0, Ordering::SeqCst);
+        let status = try!(status);
+        Ok(status.code().or(status.signal().map(|s| 128 + s)).unwrap_or(1))
+    }
+
+    // Console Ctrl-C events already reach all attached processes
+    #[cfg(windows)]
+    fn run(cmd: &mut Command) -> io::Result<i32> {
+        let status = try!(cmd.status());
+        Ok(status.code().unwrap_or(1))
+    }
+}
+
 pub fn remove_dir(path: &Path) -> io::Result<()> {
     if try!(fs::symlink_metadata(path)).file_type().is_symlink() {
         if cfg!(windows) {
@@ -319,6 +473,39 @@ pub fn remove_dir(path: &Path) -> io::Result<()> {
     }
 }
 
+/// Makes `path` and everything below it writable, not following links.
+/// Returns whether anything was read-only.
+pub fn clear_readonly(path: &Path) -> io::Result<bool> {
+    #[cfg(unix)]
+    fn make_writable(permissions: &mut fs::Permissions) {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = permissions.mode();
+        permissions.set_mode(mode | 0o200);
+    }
+    #[cfg(windows)]
+    fn make_writable(permissions: &mut fs::Permissions) {
+        permissions.set_readonly(false);
+    }
+
+    let metadata = try!(fs::symlink_metadata(path));
+    if metadata.file_type().is_symlink() {
+        return Ok(false);
+    }
+    let mut cleared = false;
+    let mut permissions = metadata.permissions();
+    if permissions.readonly() {
+        make_writable(&mut permissions);
+        try!(fs::set_permissions(path, permissions));
+        cleared = true;
+    }
+    if metadata.is_dir() {
+        for entry in try!(fs::read_dir(path)) {
+            cleared |= try!(clear_readonly(&try!(entry).path()));
+        }
+    }
+    Ok(cleared)
+}
+
 pub fn copy_dir(src: &Path, dest: &Path) -> io::Result<()> {
     try!(fs::create_dir(dest));
     for entry in try!(src.read_dir()) {
@@ -335,6 +522,34 @@ pub fn copy_dir(src: &Path, dest: &Path) -> io::Result<()> {
     Ok(())
 }
 
+/// Like `copy_dir`, but recreates links instead of copying what they point
+/// to, with their targets passed through `relink`
+pub fn copy_dir_with_links(src: &Path, dest: &Path, relink: &Fn(PathBuf) -> PathBuf) -> io::Result<()> {
+    try!(fs::create_dir(dest));
+    for entry in try!(src.read_dir()) {
+        let entry = try!(entry);
+        let kind = try!(entry.file_type());
+        let src = entry.path();
+        let dest = dest.join(entry.file_name());
+        if kind.is_symlink() {
+            let target = relink(try!(fs::read_link(&src)));
+            if src.is_dir() {
+                try!(symlink_dir(&target, &dest));
+            } else {
+                #[cfg(unix)]
+                try!(::std::os::unix::fs::symlink(&target, &dest));
+                #[cfg(windows)]
+                try!(::std::os::windows::fs::symlink_file(&target, &dest));
+            }
+        } else if kind.is_dir() {
+            try!(copy_dir_with_links(&src, &dest, relink));
+        } else {
+            try!(fs::copy(&src, &dest));
+        }
+    }
+    Ok(())
+}
+
 pub fn prefix_arg<S: AsRef<OsStr>>(name: &str, s: S) -> OsString {
     let mut arg = OsString::from(name);
     arg.push(s);
@@ -408,6 +623,104 @@ pub fn open_browser(path: &Path) -> io::Result<bool> {
     inner(path)
 }
 
+/// Bytes available to unprivileged users on the volume of `path`
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    #[cfg(unix)]
+    fn inner(path: &Path) -> io::Result<u64> {
+        use std::ffi::CString;
+        use std::mem;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = try!(CString::new(path.as_os_str().as_bytes())
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e)));
+        unsafe {
+            let mut stat: libc::statvfs = mem::zeroed();
+            if libc::statvfs(path.as_ptr(), &mut stat) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+        }
+    }
+    #[cfg(windows)]
+    fn inner(path: &Path) -> io::Result<u64> {
+        use winapi::um::fileapi::GetDiskFreeSpaceExW;
+        use winapi::shared::ntdef::ULARGE_INTEGER;
+        use std::mem;
+        use std::ptr;
+
+        let path = windows::to_u16s(path)?;
+        unsafe {
+            let mut available: ULARGE_INTEGER = mem::zeroed();
+            if GetDiskFreeSpaceExW(path.as_ptr(), &mut available, ptr::null_mut(), ptr::null_mut()) == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(*available.QuadPart())
+        }
+    }
+    inner(path)
+}
+
+/// The machine name reported by the operating system for the hardware
+/// elan is running on, e.g. `x86_64` or `arm64`. A process translated by
+/// Rosetta reports the Apple Silicon host rather than the Intel machine
+/// it appears to run on.
+pub fn host_machine() -> io::Result<String> {
+    #[cfg(unix)]
+    fn inner() -> io::Result<String> {
+        use std::ffi::CStr;
+        use std::mem;
+
+        if translated() {
+            return Ok("arm64".to_owned());
+        }
+        unsafe {
+            let mut name: libc::utsname = mem::zeroed();
+            if libc::uname(&mut name) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(CStr::from_ptr(name.machine.as_ptr()).to_string_lossy().into_owned())
+        }
+    }
+    #[cfg(target_os = "macos")]
+    fn translated() -> bool {
+        use std::mem;
+
+        let mut value: libc::c_int = 0;
+        let mut size = mem::size_of::<libc::c_int>();
+        let result = unsafe {
+            libc::sysctlbyname(b"sysctl.proc_translated\0".as_ptr() as *const libc::c_char,
+                               &mut value as *mut libc::c_int as *mut libc::c_void,
+                               &mut size, ::std::ptr::null_mut(), 0)
+        };
+        // Fails with ENOENT on Intel Macs
+        result == 0 && value == 1
+    }
+    #[cfg(all(unix, not(target_os = "macos")))]
+    fn translated() -> bool {
+        false
+    }
+    #[cfg(windows)]
+    fn inner() -> io::Result<String> {
+        use winapi::um::sysinfoapi::{GetNativeSystemInfo, SYSTEM_INFO};
+        use winapi::um::winnt;
+        use std::mem;
+
+        let arch = unsafe {
+            let mut info: SYSTEM_INFO = mem::zeroed();
+            GetNativeSystemInfo(&mut info);
+            info.u.s().wProcessorArchitecture
+        };
+        Ok(match arch {
+            winnt::PROCESSOR_ARCHITECTURE_AMD64 => "x86_64",
+            winnt::PROCESSOR_ARCHITECTURE_ARM64 => "aarch64",
+            winnt::PROCESSOR_ARCHITECTURE_INTEL => "x86",
+            winnt::PROCESSOR_ARCHITECTURE_ARM => "arm",
+            _ => "unknown",
+        }.to_owned())
+    }
+    inner()
+}
+
 #[cfg(windows)]
 pub mod windows {
     use winapi::um::{combaseapi, shlobj, shtypes};
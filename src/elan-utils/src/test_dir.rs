@@ -0,0 +1,47 @@
+//! Scratch directories for tests
+
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// An empty directory below the system's temporary directory that is
+/// removed again when dropped, also if the test panics
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// Creates a directory named after `name` that is unique to this call.
+    /// Whatever an earlier run of the same process id left there is
+    /// removed first.
+    pub fn new(name: &str) -> TestDir {
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        let path = env::temp_dir().join(format!("elan-test-{}-{}-{}", name, process::id(), id));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TestDir(path)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
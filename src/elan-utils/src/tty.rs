@@ -48,3 +48,84 @@ pub fn stdout_isatty() -> bool {
         GetConsoleMode(handle, &mut out) != 0
     }
 }
+
+/// How confirmations are answered in non-interactive mode, e.g. in CI
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NonInteractivePolicy {
+    /// Go ahead with every action
+    Approve,
+    /// Refuse every action that asks for confirmation
+    Deny,
+}
+
+impl NonInteractivePolicy {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "approve" => Some(NonInteractivePolicy::Approve),
+            "deny" => Some(NonInteractivePolicy::Deny),
+            _ => None,
+        }
+    }
+
+    /// The answer to every confirmation
+    pub fn approves(&self) -> bool {
+        *self == NonInteractivePolicy::Approve
+    }
+}
+
+/// How a confirmation was answered, see `confirm`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Confirmation {
+    Approved,
+    ApprovedNonInteractively,
+    /// Refused by the confirmation handler
+    Denied,
+    /// Refused by the non-interactive policy
+    Required,
+}
+
+/// Answers the confirmation of `action` by the non-interactive `policy`
+/// if there is one, and by asking `handler` otherwise
+pub fn confirm(policy: Option<NonInteractivePolicy>, handler: &Fn(&str) -> bool, action: &str) -> Confirmation {
+    match policy {
+        Some(policy) if policy.approves() => Confirmation::ApprovedNonInteractively,
+        Some(_) => Confirmation::Required,
+        None if handler(action) => Confirmation::Approved,
+        None => Confirmation::Denied,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_non_interactive_policy() {
+        assert_eq!(NonInteractivePolicy::from_str("approve"), Some(NonInteractivePolicy::Approve));
+        assert_eq!(NonInteractivePolicy::from_str("deny"), Some(NonInteractivePolicy::Deny));
+        assert_eq!(NonInteractivePolicy::from_str("yes"), None);
+        assert_eq!(NonInteractivePolicy::from_str("Approve"), None);
+        assert!(NonInteractivePolicy::Approve.approves());
+        assert!(!NonInteractivePolicy::Deny.approves());
+    }
+
+    #[test]
+    fn test_confirm() {
+        use std::cell::RefCell;
+
+        let asked = RefCell::new(vec![]);
+        let deny = |action: &str| { asked.borrow_mut().push(action.to_owned()); false };
+        let approve = |_: &str| true;
+
+        assert_eq!(confirm(None, &deny, "remove toolchain 'stable'"), Confirmation::Denied);
+        assert_eq!(*asked.borrow(), vec!["remove toolchain 'stable'".to_owned()]);
+        assert_eq!(confirm(None, &approve, "remove toolchain 'stable'"), Confirmation::Approved);
+
+        // The policy answers without asking
+        asked.borrow_mut().clear();
+        assert_eq!(confirm(Some(NonInteractivePolicy::Approve), &deny, "prune"),
+                   Confirmation::ApprovedNonInteractively);
+        assert_eq!(confirm(Some(NonInteractivePolicy::Deny), &approve, "prune"), Confirmation::Required);
+        assert!(asked.borrow().is_empty());
+    }
+}
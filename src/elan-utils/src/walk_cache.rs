@@ -0,0 +1,143 @@
+//! Remembers which file was found by walking up the directory tree from a
+//! directory, for long-running processes like language servers that look
+//! up the same project files over and over
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+// What a file looked like when it was last read, `None` if it didn't exist
+type Stamp = Option<(SystemTime, u64)>;
+
+fn stamp(path: &Path) -> Stamp {
+    fs::metadata(path).ok().and_then(|m| m.modified().ok().map(|t| (t, m.len())))
+}
+
+struct Walk {
+    /// The directories looked in, nearest first
+    dirs: Vec<PathBuf>,
+    /// Every file looked for, including rejected and missing ones
+    files: Vec<(PathBuf, Stamp)>,
+    /// The file accepted in the last directory, and its contents
+    found: Option<(PathBuf, String)>,
+}
+
+impl Walk {
+    fn is_current(&self) -> bool {
+        self.files.iter().all(|&(ref file, ref s)| stamp(file) == *s)
+    }
+
+    fn result(&self) -> WalkResult {
+        (self.dirs.clone(), self.found.clone())
+    }
+}
+
+/// The result of a walk: the directories looked in, nearest first, and
+/// the file that was found in the last of them with its contents
+pub type WalkResult = (Vec<PathBuf>, Option<(PathBuf, String)>);
+
+#[derive(Default)]
+pub struct WalkCache {
+    walks: Mutex<HashMap<PathBuf, Walk>>,
+}
+
+impl WalkCache {
+    pub fn new() -> Self {
+        WalkCache::default()
+    }
+
+    /// Looks for the nearest of the files `names`, in order of preference,
+    /// in `dir` and its parents, skipping those whose contents `accept`
+    /// rejects. Results are reused for as long as none of the files looked
+    /// for have been created, changed or removed.
+    pub fn find(&self, dir: &Path, names: &[&str], accept: &Fn(&Path, &str) -> bool) -> WalkResult {
+        let mut walks = self.walks.lock().unwrap();
+        if let Some(walk) = walks.get(dir) {
+            if walk.is_current() {
+                return walk.result();
+            }
+        }
+
+        let mut walk = Walk { dirs: vec![], files: vec![], found: None };
+        let mut d = Some(dir);
+        'walk: while let Some(current) = d {
+            walk.dirs.push(current.to_owned());
+            for name in names {
+                let file = current.join(name);
+                // Stamped before reading, so that changes made meanwhile
+                // are picked up next time
+                walk.files.push((file.clone(), stamp(&file)));
+                if let Ok(contents) = fs::read_to_string(&file) {
+                    if accept(&file, &contents) {
+                        walk.found = Some((file, contents));
+                        break 'walk;
+                    }
+                }
+            }
+            d = current.parent();
+        }
+
+        let result = walk.result();
+        walks.insert(dir.to_owned(), walk);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_dir::TestDir;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_walk_cache() {
+        let root = TestDir::new("walk-cache");
+        let inner = root.join("a").join("b");
+        fs::create_dir_all(&inner).unwrap();
+        fs::write(root.join("lean-toolchain"), "stable\n").unwrap();
+        fs::write(root.join("a").join("lean-toolchain"), "").unwrap();
+
+        let cache = WalkCache::new();
+        let reads = Cell::new(0);
+        let find = || {
+            let accept = |_: &Path, contents: &str| {
+                reads.set(reads.get() + 1);
+                !contents.is_empty()
+            };
+            let (dirs, found) = cache.find(&inner, &["lean-toolchain"], &accept);
+            (dirs.len(), found)
+        };
+
+        // Empty files are skipped
+        let found = Some((root.join("lean-toolchain"), "stable\n".to_owned()));
+        assert_eq!(find(), (3, found.clone()));
+        assert_eq!(reads.get(), 2);
+
+        // Nothing changed, so nothing is read again
+        assert_eq!(find(), (3, found));
+        assert_eq!(reads.get(), 2);
+
+        // Edited files are read again
+        fs::write(root.join("lean-toolchain"), "nightly\n").unwrap();
+        assert_eq!(find(), (3, Some((root.join("lean-toolchain"), "nightly\n".to_owned()))));
+
+        // So are skipped files
+        fs::write(root.join("a").join("lean-toolchain"), "v4.1.0\n").unwrap();
+        assert_eq!(find(), (2, Some((root.join("a").join("lean-toolchain"), "v4.1.0\n".to_owned()))));
+
+        // Files created closer to the directory take precedence
+        fs::write(inner.join("lean-toolchain"), "v4.0.0\n").unwrap();
+        assert_eq!(find(), (1, Some((inner.join("lean-toolchain"), "v4.0.0\n".to_owned()))));
+
+        // Removed files are no longer found
+        fs::remove_file(inner.join("lean-toolchain")).unwrap();
+        assert_eq!(find(), (2, Some((root.join("a").join("lean-toolchain"), "v4.1.0\n".to_owned()))));
+        fs::remove_file(root.join("a").join("lean-toolchain")).unwrap();
+        fs::remove_file(root.join("lean-toolchain")).unwrap();
+        let (dirs, found) = find();
+        assert!(dirs > 3);
+        assert_eq!(found, None);
+    }
+}
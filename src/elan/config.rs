@@ -1,19 +1,84 @@
 use std::path::{Path, PathBuf};
 use std::env;
+use std::ffi::OsStr;
 use std::io;
-use std::process::Command;
+use std::process::{Command, Stdio};
+use std::fs;
+use std::env::consts::EXE_SUFFIX;
 use std::fmt::{self, Display};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, SystemTime};
 
 use errors::*;
 use notifications::*;
-use elan_dist::{temp};
+use elan_dist::{self, channel_history, download, provenance, temp};
+use elan_utils;
+use elan_dist::dist::{self, DistConfig, ToolchainDesc, ToolchainKind};
+use elan_dist::lockfile::{self, Lockfile};
+use elan_dist::manifest::OPTIONAL_COMPONENTS;
+use elan_dist::prefix::InstallPrefix;
+use elan_dist::bundle::Bundle;
+use elan_dist::toolchain_file::{self, ToolchainConfig, ToolchainNameSource};
+use elan_dist::manifest::Profile;
 use elan_utils::utils;
-use toolchain::{Toolchain, UpdateStatus};
+use elan_utils::notify::Throttle;
+pub use elan_utils::tty::NonInteractivePolicy;
+use elan_utils::tty::{self, Confirmation};
+use elan_utils::walk_cache::WalkCache;
+use toolchain::{self, Toolchain, UpdateStatus};
 use telemetry_analysis::*;
-use settings::{TelemetryMode, SettingsFile, Settings};
+use log_file::LogFile;
+use settings::{EnvSettings, TelemetryMode, SettingsFile, Settings, ToolchainLayout};
+use telemetry::Telemetry;
+use update_check::{self, UpdateCheck};
+use doctor::Problem;
+
+use serde_json;
+use sha2::{Digest, Sha256};
+
+/// An installed toolchain, as returned by `Cfg::search_toolchains`
+#[derive(Debug, Clone)]
+pub struct ToolchainInfo {
+    pub name: String,
+    pub kind: ToolchainKind,
+    pub is_default: bool,
+    pub read_only: bool,
+    /// The version from `Toolchain::describe`, if known
+    pub version: Option<String>,
+}
+
+/// Where a linked toolchain points, see `Cfg::toolchain_link_target`
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinkTarget {
+    pub path: PathBuf,
+    /// Whether `path` is not an existing directory
+    pub broken: bool,
+}
 
-use toml;
+/// What to do when installing a toolchain would exceed `ELAN_MAX_TOOLCHAINS`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum QuotaPolicy {
+    /// Refuse to install
+    Error,
+    /// Uninstall the least recently used toolchains after the install,
+    /// keeping those that are still referred to, see
+    /// `Cfg::evict_toolchains`
+    EvictLeastRecentlyUsed,
+}
+
+impl FromStr for QuotaPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "error" => Ok(QuotaPolicy::Error),
+            "evict" => Ok(QuotaPolicy::EvictLeastRecentlyUsed),
+            _ => Err(ErrorKind::InvalidQuotaPolicy(s.to_owned()).into()),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum OverrideReason {
@@ -46,10 +111,101 @@ pub struct Cfg {
     pub toolchains_dir: PathBuf,
     pub update_hash_dir: PathBuf,
     pub download_dir: PathBuf,
+    /// Executables run after a toolchain is installed or updated, see
+    /// `post_install_hooks`
+    pub hooks_dir: PathBuf,
+    pub keep_downloads: bool,
+    pub include_prereleases: bool,
+    /// Profile to install with instead of the configured one, from
+    /// an install flag
+    pub profile_override: Option<Profile>,
+    // `ELAN_PROFILE`, only checked once something is installed with it
+    profile_env: Option<String>,
+    /// Size in bytes above which `download_dir` is pruned after installs
+    pub download_dir_limit: Option<u64>,
+    /// Whether update checks may run in the background
+    pub update_checks: bool,
     pub temp_cfg: temp::Cfg,
     //pub gpg_key: Cow<'static, str>,
     pub env_override: Option<String>,
+    pub strict_home: bool,
+    /// Variables the toolchain's `lib` directory is prepended to
+    pub loader_path_vars: Vec<String>,
+    pub strict_elan_version: bool,
+    /// Whether to check that toolchain binaries match the host architecture
+    pub check_arch: bool,
+    /// Drops repeated notifications, see `set_notify_throttle`
+    notify_throttle: Arc<Throttle>,
+    /// Whether project toolchains must match an up-to-date `elan.lock`,
+    /// from `ELAN_LOCKED` or `elan update --locked`
+    pub locked: bool,
+    /// Set by frontends to abort running installs, which then fail with
+    /// `elan_dist::ErrorKind::Cancelled`
+    pub cancel: Arc<AtomicBool>,
+    /// How many toolchains may be installed, from `ELAN_MAX_TOOLCHAINS`
+    pub max_toolchains: Option<usize>,
+    pub quota_policy: QuotaPolicy,
+    /// Mirrors of the dist server to download from, in order, from
+    /// `ELAN_DIST_SERVERS`
+    pub dist_servers: Vec<String>,
+    /// The multiple of an archive's size that must be free before it is
+    /// installed, from `ELAN_DISK_SPACE_FACTOR`. `None` if the check is
+    /// disabled with `ELAN_SKIP_DISK_SPACE_CHECK=1`.
+    pub disk_space_factor: Option<u64>,
+    /// Whether updates first try a delta from the installed release, from
+    /// `ELAN_DELTA_UPDATES=1`
+    pub delta_updates: bool,
+    /// The provenance attestation release archives must have, if
+    /// verification is enabled with `ELAN_VERIFY_PROVENANCE=1`. The builder
+    /// is overridden with `ELAN_PROVENANCE_BUILDER`, and signatures are only
+    /// checked against a key given with `ELAN_PROVENANCE_KEY`.
+    pub provenance: Option<provenance::Policy>,
+    /// Whether extracted toolchains are normalized to be identical across
+    /// installs, enabled with `ELAN_DETERMINISTIC_EXTRACT=1`
+    pub deterministic_extract: bool,
+    /// Whether archives are checked for paths that only differ in case,
+    /// always on Windows and macOS and otherwise with `ELAN_CHECK_CASE=1`
+    pub check_case: bool,
+    /// Whether installing a toolchain while no default is set makes it the
+    /// default, unless disabled with `ELAN_AUTO_DEFAULT=0`
+    pub default_on_first_install: bool,
     pub notify_handler: Arc<Fn(Notification)>,
+    /// Asked before destructive operations, with a description of what is
+    /// about to happen. Defaults to always proceeding.
+    pub confirm_handler: Arc<Fn(&str) -> bool>,
+    /// Where project files were found, for processes that resolve
+    /// toolchains repeatedly
+    walk_cache: WalkCache,
+    /// Answers confirmations instead of `confirm_handler` if set, from
+    /// `ELAN_NON_INTERACTIVE` or `--force-non-interactive`
+    pub non_interactive: Option<NonInteractivePolicy>,
+}
+
+// Whether a `lean-toolchain` file names a toolchain directory relative to
+// itself rather than a toolchain
+fn is_local_toolchain_path(name: &str) -> bool {
+    name.starts_with("./") || name.starts_with("/")
+}
+
+// Moves `legacy` from `~/.elan` to its place in the XDG layout, if it has
+// not been moved yet. If that fails, the legacy location keeps being used.
+fn migrate_to_xdg(legacy: &Path, new: &Path, notify_handler: &Fn(Notification)) -> PathBuf {
+    if utils::path_exists(new) || !utils::path_exists(legacy) {
+        return new.to_owned();
+    }
+    let moved = utils::ensure_dir_exists("xdg directory", new.parent().unwrap(),
+                                         &|n| notify_handler(n.into()))
+        .and_then(|_| utils::rename_dir("xdg directory", legacy, new));
+    match moved {
+        Ok(()) => {
+            notify_handler(Notification::MigratedToXdg(legacy, new));
+            new.to_owned()
+        }
+        Err(_) => {
+            notify_handler(Notification::XdgMigrationFailed(legacy, new));
+            legacy.to_owned()
+        }
+    }
 }
 
 impl Cfg {
@@ -60,11 +216,46 @@ impl Cfg {
         try!(utils::ensure_dir_exists("home", &elan_dir,
                                       &|n| notify_handler(n.into())));
 
-        let settings_file = SettingsFile::new(elan_dir.join("settings.toml"));
+        // Opt into the XDG base directory layout. `elan_dir` keeps the proxy
+        // binaries and everything else not covered by the layout.
+        let use_xdg = env::var("ELAN_USE_XDG").ok().as_ref().map(|s| &**s) == Some("1") &&
+            env::var_os("ELAN_HOME").is_none();
 
-        let toolchains_dir = elan_dir.join("toolchains");
-        let update_hash_dir = elan_dir.join("update-hashes");
-        let download_dir = elan_dir.join("downloads");
+        let (settings_path, toolchains_dir, update_hash_dir, download_dir, tmp_dir) = if use_xdg {
+            let (data_dir, cache_dir, config_dir) = try!(utils::xdg_dirs());
+            let migrate = |name: &str, new_dir: &Path| {
+                migrate_to_xdg(&elan_dir.join(name), &new_dir.join(name), notify_handler.as_ref())
+            };
+            (migrate("settings.toml", &config_dir),
+             migrate("toolchains", &data_dir),
+             migrate("update-hashes", &data_dir),
+             migrate("downloads", &cache_dir),
+             cache_dir.join("tmp"))
+        } else {
+            (elan_dir.join("settings.toml"),
+             elan_dir.join("toolchains"),
+             elan_dir.join("update-hashes"),
+             elan_dir.join("downloads"),
+             elan_dir.join("tmp"))
+        };
+
+        // Only move the toolchains, e.g. to a larger disk. Installs stage
+        // inside this directory and unpack downloads rather than linking
+        // them, so it may be on a different device than the rest.
+        let toolchains_dir = try!(utils::relocated_toolchains_dir(toolchains_dir,
+                                                                  env::var_os("ELAN_TOOLCHAINS_DIR")));
+
+        // Temporary files, e.g. downloads that aren't kept, may go to a
+        // faster disk. They are only read from, never moved into the
+        // toolchains, so any device will do.
+        let custom_tmp_dir = match env::var_os("ELAN_TEMP_DIR").and_then(utils::if_not_empty) {
+            Some(dir) => Some(try!(utils::to_absolute(PathBuf::from(dir)))),
+            None => None,
+        };
+        let tmp_dir = custom_tmp_dir.clone().unwrap_or(tmp_dir);
+
+        let hooks_dir = settings_path.with_file_name("hooks");
+        let settings_file = SettingsFile::with_env(settings_path, EnvSettings::from_env());
 
         // GPG key
         /*let gpg_key = ""; if let Some(path) = env::var_os("ELAN_GPG_KEY")
@@ -79,11 +270,143 @@ impl Cfg {
                                .ok()
                                .and_then(utils::if_not_empty);
 
+        // Keep verified downloads around for reuse by later installs
+        let keep_downloads = env::var("ELAN_KEEP_DOWNLOADS").ok().as_ref().map(|s| &**s) == Some("1");
+
+        // Let `stable` resolve to release candidates as well
+        let include_prereleases = env::var("ELAN_STABLE_PRERELEASES").ok().as_ref().map(|s| &**s) == Some("1");
+
+        let profile_env = env::var("ELAN_PROFILE").ok().and_then(utils::if_not_empty);
+
+        // Prune the download cache to this many megabytes after installs
+        let download_dir_limit = env::var("ELAN_DOWNLOAD_CACHE_LIMIT").ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .map(|mb| mb * 1024 * 1024);
+
+        // Opt out of background update checks
+        let update_checks = env::var("ELAN_NO_UPDATE_CHECK").ok().as_ref().map(|s| &**s) != Some("1");
+
+        let loader_path_vars = utils::loader_path_vars(env::var("ELAN_LOADER_PATH_VAR").ok().as_ref().map(|s| &**s));
+
+        // Turn a missing elan home during command setup into a hard error
+        let strict_home = env::var("ELAN_STRICT_HOME").ok().as_ref().map(|s| &**s) == Some("1");
+
+        // Refuse to run toolchains that require a newer elan
+        let strict_elan_version = env::var("ELAN_STRICT_VERSION").ok().as_ref().map(|s| &**s) == Some("1");
+
+        // Cap the number of installed toolchains on shared machines
+        let max_toolchains = env::var("ELAN_MAX_TOOLCHAINS").ok()
+            .and_then(|s| s.parse::<usize>().ok());
+        let quota_policy = match env::var("ELAN_TOOLCHAIN_QUOTA_POLICY").ok().and_then(utils::if_not_empty) {
+            Some(policy) => try!(policy.parse()),
+            None => QuotaPolicy::Error,
+        };
+
+        // Never wait for an answer, e.g. in CI
+        let non_interactive = env::var("ELAN_NON_INTERACTIVE").ok().and_then(utils::if_not_empty).map(|policy| {
+            NonInteractivePolicy::from_str(&policy).unwrap_or_else(|| {
+                notify_handler(Notification::InvalidNonInteractivePolicy(&policy));
+                NonInteractivePolicy::Deny
+            })
+        });
+
+        // Download from mirrors, e.g. behind a firewall. The official server
+        // is only used if it is listed as well.
+        let dist_servers = env::var("ELAN_DIST_SERVERS").ok()
+            .map(|s| s.split(',').map(|s| s.trim().to_owned()).filter(|s| !s.is_empty()).collect())
+            .unwrap_or_else(Vec::new);
+
+        // Some filesystems report less free space than they have
+        let disk_space_factor = if env::var("ELAN_SKIP_DISK_SPACE_CHECK").ok().as_ref().map(|s| &**s) == Some("1") {
+            None
+        } else {
+            Some(env::var("ELAN_DISK_SPACE_FACTOR").ok()
+                 .and_then(|s| s.parse().ok())
+                 .unwrap_or(download::DEFAULT_DISK_SPACE_FACTOR))
+        };
+
+        // Opt into patching installed releases with deltas
+        let delta_updates = env::var("ELAN_DELTA_UPDATES").ok().as_ref().map(|s| &**s) == Some("1");
+        let provenance = if env::var("ELAN_VERIFY_PROVENANCE").ok().as_ref().map(|s| &**s) == Some("1") {
+            Some(provenance::Policy {
+                builder: env::var("ELAN_PROVENANCE_BUILDER").ok().filter(|s| !s.is_empty())
+                    .unwrap_or_else(|| provenance::DEFAULT_BUILDER.to_owned()),
+                public_key: env::var_os("ELAN_PROVENANCE_KEY").filter(|s| !s.is_empty()).map(PathBuf::from),
+            })
+        } else {
+            None
+        };
+        let deterministic_extract = env::var("ELAN_DETERMINISTIC_EXTRACT").ok().as_ref().map(|s| &**s) == Some("1");
+        let check_case = cfg!(any(windows, target_os = "macos")) ||
+            env::var("ELAN_CHECK_CASE").ok().as_ref().map(|s| &**s) == Some("1");
+
+        // The first toolchain installed becomes the default
+        let default_on_first_install = env::var("ELAN_AUTO_DEFAULT").ok().as_ref().map(|s| &**s) != Some("0");
+
+        // Require project toolchains to be pinned by an up-to-date lockfile
+        let locked = env::var("ELAN_LOCKED").ok().as_ref().map(|s| &**s) == Some("1");
+
+        // Identify ourselves to servers, and authenticate to GitHub to raise
+        // its rate limits
+        utils::configure_requests(utils::RequestConfig {
+            user_agent: Some(format!("elan/{}", env!("CARGO_PKG_VERSION"))),
+            github_token: env::var("ELAN_GITHUB_TOKEN").ok().and_then(utils::if_not_empty),
+        });
+
+        // Give up on stalled connections instead of hanging, after the
+        // given number of seconds
+        let timeout = |var, default| env::var(var).ok()
+            .and_then(|s| s.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(default);
+        utils::configure_timeouts(timeout("ELAN_CONNECT_TIMEOUT", utils::DEFAULT_CONNECT_TIMEOUT),
+                                  timeout("ELAN_READ_TIMEOUT", utils::DEFAULT_READ_TIMEOUT));
+
+        // Skip comparing toolchain binaries against the host architecture
+        let check_arch = env::var("ELAN_NO_ARCH_CHECK").ok().as_ref().map(|s| &**s) != Some("1");
+
+        // Optionally also record all notifications in `log/elan.log`
+        let notify_handler: Arc<Fn(Notification)> =
+            if env::var("ELAN_LOG_FILE").ok().as_ref().map(|s| &**s) == Some("1") {
+                let log = LogFile::new(elan_dir.join("log"));
+                let inner = notify_handler;
+                Arc::new(move |n: Notification| {
+                    match n {
+                        // Way too noisy for a log
+                        Notification::Install(elan_dist::Notification::Utils(
+                            elan_utils::Notification::DownloadDataReceived(_))) => {}
+                        _ => log.log(n.level(), &utils::redact(&n.to_string())),
+                    }
+                    inner(n)
+                })
+            } else {
+                notify_handler
+            };
+
+        // Optionally collapse repeated notifications for logging frontends,
+        // see `set_notify_throttle`. Downloads are always passed on since
+        // progress bars track them.
+        let notify_throttle = Arc::new(Throttle::new(env::var("ELAN_NOTIFY_THROTTLE_MS").ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .map(Duration::from_millis)));
+        let notify_handler: Arc<Fn(Notification)> = {
+            let throttle = notify_throttle.clone();
+            let inner = notify_handler;
+            Arc::new(move |n: Notification| {
+                if n.is_download_lifecycle() || throttle.should_pass(&n.level(), &n.to_string()) {
+                    inner(n)
+                }
+            })
+        };
+
         let notify_clone = notify_handler.clone();
-        let temp_cfg = temp::Cfg::new(elan_dir.join("tmp"),
-                                      Box::new(move |n| {
-                                          (notify_clone)(n.into())
-                                      }));
+        let mut temp_cfg = temp::Cfg::new(tmp_dir,
+                                          Box::new(move |n| {
+                                              (notify_clone)(n.into())
+                                          }));
+        if custom_tmp_dir.is_some() {
+            temp_cfg = temp_cfg.configured_by("ELAN_TEMP_DIR");
+        }
 
         Ok(Cfg {
             elan_dir: elan_dir,
@@ -91,22 +414,332 @@ impl Cfg {
             toolchains_dir: toolchains_dir,
             update_hash_dir: update_hash_dir,
             download_dir: download_dir,
+            hooks_dir: hooks_dir,
+            keep_downloads: keep_downloads,
+            include_prereleases: include_prereleases,
+            profile_override: None,
+            profile_env: profile_env,
+            download_dir_limit: download_dir_limit,
+            update_checks: update_checks,
             temp_cfg: temp_cfg,
             //gpg_key: gpg_key,
             notify_handler: notify_handler,
+            confirm_handler: Arc::new(|_| true),
+            non_interactive: non_interactive,
+            walk_cache: WalkCache::new(),
             env_override: env_override,
+            strict_home: strict_home,
+            loader_path_vars: loader_path_vars,
+            strict_elan_version: strict_elan_version,
+            check_arch: check_arch,
+            notify_throttle: notify_throttle,
+            locked: locked,
+            cancel: Arc::new(AtomicBool::new(false)),
+            max_toolchains: max_toolchains,
+            quota_policy: quota_policy,
+            dist_servers: dist_servers,
+            disk_space_factor: disk_space_factor,
+            delta_updates: delta_updates,
+            provenance: provenance,
+            deterministic_extract: deterministic_extract,
+            check_case: check_case,
+            default_on_first_install: default_on_first_install,
         })
     }
 
+    /// Drops notifications repeated within `interval` before they reach
+    /// the frontend, or stops doing so. Defaults to `ELAN_NOTIFY_THROTTLE_MS`.
+    /// Errors and downloads always pass.
+    pub fn set_notify_throttle(&self, interval: Option<Duration>) {
+        self.notify_throttle.set_interval(interval);
+    }
+
+    /// Asks the confirmation handler whether to go ahead with `action`, or
+    /// answers according to the non-interactive policy
+    pub fn confirm(&self, action: &str) -> Result<()> {
+        match tty::confirm(self.non_interactive, &*self.confirm_handler, action) {
+            Confirmation::Approved => Ok(()),
+            Confirmation::ApprovedNonInteractively => {
+                (self.notify_handler)(Notification::ConfirmedNonInteractively(action));
+                Ok(())
+            }
+            Confirmation::Required => {
+                Err(ErrorKind::ConfirmationRequired(action.to_owned()).into())
+            }
+            Confirmation::Denied => Err(ErrorKind::OperationCancelled(action.to_owned()).into()),
+        }
+    }
+
+    /// All directory overrides sorted by path, with their toolchain and
+    /// whether the directory still exists. The toolchain need not be
+    /// installed.
+    pub fn list_overrides(&self) -> Result<Vec<(PathBuf, String, bool)>> {
+        let overrides = try!(self.settings_file.with(|s| Ok(s.overrides.clone())));
+        Ok(utils::existing_dirs(overrides))
+    }
+
+    /// The toolchains referenced by the default, overrides of existing
+    /// directories and the project at `path` and its `elan.lock` that
+    /// aren't installed, each with where it is referenced. Never installs
+    /// or downloads anything.
+    pub fn find_missing_toolchains(&self, path: &Path) -> Result<Vec<(String, Vec<String>)>> {
+        let references = try!(self.toolchain_references(path));
+
+        // Broken links and interrupted installs count as missing as well
+        let is_installed = |name: &str| {
+            self.get_toolchain(name, false)
+                .map(|t| utils::is_directory(t.path()) && !t.is_incomplete())
+                .unwrap_or(false)
+        };
+        Ok(utils::missing_toolchains(&references, &is_installed))
+    }
+
+    // The toolchains the default, directory overrides and the project
+    // containing `path` refer to, with where they are referred to
+    fn toolchain_references(&self, path: &Path) -> Result<Vec<(String, String)>> {
+        let mut references = vec![];
+        if let Some(name) = try!(self.get_default()) {
+            references.push((name, "the default toolchain".to_owned()));
+        }
+        for (dir, name, exists) in try!(self.list_overrides()) {
+            if exists {
+                references.push((name, format!("the override for '{}'", dir.display())));
+            }
+        }
+        let project = try!(self.settings_file.with(|s| self.find_override_from_dir_walk(path, s)));
+        match project {
+            Some((ref name, OverrideReason::ToolchainFile(ref file))) if is_local_toolchain_path(name) => {
+                // Linked when used, so only the directory has to exist
+                if self.local_toolchain(file.parent().unwrap(), name).is_err() {
+                    references.push((name.clone(), format!("'{}'", file.display())));
+                }
+            }
+            Some((name, OverrideReason::ToolchainFile(file))) |
+            Some((name, OverrideReason::LeanpkgFile(file))) => {
+                let dir = file.parent().unwrap();
+                match try!(Lockfile::load(dir)) {
+                    Some(ref lock) if lock.requested == name => {
+                        references.push((lock.resolved.clone(), format!("'{}'", dir.join(lockfile::LOCKFILE_NAME).display())));
+                    }
+                    Some(lock) => {
+                        references.push((lock.resolved, format!("'{}'", dir.join(lockfile::LOCKFILE_NAME).display())));
+                        references.push((name, format!("'{}'", file.display())));
+                    }
+                    None => references.push((name, format!("'{}'", file.display()))),
+                }
+            }
+            _ => {}
+        }
+        Ok(references)
+    }
+
+    /// Removes all overrides for directories that no longer exist,
+    /// returning their paths
+    pub fn prune_overrides(&self) -> Result<Vec<String>> {
+        let stale: Vec<String> = try!(self.list_overrides()).into_iter()
+            .filter(|&(_, _, exists)| !exists)
+            .map(|(path, _, _)| path.to_string_lossy().into_owned())
+            .collect();
+        if stale.is_empty() {
+            return Ok(stale);
+        }
+
+        try!(self.confirm(&format!("remove overrides for {} nonexistent directories", stale.len())));
+        try!(self.settings_file.with_mut(|s| {
+            for path in &stale {
+                s.overrides.remove(path);
+            }
+            Ok(())
+        }));
+        Ok(stale)
+    }
+
+    /// Copies the elan home to `dest`, pointing overrides for directories
+    /// and links and toolchain names of paths inside it at the copy. Unless
+    /// `keep_source`, the old home is removed afterwards. Nothing changes
+    /// unless every toolchain that resolves now still does in the copy.
+    pub fn move_elan_home(&self, dest: &Path, keep_source: bool) -> Result<PathBuf> {
+        let dest = try!(utils::to_absolute(dest));
+        let (old, notify) = (&self.elan_dir, &|n: ::elan_utils::Notification| (self.notify_handler)(n.into()));
+        if !keep_source {
+            try!(self.confirm(&format!("move '{}' to '{}'", old.display(), dest.display())));
+        }
+        let settings = try!(self.settings_file.with(|s| Ok(s.clone())));
+        let mut names = try!(self.list_toolchains());
+        names.extend(settings.default_toolchain.iter().cloned());
+        names.extend(settings.overrides.values().cloned());
+        names.extend(settings.aliases.keys().cloned());
+        names.sort();
+        names.dedup();
+        let resolved: Vec<String> = names.into_iter()
+            .filter(|name| toolchain::resolve_toolchain(&self.toolchains_dir, &settings, name)
+                    .map(|(_, path)| toolchain::assert_is_toolchain_dir(&path).is_ok())
+                    .unwrap_or(false))
+            .collect();
+
+        // Settings kept outside the home, e.g. in the XDG layout, are
+        // shared with the old home while it exists
+        let settings_path = self.settings_file.path();
+        let new_settings_file = match utils::relocated_path(settings_path, old, &dest) {
+            Some(path) => Some(SettingsFile::new(path)),
+            None if !keep_source => Some(SettingsFile::new(settings_path.to_owned())),
+            None => None,
+        };
+        let mut new_settings = settings.clone();
+        if new_settings_file.is_some() {
+            new_settings.relocate(old, &dest);
+        }
+        let toolchains_dir = utils::relocated_path(&self.toolchains_dir, old, &dest)
+            .unwrap_or_else(|| self.toolchains_dir.clone());
+
+        try!(utils::relocate_dir(old, &dest, notify));
+        let result = (|| -> Result<()> {
+            let broken: Vec<String> = resolved.into_iter()
+                .filter(|name| toolchain::resolve_toolchain(&toolchains_dir, &new_settings, name)
+                        .map(|(_, path)| toolchain::assert_is_toolchain_dir(&path).is_err())
+                        .unwrap_or(true))
+                .collect();
+            if !broken.is_empty() {
+                return Err(ErrorKind::RelocationBreaksToolchains(broken).into());
+            }
+            match new_settings_file {
+                Some(ref file) if utils::is_file(file.path()) => {
+                    file.with_mut(|s| {
+                        s.relocate(old, &dest);
+                        Ok(())
+                    })
+                }
+                _ => Ok(()),
+            }
+        })();
+        if let Err(e) = result {
+            let _ = utils::remove_dir("elan home", &dest, notify);
+            return Err(e);
+        }
+
+        if !keep_source {
+            try!(utils::remove_dir("elan home", old, notify));
+        }
+        Ok(dest)
+    }
+
     pub fn set_default(&self, toolchain: &str) -> Result<()> {
         try!(self.settings_file.with_mut(|s| {
             s.default_toolchain = Some(toolchain.to_owned());
             Ok(())
         }));
         (self.notify_handler)(Notification::SetDefaultToolchain(toolchain));
+        self.warn_if_ephemeral();
+        if self.settings_file.env.default_toolchain.is_some() {
+            (self.notify_handler)(Notification::SettingShadowedByEnv("ELAN_DEFAULT_TOOLCHAIN"));
+        }
         Ok(())
     }
 
+    /// Sets the default toolchain only if none is set yet, returning
+    /// whether it did. The check happens under the settings lock, so of
+    /// several elan processes initializing the default at once only the
+    /// first one wins.
+    pub fn set_default_if_unset(&self, toolchain: &str) -> Result<bool> {
+        if !try!(self.get_toolchain(toolchain, false)).exists() {
+            return Err(ErrorKind::ToolchainNotInstalled(toolchain.to_owned()).into());
+        }
+        let set = try!(self.settings_file.with_mut(|s| {
+            if s.default_toolchain.is_some() {
+                return Ok(false);
+            }
+            s.default_toolchain = Some(toolchain.to_owned());
+            Ok(true)
+        }));
+        if set {
+            (self.notify_handler)(Notification::SetDefaultToolchain(toolchain));
+            self.warn_if_ephemeral();
+        }
+        Ok(set)
+    }
+
+    /// Makes `toolchain`, which was just installed, the default if asked
+    /// to `set_default`. Otherwise it only becomes the default on the first
+    /// install, while no default is set yet, unless
+    /// `default_on_first_install` is disabled. Returns whether it did.
+    pub fn set_default_after_install(&self, toolchain: &str, set_default: bool) -> Result<bool> {
+        let set = try!(self.settings_file.with_mut(|s| {
+            if !set_default && !(self.default_on_first_install && s.default_toolchain.is_none()) {
+                return Ok(false);
+            }
+            s.default_toolchain = Some(toolchain.to_owned());
+            Ok(true)
+        }));
+        if set {
+            (self.notify_handler)(Notification::SetDefaultToolchain(toolchain));
+            self.warn_if_ephemeral();
+            if set_default && self.settings_file.env.default_toolchain.is_some() {
+                (self.notify_handler)(Notification::SettingShadowedByEnv("ELAN_DEFAULT_TOOLCHAIN"));
+            }
+        }
+        Ok(set)
+    }
+
+    pub(crate) fn warn_if_ephemeral(&self) {
+        if self.settings_file.env.ephemeral {
+            (self.notify_handler)(Notification::SettingsNotPersisted);
+        }
+    }
+
+    pub fn add_alias(&self, alias: &str, toolchain: &str) -> Result<()> {
+        if self.alias_shadows_toolchain(alias)? {
+            (self.notify_handler)(Notification::AliasShadowsToolchain(alias));
+        }
+        self.settings_file.with_mut(|s| {
+            s.aliases.insert(alias.to_owned(), toolchain.to_owned());
+            Ok(())
+        })?;
+        (self.notify_handler)(Notification::SetAlias(alias, toolchain));
+        Ok(())
+    }
+
+    pub fn remove_alias(&self, alias: &str) -> Result<bool> {
+        let removed = self.settings_file.with_mut(|s| Ok(s.aliases.remove(alias).is_some()))?;
+        if removed {
+            (self.notify_handler)(Notification::RemovedAlias(alias));
+        }
+        Ok(removed)
+    }
+
+    pub fn list_aliases(&self) -> Result<Vec<(String, String)>> {
+        self.settings_file.with(|s| {
+            Ok(s.aliases.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+        })
+    }
+
+    // An alias shadows a toolchain if it is already installed under that name
+    // or if it would otherwise be resolved from a release channel.
+    fn alias_shadows_toolchain(&self, alias: &str) -> Result<bool> {
+        if self.list_toolchains()?.iter().any(|t| t == alias) {
+            return Ok(true);
+        }
+        Ok(match ToolchainDesc::from_str(alias) {
+            Ok(desc) => {
+                let looks_like_version = desc.channel.starts_with(|c: char| c.is_numeric()) ||
+                    (desc.channel.starts_with('v') &&
+                     desc.channel[1..].starts_with(|c: char| c.is_numeric()));
+                desc.origin.is_some() || desc.date.is_some() || desc.is_tracking() ||
+                    looks_like_version
+            }
+            Err(_) => false,
+        })
+    }
+
+    /// The directory a linked toolchain points to, or `None` if the
+    /// toolchain is not linked
+    pub fn toolchain_link_target(&self, name: &str) -> Result<Option<LinkTarget>> {
+        let toolchain = try!(self.get_toolchain(name, false));
+        Ok(utils::link_target(toolchain.path()).map(|path| LinkTarget {
+            broken: !utils::is_directory(&path),
+            path: path,
+        }))
+    }
+
     pub fn get_toolchain(&self, name: &str, create_parent: bool) -> Result<Toolchain> {
         if create_parent {
             try!(utils::ensure_dir_exists("toolchains",
@@ -117,6 +750,65 @@ impl Cfg {
         Toolchain::from(self, name)
     }
 
+    /// Like `get_toolchain`, for selecting or running a toolchain that is
+    /// meant to be installed already: a name without an owner like
+    /// `lean4:v4` that isn't installed itself refers to the one installed
+    /// toolchain it is the beginning of.
+    pub fn select_toolchain(&self, name: &str) -> Result<Toolchain> {
+        let toolchain = try!(self.get_toolchain(name, false));
+        // Aliases and release pages already resolved to a full name
+        if toolchain.exists() || toolchain.name() != name {
+            return Ok(toolchain);
+        }
+        let mut candidates: Vec<String> = try!(self.list_toolchains()).into_iter()
+            .filter(|t| utils::toolchain_prefix_matches(t, name))
+            .collect();
+        match candidates.len() {
+            0 => Ok(toolchain),
+            1 => self.get_toolchain(&candidates.pop().unwrap(), false),
+            _ => Err(ErrorKind::AmbiguousToolchain {
+                name: name.to_owned(),
+                candidates: candidates,
+            }.into()),
+        }
+    }
+
+    /// Installs or updates the toolchain `name` from the dist server, as
+    /// `elan install` does. `name` is resolved like in `get_toolchain`,
+    /// through aliases, release pages and installed toolchains it is short
+    /// for. Custom toolchains can't be installed this way.
+    pub fn resolve_and_install(&self, name: &str, force: bool) -> Result<(Toolchain, UpdateStatus)> {
+        let toolchain = try!(self.get_toolchain(name, true));
+        let status = try!(self.install_resolved(&toolchain, force));
+        Ok((toolchain, status))
+    }
+
+    /// Like `resolve_and_install`, for a toolchain already resolved with
+    /// `get_toolchain`
+    pub fn install_resolved(&self, toolchain: &Toolchain, force: bool) -> Result<UpdateStatus> {
+        if toolchain.exists() && toolchain.is_custom() {
+            let kind = elan_dist::ErrorKind::CustomToolchainName(toolchain.name().to_owned());
+            return Err(elan_dist::Error::from(kind).into());
+        }
+        try!(dist::dist_toolchain_name(toolchain.name()));
+        toolchain.install_from_dist_and_set_default(force, false)
+    }
+
+    /// Downloads the toolchain `name` into a bundle in `dir`, which
+    /// `import_toolchain` installs on machines without network access
+    pub fn export_manifest_bundle(&self, name: &str, dir: &Path) -> Result<Bundle> {
+        try!(self.get_toolchain(name, false)).export_bundle(dir)
+    }
+
+    /// Installs the toolchain bundled in `dir` by `export_manifest_bundle`,
+    /// returning its name
+    pub fn import_toolchain(&self, dir: &Path) -> Result<(String, UpdateStatus)> {
+        let name = try!(Bundle::load(dir)).name;
+        let toolchain = try!(self.get_toolchain(&name, true));
+        let status = try!(toolchain.install_from_bundle(dir));
+        Ok((name, status))
+    }
+
     pub fn verify_toolchain(&self, name: &str) -> Result<Toolchain> {
         let toolchain = try!(self.get_toolchain(name, false));
         try!(toolchain.verify());
@@ -155,7 +847,15 @@ impl Cfg {
         }
     }
 
-    pub fn find_override(&self, path: &Path) -> Result<Option<(Toolchain, OverrideReason)>> {
+    /// Determines the name of the override toolchain for `path`, if any,
+    /// without checking whether it is installed
+    pub fn find_override_name(&self, path: &Path) -> Result<Option<(String, OverrideReason)>> {
+        Ok(try!(self.find_override_entry(path)).map(|(name, reason, _)| (name, reason)))
+    }
+
+    // Like `find_override_name`, also returning the directory a local
+    // toolchain named by a `lean-toolchain` file is to be linked from
+    fn find_override_entry(&self, path: &Path) -> Result<Option<(String, OverrideReason, Option<PathBuf>)>> {
         let mut override_ = None;
 
         // First check ELAN_TOOLCHAIN
@@ -174,7 +874,128 @@ impl Cfg {
             })?;
         }
 
-        if let Some((name, reason)) = override_ {
+        // `lean-toolchain` files may name a local toolchain directory
+        let mut local_dir = None;
+        if let Some((name, OverrideReason::ToolchainFile(file))) = override_ {
+            let name = if is_local_toolchain_path(&name) {
+                let base = file.parent().unwrap();
+                let (local_name, dir) = try!(self.local_toolchain(base, &name)
+                     .chain_err(|| ErrorKind::InvalidLocalToolchain(file.clone(), name.clone())));
+                local_dir = Some(dir);
+                local_name
+            } else {
+                name
+            };
+            override_ = Some((name, OverrideReason::ToolchainFile(file)));
+        }
+
+        // Project toolchains may be pinned by an `elan.lock` next to the
+        // file naming them
+        let override_ = match override_ {
+            Some((name, reason)) => {
+                let locked = match reason {
+                    OverrideReason::ToolchainFile(ref file) |
+                    OverrideReason::LeanpkgFile(ref file) => try!(self.locked_toolchain(file.parent().unwrap(), name.clone())),
+                    _ => name.clone(),
+                };
+                let local_dir = if locked == name { local_dir } else { None };
+                Some((locked, reason, local_dir))
+            }
+            None => None,
+        };
+
+        Ok(override_)
+    }
+
+    // The toolchain pinned for `requested` by the lockfile in `dir`, if
+    // any. In locked mode, tracking toolchains must be pinned.
+    fn locked_toolchain(&self, dir: &Path, requested: String) -> Result<String> {
+        match try!(Lockfile::load(dir)) {
+            Some(ref lock) if lock.requested == requested => {
+                (self.notify_handler)(Notification::UsingLockedToolchain(&requested, &lock.resolved));
+                Ok(lock.resolved.clone())
+            }
+            Some(lock) => {
+                if self.locked {
+                    Err(ErrorKind::LockfileMismatch(dir.join(lockfile::LOCKFILE_NAME), lock.requested, requested).into())
+                } else {
+                    Ok(requested)
+                }
+            }
+            None => {
+                let tracking = ToolchainDesc::from_str(&requested).map(|d| d.is_tracking()).unwrap_or(false);
+                if self.locked && tracking {
+                    Err(ErrorKind::MissingLockfile(dir.to_owned()).into())
+                } else {
+                    Ok(requested)
+                }
+            }
+        }
+    }
+
+    /// Resolves the toolchain named by the project file for `path` and
+    /// writes the release it currently stands for to `elan.lock` next to
+    /// it, unless `only_existing` and there is no lockfile yet. In locked
+    /// mode, the lockfile is only checked and never changed.
+    pub fn update_lockfile(&self, path: &Path, only_existing: bool) -> Result<Option<(PathBuf, Lockfile)>> {
+        let project = try!(self.settings_file.with(|s| self.find_override_from_dir_walk(path, s)));
+        let (requested, file) = match project {
+            Some((name, OverrideReason::ToolchainFile(file))) |
+            Some((name, OverrideReason::LeanpkgFile(file))) => (name, file),
+            _ => {
+                if only_existing {
+                    return Ok(None);
+                }
+                return Err(ErrorKind::NoProjectToolchain(path.to_owned()).into());
+            }
+        };
+        let dir = file.parent().unwrap();
+        let path = dir.join(lockfile::LOCKFILE_NAME);
+        let existing = try!(Lockfile::load(dir));
+        if existing.is_none() && (only_existing || self.locked) {
+            if self.locked {
+                return Err(ErrorKind::MissingLockfile(dir.to_owned()).into());
+            }
+            return Ok(None);
+        }
+
+        let desc = try!(ToolchainDesc::from_str(&requested))
+            .with_default_origin(try!(self.default_origin()).as_ref().map(|s| &**s));
+        let lock = try!(Lockfile::resolve(&requested, &desc, self.include_prereleases,
+                                          &|n| (self.notify_handler)(n.into())));
+
+        if self.locked {
+            let existing = existing.unwrap();
+            if existing.requested != lock.requested {
+                return Err(ErrorKind::LockfileMismatch(path, existing.requested, lock.requested).into());
+            }
+            if existing.resolved != lock.resolved {
+                return Err(ErrorKind::LockfileOutdated(path, existing.resolved, lock.resolved).into());
+            }
+        } else if existing.as_ref() != Some(&lock) {
+            try!(lock.save(dir));
+            (self.notify_handler)(Notification::WroteLockfile(&path, &lock.resolved));
+        }
+        Ok(Some((path, lock)))
+    }
+
+    /// The name of the toolchain that applies to `cwd`, exactly as given by
+    /// `ELAN_TOOLCHAIN`, an override, a toolchain file or the default, for
+    /// display in shell prompts. Unlike `find_override_name`, this neither
+    /// resolves aliases and local toolchain paths nor installs anything.
+    pub fn effective_toolchain_name(&self, cwd: &Path) -> Result<Option<String>> {
+        let notify = self.notify_handler.as_ref();
+        self.settings_file.with(|s| {
+            Ok(try!(toolchain_file::effective_toolchain_name(self.env_override.as_ref().map(|s| &**s),
+                                                             &self.walk_cache, cwd,
+                                                             &|d| s.dir_override(d, notify),
+                                                             s.default_toolchain.as_ref().map(|s| &**s),
+                                                             &|n| notify(n.into()))))
+        })
+    }
+
+    pub fn find_override(&self, path: &Path) -> Result<Option<(Toolchain, OverrideReason)>> {
+        if let Some((name, reason, local_dir)) = self.find_override_entry(path)? {
             // This is hackishly using the error chain to provide a bit of
             // extra context about what went wrong. The CLI will display it
             // on a line after the proximate error.
@@ -195,13 +1016,17 @@ impl Cfg {
             };
 
             match self.get_toolchain(&name, false) {
-                Ok(toolchain) => {
-                    if toolchain.exists() {
-                        Ok(Some((toolchain, reason)))
-                    } else {
-                        try!(toolchain.install_from_dist(false));
-                        Ok(Some((toolchain, reason)))
+                Ok(mut toolchain) => {
+                    if let OverrideReason::ToolchainFile(ref file) = reason {
+                        toolchain.set_project_dir(file.parent().map(|d| d.to_owned()));
+                    }
+                    if !toolchain.exists() {
+                        match local_dir {
+                            Some(ref dir) => try!(toolchain.install_from_dir(dir, true)),
+                            None => { try!(toolchain.install_from_dist(false)); }
+                        }
                     }
+                    Ok(Some((toolchain, reason)))
                 }
                 Err(e) => {
                     Err(e)
@@ -214,50 +1039,86 @@ impl Cfg {
         }
     }
 
-    fn find_override_from_dir_walk(&self, dir: &Path, settings: &Settings)
-                                   -> Result<Option<(String, OverrideReason)>>
-    {
-        let notify = self.notify_handler.as_ref();
-        let dir = utils::canonicalize_path(dir, &|n| notify(n.into()));
-        let mut dir = Some(&*dir);
-
-        while let Some(d) = dir {
-            // First check the override database
-            if let Some(name) = settings.dir_override(d, notify) {
-                let reason = OverrideReason::OverrideDB(d.to_owned());
-                return Ok(Some((name, reason)));
-            }
-
-            // Then look for 'lean-toolchain'
-            let toolchain_file = d.join("lean-toolchain");
-            if let Ok(s) = utils::read_file("toolchain file", &toolchain_file) {
-                if let Some(s) = s.lines().next() {
-                    let toolchain_name = s.trim();
-                    let reason = OverrideReason::ToolchainFile(toolchain_file);
-                    return Ok(Some((toolchain_name.to_string(), reason)));
-                }
-            }
+    /// Installs the components the project containing `dir` requires into
+    /// the installed toolchain its toolchain file selects. Done by `elan
+    /// install` and `elan update` only, never while resolving overrides,
+    /// as that would download on every run.
+    pub fn ensure_project_components_for_dir(&self, dir: &Path) -> Result<()> {
+        let (name, file) = match try!(self.find_override_name(dir)) {
+            Some((name, OverrideReason::ToolchainFile(file))) |
+            Some((name, OverrideReason::LeanpkgFile(file))) => (name, file),
+            _ => return Ok(()),
+        };
+        let toolchain = try!(self.get_toolchain(&name, false));
+        if !toolchain.exists() {
+            return Ok(());
+        }
+        self.ensure_project_components(&toolchain, file.parent().unwrap())
+    }
 
-            // Then look for 'leanpkg.toml'
-            let leanpkg_file = d.join("leanpkg.toml");
-            if let Ok(content) = utils::read_file("leanpkg.toml", &leanpkg_file) {
-                let value = content.parse::<toml::Value>()
-                    .map_err(|error| ErrorKind::InvalidLeanpkgFile(leanpkg_file.clone(), error))?;
-                match value.get("package").and_then(|package| package.get("lean_version")) {
-                    None => {}
-                    Some(toml::Value::String(s)) => {
-                        return Ok(Some((s.to_string(), OverrideReason::LeanpkgFile(leanpkg_file))))
-                    }
-                    Some(a) => {
-                        return Err(ErrorKind::InvalidLeanVersion(leanpkg_file, a.type_str()).into())
-                    }
-                }
+    /// Installs the components the `lean-toolchain.toml` in `project_dir`
+    /// requires into `toolchain`, if any are missing. Unknown components
+    /// are ignored with a warning.
+    pub fn ensure_project_components(&self, toolchain: &Toolchain, project_dir: &Path) -> Result<()> {
+        let config = match try!(ToolchainConfig::load(project_dir)) {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+        let config_path = project_dir.join(toolchain_file::TOOLCHAIN_CONFIG_NAME);
+        let known: Vec<String> = config.components.into_iter().filter(|c| {
+            let known = c == "lean" || OPTIONAL_COMPONENTS.iter().any(|&(o, _)| o == c);
+            if !known {
+                (self.notify_handler)(Notification::UnknownComponent(c, &config_path));
             }
+            known
+        }).collect();
+        try!(toolchain.add_components(&known));
+        Ok(())
+    }
 
-            dir = d.parent();
+    /// The directory of the project containing `dir`, if its toolchain
+    /// file selects `toolchain`. Used for toolchains named explicitly, which
+    /// still pick up the project's `LEAN_PATH` when they match.
+    pub fn project_dir_for(&self, toolchain: &Toolchain, dir: &Path) -> Option<PathBuf> {
+        let (name, file) = match self.find_override_name(dir) {
+            Ok(Some((name, OverrideReason::ToolchainFile(file)))) => (name, file),
+            _ => return None,
+        };
+        match self.get_toolchain(&name, false) {
+            Ok(ref t) if t.path() == toolchain.path() => file.parent().map(|d| d.to_owned()),
+            _ => None,
         }
+    }
+
+    fn find_override_from_dir_walk(&self, dir: &Path, settings: &Settings)
+                                   -> Result<Option<(String, OverrideReason)>>
+    {
+        let notify = self.notify_handler.as_ref();
+        let found = try!(toolchain_file::find_toolchain_name(&self.walk_cache, dir,
+                                                             &|d| settings.dir_override(d, notify),
+                                                             &|n| notify(n.into())));
+        Ok(found.map(|(name, source)| {
+            let reason = match source {
+                ToolchainNameSource::Override(d) => OverrideReason::OverrideDB(d),
+                ToolchainNameSource::ToolchainFile(file) => OverrideReason::ToolchainFile(file),
+                ToolchainNameSource::LeanpkgFile(file) => OverrideReason::LeanpkgFile(file),
+            };
+            (name, reason)
+        }))
+    }
 
-        Ok(None)
+    // The name the toolchain directory referred to by a `lean-toolchain`
+    // file, relative to the file itself, is linked under by `find_override`,
+    // derived from its location so it can be used like any other
+    // toolchain. Also returns the resolved directory.
+    fn local_toolchain(&self, base: &Path, target: &str) -> Result<(String, PathBuf)> {
+        let target = try!(base.join(target).canonicalize()
+                          .chain_err(|| ErrorKind::NotADirectory(base.join(target))));
+        let dir_name = target.file_name().map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "root".to_owned());
+        let hash = format!("{:x}", Sha256::digest(target.to_string_lossy().as_bytes()));
+        let name = format!("local-{}-{}", dir_name, &hash[..8]);
+        Ok((name, target))
     }
 
     pub fn find_override_toolchain_or_default
@@ -282,15 +1143,48 @@ impl Cfg {
         fn insane(s: String) -> String {
             s.replace("---", ":").replace("--", "/")
         }
+        // Directories of `path`, except for staging areas of in-progress
+        // installs
+        let subdirs = |path: &Path| -> Result<Vec<(String, PathBuf)>> {
+            let (entries, files) = try!(utils::toolchain_dir_entries(path));
+            for file in &files {
+                (self.notify_handler)(Notification::SkippingNonDirectoryToolchain(file));
+            }
+            Ok(entries)
+        };
+        // Toolchains are links or contain `bin`. Owner directories of the
+        // nested layout are recognized by the toolchains two levels below
+        // them, so that broken flat toolchains are still listed.
+        let is_toolchain = |path: &Path| {
+            fs::symlink_metadata(path).map(|m| m.file_type().is_symlink()).unwrap_or(false) ||
+                utils::path_exists(InstallPrefix::from(path.to_owned()).bin_dir())
+        };
+        let is_nested_owner = |path: &Path| {
+            !is_toolchain(path) && utils::read_dir("toolchains", path).map(|repos| {
+                repos.filter_map(io::Result::ok).any(|repo| {
+                    utils::read_dir("toolchains", &repo.path())
+                        .map(|mut tcs| tcs.any(|tc| tc.map(|tc| is_toolchain(&tc.path())).unwrap_or(false)))
+                        .unwrap_or(false)
+                })
+            }).unwrap_or(false)
+        };
+
         if utils::is_directory(&self.toolchains_dir) {
-            let mut toolchains: Vec<_> = try!(utils::read_dir("toolchains", &self.toolchains_dir))
-                                         .filter_map(io::Result::ok)
-                                         .filter(|e| e.file_type().map(|f| !f.is_file()).unwrap_or(false))
-                                         .filter_map(|e| e.file_name().into_string().ok())
-                                         .map(insane)
-                                         .collect();
+            let mut toolchains = vec![];
+            for (name, path) in try!(subdirs(&self.toolchains_dir)) {
+                if !is_nested_owner(&path) {
+                    toolchains.push(insane(name));
+                    continue;
+                }
+                for (repo, path) in try!(subdirs(&path)) {
+                    for (channel, _) in try!(subdirs(&path)) {
+                        toolchains.push(format!("{}/{}:{}", name, repo, insane(channel)));
+                    }
+                }
+            }
 
             utils::toolchain_sort(&mut toolchains);
+            toolchains.dedup();
 
             Ok(toolchains)
         } else {
@@ -298,6 +1192,114 @@ impl Cfg {
         }
     }
 
+    pub fn toolchain_layout(&self) -> Result<ToolchainLayout> {
+        self.settings_file.with(|s| Ok(s.toolchain_layout))
+    }
+
+    /// Switches to `layout` and moves the installed toolchains accordingly.
+    /// Toolchains that cannot be moved keep working from where they are.
+    pub fn set_toolchain_layout(&self, layout: ToolchainLayout) -> Result<()> {
+        let toolchains = try!(self.list_toolchains());
+        try!(self.settings_file.with_mut(|s| {
+            s.toolchain_layout = layout;
+            Ok(())
+        }));
+        for name in toolchains {
+            let flat = self.toolchains_dir.join(toolchain::sanitize_name(&name));
+            let nested = match toolchain::nested_path(&self.toolchains_dir, &name) {
+                Some(nested) => nested,
+                None => continue,
+            };
+            let (from, to) = match layout {
+                ToolchainLayout::Nested => (flat, nested),
+                ToolchainLayout::Flat => (nested, flat),
+            };
+            if !utils::path_exists(&from) || utils::path_exists(&to) {
+                continue;
+            }
+            let moved = utils::ensure_dir_exists("toolchains", to.parent().unwrap(),
+                                                 &|n| (self.notify_handler)(n.into()))
+                .and_then(|_| utils::rename_dir("toolchain directory", &from, &to));
+            match moved {
+                Ok(()) => {
+                    (self.notify_handler)(Notification::MovedToolchain(&name, &to));
+                    // Drop owner directories that are now empty
+                    utils::remove_empty_parents(&from, &self.toolchains_dir);
+                }
+                Err(e) => (self.notify_handler)(Notification::NonFatalError(&e.into())),
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves tracking toolchains that are still stored in another layout,
+    /// e.g. after switching to `ToolchainLayout::Nested` when they couldn't
+    /// be moved, by reinstalling their current release where the layout
+    /// puts them. Custom toolchains can't be downloaded again and are
+    /// skipped. Safe to run again after an interruption.
+    pub fn reinstall_all_tracking(&self) -> Result<Vec<(String, UpdateStatus)>> {
+        let mut results = vec![];
+        let mut skipped = 0;
+        for name in try!(self.list_toolchains()) {
+            let toolchain = try!(self.get_toolchain(&name, false));
+            if toolchain.classify() == ToolchainKind::Custom {
+                skipped += 1;
+            } else if toolchain.is_tracking() {
+                let status = try!(toolchain.reinstall_into_layout());
+                results.push((name, status));
+            }
+        }
+        let reinstalled = results.iter().filter(|&&(_, status)| status != UpdateStatus::Unchanged).count();
+        (self.notify_handler)(Notification::ReinstalledToolchains(reinstalled, skipped));
+        Ok(results)
+    }
+
+    /// Protects the toolchain `name` from updates and removal unless they
+    /// are forced
+    pub fn mark_readonly(&self, name: &str) -> Result<()> {
+        try!(self.get_toolchain(name, false)).set_readonly(true)
+    }
+
+    pub fn clear_readonly(&self, name: &str) -> Result<()> {
+        try!(self.get_toolchain(name, false)).set_readonly(false)
+    }
+
+    /// The installed toolchains whose names match `pattern`, see
+    /// `utils::toolchain_name_matches`
+    pub fn search_toolchains(&self, pattern: &str, exact: bool) -> Result<Vec<ToolchainInfo>> {
+        let default = match self.find_default() {
+            Ok(Some(t)) => Some(t.name().to_owned()),
+            _ => None,
+        };
+        let mut result = vec![];
+        for name in try!(self.list_toolchains()) {
+            if !utils::toolchain_name_matches(&name, pattern, exact) {
+                continue;
+            }
+            let toolchain = try!(self.get_toolchain(&name, false));
+            result.push(ToolchainInfo {
+                kind: toolchain.classify(),
+                read_only: toolchain.is_readonly(),
+                version: toolchain.describe().version,
+                is_default: default.as_ref() == Some(&name),
+                name: name,
+            });
+        }
+        Ok(result)
+    }
+
+    /// The hooks to run after installing a toolchain from `channel`, in
+    /// order: the generic `hooks/post-install` followed by
+    /// `hooks/post-install-<channel>`, where the channel is e.g. `nightly`,
+    /// `stable` or a version tag. Hooks that don't exist are skipped.
+    pub fn post_install_hooks(&self, channel: &str) -> Vec<PathBuf> {
+        let names = vec!["post-install".to_owned(), format!("post-install-{}", channel)];
+        names.into_iter()
+            .map(|n| self.hooks_dir.join(format!("{}{}", n, env::consts::EXE_SUFFIX)))
+            .filter(|p| utils::is_file(p))
+            .collect()
+    }
+
     pub fn update_all_channels(&self, force_update: bool) -> Result<Vec<(String, Result<UpdateStatus>)>> {
         let toolchains = try!(self.list_toolchains());
 
@@ -305,9 +1307,10 @@ impl Cfg {
         let toolchains = toolchains.into_iter();
         let toolchains = toolchains.map(|n| (n.clone(), self.get_toolchain(&n, true)));
 
-        // Filter out toolchains that don't track a release channel
+        // Filter out toolchains that don't track a release channel, and
+        // read-only ones unless forced
         let toolchains = toolchains.filter(|&(_, ref t)| {
-            t.as_ref().map(|t| t.is_tracking()).unwrap_or(false)
+            t.as_ref().map(|t| t.is_tracking() && (force_update || !t.is_readonly())).unwrap_or(false)
         });
 
         // Update toolchains and collect the results
@@ -323,7 +1326,17 @@ impl Cfg {
             (n, t)
         });
 
-        Ok(toolchains.collect())
+        // Every toolchain is attempted; failures are only reported once the
+        // whole batch has run
+        let results: Vec<_> = toolchains.collect();
+        let failed = results.iter().filter(|&&(_, ref r)| r.is_err()).count();
+        let unchanged = results.iter()
+            .filter(|&&(_, ref r)| matches!(*r, Ok(UpdateStatus::Unchanged)))
+            .count();
+        let updated = results.len() - failed - unchanged;
+        (self.notify_handler)(Notification::BatchUpdateSummary(updated, unchanged, failed));
+
+        Ok(results)
     }
 
     pub fn toolchain_for_dir(&self, path: &Path) -> Result<(Toolchain, Option<OverrideReason>)> {
@@ -331,6 +1344,21 @@ impl Cfg {
             .and_then(|r| r.ok_or(ErrorKind::NoDefaultToolchain.into()))
     }
 
+    /// Resolves the toolchain for `path` with the usual precedence
+    /// (`ELAN_TOOLCHAIN`, directory overrides, toolchain files, default)
+    /// but, unlike `toolchain_for_dir`, never installs it. The
+    /// returned flag is true if the toolchain still needs to be installed,
+    /// which lets frontends prompt before doing so.
+    pub fn toolchain_for_dir_without_installing(&self, path: &Path) -> Result<(Toolchain, bool)> {
+        let name = match self.find_override_name(path)? {
+            Some((name, _)) => name,
+            None => self.get_default()?.ok_or(ErrorKind::NoDefaultToolchain)?,
+        };
+        let toolchain = self.get_toolchain(&name, false)?;
+        let needs_install = !toolchain.exists();
+        Ok((toolchain, needs_install))
+    }
+
     pub fn create_command_for_dir(&self, path: &Path, binary: &str) -> Result<Command> {
         let (ref toolchain, _) = try!(self.toolchain_for_dir(path));
 
@@ -339,22 +1367,414 @@ impl Cfg {
 
     pub fn create_command_for_toolchain(&self, toolchain: &str, install_if_missing: bool,
                                         binary: &str) -> Result<Command> {
-        let ref toolchain = try!(self.get_toolchain(toolchain, false));
+        let cwd = try!(utils::current_dir());
+        self.create_command_for_toolchain_in(toolchain, install_if_missing, binary, &cwd)
+    }
+
+    fn create_command_for_toolchain_in(&self, toolchain: &str, install_if_missing: bool,
+                                       binary: &str, dir: &Path) -> Result<Command> {
+        let mut toolchain = try!(self.select_toolchain(toolchain));
         if install_if_missing && !toolchain.exists() {
             try!(toolchain.install_from_dist(false));
         }
+        let project_dir = self.project_dir_for(&toolchain, dir);
+        toolchain.set_project_dir(project_dir);
 
         toolchain.create_command(binary)
     }
 
-    pub fn doc_path_for_dir(&self, path: &Path, relative: &str) -> Result<PathBuf> {
+    /// Runs `f` with `toolchain`, as used in `dir`, installing it first if
+    /// needed. A toolchain installed this way is removed again afterwards,
+    /// even if `f` or the install fails, and doesn't evict others.
+    pub fn with_temp_toolchain<F, R>(&self, toolchain: &str, dir: &Path, f: F) -> Result<R>
+        where F: FnOnce(&Toolchain) -> Result<R>
+    {
+        let mut toolchain = try!(self.get_toolchain(toolchain, false));
+        let project_dir = self.project_dir_for(&toolchain, dir);
+        toolchain.set_project_dir(project_dir);
+        let installed = toolchain.exists();
+        if !installed {
+            toolchain.set_temporary();
+        }
+        // Set up before installing, so that an interrupted install is
+        // removed as well
+        utils::with_cleanup(installed, || {
+            if !installed {
+                try!(toolchain.install_from_dist(false));
+            }
+            f(&toolchain)
+        }, || {
+            if !toolchain.exists() {
+                return;
+            }
+            if let Err(e) = toolchain.remove_unconfirmed() {
+                (self.notify_handler)(Notification::NonFatalError(&e));
+            }
+        })
+    }
+
+    /// Runs `binary` of `toolchain` in `dir` like `elan run`, but as a
+    /// child process that Ctrl-C is passed on to, and returns its exit code
+    pub fn run_in_dir<S: AsRef<OsStr>>(&self, toolchain: &str, install_if_missing: bool,
+                                       binary: &str, args: &[S], dir: &Path) -> Result<i32> {
+        let mut cmd = try!(self.create_command_for_toolchain_in(toolchain, install_if_missing, binary, dir));
+        cmd.args(args).current_dir(dir);
+        Ok(try!(utils::run_forwarding_signals(binary, &mut cmd)))
+    }
+
+    pub fn doc_path_for_dir(&self, path: &Path, component: &str, relative: &str) -> Result<PathBuf> {
         let (toolchain, _) = try!(self.toolchain_for_dir(path));
-        toolchain.doc_path(relative)
+        toolchain.doc_path(component, relative)
     }
 
-    pub fn open_docs_for_dir(&self, path: &Path, relative: &str) -> Result<()> {
+    pub fn open_docs_for_dir(&self, path: &Path, component: &str, relative: &str) -> Result<()> {
         let (toolchain, _) = try!(self.toolchain_for_dir(path));
-        toolchain.open_docs(relative)
+        toolchain.open_docs(component, relative)
+    }
+
+    /// Removes all artifacts kept by `ELAN_KEEP_DOWNLOADS`
+    pub fn clean_download_cache(&self) -> Result<()> {
+        if utils::is_directory(&self.download_dir) {
+            utils::remove_dir("downloads", &self.download_dir,
+                              &|n| (self.notify_handler)(n.into()))?;
+        }
+        Ok(())
+    }
+
+    pub fn installed_toolchain_count(&self) -> Result<usize> {
+        Ok(try!(self.list_toolchains()).len())
+    }
+
+    fn channel_history_file(&self, toolchain: &Toolchain) -> PathBuf {
+        self.elan_dir.join("channel-history").join(toolchain.dir_name())
+    }
+
+    /// The releases the tracking toolchain `channel` resolved to on
+    /// updates, with when it changed to each, oldest first
+    pub fn channel_history(&self, channel: &str) -> Result<Vec<(SystemTime, ToolchainDesc)>> {
+        let toolchain = try!(self.get_toolchain(channel, false));
+        Ok(try!(channel_history::read(&self.channel_history_file(&toolchain))))
+    }
+
+    /// Adds the release `toolchain` is installed at to its channel
+    /// history. Best-effort, so that recording it never fails an update.
+    pub fn record_channel_history(&self, toolchain: &Toolchain) {
+        let desc = match toolchain.desc() {
+            Ok(ref desc) if desc.is_tracking() => desc.clone(),
+            _ => return,
+        };
+        let tag = match toolchain.installed_release() {
+            Ok(Some(tag)) => tag,
+            _ => return,
+        };
+        let path = self.channel_history_file(toolchain);
+        if utils::ensure_dir_exists("channel history", path.parent().unwrap(), &|_| ()).is_ok() {
+            let _ = channel_history::record(&path, SystemTime::now(), &desc.at_release(&tag));
+        }
+    }
+
+    fn last_used_file(&self, toolchain: &Toolchain) -> PathBuf {
+        self.elan_dir.join("last-used").join(toolchain.dir_name())
+    }
+
+    /// Records that a binary of `toolchain` is being run, for evicting the
+    /// least recently used toolchains. Only done while a quota is set.
+    pub fn record_toolchain_use(&self, toolchain: &Toolchain) {
+        if self.max_toolchains.is_none() {
+            return;
+        }
+        let _ = utils::record_last_use(&self.last_used_file(toolchain), SystemTime::now());
+    }
+
+    // When the toolchain was last run, or else installed
+    fn toolchain_last_used(&self, toolchain: &Toolchain) -> SystemTime {
+        utils::last_use(&self.last_used_file(toolchain), toolchain.path())
+    }
+
+    /// Fails before another toolchain is installed if that would exceed
+    /// `max_toolchains` and the quota policy doesn't allow evicting
+    pub fn check_toolchain_quota(&self) -> Result<()> {
+        match self.max_toolchains {
+            Some(max) if self.quota_policy == QuotaPolicy::Error && try!(self.list_toolchains()).len() >= max => {
+                Err(ErrorKind::ToolchainQuotaExceeded(max).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Once `installed` has been installed, evicts the least recently used
+    /// toolchains until `max_toolchains` holds again, if the quota policy
+    /// allows. Toolchains that the default, directory overrides, the
+    /// project in the current directory or `ELAN_TOOLCHAIN` refer to are
+    /// kept, as are read-only and linked ones and any running this
+    /// process.
+    pub fn evict_toolchains(&self, installed: &Toolchain) -> Result<()> {
+        let max = match self.max_toolchains {
+            Some(max) if self.quota_policy == QuotaPolicy::EvictLeastRecentlyUsed => max,
+            _ => return Ok(()),
+        };
+        let names = try!(self.list_toolchains());
+        if names.len() <= max {
+            return Ok(());
+        }
+
+        let mut references = try!(self.toolchain_references(&try!(utils::current_dir())));
+        if let Some(ref name) = self.env_override {
+            references.push((name.clone(), "ELAN_TOOLCHAIN".to_owned()));
+        }
+        // Referred to by any name, like an alias
+        let referenced: Vec<PathBuf> = references.iter()
+            .filter_map(|&(ref name, _)| self.get_toolchain(name, false).ok())
+            .map(|t| t.path().to_owned())
+            .collect();
+        let mut candidates = vec![];
+        for name in &names {
+            let toolchain = try!(self.get_toolchain(name, false));
+            if toolchain.path() == installed.path() || referenced.iter().any(|p| p == toolchain.path()) ||
+               toolchain.is_readonly() || toolchain.is_symlink() || toolchain.is_running() {
+                continue;
+            }
+            candidates.push((self.toolchain_last_used(&toolchain), name.clone()));
+        }
+
+        // Least recently used first
+        candidates.sort();
+        let excess = names.len() - max;
+        if candidates.len() < excess {
+            return Err(ErrorKind::ToolchainQuotaExceeded(max).into());
+        }
+        for (_, name) in candidates.into_iter().take(excess) {
+            let toolchain = try!(self.get_toolchain(&name, false));
+            // Part of installing, which was already asked for
+            try!(toolchain.remove_unconfirmed());
+            let _ = utils::remove_file("last used", &self.last_used_file(&toolchain));
+            (self.notify_handler)(Notification::EvictedToolchain(&name, max));
+        }
+        Ok(())
+    }
+
+    /// Removes files from `download_dir` that are older than `max_age`, then
+    /// evicts the oldest remaining ones until it is at most `max_size` bytes.
+    /// Downloads in progress are never touched. Returns the number of bytes
+    /// reclaimed.
+    pub fn prune_download_dir(&self, max_age: Option<Duration>, max_size: Option<u64>) -> Result<u64> {
+        if !utils::is_directory(&self.download_dir) {
+            return Ok(0);
+        }
+
+        let mut files = Vec::new();
+        for entry in try!(utils::read_dir("downloads", &self.download_dir)) {
+            let entry = try!(entry.chain_err(|| "reading download directory"));
+            let in_progress = entry.file_name().to_str()
+                .map(|n| n.ends_with(".partial") || n.contains(".partial.part"))
+                .unwrap_or(true);
+            let metadata = try!(entry.metadata().chain_err(|| "reading download directory"));
+            if in_progress || !metadata.is_file() {
+                continue;
+            }
+            let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+            files.push((modified, metadata.len(), entry.path()));
+        }
+        files.sort();
+
+        let now = SystemTime::now();
+        let mut total: u64 = files.iter().map(|f| f.1).sum();
+        let mut removed = 0;
+        let mut reclaimed = 0;
+        for (modified, size, path) in files {
+            let too_old = max_age.map_or(false, |max_age| {
+                now.duration_since(modified).map(|age| age > max_age).unwrap_or(false)
+            });
+            let too_big = max_size.map_or(false, |max_size| total > max_size);
+            if !too_old && !too_big {
+                continue;
+            }
+            try!(utils::remove_file("cached download", &path));
+            total -= size;
+            removed += 1;
+            reclaimed += size;
+        }
+
+        (self.notify_handler)(Notification::PrunedDownloads(removed, reclaimed));
+        Ok(reclaimed)
+    }
+
+    fn update_check_file(&self, toolchain: &Toolchain) -> PathBuf {
+        self.elan_dir.join("update-checks").join(format!("{}.json", toolchain.dir_name()))
+    }
+
+    /// Returns the result of the last update check for `toolchain` unless it
+    /// is older than `max_staleness`. This never accesses the network.
+    pub fn cached_update_check(&self, toolchain: &Toolchain, max_staleness: Duration) -> Option<UpdateCheck> {
+        let contents = utils::read_file("update check", &self.update_check_file(toolchain)).ok()?;
+        let check: UpdateCheck = serde_json::from_str(&contents).ok()?;
+        if check.is_fresh(max_staleness) { Some(check) } else { None }
+    }
+
+    /// Starts a detached `elan check-update --refresh` process for
+    /// `toolchain`, unless update checks are disabled or one was started
+    /// recently. Returns whether a process was started.
+    pub fn spawn_update_check(&self, toolchain: &Toolchain) -> Result<bool> {
+        if !self.update_checks {
+            return Ok(false);
+        }
+
+        let pending = self.update_check_file(toolchain).with_extension("pending");
+        let running = fs::metadata(&pending).and_then(|m| m.modified()).ok()
+            .and_then(|t| SystemTime::now().duration_since(t).ok())
+            .map_or(false, |age| age < Duration::from_secs(update_check::TIMEOUT_SECS));
+        if running {
+            return Ok(false);
+        }
+        try!(utils::ensure_dir_exists("update checks", pending.parent().unwrap(),
+                                      &|n| (self.notify_handler)(n.into())));
+        try!(utils::write_file("update check marker", &pending, ""));
+
+        // The installed `elan`, since the current executable may be a proxy
+        let elan = self.elan_dir.join("bin").join(format!("elan{}", EXE_SUFFIX));
+        let elan = if utils::is_file(&elan) { elan } else { try!(utils::current_exe()) };
+        try!(Command::new(elan)
+             .args(&["check-update", "--refresh", toolchain.name()])
+             .stdin(Stdio::null())
+             .stdout(Stdio::null())
+             .stderr(Stdio::null())
+             .spawn()
+             .chain_err(|| "could not start background update check"));
+        Ok(true)
+    }
+
+    /// Checks `toolchain` for updates and caches the result
+    pub fn refresh_update_check(&self, toolchain: &Toolchain) -> Result<UpdateCheck> {
+        let file = self.update_check_file(toolchain);
+        let _ = fs::remove_file(file.with_extension("pending"));
+
+        let latest = try!(toolchain.check_for_update())
+            .map(|url| url.rsplit('/').next().unwrap_or(&url).to_owned());
+        let check = UpdateCheck::new(toolchain.name(), latest);
+
+        try!(utils::ensure_dir_exists("update checks", file.parent().unwrap(),
+                                      &|n| (self.notify_handler)(n.into())));
+        let contents = try!(serde_json::to_string(&check).chain_err(|| "serializing update check"));
+        try!(utils::write_file_atomic("update check", &file, &contents));
+        Ok(check)
+    }
+
+    /// The installed toolchains whose `bin` directory contains `binary`,
+    /// sorted by name. Broken links are skipped.
+    pub fn which_toolchain_provides(&self, binary: &str) -> Result<Vec<String>> {
+        let mut toolchains = vec![];
+        for name in try!(self.list_toolchains()) {
+            let toolchain = try!(self.get_toolchain(&name, false));
+            toolchains.push((name, toolchain.bin_dir()));
+        }
+        Ok(try!(utils::toolchains_providing(&toolchains, binary, &toolchain::binary_suffixes())))
+    }
+
+    /// Looks for broken toolchains and stale settings
+    pub fn verify_all(&self) -> Result<Vec<Problem>> {
+        let mut problems = Vec::new();
+
+        for name in try!(self.list_toolchains()) {
+            let toolchain = try!(self.get_toolchain(&name, false));
+            if toolchain.is_symlink() && !utils::is_directory(toolchain.path()) {
+                problems.push(Problem::BrokenLink(name));
+            } else if !utils::is_file(&toolchain.binary_file("lean")) {
+                problems.push(Problem::BrokenToolchain(name));
+            }
+        }
+
+        let overrides = try!(self.settings_file.with(|s| Ok(s.overrides.clone())));
+        for (path, toolchain) in overrides {
+            if !Path::new(&path).is_dir() {
+                problems.push(Problem::DanglingOverride(PathBuf::from(path), toolchain));
+            }
+        }
+
+        if let Some(name) = try!(self.get_default()) {
+            // Broken links count as missing as well
+            if !utils::is_directory(try!(self.get_toolchain(&name, false)).path()) {
+                problems.push(Problem::MissingDefault(name));
+            }
+        }
+
+        if let Some(problem) = self.find_shadowed_elan() {
+            problems.push(problem);
+        }
+
+        Ok(problems)
+    }
+
+    // Whether this process or `elan` on PATH isn't the elan in `elan_dir`
+    fn find_shadowed_elan(&self) -> Option<Problem> {
+        let bin_dir = self.elan_dir.join("bin");
+        let managed = bin_dir.join(format!("elan{}", EXE_SUFFIX));
+        if !utils::is_file(&managed) {
+            return None;
+        }
+        let running = utils::current_exe().ok()
+            .filter(|exe| !exe.parent().map_or(false, |dir| utils::same_path(dir, &bin_dir)));
+        let on_path = env::var_os("PATH")
+            .and_then(|path| utils::shadowing_binary(&format!("elan{}", EXE_SUFFIX), &bin_dir, &path));
+        running.or(on_path).map(|other| Problem::ShadowedElan { other: other, managed: managed })
+    }
+
+    /// Attempts to fix each problem found by `verify_all`, asking the
+    /// confirmation handler first. Problems that cannot be fixed, like
+    /// broken toolchains that weren't installed from the dist server, are
+    /// skipped. Returns the problems that were fixed.
+    pub fn doctor_fix(&self) -> Result<Vec<Problem>> {
+        let mut fixed = Vec::new();
+        for problem in try!(self.verify_all()).into_iter().filter(|p| self.can_fix(p)) {
+            let description = problem.fix_description();
+            let result = self.confirm(&description).and_then(|()| self.fix_problem(&problem));
+            match result {
+                Ok(()) => {
+                    (self.notify_handler)(Notification::FixedProblem(&description));
+                    fixed.push(problem);
+                }
+                Err(e) => (self.notify_handler)(Notification::NonFatalError(&e)),
+            }
+        }
+        Ok(fixed)
+    }
+
+    fn can_fix(&self, problem: &Problem) -> bool {
+        match *problem {
+            // Only reinstalling is known to recreate the same toolchain
+            Problem::BrokenToolchain(ref name) => {
+                self.get_toolchain(name, false).map(|t| t.has_update_hash()).unwrap_or(false)
+            }
+            _ => problem.is_fixable(),
+        }
+    }
+
+    fn fix_problem(&self, problem: &Problem) -> Result<()> {
+        match *problem {
+            Problem::BrokenLink(ref name) => {
+                let path = try!(self.get_toolchain(name, false)).path().to_owned();
+                Ok(try!(utils::remove_file("broken link", &path)))
+            }
+            Problem::BrokenToolchain(ref name) => {
+                // Already confirmed by `doctor_fix`
+                let toolchain = try!(self.get_toolchain(name, false));
+                try!(toolchain.remove_unconfirmed());
+                try!(toolchain.install_from_dist(false));
+                Ok(())
+            }
+            Problem::DanglingOverride(ref path, _) => {
+                self.settings_file.with_mut(|s| {
+                    s.overrides.remove(&*path.to_string_lossy());
+                    Ok(())
+                })
+            }
+            Problem::MissingDefault(_) => {
+                self.settings_file.with_mut(|s| {
+                    s.default_toolchain = None;
+                    Ok(())
+                })
+            }
+            Problem::ShadowedElan { .. } => unreachable!(),
+        }
     }
 
     pub fn set_telemetry(&self, telemetry_enabled: bool) -> Result<()> {
@@ -386,6 +1806,16 @@ impl Cfg {
         Ok(())
     }
 
+    /// Disables telemetry and deletes all events collected so far
+    pub fn disable_and_clear_telemetry(&self) -> Result<()> {
+        try!(self.disable_telemetry());
+
+        let removed = try!(Telemetry::new(self.elan_dir.join("telemetry")).clear());
+        (self.notify_handler)(Notification::TelemetryCleared(removed));
+
+        Ok(())
+    }
+
     pub fn telemetry_enabled(&self) -> Result<bool> {
         Ok(match try!(self.settings_file.with(|s| Ok(s.telemetry))) {
             TelemetryMode::On => true,
@@ -393,6 +1823,55 @@ impl Cfg {
         })
     }
 
+    /// The repository bare channels are resolved against, if not the
+    /// official ones
+    pub fn default_origin(&self) -> Result<Option<String>> {
+        let origin = try!(self.settings_file.with(|s| Ok(s.default_origin.clone())));
+        match origin {
+            Some(ref o) if !ToolchainDesc::is_valid_origin(o) => {
+                Err(ErrorKind::InvalidDefaultOrigin(o.clone()).into())
+            }
+            _ => Ok(origin),
+        }
+    }
+
+    /// Where toolchains are resolved and downloaded from, with settings
+    /// and environment overrides like `ELAN_DEFAULT_ORIGIN` applied
+    pub fn dist_config(&self) -> Result<DistConfig> {
+        let origin = try!(self.default_origin());
+        let has_token = env::var("ELAN_GITHUB_TOKEN").ok().and_then(utils::if_not_empty).is_some();
+        let mut config = DistConfig::new(origin.as_ref().map(|s| &**s), self.include_prereleases, has_token);
+        config.mirrors = self.dist_servers.clone();
+        Ok(config)
+    }
+
+    /// The profile to install toolchains with
+    pub fn profile(&self) -> Result<Profile> {
+        if let Some(profile) = self.profile_override {
+            return Ok(profile);
+        }
+        if let Some(ref name) = self.profile_env {
+            return Ok(try!(Profile::from_str(name).chain_err(|| "invalid ELAN_PROFILE")));
+        }
+        match try!(self.settings_file.with(|s| Ok(s.profile.clone()))) {
+            Some(name) => Ok(try!(Profile::from_str(&name))),
+            None => Ok(Profile::default()),
+        }
+    }
+
+    pub fn set_profile(&self, profile: Profile) -> Result<()> {
+        try!(self.settings_file.with_mut(|s| {
+            s.profile = Some(profile.name().to_owned());
+            Ok(())
+        }));
+        (self.notify_handler)(Notification::SetProfile(profile.name()));
+        Ok(())
+    }
+
+    pub fn fallback_disabled(&self) -> Result<bool> {
+        self.settings_file.with(|s| Ok(s.disable_fallback))
+    }
+
     pub fn analyze_telemetry(&self) -> Result<TelemetryAnalysis> {
         let mut t = TelemetryAnalysis::new(self.elan_dir.join("telemetry"));
 
@@ -402,3 +1881,192 @@ impl Cfg {
         Ok(t)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use elan_utils::test_dir::TestDir;
+
+    // `Cfg::from_env` reads the process environment
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    fn test_cfg(home: &Path, notify_handler: Arc<Fn(Notification)>) -> Cfg {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        env::set_var("ELAN_HOME", home);
+        let cfg = Cfg::from_env(notify_handler);
+        env::remove_var("ELAN_HOME");
+        let mut cfg = cfg.unwrap();
+        cfg.env_override = None;
+        cfg
+    }
+
+    // A `bin` directory is enough for a toolchain to be listed
+    fn fake_toolchain<'a>(cfg: &'a Cfg, name: &str) -> Toolchain<'a> {
+        let toolchain = cfg.get_toolchain(name, true).unwrap();
+        fs::create_dir_all(toolchain.path().join("bin")).unwrap();
+        toolchain
+    }
+
+    #[test]
+    fn test_update_all_channels_attempts_every_toolchain() {
+        let home = TestDir::new("update-all");
+        let summaries = Arc::new(Mutex::new(vec![]));
+        let recorded = summaries.clone();
+        let mut cfg = test_cfg(&home, Arc::new(move |n| {
+            if let Notification::BatchUpdateSummary(updated, unchanged, failed) = n {
+                recorded.lock().unwrap().push((updated, unchanged, failed));
+            }
+        }));
+        // Fails each install before anything is downloaded
+        cfg.profile_env = Some("no-such-profile".to_owned());
+        fake_toolchain(&cfg, "stable");
+        fake_toolchain(&cfg, "nightly");
+        fake_toolchain(&cfg, "v4.1.0");
+        fake_toolchain(&cfg, "v4.2").set_readonly(true).unwrap();
+
+        // The release and the read-only channel are skipped, and the
+        // first failure doesn't stop the batch
+        let results = cfg.update_all_channels(false).unwrap();
+        let mut failed: Vec<_> = results.iter().filter(|&&(_, ref r)| r.is_err()).map(|&(ref n, _)| &**n).collect();
+        failed.sort();
+        assert_eq!(results.len(), 2);
+        assert_eq!(failed, vec!["nightly", "stable"]);
+        assert_eq!(*summaries.lock().unwrap(), vec![(0, 0, 2)]);
+    }
+
+    #[test]
+    fn test_disable_fallback() {
+        let home = TestDir::new("disable-fallback");
+        let cfg = test_cfg(&home, Arc::new(|_| ()));
+        let toolchain = fake_toolchain(&cfg, "v4.1.0");
+        // Commands read `LEAN_RECURSION_COUNT`
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        // `lake` is looked up on `PATH` instead
+        assert_eq!(toolchain.create_command("lake").unwrap().get_program(), "lake");
+
+        cfg.settings_file.with_mut(|s| {
+            s.disable_fallback = true;
+            Ok(())
+        }).unwrap();
+        match toolchain.create_command("lake") {
+            Err(Error(ErrorKind::BinaryNotFound(..), _)) => {}
+            r => panic!("expected `BinaryNotFound`, got {:?}", r),
+        }
+    }
+
+    // Like concurrent `elan override set` calls, each by its own process
+    #[test]
+    fn test_concurrent_overrides_are_not_lost() {
+        let home = TestDir::new("concurrent-overrides");
+        let threads: Vec<_> = (0..8).map(|i| {
+            let home = home.to_path_buf();
+            ::std::thread::spawn(move || {
+                let cfg = test_cfg(&home, Arc::new(|_| ()));
+                let project = home.join(format!("project-{}", i));
+                fs::create_dir_all(&project).unwrap();
+                cfg.get_toolchain("stable", false).unwrap().make_override(&project).unwrap();
+            })
+        }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let cfg = test_cfg(&home, Arc::new(|_| ()));
+        let overrides = cfg.list_overrides().unwrap();
+        assert_eq!(overrides.len(), 8);
+        for i in 0..8 {
+            let project = utils::canonicalize_path(&home.join(format!("project-{}", i)), &|_| ());
+            assert!(overrides.iter().any(|&(ref dir, _, _)| *dir == project), "override {} was lost", i);
+        }
+    }
+
+    #[test]
+    fn test_evict_toolchains_keeps_referenced_ones() {
+        let home = TestDir::new("evict");
+        let evicted = Arc::new(Mutex::new(vec![]));
+        let recorded = evicted.clone();
+        let mut cfg = test_cfg(&home, Arc::new(move |n| {
+            if let Notification::EvictedToolchain(name, _) = n {
+                recorded.lock().unwrap().push(name.to_owned());
+            }
+        }));
+        cfg.quota_policy = QuotaPolicy::EvictLeastRecentlyUsed;
+        // Evicting is part of an install and must not ask
+        cfg.non_interactive = Some(NonInteractivePolicy::Deny);
+        cfg.env_override = Some("v4.3.0".to_owned());
+
+        // Everything but `v4.2.0` and `v4.7.0` is protected, even though
+        // it was used longer ago
+        let names = ["v4.0.0", "v4.1.0", "v4.2.0", "v4.3.0", "v4.4.0", "v4.6.0", "v4.7.0", "v4.8.0"];
+        for (i, name) in names.iter().enumerate() {
+            let toolchain = fake_toolchain(&cfg, name);
+            let used = if *name == "v4.2.0" || *name == "v4.7.0" { 100 + i } else { i };
+            utils::record_last_use(&cfg.last_used_file(&toolchain),
+                                   SystemTime::UNIX_EPOCH + Duration::from_secs(used as u64)).unwrap();
+        }
+        cfg.settings_file.with_mut(|s| {
+            s.aliases.insert("mine".to_owned(), "v4.0.0".to_owned());
+            s.default_toolchain = Some("mine".to_owned());
+            Ok(())
+        }).unwrap();
+        let project = home.join("project");
+        fs::create_dir_all(&project).unwrap();
+        cfg.get_toolchain("v4.1.0", false).unwrap().make_override(&project).unwrap();
+        cfg.get_toolchain("v4.4.0", false).unwrap().set_readonly(true).unwrap();
+        let linked = home.join("linked");
+        fs::create_dir_all(linked.join("bin")).unwrap();
+        utils::write_file("lean", &linked.join("bin").join(format!("lean{}", EXE_SUFFIX)), "").unwrap();
+        cfg.get_toolchain("linked", true).unwrap().install_from_dir(&linked, true).unwrap();
+        // Just installed
+        let evict = |cfg: &Cfg| cfg.evict_toolchains(&cfg.get_toolchain("v4.8.0", false).unwrap());
+
+        // `v4.6.0` runs this process
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        env::set_var("ELAN_TOOLCHAIN", "v4.6.0");
+        env::set_var("LEAN_RECURSION_COUNT", "1");
+        cfg.max_toolchains = Some(8);
+        let first = evict(&cfg);
+        cfg.max_toolchains = Some(7);
+        let second = evict(&cfg);
+        cfg.max_toolchains = Some(6);
+        let third = evict(&cfg);
+        env::remove_var("ELAN_TOOLCHAIN");
+        env::remove_var("LEAN_RECURSION_COUNT");
+
+        first.unwrap();
+        second.unwrap();
+        match third {
+            Err(Error(ErrorKind::ToolchainQuotaExceeded(6), _)) => {}
+            r => panic!("expected `ToolchainQuotaExceeded`, got {:?}", r),
+        }
+        assert_eq!(*evicted.lock().unwrap(), vec!["v4.2.0", "v4.7.0"]);
+        let mut remaining = cfg.list_toolchains().unwrap();
+        remaining.sort();
+        assert_eq!(remaining, vec!["linked", "v4.0.0", "v4.1.0", "v4.3.0", "v4.4.0", "v4.6.0", "v4.8.0"]);
+    }
+
+    #[test]
+    fn test_first_install_becomes_default() {
+        let home = TestDir::new("first-install");
+        let mut cfg = test_cfg(&home, Arc::new(|_| ()));
+        // What a later elan process reads
+        let settings_path = cfg.settings_file.path().to_owned();
+        let stored_default = || {
+            SettingsFile::new(settings_path.clone()).with(|s| Ok(s.default_toolchain.clone())).unwrap()
+        };
+
+        cfg.default_on_first_install = false;
+        assert!(!cfg.set_default_after_install("v4.0.0", false).unwrap());
+        assert_eq!(stored_default(), None);
+
+        cfg.default_on_first_install = true;
+        assert!(cfg.set_default_after_install("v4.1.0", false).unwrap());
+        assert_eq!(stored_default(), Some("v4.1.0".to_owned()));
+        assert!(!cfg.set_default_after_install("v4.2.0", false).unwrap());
+        assert_eq!(stored_default(), Some("v4.1.0".to_owned()));
+        assert!(cfg.set_default_after_install("v4.3.0", true).unwrap());
+        assert_eq!(stored_default(), Some("v4.3.0".to_owned()));
+    }
+}
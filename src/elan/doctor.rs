@@ -0,0 +1,62 @@
+//! Problems with an elan installation that `Cfg::verify_all` can detect and
+//! `Cfg::doctor_fix` can repair
+
+use std::fmt::{self, Display};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// A linked toolchain whose target no longer exists
+    BrokenLink(String),
+    /// An installed toolchain without a `lean` binary
+    BrokenToolchain(String),
+    /// A directory override for a directory that no longer exists
+    DanglingOverride(PathBuf, String),
+    /// The default toolchain is not installed
+    MissingDefault(String),
+    /// Another elan, e.g. from a package manager, is run instead of the
+    /// one in `ELAN_HOME`
+    ShadowedElan { other: PathBuf, managed: PathBuf },
+}
+
+impl Problem {
+    /// Whether `Cfg::doctor_fix` can repair the problem
+    pub fn is_fixable(&self) -> bool {
+        !matches!(*self, Problem::ShadowedElan { .. })
+    }
+
+    /// What fixing the problem entails, as passed to the confirmation
+    /// handler
+    pub fn fix_description(&self) -> String {
+        match *self {
+            Problem::BrokenLink(ref t) => format!("remove broken link '{}'", t),
+            Problem::BrokenToolchain(ref t) => format!("reinstall toolchain '{}'", t),
+            Problem::DanglingOverride(ref p, _) => format!("remove override for '{}'", p.display()),
+            Problem::MissingDefault(ref t) => format!("unset default toolchain '{}'", t),
+            Problem::ShadowedElan { ref other, .. } => format!("remove '{}'", other.display()),
+        }
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::BrokenLink(ref t) => {
+                write!(f, "linked toolchain '{}' points to a directory that does not exist", t)
+            }
+            Problem::BrokenToolchain(ref t) => {
+                write!(f, "toolchain '{}' is missing its 'lean' binary", t)
+            }
+            Problem::DanglingOverride(ref p, ref t) => {
+                write!(f, "override '{}' is set for '{}', which does not exist", t, p.display())
+            }
+            Problem::MissingDefault(ref t) => {
+                write!(f, "default toolchain '{}' is not installed", t)
+            }
+            Problem::ShadowedElan { ref other, ref managed } => {
+                write!(f, "'{}' is used instead of '{}', remove the other installation of elan or put '{}' first on PATH",
+                       other.display(), managed.display(), managed.parent().unwrap().display())
+            }
+        }
+    }
+}
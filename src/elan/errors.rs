@@ -1,5 +1,6 @@
 use elan_dist::{self, temp};
 use elan_utils;
+pub use elan_utils::codes;
 use elan_dist::manifest::Component;
 use std::path::PathBuf;
 use toml;
@@ -23,13 +24,73 @@ error_chain! {
             description("toolchain is not installed")
             display("toolchain '{}' is not installed", t)
         }
+        AmbiguousToolchain {
+            name: String,
+            candidates: Vec<String>,
+        } {
+            description("toolchain name is ambiguous")
+            display("toolchain '{}' is ambiguous, it could be any of: {}", name, candidates.join(", "))
+        }
         NoDefaultToolchain {
             description("no default toolchain configured. run `elan default stable` to install & configure the latest Lean 3 community release.")
         }
+        OperationCancelled(action: String) {
+            description("operation cancelled")
+            display("cancelled: {}", action)
+        }
+        RelocationBreaksToolchains(toolchains: Vec<String>) {
+            description("toolchains would no longer resolve after moving the elan home")
+            display("toolchains would no longer resolve after moving the elan home: {}", toolchains.join(", "))
+        }
+        DocComponentNotFound(t: String, component: String, available: Vec<String>) {
+            description("toolchain has no documentation for the component")
+            display("toolchain '{}' has no documentation for '{}'{}", t, component,
+                    if available.is_empty() {
+                        String::new()
+                    } else {
+                        format!("; available: {}", available.join(", "))
+                    })
+        }
+        ToolchainNotLinked(t: String) {
+            description("toolchain is not a linked toolchain")
+            display("toolchain '{}' is not a linked toolchain", t)
+        }
+        ToolchainPathConflict(p: PathBuf) {
+            description("toolchain path is occupied by a file")
+            display("cannot install toolchain to '{}': a file of that name already exists", p.display())
+        }
         OverrideToolchainNotInstalled(t: String) {
             description("override toolchain is not installed")
             display("override toolchain '{}' is not installed", t)
         }
+        ElanHomeUnavailable {
+            description("could not determine elan home (ELAN_STRICT_HOME is set)")
+        }
+        InvalidToolchainMetadata(p: PathBuf) {
+            description("invalid toolchain metadata")
+            display("could not parse toolchain metadata in '{}'", p.display())
+        }
+        ElanVersionTooOld(t: String, required: String, current: String) {
+            description("toolchain requires a newer version of elan")
+            display("toolchain '{}' requires elan {} or newer, but this is elan {}", t, required, current)
+        }
+        ArchitectureMismatch {
+            toolchain: String,
+            toolchain_arch: String,
+            host_arch: String,
+        } {
+            description("toolchain was built for a different architecture")
+            display("toolchain '{}' was built for {}, but this machine is {}; install a toolchain for {} instead",
+                    toolchain, toolchain_arch, host_arch, host_arch)
+        }
+        ToolchainReadOnly(t: String) {
+            description("toolchain is read-only")
+            display("toolchain '{}' is read-only; use --force to modify it anyway", t)
+        }
+        RemovingRunningToolchain(t: String) {
+            description("cannot remove a toolchain that is currently running")
+            display("cannot remove toolchain '{}' while one of its binaries is running", t)
+        }
         BinaryNotFound(t: String, bin: String) {
             description("toolchain does not contain binary")
             display("toolchain '{}' does not have the binary `{}`", t, bin)
@@ -74,13 +135,134 @@ error_chain! {
         TelemetryAnalysisError {
             description("error analyzing telemetry files")
         }
-        InvalidLeanpkgFile(path: PathBuf, error: toml::de::Error) {
-            description("couldn't parse 'leanpkg.toml'")
-            display("couldn't parse '{}': '{}'", path.display(), error)
+        ComputingFileDigests(p: PathBuf) {
+            description("could not compute digests of the toolchain's files")
+            display("could not compute digests of the files in '{}'", p.display())
+        }
+        InvalidDefaultOrigin(o: String) {
+            description("invalid default origin")
+            display("invalid default_origin '{}' in settings, expected 'owner/repo'", o)
+        }
+        InvalidToolchainLayout(l: String) {
+            description("invalid toolchain layout")
+            display("invalid toolchain_layout '{}' in settings, expected 'flat' or 'nested'", l)
+        }
+        InvalidQuotaPolicy(p: String) {
+            description("invalid toolchain quota policy")
+            display("invalid ELAN_TOOLCHAIN_QUOTA_POLICY '{}', expected 'error' or 'evict'", p)
+        }
+        ConfirmationRequired(action: String) {
+            description("confirmation required")
+            display("cannot {} without confirmation in non-interactive mode, set ELAN_NON_INTERACTIVE=approve to allow it", action)
+        }
+        ToolchainQuotaExceeded(max: usize) {
+            description("too many toolchains installed")
+            display("at most {} toolchains may be installed (ELAN_MAX_TOOLCHAINS); uninstall one first", max)
+        }
+        HookFailed(path: PathBuf) {
+            description("hook failed")
+            display("hook '{}' failed", path.display())
         }
-        InvalidLeanVersion(path: PathBuf, t: &'static str) {
-            description("invalid 'package.lean_version' value")
-            display("invalid 'package.lean_version' value in '{}': expected string instead of {}", path.display(), t)
+        NotAToolchainDirectory(path: PathBuf, missing: Vec<PathBuf>) {
+            description("not a toolchain directory")
+            display("'{}' is not a toolchain directory, it lacks {}", path.display(),
+                    missing.iter().map(|p| format!("'{}'", p.display())).collect::<Vec<_>>().join(", "))
+        }
+        InvalidLocalToolchain(path: PathBuf, target: String) {
+            description("toolchain file refers to an invalid toolchain directory")
+            display("the toolchain file at '{}' refers to '{}', which is not a toolchain directory containing 'bin/lean'",
+                    path.display(), target)
+        }
+        LockfileMismatch(path: PathBuf, locked: String, requested: String) {
+            description("lockfile does not match the requested toolchain")
+            display("'{}' was written for toolchain '{}', but '{}' is requested; run `elan lock` to update it",
+                    path.display(), locked, requested)
+        }
+        LockfileOutdated(path: PathBuf, locked: String, resolved: String) {
+            description("lockfile is out of date")
+            display("'{}' pins '{}', but the toolchain now resolves to '{}'", path.display(), locked, resolved)
+        }
+        MissingLockfile(dir: PathBuf) {
+            description("no lockfile for the project in locked mode")
+            display("'{}' has no elan.lock, but ELAN_LOCKED or --locked requires one", dir.display())
+        }
+        NoProjectToolchain(path: PathBuf) {
+            description("no lean-toolchain or leanpkg.toml file")
+            display("no lean-toolchain or leanpkg.toml file naming a toolchain for '{}'", path.display())
+        }
+        NotADirectory(path: PathBuf) {
+            description("not a directory")
+            display("'{}' is not a directory", path.display())
+        }
+    }
+}
+
+impl Error {
+    /// The exit code for this error, see `codes`. Errors without a specific
+    /// code of their own take the code of the error they were caused by.
+    pub fn error_code(&self) -> u32 {
+        error_code(self)
+    }
+}
+
+/// Like `Error::error_code`, for errors of any of elan's crates
+pub fn error_code(error: &(::std::error::Error + 'static)) -> u32 {
+    let mut error = Some(error);
+    while let Some(e) = error {
+        let code = if let Some(e) = e.downcast_ref::<Error>() {
+            e.kind().error_code()
+        } else if let Some(e) = e.downcast_ref::<elan_dist::Error>() {
+            e.kind().error_code()
+        } else if let Some(e) = e.downcast_ref::<elan_utils::Error>() {
+            e.kind().error_code()
+        } else if let Some(e) = e.downcast_ref::<temp::Error>() {
+            e.error_code()
+        } else {
+            codes::FAILURE
+        };
+        if code != codes::FAILURE {
+            return code;
+        }
+        error = e.source();
+    }
+    codes::FAILURE
+}
+
+impl ErrorKind {
+    /// The exit code for this kind of error, see `codes`
+    pub fn error_code(&self) -> u32 {
+        match *self {
+            ErrorKind::Dist(ref kind) => kind.error_code(),
+            ErrorKind::Utils(ref kind) => kind.error_code(),
+            ErrorKind::Temp(ref e) => e.error_code(),
+            ErrorKind::ToolchainNotInstalled(_) |
+            ErrorKind::OverrideToolchainNotInstalled(_) |
+            ErrorKind::BinaryNotFound(_, _) |
+            ErrorKind::ToolchainNotLinked(_) => codes::NOT_INSTALLED,
+            ErrorKind::NoDefaultToolchain => codes::NO_DEFAULT_TOOLCHAIN,
+            ErrorKind::AmbiguousToolchain { .. } |
+            ErrorKind::InvalidLocalToolchain(_, _) |
+            ErrorKind::NotAToolchainDirectory(_, _) |
+            ErrorKind::NoProjectToolchain(_) => codes::INVALID_TOOLCHAIN,
+            ErrorKind::ParsingSettings(_) |
+            ErrorKind::InvalidToolchainMetadata(_) |
+            ErrorKind::InvalidDefaultOrigin(_) |
+            ErrorKind::InvalidToolchainLayout(_) |
+            ErrorKind::InvalidQuotaPolicy(_) |
+            ErrorKind::UnknownMetadataVersion(_) |
+            ErrorKind::NeedMetadataUpgrade => codes::INVALID_CONFIG,
+            ErrorKind::LockfileMismatch(_, _, _) |
+            ErrorKind::LockfileOutdated(_, _, _) |
+            ErrorKind::MissingLockfile(_) => codes::LOCKFILE,
+            ErrorKind::ToolchainReadOnly(_) |
+            ErrorKind::RemovingRunningToolchain(_) |
+            ErrorKind::ToolchainPathConflict(_) => codes::TOOLCHAIN_BUSY,
+            ErrorKind::ElanVersionTooOld(_, _, _) |
+            ErrorKind::ArchitectureMismatch { .. } => codes::INCOMPATIBLE,
+            ErrorKind::ToolchainQuotaExceeded(_) => codes::NO_SPACE,
+            ErrorKind::ConfirmationRequired(_) |
+            ErrorKind::OperationCancelled(_) => codes::CANCELLED,
+            _ => codes::FAILURE,
         }
     }
 }
@@ -4,7 +4,7 @@
 use elan_dist::{Notification};
 use elan_dist::prefix::InstallPrefix;
 use elan_utils::utils;
-use elan_dist::dist;
+use elan_dist::{bundle, dist};
 use elan_dist::download::DownloadCfg;
 use errors::Result;
 use std::path::Path;
@@ -13,8 +13,17 @@ use std::path::Path;
 pub enum InstallMethod<'a> {
     Copy(&'a Path),
     Link(&'a Path),
-    // bool is whether to force an update
-    Dist(&'a dist::ToolchainDesc, Option<&'a Path>, DownloadCfg<'a>, bool),
+    /// With `dry_run`, the toolchain is only resolved and compared with the
+    /// update hash, and nothing is written
+    Dist {
+        toolchain: &'a dist::ToolchainDesc,
+        update_hash: Option<&'a Path>,
+        dl_cfg: DownloadCfg<'a>,
+        force_update: bool,
+        dry_run: bool,
+    },
+    /// Installs the bundle in the directory without network access
+    Bundle(&'a Path, Option<&'a Path>, DownloadCfg<'a>),
 }
 
 impl<'a> InstallMethod<'a> {
@@ -22,7 +31,7 @@ impl<'a> InstallMethod<'a> {
         if path.exists() {
             // Don't uninstall first for Dist method
             match self {
-                InstallMethod::Dist(..) => {}
+                InstallMethod::Dist { .. } | InstallMethod::Bundle(..) => {}
                 _ => {
                     try!(uninstall(path, notify_handler));
                 }
@@ -38,7 +47,11 @@ impl<'a> InstallMethod<'a> {
                 try!(utils::symlink_dir(src, &path, &|n| notify_handler(n.into())));
                 Ok(true)
             }
-            InstallMethod::Dist(toolchain, update_hash, dl_cfg, force_update) => {
+            InstallMethod::Dist { toolchain, update_hash, dl_cfg, force_update, dry_run: true } => {
+                let prefix = &InstallPrefix::from(path.to_owned());
+                Ok(try!(dist::update_available(dl_cfg, update_hash, toolchain, prefix, force_update)))
+            }
+            InstallMethod::Dist { toolchain, update_hash, dl_cfg, force_update, dry_run: false } => {
                 let prefix = &InstallPrefix::from(path.to_owned());
                 let maybe_new_hash =
                     try!(dist::update_from_dist(
@@ -53,7 +66,7 @@ impl<'a> InstallMethod<'a> {
 
                 if let Some(hash) = maybe_new_hash {
                     if let Some(hash_file) = update_hash {
-                        try!(utils::write_file("update hash", hash_file, &hash));
+                        try!(utils::write_file_atomic("update hash", hash_file, &hash));
                     }
 
                     Ok(true)
@@ -61,6 +74,15 @@ impl<'a> InstallMethod<'a> {
                     Ok(false)
                 }
             }
+            InstallMethod::Bundle(dir, update_hash, dl_cfg) => {
+                let prefix = &InstallPrefix::from(path.to_owned());
+                let bundle = try!(bundle::install(dl_cfg, dir, prefix));
+                // Later updates know which release is installed
+                if let Some(hash_file) = update_hash {
+                    try!(utils::write_file_atomic("update hash", hash_file, &bundle.url));
+                }
+                Ok(true)
+            }
         }
     }
 }
@@ -10,9 +10,11 @@ extern crate itertools;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate json;
 extern crate tempfile;
 extern crate time;
 extern crate toml;
+extern crate sha2;
 #[cfg(unix)]
 extern crate libc;
 
@@ -25,8 +27,12 @@ pub use elan_utils::{utils, notify, toml_utils};
 mod errors;
 mod notifications;
 mod toolchain;
+mod toolchain_metadata;
 mod config;
 mod install;
+mod log_file;
+pub mod doctor;
+pub mod update_check;
 pub mod settings;
 pub mod telemetry;
 pub mod command;
@@ -0,0 +1,59 @@
+//! Append-only diagnostic log of all notifications, enabled by
+//! `ELAN_LOG_FILE=1`. Writing to the log is strictly best-effort: any
+//! failure is ignored so that logging can never break the operation
+//! being logged.
+
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use elan_utils::notify::NotificationLevel;
+use time::OffsetDateTime;
+
+/// Rotate the log once it grows beyond this many bytes
+const MAX_LOG_SIZE: u64 = 1024 * 1024;
+
+pub struct LogFile {
+    path: PathBuf,
+    file: RefCell<Option<File>>,
+}
+
+impl LogFile {
+    pub fn new(log_dir: PathBuf) -> Self {
+        LogFile {
+            path: log_dir.join("elan.log"),
+            file: RefCell::new(None),
+        }
+    }
+
+    pub fn log(&self, level: NotificationLevel, message: &str) {
+        let _ = self.try_log(level, message);
+    }
+
+    fn try_log(&self, level: NotificationLevel, message: &str) -> ::std::io::Result<()> {
+        let mut file = self.file.borrow_mut();
+
+        let too_large = fs::metadata(&self.path).map(|m| m.len() > MAX_LOG_SIZE).unwrap_or(false);
+        if too_large {
+            *file = None;
+            fs::rename(&self.path, self.path.with_extension("log.1"))?;
+        }
+
+        if file.is_none() {
+            if let Some(parent) = self.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            *file = Some(OpenOptions::new().append(true).create(true).open(&self.path)?);
+        }
+
+        let level = match level {
+            NotificationLevel::Verbose => "verbose",
+            NotificationLevel::Info => "info",
+            NotificationLevel::Warn => "warning",
+            NotificationLevel::Error => "error",
+        };
+        let timestamp = OffsetDateTime::now_utc().format("%Y-%m-%dT%H:%M:%SZ");
+        writeln!(file.as_mut().unwrap(), "{} {}: {}", timestamp, level, message)
+    }
+}
@@ -2,6 +2,7 @@ use std::path::{Path, PathBuf};
 use std::fmt::{self, Display};
 
 use errors::*;
+use toolchain::UpdateStatus;
 
 use elan_dist::{self, temp};
 use elan_utils;
@@ -15,7 +16,13 @@ pub enum Notification<'a> {
 
     SetDefaultToolchain(&'a str),
     SetOverrideToolchain(&'a Path, &'a str),
+    SetAlias(&'a str, &'a str),
+    SetProfile(&'a str),
+    RemovedAlias(&'a str),
+    AliasShadowsToolchain(&'a str),
     LookingForToolchain(&'a str),
+    WouldInstall(&'a str, UpdateStatus),
+    WouldSkipReadOnly(&'a str),
     ToolchainDirectory(&'a Path, &'a str),
     UpdatingToolchain(&'a str),
     InstallingToolchain(&'a str),
@@ -25,6 +32,7 @@ pub enum Notification<'a> {
     UninstalledToolchain(&'a str),
     ToolchainNotInstalled(&'a str),
     UpdateHashMatches,
+    ResetUpdateHash(&'a str),
     UpgradingMetadata(&'a str, &'a str),
     MetadataUpgradeNotNeeded(&'a str),
     WritingMetadataVersion(&'a str),
@@ -32,7 +40,32 @@ pub enum Notification<'a> {
     NonFatalError(&'a Error),
     UpgradeRemovesToolchains,
     MissingFileDuringSelfUninstall(PathBuf),
+    ElanHomeUnavailable,
+    ElanVersionTooOld(&'a str, &'a str, &'a str),
+    SkippingNonDirectoryToolchain(&'a Path),
     SetTelemetry(&'a str),
+    TelemetryCleared(usize),
+    PrunedDownloads(usize, u64),
+    FixedProblem(&'a str),
+    BatchUpdateSummary(usize, usize, usize),
+    RunningHook(&'a Path),
+    MigratedToXdg(&'a Path, &'a Path),
+    MovedToolchain(&'a str, &'a Path),
+    XdgMigrationFailed(&'a Path, &'a Path),
+    SettingsNotPersisted,
+    SettingShadowedByEnv(&'a str),
+    AliasShadowedByToolchain(&'a str),
+    UsingLockedToolchain(&'a str, &'a str),
+    WroteLockfile(&'a Path, &'a str),
+    EvictedToolchain(&'a str, usize),
+    UnknownComponent(&'a str, &'a Path),
+    AddingComponents(&'a str, &'a str),
+    ComponentsUnavailable(&'a str, &'a str),
+    ReinstallingToolchain(&'a str),
+    ReinstalledToolchains(usize, usize),
+    ConfirmedNonInteractively(&'a str),
+    InvalidNonInteractivePolicy(&'a str),
+    IncompleteToolchain(&'a str),
 
     TelemetryCleanupError(&'a Error),
 }
@@ -54,6 +87,15 @@ impl<'a> From<temp::Notification<'a>> for Notification<'a> {
 }
 
 impl<'a> Notification<'a> {
+    /// See `elan_utils::Notification::is_download_lifecycle`
+    pub fn is_download_lifecycle(&self) -> bool {
+        match *self {
+            Notification::Install(ref n) => n.is_download_lifecycle(),
+            Notification::Utils(ref n) => n.is_download_lifecycle(),
+            _ => false,
+        }
+    }
+
     pub fn level(&self) -> NotificationLevel {
         use self::Notification::*;
         match *self {
@@ -68,19 +110,52 @@ impl<'a> Notification<'a> {
             ReadMetadataVersion(_) |
             InstalledToolchain(_) |
             UpdateHashMatches |
+            RunningHook(_) |
+            UsingLockedToolchain(_, _) |
             TelemetryCleanupError(_) => NotificationLevel::Verbose,
             SetDefaultToolchain(_) |
             SetOverrideToolchain(_, _) |
+            SetAlias(_, _) |
+            SetProfile(_) |
+            RemovedAlias(_) |
             UsingExistingToolchain(_) |
+            WouldInstall(_, _) |
+            WouldSkipReadOnly(_) |
             UninstallingToolchain(_) |
             UninstalledToolchain(_) |
             ToolchainNotInstalled(_) |
+            ResetUpdateHash(_) |
             UpgradingMetadata(_, _) |
             MetadataUpgradeNotNeeded(_) |
-            SetTelemetry(_) => NotificationLevel::Info,
+            SetTelemetry(_) |
+            TelemetryCleared(_) |
+            PrunedDownloads(_, _) |
+            FixedProblem(_) |
+            BatchUpdateSummary(_, _, 0) |
+            MovedToolchain(_, _) |
+            WroteLockfile(_, _) |
+            EvictedToolchain(_, _) |
+            AddingComponents(_, _) |
+            ReinstallingToolchain(_) |
+            ReinstalledToolchains(_, _) |
+            ConfirmedNonInteractively(_) |
+            MigratedToXdg(_, _) => NotificationLevel::Info,
             NonFatalError(_) => NotificationLevel::Error,
             UpgradeRemovesToolchains |
-            MissingFileDuringSelfUninstall(_) => NotificationLevel::Warn,
+            MissingFileDuringSelfUninstall(_) |
+            ElanHomeUnavailable |
+            ElanVersionTooOld(_, _, _) |
+            XdgMigrationFailed(_, _) |
+            SettingsNotPersisted |
+            SettingShadowedByEnv(_) |
+            AliasShadowedByToolchain(_) |
+            InvalidNonInteractivePolicy(_) |
+            UnknownComponent(_, _) |
+            ComponentsUnavailable(_, _) |
+            IncompleteToolchain(_) |
+            BatchUpdateSummary(_, _, _) |
+            AliasShadowsToolchain(_) |
+            SkippingNonDirectoryToolchain(_) => NotificationLevel::Warn,
         }
     }
 }
@@ -99,7 +174,19 @@ impl<'a> Display for Notification<'a> {
                        path.display(),
                        name)
             }
+            SetAlias(alias, name) => write!(f, "alias '{}' set to '{}'", alias, name),
+            SetProfile(name) => write!(f, "profile set to '{}'", name),
+            RemovedAlias(alias) => write!(f, "alias '{}' removed", alias),
+            AliasShadowsToolchain(alias) => {
+                write!(f, "alias '{}' shadows a toolchain of the same name", alias)
+            }
             LookingForToolchain(name) => write!(f, "looking for installed toolchain '{}'", name),
+            WouldInstall(name, UpdateStatus::Installed) => write!(f, "toolchain '{}' would be installed", name),
+            WouldInstall(name, UpdateStatus::Updated) => write!(f, "toolchain '{}' would be updated", name),
+            WouldInstall(name, UpdateStatus::Unchanged) => write!(f, "toolchain '{}' is up to date", name),
+            WouldSkipReadOnly(name) => {
+                write!(f, "toolchain '{}' is read-only and would not be updated without --force", name)
+            }
             ToolchainDirectory(path, _) => write!(f, "toolchain directory: '{}'", path.display()),
             UpdatingToolchain(name) => write!(f, "updating existing install for '{}'", name),
             InstallingToolchain(name) => write!(f, "installing toolchain '{}'", name),
@@ -111,6 +198,9 @@ impl<'a> Display for Notification<'a> {
             UpdateHashMatches => {
                 write!(f, "toolchain is already up to date")
             }
+            ResetUpdateHash(name) => {
+                write!(f, "the next update of '{}' will reinstall it", name)
+            }
             UpgradingMetadata(from_ver, to_ver) => {
                 write!(f,
                        "upgrading metadata version from '{}' to '{}'",
@@ -129,7 +219,69 @@ impl<'a> Display for Notification<'a> {
             MissingFileDuringSelfUninstall(ref p) => {
                 write!(f, "expected file does not exist to uninstall: {}", p.display())
             }
+            ElanHomeUnavailable => {
+                write!(f, "could not determine elan home; elan's proxy binaries will not be added to PATH")
+            }
+            ElanVersionTooOld(name, required, current) => {
+                write!(f, "toolchain '{}' requires elan {} or newer, but this is elan {}; it may not work correctly",
+                       name, required, current)
+            }
+            SkippingNonDirectoryToolchain(path) => {
+                write!(f, "ignoring '{}' in toolchains directory: not a directory", path.display())
+            }
             SetTelemetry(telemetry_status) => write!(f, "telemetry set to '{}'", telemetry_status),
+            PrunedDownloads(n, bytes) => {
+                write!(f, "removed {} cached downloads, freeing {:.1} MiB", n, bytes as f64 / (1024.0 * 1024.0))
+            }
+            MovedToolchain(name, path) => write!(f, "moved toolchain '{}' to '{}'", name, path.display()),
+            UsingLockedToolchain(requested, resolved) => {
+                write!(f, "using '{}' for '{}' as pinned by elan.lock", resolved, requested)
+            }
+            WroteLockfile(path, resolved) => write!(f, "pinned '{}' in '{}'", resolved, path.display()),
+            UnknownComponent(component, path) => {
+                write!(f, "ignoring unknown component '{}' required by '{}'", component, path.display())
+            }
+            AddingComponents(name, components) => {
+                write!(f, "installing components required by the project into '{}': {}", name, components)
+            }
+            ComponentsUnavailable(name, components) => {
+                write!(f, "toolchain '{}' is missing components required by the project: {}", name, components)
+            }
+            EvictedToolchain(name, max) => {
+                write!(f, "removed least recently used toolchain '{}' to stay within {} toolchains", name, max)
+            }
+            MigratedToXdg(legacy, new) => {
+                write!(f, "moved '{}' to '{}'", legacy.display(), new.display())
+            }
+            XdgMigrationFailed(legacy, new) => {
+                write!(f, "could not move '{}' to '{}'; continuing to use the old location",
+                       legacy.display(), new.display())
+            }
+            SettingsNotPersisted => {
+                write!(f, "settings are ephemeral (ELAN_EPHEMERAL_SETTINGS), this change won't persist")
+            }
+            SettingShadowedByEnv(var) => {
+                write!(f, "{} is set and takes precedence over this setting", var)
+            }
+            AliasShadowedByToolchain(name) => {
+                write!(f, "the alias '{}' is ignored because a toolchain of that name is installed", name)
+            }
+            FixedProblem(fix) => write!(f, "fixed: {}", fix),
+            RunningHook(path) => write!(f, "running hook '{}'", path.display()),
+            IncompleteToolchain(name) => write!(f, "toolchain '{}' was not installed completely, installing it again", name),
+            ReinstallingToolchain(name) => write!(f, "reinstalling '{}' in the current toolchain layout", name),
+            ReinstalledToolchains(reinstalled, skipped) => {
+                write!(f, "reinstalled {} tracking toolchains, skipped {} custom toolchains", reinstalled, skipped)
+            }
+            ConfirmedNonInteractively(action) => write!(f, "going ahead without confirmation: {}", action),
+            InvalidNonInteractivePolicy(policy) => {
+                write!(f, "invalid ELAN_NON_INTERACTIVE '{}', expected 'approve' or 'deny'; \
+                           actions that need confirmation are refused", policy)
+            }
+            BatchUpdateSummary(updated, unchanged, failed) => {
+                write!(f, "{} toolchains updated, {} unchanged, {} failed", updated, unchanged, failed)
+            }
+            TelemetryCleared(n) => write!(f, "removed {} telemetry event files", n),
             TelemetryCleanupError(e) => write!(f, "unable to remove old telemetry files: '{}'", e),
         }
     }
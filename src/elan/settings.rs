@@ -3,6 +3,8 @@ use notifications::*;
 use toml_utils::*;
 use utils;
 use toml;
+use elan_dist::download::UrlRewrite;
+use elan_utils;
 use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use std::cell::RefCell;
@@ -11,58 +13,110 @@ pub const SUPPORTED_METADATA_VERSIONS: [&'static str; 2] = ["2", "12"];
 pub const DEFAULT_METADATA_VERSION: &'static str = "12";
 
 
+pub use elan_utils::env_settings::EnvSettings;
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct SettingsFile {
     path: PathBuf,
-    cache: RefCell<Option<Settings>>
+    cache: RefCell<Option<Settings>>,
+    // `cache` with `env` applied, so that reading doesn't copy the
+    // settings each time
+    effective: RefCell<Option<Settings>>,
+    pub env: EnvSettings,
 }
 
 impl SettingsFile {
     pub fn new(path: PathBuf) -> Self {
+        SettingsFile::with_env(path, EnvSettings::default())
+    }
+    pub fn with_env(path: PathBuf, env: EnvSettings) -> Self {
         SettingsFile {
             path: path,
-            cache: RefCell::new(None)
+            cache: RefCell::new(None),
+            effective: RefCell::new(None),
+            env: env,
         }
     }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
     fn write_settings(&self) -> Result<()> {
+        if self.env.ephemeral {
+            return Ok(());
+        }
         let s = self.cache.borrow().as_ref().unwrap().clone();
-        try!(utils::write_file("settings", &self.path, &s.stringify()));
+        try!(utils::write_file_atomic("settings", &self.path, &s.stringify()));
         Ok(())
     }
-    fn read_settings(&self) -> Result<()> {
-        let mut needs_save = false;
-        {
-            let mut b = self.cache.borrow_mut();
-            if b.is_none() {
-                *b = Some(if utils::is_file(&self.path) {
-                    let content = try!(utils::read_file("settings", &self.path));
-                    try!(Settings::parse(&content))
-                } else {
-                    needs_save = true;
-                    Default::default()
-                });
-            }
+    // Taken while writing, shared by all elan processes
+    fn lock_path(&self) -> PathBuf {
+        let name = self.path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        self.path.with_file_name(format!("{}.lock", name))
+    }
+    fn lock(&self) -> Result<utils::FileLock> {
+        Ok(try!(utils::lock_file("settings", &self.lock_path())))
+    }
+    // Unless `locked`, the lock is taken if the file has to be created
+    fn read_settings(&self, locked: bool) -> Result<()> {
+        if self.cache.borrow().is_some() {
+            return Ok(());
         }
-        if needs_save {
-            try!(self.write_settings());
+        if self.env.ephemeral {
+            *self.cache.borrow_mut() = Some(Default::default());
+            return Ok(());
         }
+        if !utils::is_file(&self.path) {
+            // Another process may create it in the meantime
+            let _lock = if locked { None } else { Some(try!(self.lock())) };
+            if !utils::is_file(&self.path) {
+                *self.cache.borrow_mut() = Some(Default::default());
+                return self.write_settings();
+            }
+        }
+        let content = try!(utils::read_file("settings", &self.path));
+        *self.cache.borrow_mut() = Some(try!(Settings::parse(&content)));
         Ok(())
     }
+    /// Reads the settings, with those from the environment applied on top
     pub fn with<T, F: FnOnce(&Settings) -> Result<T>>(&self, f: F) -> Result<T> {
-        try!(self.read_settings());
+        try!(self.read_settings(false));
 
         // Settings can no longer be None so it's OK to unwrap
-        f(self.cache.borrow().as_ref().unwrap())
+        if self.env.overrides_anything() {
+            if self.effective.borrow().is_none() {
+                let mut settings = self.cache.borrow().as_ref().unwrap().clone();
+                self.env.apply(&mut settings.default_toolchain, &mut settings.default_origin,
+                               &mut settings.disable_fallback);
+                *self.effective.borrow_mut() = Some(settings);
+            }
+            f(self.effective.borrow().as_ref().unwrap())
+        } else {
+            f(self.cache.borrow().as_ref().unwrap())
+        }
     }
+    /// Modifies the settings as stored in `settings.toml`, or only in
+    /// memory if they are ephemeral. Settings from the environment still
+    /// take precedence afterwards. Other elan processes may have changed
+    /// the file since it was read, so it is read again under a lock and
+    /// `f` is applied to the current contents.
     pub fn with_mut<T, F: FnOnce(&mut Settings) -> Result<T>>(&self, f: F) -> Result<T> {
-        try!(self.read_settings());
-
-        // Settings can no longer be None so it's OK to unwrap
-        let result = {
-            try!(f(self.cache.borrow_mut().as_mut().unwrap()))
-        };
-        try!(self.write_settings());
-        Ok(result)
+        *self.effective.borrow_mut() = None;
+        if self.env.ephemeral {
+            try!(self.read_settings(true));
+            // Settings can no longer be None so it's OK to unwrap
+            return f(self.cache.borrow_mut().as_mut().unwrap());
+        }
+        utils::update_file_locked("settings", &self.path, &self.lock_path(), |contents| {
+            let mut settings = match contents {
+                Some(ref contents) => try!(Settings::parse(contents)),
+                None => Settings::default(),
+            };
+            let result = try!(f(&mut settings));
+            let data = settings.clone().stringify();
+            *self.cache.borrow_mut() = Some(settings);
+            *self.effective.borrow_mut() = None;
+            Ok((data, result))
+        })
     }
 }
 
@@ -73,12 +127,34 @@ pub enum TelemetryMode {
     Off,
 }
 
+/// How toolchain directories are named inside `toolchains_dir`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ToolchainLayout {
+    /// `leanprover--lean4---v4.1.0`
+    Flat,
+    /// `leanprover/lean4/v4.1.0`
+    Nested,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Settings {
     pub version: String,
     pub default_toolchain: Option<String>,
     pub overrides: BTreeMap<String, String>,
-    pub telemetry: TelemetryMode
+    pub aliases: BTreeMap<String, String>,
+    pub telemetry: TelemetryMode,
+    /// Only ever run binaries of the active toolchain, instead of falling
+    /// back to whatever else is on the `PATH`
+    pub disable_fallback: bool,
+    /// Name of the profile to install toolchains with
+    pub profile: Option<String>,
+    pub toolchain_layout: ToolchainLayout,
+    /// Repository that bare channels like `stable` are resolved against
+    /// when installed, see `ToolchainDesc::with_default_origin`
+    pub default_origin: Option<String>,
+    /// Rules applied to download urls in order, from `[[url_rewrites]]`
+    /// tables with a `pattern` regex and its `replacement`
+    pub url_rewrites: Vec<UrlRewrite>,
 }
 
 impl Default for Settings {
@@ -87,18 +163,20 @@ impl Default for Settings {
             version: DEFAULT_METADATA_VERSION.to_owned(),
             default_toolchain: None,
             overrides: BTreeMap::new(),
-            telemetry: TelemetryMode::Off
+            aliases: BTreeMap::new(),
+            telemetry: TelemetryMode::Off,
+            disable_fallback: false,
+            profile: None,
+            toolchain_layout: ToolchainLayout::Flat,
+            default_origin: None,
+            url_rewrites: vec![],
         }
     }
 }
 
 impl Settings {
     fn path_to_key(path: &Path, notify_handler: &Fn(Notification)) -> String {
-        if path.exists() {
-            utils::canonicalize_path(path, &|n| notify_handler(n.into())).display().to_string()
-        } else {
-            path.display().to_string()
-        }
+        utils::override_key(path, &|n| notify_handler(n.into()))
     }
 
     pub fn remove_override(&mut self, path: &Path, notify_handler: &Fn(Notification)) -> bool {
@@ -117,6 +195,18 @@ impl Settings {
         self.overrides.get(&key).map(|s| s.clone())
     }
 
+    /// Points overrides of directories inside `old`, and toolchains named
+    /// by their path inside it, at the same places in `new`
+    pub fn relocate(&mut self, old: &Path, new: &Path) {
+        self.overrides = utils::relocated_settings(&self.overrides, old, new);
+        self.aliases = utils::relocated_settings(&self.aliases, old, new);
+        self.default_toolchain = self.default_toolchain.as_ref().map(|t| utils::relocated_setting(t, old, new));
+    }
+
+    pub fn resolve_alias(&self, name: &str) -> Option<String> {
+        self.aliases.get(name).map(|s| s.clone())
+    }
+
     pub fn parse(data: &str) -> Result<Self> {
         let value = toml::from_str(data).map_err(ErrorKind::ParsingSettings)?;
         Self::from_toml(value, "")
@@ -134,11 +224,22 @@ impl Settings {
             version: version,
             default_toolchain: try!(get_opt_string(&mut table, "default_toolchain", path)),
             overrides: try!(Self::table_to_overrides(&mut table, path)),
+            aliases: try!(Self::table_to_aliases(&mut table, path)),
             telemetry: if try!(get_opt_bool(&mut table, "telemetry", path)).unwrap_or(false) {
                 TelemetryMode::On
             } else {
                 TelemetryMode::Off
-            }
+            },
+            disable_fallback: try!(get_opt_bool(&mut table, "disable_fallback", path)).unwrap_or(false),
+            profile: try!(get_opt_string(&mut table, "profile", path)),
+            toolchain_layout: match try!(get_opt_string(&mut table, "toolchain_layout", path)) {
+                Some(ref l) if l == "nested" => ToolchainLayout::Nested,
+                Some(ref l) if l == "flat" => ToolchainLayout::Flat,
+                None => ToolchainLayout::Flat,
+                Some(l) => return Err(ErrorKind::InvalidToolchainLayout(l).into()),
+            },
+            default_origin: try!(get_opt_string(&mut table, "default_origin", path)),
+            url_rewrites: try!(Self::array_to_url_rewrites(&mut table, path)),
         })
     }
     pub fn to_toml(self) -> toml::value::Table {
@@ -154,9 +255,40 @@ impl Settings {
         let overrides = Self::overrides_to_table(self.overrides);
         result.insert("overrides".to_owned(), toml::Value::Table(overrides));
 
+        if !self.aliases.is_empty() {
+            let aliases = Self::overrides_to_table(self.aliases);
+            result.insert("aliases".to_owned(), toml::Value::Table(aliases));
+        }
+
         let telemetry = self.telemetry == TelemetryMode::On;
         result.insert("telemetry".to_owned(), toml::Value::Boolean(telemetry));
 
+        if self.disable_fallback {
+            result.insert("disable_fallback".to_owned(), toml::Value::Boolean(true));
+        }
+
+        if let Some(v) = self.profile {
+            result.insert("profile".to_owned(), toml::Value::String(v));
+        }
+
+        if let Some(v) = self.default_origin {
+            result.insert("default_origin".to_owned(), toml::Value::String(v));
+        }
+
+        if !self.url_rewrites.is_empty() {
+            let rules = self.url_rewrites.into_iter().map(|rule| {
+                let mut table = toml::value::Table::new();
+                table.insert("pattern".to_owned(), toml::Value::String(rule.pattern().to_owned()));
+                table.insert("replacement".to_owned(), toml::Value::String(rule.replacement));
+                toml::Value::Table(table)
+            }).collect();
+            result.insert("url_rewrites".to_owned(), toml::Value::Array(rules));
+        }
+
+        if self.toolchain_layout == ToolchainLayout::Nested {
+            result.insert("toolchain_layout".to_owned(), toml::Value::String("nested".to_owned()));
+        }
+
         result
     }
 
@@ -173,6 +305,36 @@ impl Settings {
         Ok(result)
     }
 
+    // The patterns are compiled here so that invalid ones are reported
+    // when the settings are loaded
+    fn array_to_url_rewrites(table: &mut toml::value::Table, path: &str) -> Result<Vec<UrlRewrite>> {
+        let mut result = vec![];
+        for (i, v) in try!(get_array(table, "url_rewrites", path)).into_iter().enumerate() {
+            let path = format!("{}url_rewrites[{}].", path, i);
+            let mut rule = match v {
+                toml::Value::Table(t) => t,
+                _ => return Err(elan_utils::Error::from(elan_utils::ErrorKind::ExpectedType("table", path)).into()),
+            };
+            let pattern = try!(get_string(&mut rule, "pattern", &path));
+            let replacement = try!(get_string(&mut rule, "replacement", &path));
+            result.push(try!(UrlRewrite::new(&pattern, &replacement)));
+        }
+        Ok(result)
+    }
+
+    fn table_to_aliases(table: &mut toml::value::Table, path: &str) -> Result<BTreeMap<String, String>> {
+        let mut result = BTreeMap::new();
+        let alias_table = try!(get_table(table, "aliases", path));
+
+        for (k, v) in alias_table {
+            if let toml::Value::String(t) = v {
+                result.insert(k, t);
+            }
+        }
+
+        Ok(result)
+    }
+
     fn overrides_to_table(overrides: BTreeMap<String, String>) -> toml::value::Table {
         let mut result = toml::value::Table::new();
         for (k, v) in overrides {
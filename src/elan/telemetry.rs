@@ -43,6 +43,30 @@ impl Telemetry {
         Ok(())
     }
 
+    /// Deletes all recorded telemetry events, returning how many files
+    /// were removed. A missing telemetry directory is not an error.
+    pub fn clear(&self) -> Result<usize> {
+        if !utils::is_directory(&self.telemetry_dir) {
+            return Ok(0);
+        }
+
+        let contents = try!(self.telemetry_dir.read_dir().chain_err(|| ErrorKind::TelemetryCleanupError));
+
+        let mut removed = 0;
+        for c in contents {
+            let path = try!(c.chain_err(|| ErrorKind::TelemetryCleanupError)).path();
+            let is_event_file = path.file_name().and_then(|f| f.to_str())
+                .map(|f| f.starts_with("log") && f.ends_with("json"))
+                .unwrap_or(false);
+            if is_event_file {
+                try!(fs::remove_file(&path).chain_err(|| ErrorKind::TelemetryCleanupError));
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
     pub fn clean_telemetry_dir(&self) -> Result<()> {
         let telemetry_dir_contents = self.telemetry_dir.read_dir();
 
@@ -1,25 +1,46 @@
 use errors::*;
 use notifications::*;
 use elan_dist;
-use elan_dist::download::DownloadCfg;
-use elan_utils::utils;
-use elan_dist::dist::{ToolchainDesc};
-use elan_dist::manifest::Component;
+use elan_dist::bundle::{self, Bundle};
+use elan_dist::download::{DownloadCfg, UrlRewrite};
+use elan_utils::{self, utils};
+use elan_dist::dist::{ToolchainDesc, ToolchainKind};
+use elan_dist::manifest::{Component, Profile, OPTIONAL_COMPONENTS};
+use elan_dist::prefix::InstallPrefix;
+pub use elan_dist::prefix::ToolchainDirReport;
+pub use elan_dist::dist::UpdateAvailability;
+use elan_dist::toolchain_file::ToolchainConfig;
 use config::Cfg;
+use settings::{Settings, ToolchainLayout};
 use env_var;
 use install::{self, InstallMethod};
 use telemetry;
 use telemetry::{Telemetry, TelemetryEvent};
+use toolchain_metadata::{self, ToolchainMetadata};
+use update_check;
 
 use std::env::consts::EXE_SUFFIX;
-use std::ffi::OsString;
-use std::process::Command;
+use std::process::{Child, Command, Stdio};
 use std::path::{Path, PathBuf};
 use std::ffi::OsStr;
 use std::env;
+use std::fs;
+use std::time::Duration;
+use std::cell::RefCell;
+use std::rc::Rc;
 
 use regex::Regex;
 
+/// Marks a toolchain as read-only when present in its directory
+const READONLY_MARKER: &'static str = ".elan-readonly";
+
+/// What is known about the contents of a toolchain, for display
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ToolchainDescription {
+    pub version: Option<String>,
+    pub origin: Option<String>,
+}
+
 /// A fully resolved reference to a toolchain which may or may not exist
 pub struct Toolchain<'a> {
     cfg: &'a Cfg,
@@ -27,53 +48,238 @@ pub struct Toolchain<'a> {
     dir_name: String,
     path: PathBuf,
     telemetry: telemetry::Telemetry,
+    url_rewrites: Vec<UrlRewrite>,
+    /// The toolchain's metadata once it has been read, see `metadata`
+    metadata: RefCell<Option<Rc<Option<ToolchainMetadata>>>>,
+    /// The project whose toolchain file selected this toolchain, see
+    /// `set_project_dir`
+    project_dir: Option<PathBuf>,
+    /// Whether the toolchain is only installed for the duration of one
+    /// command, see `set_temporary`
+    temporary: bool,
     dist_handler: Box<Fn(elan_dist::Notification) + 'a>,
 }
 
-/// Used by the `list_component` function
+pub(crate) fn assert_is_toolchain_dir(src: &Path) -> Result<()> {
+    let report = validate_toolchain_dir(src);
+    if !report.is_valid() {
+        return Err(ErrorKind::NotAToolchainDirectory(report.path, report.missing).into());
+    }
+    Ok(())
+}
+
+/// Performs the checks of `Toolchain::install_from_dir` without
+/// installing anything, collecting all problems instead of stopping at
+/// the first
+pub fn validate_toolchain_dir(src: &Path) -> ToolchainDirReport {
+    InstallPrefix::from(src.to_owned()).check_toolchain(EXE_SUFFIX)
+}
+
+pub(crate) fn sanitize_name(name: &str) -> String {
+    name.replace("/", "--").replace(":", "---")
+}
+
+/// Where `origin:channel` toolchains like `leanprover/lean4:v4.1.0` live
+/// in the nested layout: `leanprover/lean4/v4.1.0`. Other toolchains are
+/// stored the same way in both layouts.
+pub fn nested_path(toolchains_dir: &Path, name: &str) -> Option<PathBuf> {
+    let mut parts = name.splitn(2, ':');
+    let origin = parts.next().unwrap();
+    let channel = parts.next()?;
+    let mut origin = origin.split('/');
+    match (origin.next(), origin.next(), origin.next()) {
+        (Some(owner), Some(repo), None) if !owner.is_empty() && !repo.is_empty() && !channel.is_empty() => {
+            Some(toolchains_dir.join(owner).join(repo).join(sanitize_name(channel)))
+        }
+        _ => None,
+    }
+}
+
+/// Where `layout` stores the toolchain `name`, and where the other layout
+/// does if that is a different place
+fn layout_paths(toolchains_dir: &Path, name: &str, layout: ToolchainLayout) -> (PathBuf, Option<PathBuf>) {
+    let flat = toolchains_dir.join(sanitize_name(name));
+    match nested_path(toolchains_dir, name) {
+        Some(nested) => match layout {
+            ToolchainLayout::Nested => (nested, Some(flat)),
+            ToolchainLayout::Flat => (flat, Some(nested)),
+        },
+        None => (flat, None),
+    }
+}
+
+/// Used by the `list_components` function
 pub struct ComponentStatus {
     pub component: Component,
     pub required: bool,
     pub installed: bool,
     pub available: bool,
+    /// Whether the configured profile installs the component
+    pub in_profile: bool,
+    /// The release the installed component is from, if known
+    pub installed_version: Option<String>,
+    /// The latest release according to the last update check, if there
+    /// was a recent one
+    pub available_version: Option<String>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum UpdateStatus {
     Installed,
     Updated,
     Unchanged,
 }
 
+/// The toolchain `name` stands for with `settings`, and where it is stored
+/// in `toolchains_dir`
+pub fn resolve_toolchain(toolchains_dir: &Path, settings: &Settings, name: &str) -> Result<(String, PathBuf)> {
+    // User-defined aliases from the settings file are resolved first,
+    // unless a toolchain of the same name is installed
+    let alias = settings.resolve_alias(name)
+        .filter(|_| !utils::path_exists(stored_path(toolchains_dir, name, settings.toolchain_layout)));
+    let name = alias.as_ref().map(|s| &**s).unwrap_or(name);
+    // Release pages are named like the toolchain they stand for
+    let name = if name.contains("://") {
+        try!(ToolchainDesc::from_release_url(name)).name()
+    } else {
+        name.to_owned()
+    };
+
+    let path = stored_path(toolchains_dir, &name, settings.toolchain_layout);
+    Ok((name, path))
+}
+
+// While switching layouts, toolchains are found wherever they are
+fn stored_path(toolchains_dir: &Path, name: &str, layout: ToolchainLayout) -> PathBuf {
+    match layout_paths(toolchains_dir, name, layout) {
+        (ref preferred, Some(ref other)) if !utils::path_exists(preferred) &&
+                                            utils::path_exists(other) => other.clone(),
+        (preferred, _) => preferred,
+    }
+}
+
 impl<'a> Toolchain<'a> {
     pub fn from(cfg: &'a Cfg, name: &str) -> Result<Self> {
+        let ((resolved, path), shadowed, url_rewrites) = cfg.settings_file.with(|s| {
+            let resolved = try!(resolve_toolchain(&cfg.toolchains_dir, s, name));
+            let shadowed = s.aliases.contains_key(name) && resolved.0 == name;
+            Ok((resolved, shadowed, s.url_rewrites.clone()))
+        })?;
+        if shadowed {
+            (cfg.notify_handler)(Notification::AliasShadowedByToolchain(name));
+        }
+        let name = resolved;
+
         //We need to replace ":" and "/" with "-" in the toolchain name in order to make a name which is a valid
         //name for a directory.
-        let dir_name = name.replace("/", "--").replace(":", "---");
-
-        let path = cfg.toolchains_dir.join(&dir_name[..]);
+        let dir_name = sanitize_name(&name);
 
         Ok(Toolchain {
             cfg: cfg,
-            name: name.to_owned(),
+            name: name,
             dir_name: dir_name,
             path: path.clone(),
             telemetry: Telemetry::new(cfg.elan_dir.join("telemetry")),
+            url_rewrites: url_rewrites,
+            metadata: RefCell::new(None),
+            project_dir: None,
+            temporary: false,
             dist_handler: Box::new(move |n| {
                 (cfg.notify_handler)(n.into())
             })
         })
     }
+    // The same toolchain stored at `path`
+    fn at_path(&self, path: PathBuf) -> Toolchain<'a> {
+        let cfg = self.cfg;
+        Toolchain {
+            cfg: cfg,
+            name: self.name.clone(),
+            dir_name: self.dir_name.clone(),
+            path: path,
+            telemetry: Telemetry::new(cfg.elan_dir.join("telemetry")),
+            url_rewrites: self.url_rewrites.clone(),
+            metadata: RefCell::new(None),
+            project_dir: self.project_dir.clone(),
+            temporary: self.temporary,
+            dist_handler: Box::new(move |n| {
+                (cfg.notify_handler)(n.into())
+            })
+        }
+    }
     pub fn name(&self) -> &str {
         &self.name
     }
+    /// Makes commands of the toolchain use the `LEAN_PATH` declared by the
+    /// project in `dir`, whose toolchain file resolved to it
+    pub fn set_project_dir(&mut self, dir: Option<PathBuf>) {
+        self.project_dir = dir;
+    }
+    /// Marks the toolchain as removed again right after use, so that
+    /// installing it neither counts toward `ELAN_MAX_TOOLCHAINS` nor
+    /// evicts other toolchains
+    pub(crate) fn set_temporary(&mut self) {
+        self.temporary = true;
+    }
     pub fn desc(&self) -> Result<ToolchainDesc> {
-        Ok(try!(ToolchainDesc::from_str(&self.name)))
+        let origin = try!(self.cfg.default_origin());
+        Ok(try!(elan_dist::dist::installed_desc(&self.name, &self.path, origin.as_ref().map(|s| &**s))))
+    }
+    /// Like `desc`, but also works for custom toolchains that describe
+    /// themselves in their `elan-toolchain.json`, which takes precedence.
+    /// Unknown fields are left empty rather than failing.
+    pub fn describe(&self) -> ToolchainDescription {
+        let mut description = ToolchainDescription::default();
+        if self.classify() != ToolchainKind::Custom {
+            if let Ok(desc) = self.desc() {
+                description.origin = desc.origin.clone();
+                description.version = match self.installed_release() {
+                    Ok(Some(tag)) => Some(tag),
+                    _ if !desc.is_tracking() => Some(desc.manifest_name()),
+                    _ => None,
+                };
+            }
+        }
+        if let Ok(metadata) = self.metadata() {
+            if let Some(ref metadata) = *metadata {
+                description.version = metadata.version.clone().or(description.version);
+                description.origin = metadata.origin.clone().or(description.origin);
+            }
+        }
+        description
     }
     pub fn path(&self) -> &Path {
         &self.path
     }
-    fn is_symlink(&self) -> bool {
-        use std::fs;
+    // The toolchain's `elan-toolchain.json`, read only once as it is
+    // consulted for every binary run
+    fn metadata(&self) -> Result<Rc<Option<ToolchainMetadata>>> {
+        if let Some(ref metadata) = *self.metadata.borrow() {
+            return Ok(metadata.clone());
+        }
+        let metadata = Rc::new(try!(ToolchainMetadata::load(&self.path)));
+        *self.metadata.borrow_mut() = Some(metadata.clone());
+        Ok(metadata)
+    }
+    fn prefix(&self) -> InstallPrefix {
+        InstallPrefix::from(self.path.clone())
+    }
+    /// Where the toolchain's own executables are, see `bin_dirs` for all
+    /// directories executables are looked for in
+    pub fn bin_dir(&self) -> PathBuf {
+        self.prefix().bin_dir()
+    }
+    pub fn lib_dir(&self) -> PathBuf {
+        self.prefix().lib_dir()
+    }
+    pub fn doc_dir(&self) -> PathBuf {
+        self.prefix().doc_dir()
+    }
+    pub fn man_dir(&self) -> PathBuf {
+        self.prefix().man_dir()
+    }
+    /// Whether this is a linked toolchain, even if its target is gone
+    pub fn is_symlink(&self) -> bool {
         fs::symlink_metadata(&self.path).map(|m| m.file_type().is_symlink()).unwrap_or(false)
     }
     pub fn exists(&self) -> bool {
@@ -82,32 +288,221 @@ impl<'a> Toolchain<'a> {
         // seem to follow symlinks on windows.
         utils::is_directory(&self.path) || self.is_symlink()
     }
+    /// Whether an installation of the toolchain was interrupted and never
+    /// recorded, so its files can't be trusted
+    pub fn is_incomplete(&self) -> bool {
+        !self.is_symlink() && elan_dist::is_incomplete(&self.path) &&
+            !utils::is_file(&self.path.join(toolchain_metadata::FILE_DIGESTS_FILE))
+    }
     pub fn is_custom(&self) -> bool {
         assert!(self.exists());
-        self.is_symlink()
+        self.classify() == ToolchainKind::Custom
+    }
+    pub fn classify(&self) -> ToolchainKind {
+        ToolchainKind::of(&self.name, self.is_symlink())
     }
     pub fn verify(&self) -> Result<()> {
-        Ok(try!(utils::assert_is_directory(&self.path)))
+        try!(utils::assert_is_directory(&self.path));
+        self.check_min_elan_version()
+    }
+    // Toolchains may declare the oldest elan they work with in their
+    // metadata; running them with an older one would fail in obscure ways.
+    fn check_min_elan_version(&self) -> Result<()> {
+        let metadata = try!(self.metadata());
+        if let Some(required) = metadata.as_ref().as_ref().and_then(|m| m.min_elan_version.clone()) {
+            let current = env!("CARGO_PKG_VERSION");
+            if !toolchain_metadata::version_satisfies(current, &required) {
+                if self.cfg.strict_elan_version {
+                    return Err(ErrorKind::ElanVersionTooOld(self.name.clone(),
+                                                            required,
+                                                            current.to_owned()).into());
+                }
+                (self.cfg.notify_handler)(Notification::ElanVersionTooOld(&self.name, &required, current));
+            }
+        }
+        Ok(())
+    }
+    // Recorded at install time if the origin advised against the release.
+    // Only the outermost command warns, not every `lean` a build tool
+    // started through the proxies runs.
+    fn notify_deprecation(&self) {
+        let nested = env::var("LEAN_RECURSION_COUNT").ok()
+            .and_then(|s| s.parse::<u32>().ok())
+            .map_or(false, |count| count > 0);
+        if nested {
+            return;
+        }
+        if let Ok(metadata) = self.metadata() {
+            if let Some(ToolchainMetadata { deprecation: Some(ref d), .. }) = *metadata {
+                let n = elan_dist::Notification::ToolchainDeprecated { name: &self.name, reason: &d.reason };
+                (self.cfg.notify_handler)(n.into());
+            }
+        }
+    }
+    // Running a binary for another architecture fails with an unhelpful
+    // "bad CPU type" or "exec format error", so compare its header with
+    // the host first. The host is detected at runtime since elan itself
+    // may be running translated.
+    fn check_arch(&self, bin_path: &Path) -> Result<()> {
+        if !self.cfg.check_arch {
+            return Ok(());
+        }
+        let host_arch = match utils::host_arch() {
+            Some(arch) => arch,
+            None => return Ok(()),
+        };
+        if let Some(toolchain_arch) = try!(utils::file_arch(bin_path)) {
+            // Rosetta runs x86_64 binaries on Apple Silicon, if installed
+            let translated = cfg!(target_os = "macos") && host_arch == "aarch64" &&
+                toolchain_arch == "x86_64" &&
+                utils::is_file(Path::new("/Library/Apple/usr/libexec/oah/libRosettaRuntime"));
+            if toolchain_arch != host_arch && !translated {
+                return Err(ErrorKind::ArchitectureMismatch {
+                    toolchain: self.name.clone(),
+                    toolchain_arch: toolchain_arch.to_owned(),
+                    host_arch: host_arch.to_owned(),
+                }.into());
+            }
+        }
+        Ok(())
+    }
+    /// Whether the toolchain is protected from updates and removal, see
+    /// `Cfg::mark_readonly`
+    pub fn is_readonly(&self) -> bool {
+        utils::is_file(&self.path.join(READONLY_MARKER))
+    }
+    pub fn set_readonly(&self, readonly: bool) -> Result<()> {
+        if !self.exists() {
+            return Err(ErrorKind::ToolchainNotInstalled(self.name.to_owned()).into());
+        }
+        let marker = self.path.join(READONLY_MARKER);
+        if readonly {
+            Ok(try!(utils::write_file("read-only marker", &marker, "")))
+        } else if utils::is_file(&marker) {
+            Ok(try!(utils::remove_file("read-only marker", &marker)))
+        } else {
+            Ok(())
+        }
+    }
+    fn check_writable(&self, force: bool) -> Result<()> {
+        if self.is_readonly() && !force {
+            return Err(ErrorKind::ToolchainReadOnly(self.name.clone()).into());
+        }
+        Ok(())
     }
+    /// Removes the toolchain. This never spawns any of the toolchain's
+    /// binaries, so it is safe to call from a toolchain's own tooling
+    /// regardless of `LEAN_RECURSION_COUNT`.
     pub fn remove(&self) -> Result<()> {
+        try!(self.check_writable(false));
+        self.remove_inner(true)
+    }
+    /// Like `remove`, but also removes read-only toolchains
+    pub fn force_remove(&self) -> Result<()> {
+        // Don't leave the marker behind in the target of a link
+        if self.is_readonly() {
+            try!(self.set_readonly(false));
+        }
+        self.remove_inner(true)
+    }
+    /// Like `remove`, but without asking, for toolchains elan installed
+    /// on its own
+    pub(crate) fn remove_unconfirmed(&self) -> Result<()> {
+        try!(self.check_writable(false));
+        self.remove_inner(false)
+    }
+    fn remove_inner(&self, confirm: bool) -> Result<()> {
+        // On Windows, the binaries of the toolchain that (transitively)
+        // invoked us are locked and cannot be deleted, which would leave a
+        // half-removed toolchain behind. Unix is fine with unlinking them.
+        if cfg!(windows) && self.is_running() && !self.is_symlink() {
+            return Err(ErrorKind::RemovingRunningToolchain(self.name.clone()).into());
+        }
         if self.exists() || self.is_symlink() {
+            if confirm {
+                try!(self.cfg.confirm(&format!("remove toolchain '{}'", self.name)));
+            }
             (self.cfg.notify_handler)(Notification::UninstallingToolchain(&self.name));
         } else {
             (self.cfg.notify_handler)(Notification::ToolchainNotInstalled(&self.name));
             return Ok(());
         }
         if let Some(update_hash) = try!(self.update_hash()) {
-            try!(utils::remove_file("update hash", &update_hash));
+            if utils::is_file(&update_hash) {
+                try!(utils::remove_file("update hash", &update_hash));
+            }
         }
         let result = install::uninstall(&self.path,
                                         &|n| (self.cfg.notify_handler)(n.into()));
         if !self.exists() {
             (self.cfg.notify_handler)(Notification::UninstalledToolchain(&self.name));
+            self.remove_empty_parents();
         }
         Ok(try!(result))
     }
+    /// Reinstalls a tracking toolchain that is stored where the current
+    /// layout doesn't put it at its current release, then removes the old
+    /// copy. The update hash is only written once the new copy is
+    /// complete, so an interrupted run is picked up again by the next one.
+    /// Returns `Unchanged` if there was nothing to move.
+    pub(crate) fn reinstall_into_layout(&self) -> Result<UpdateStatus> {
+        let layout = try!(self.cfg.toolchain_layout());
+        let (old, new) = match layout_paths(&self.cfg.toolchains_dir, &self.name, layout) {
+            (preferred, Some(other)) => {
+                if !utils::path_exists(&other) {
+                    return Ok(UpdateStatus::Unchanged);
+                }
+                (self.at_path(other), self.at_path(preferred))
+            }
+            (_, None) => return Ok(UpdateStatus::Unchanged),
+        };
+        (self.cfg.notify_handler)(Notification::ReinstallingToolchain(&self.name));
+
+        let complete = new.exists() && try!(new.update_hash()).map_or(false, |h| utils::is_file(&h));
+        try!(utils::reinstall_dir(&old.path, &self.cfg.toolchains_dir, complete, || -> Result<()> {
+            try!(new.reset_update_hash());
+            try!(new.install_from_dist_inner(false, false));
+            if old.is_readonly() {
+                try!(new.set_readonly(true));
+            }
+            Ok(())
+        }, &|n| (self.cfg.notify_handler)(n.into())));
+        (self.cfg.notify_handler)(Notification::MovedToolchain(&self.name, &new.path));
+        Ok(UpdateStatus::Installed)
+    }
+    // Cleans up the origin directories of the nested layout once their last
+    // toolchain is gone
+    fn remove_empty_parents(&self) {
+        utils::remove_empty_parents(&self.path, &self.cfg.toolchains_dir);
+    }
+    pub(crate) fn dir_name(&self) -> &str {
+        &self.dir_name
+    }
+    // Whether we were invoked by a process running from this toolchain
+    pub(crate) fn is_running(&self) -> bool {
+        let recursion_count = env::var("LEAN_RECURSION_COUNT").ok()
+            .and_then(|s| s.parse::<u32>().ok()).unwrap_or(0);
+        recursion_count > 0 && env::var("ELAN_TOOLCHAIN").ok().as_ref() == Some(&self.name)
+    }
     fn install(&self, install_method: InstallMethod) -> Result<UpdateStatus> {
+        if let InstallMethod::Dist { dry_run: true, .. } = install_method {
+            return self.install_dry_run(install_method);
+        }
+        if utils::occupied_by_file(&self.path) {
+            return Err(ErrorKind::ToolchainPathConflict(self.path.clone()).into());
+        }
         let exists = self.exists();
+        let counts_toward_quota = match install_method {
+            InstallMethod::Dist { .. } | InstallMethod::Bundle(..) => !exists && !self.temporary,
+            _ => false,
+        };
+        if counts_toward_quota {
+            try!(self.cfg.check_toolchain_quota());
+        }
+        if let Some(parent) = self.path.parent() {
+            try!(utils::ensure_dir_exists("toolchains", parent,
+                                          &|n| (self.cfg.notify_handler)(n.into())));
+        }
         if exists {
             (self.cfg.notify_handler)(Notification::UpdatingToolchain(&self.name));
         } else {
@@ -115,8 +510,15 @@ impl<'a> Toolchain<'a> {
         }
         (self.cfg.notify_handler)
             (Notification::ToolchainDirectory(&self.path, &self.name));
+        // Linked toolchains are the user's own to edit
+        let record_files = !matches!(install_method, InstallMethod::Link(_));
         let updated = try!(install_method.run(&self.path,
                                               &|n| (self.cfg.notify_handler)(n.into())));
+        *self.metadata.borrow_mut() = None;
+        if updated && record_files {
+            try!(toolchain_metadata::record_bin_dirs(&self.path));
+            try!(toolchain_metadata::record_files(&self.path));
+        }
 
         if !updated {
             (self.cfg.notify_handler)(Notification::UpdateHashMatches);
@@ -131,17 +533,63 @@ impl<'a> Toolchain<'a> {
             (false, false) => UpdateStatus::Unchanged,
         };
 
+        // Only once the new toolchain is in place, so that a failed
+        // install doesn't cost another toolchain
+        if counts_toward_quota && status == UpdateStatus::Installed {
+            if let Err(e) = self.cfg.evict_toolchains(self) {
+                (self.cfg.notify_handler)(Notification::NonFatalError(&e));
+            }
+        }
+
+        Ok(status)
+    }
+    // Reports what `install` would do without touching the toolchain
+    fn install_dry_run(&self, install_method: InstallMethod) -> Result<UpdateStatus> {
+        (self.cfg.notify_handler)(Notification::LookingForToolchain(&self.name));
+        let exists = self.exists() && !self.is_incomplete();
+        let updated = try!(install_method.run(&self.path,
+                                              &|n| (self.cfg.notify_handler)(n.into())));
+        let status = match (updated, exists) {
+            (true, false) => UpdateStatus::Installed,
+            (true, true) => UpdateStatus::Updated,
+            (false, _) => UpdateStatus::Unchanged,
+        };
+        (self.cfg.notify_handler)(Notification::WouldInstall(&self.name, status));
         Ok(status)
     }
     fn install_if_not_installed(&self, install_method: InstallMethod) -> Result<UpdateStatus> {
         (self.cfg.notify_handler)(Notification::LookingForToolchain(&self.name));
         if !self.exists() {
             Ok(try!(self.install(install_method)))
+        } else if self.is_incomplete() {
+            (self.cfg.notify_handler)(Notification::IncompleteToolchain(&self.name));
+            Ok(try!(self.install(install_method)))
         } else {
             (self.cfg.notify_handler)(Notification::UsingExistingToolchain(&self.name));
             Ok(UpdateStatus::Unchanged)
         }
     }
+    /// Forgets which release is installed, so that the next update
+    /// reinstalls the toolchain even if the release hasn't changed. Does
+    /// nothing for custom toolchains or if no release is recorded.
+    pub fn reset_update_hash(&self) -> Result<()> {
+        if self.exists() && self.is_custom() {
+            return Ok(());
+        }
+        if let Some(hash_file) = try!(self.update_hash()) {
+            if try!(elan_dist::dist::reset_update_hash(&hash_file)) {
+                (self.cfg.notify_handler)(Notification::ResetUpdateHash(&self.name));
+            }
+        }
+        Ok(())
+    }
+    /// Whether the toolchain was installed from the dist server and
+    /// recorded the release it is at, so that it can be reinstalled
+    pub fn has_update_hash(&self) -> bool {
+        !self.is_symlink() && self.cfg.get_hash_file(&self.dir_name, false)
+            .map(utils::is_file)
+            .unwrap_or(false)
+    }
     fn update_hash(&self) -> Result<Option<PathBuf>> {
         if self.is_symlink() {
             Ok(None)
@@ -150,31 +598,82 @@ impl<'a> Toolchain<'a> {
         }
     }
 
-    fn download_cfg(&self) -> DownloadCfg {
-        DownloadCfg {
+    fn download_cfg(&self) -> Result<DownloadCfg> {
+        Ok(DownloadCfg {
             temp_cfg: &self.cfg.temp_cfg,
             download_dir: &self.cfg.download_dir,
+            keep_downloads: self.cfg.keep_downloads,
+            include_prereleases: self.cfg.include_prereleases,
+            profile: try!(self.cfg.profile()),
+            cancel: &self.cfg.cancel,
+            dist_servers: &self.cfg.dist_servers,
+            url_rewrites: &self.url_rewrites,
+            disk_space_factor: self.cfg.disk_space_factor,
+            delta_updates: self.cfg.delta_updates,
+            provenance: self.cfg.provenance.as_ref(),
+            deterministic_extract: self.cfg.deterministic_extract,
+            check_case: self.cfg.check_case,
             notify_handler: &*self.dist_handler,
-        }
+        })
     }
 
     pub fn install_from_dist(&self, force_update: bool) -> Result<UpdateStatus> {
         if try!(self.cfg.telemetry_enabled()) {
             return self.install_from_dist_with_telemetry(force_update);
         }
-        self.install_from_dist_inner(force_update)
+        self.install_from_dist_inner(force_update, false)
     }
 
-    pub fn install_from_dist_inner(&self, force_update: bool) -> Result<UpdateStatus> {
-        let update_hash = try!(self.update_hash());
-        self.install(InstallMethod::Dist(&try!(self.desc()),
-                                         update_hash.as_ref().map(|p| &**p),
-                                         self.download_cfg(),
-                                         force_update))
+    /// Like `install_from_dist`, but also makes the toolchain the default
+    /// if `set_default` is set. Without it, the toolchain only becomes the
+    /// default if none is set yet, i.e. on the first install, and
+    /// `Cfg::default_on_first_install` is enabled.
+    pub fn install_from_dist_and_set_default(&self, force_update: bool, set_default: bool) -> Result<UpdateStatus> {
+        let status = try!(self.install_from_dist(force_update));
+        try!(self.cfg.set_default_after_install(&self.name, set_default));
+        Ok(status)
+    }
+
+    /// Read-only toolchains are only updated with `force_update`. With
+    /// `dry_run`, the toolchain is only resolved and compared with the
+    /// installed release, and nothing is written.
+    pub fn install_from_dist_inner(&self, force_update: bool, dry_run: bool) -> Result<UpdateStatus> {
+        let readonly = self.is_readonly();
+        if dry_run && readonly && !force_update {
+            (self.cfg.notify_handler)(Notification::WouldSkipReadOnly(&self.name));
+            return Ok(UpdateStatus::Unchanged);
+        }
+        try!(self.check_writable(force_update));
+        let update_hash = if dry_run && !self.is_symlink() {
+            Some(try!(self.cfg.get_hash_file(&self.dir_name, false)))
+        } else {
+            try!(self.update_hash())
+        };
+        let desc = try!(self.desc());
+        let status = try!(self.install(InstallMethod::Dist {
+            toolchain: &desc,
+            update_hash: update_hash.as_ref().map(|p| &**p),
+            dl_cfg: try!(self.download_cfg()),
+            force_update: force_update,
+            dry_run: dry_run,
+        }));
+        if dry_run {
+            return Ok(status);
+        }
+        // The update replaced the toolchain directory, marker included
+        if readonly {
+            try!(self.set_readonly(true));
+        }
+        if status != UpdateStatus::Unchanged {
+            self.cfg.record_channel_history(self);
+        }
+        self.limit_download_dir();
+        self.run_post_install_hooks(&desc, status);
+        Ok(status)
     }
 
     pub fn install_from_dist_with_telemetry(&self, force_update: bool) -> Result<UpdateStatus> {
-        let result = self.install_from_dist_inner(force_update);
+        let result = self.install_from_dist_inner(force_update, false);
 
         match result {
             Ok(us) => {
@@ -201,22 +700,101 @@ impl<'a> Toolchain<'a> {
 
     pub fn install_from_dist_if_not_installed(&self) -> Result<UpdateStatus> {
         let update_hash = try!(self.update_hash());
-        self.install_if_not_installed(InstallMethod::Dist(&try!(self.desc()),
-                                                          update_hash.as_ref().map(|p| &**p),
-                                                          self.download_cfg(),
-                                                          false))
+        let desc = try!(self.desc());
+        let status = try!(self.install_if_not_installed(InstallMethod::Dist {
+            toolchain: &desc,
+            update_hash: update_hash.as_ref().map(|p| &**p),
+            dl_cfg: try!(self.download_cfg()),
+            force_update: false,
+            dry_run: false,
+        }));
+        if status != UpdateStatus::Unchanged {
+            self.cfg.record_channel_history(self);
+        }
+        self.limit_download_dir();
+        self.run_post_install_hooks(&desc, status);
+        Ok(status)
+    }
+    /// Installs the toolchain if necessary and sets it as the override for
+    /// `project_path`. The override is only set if the install succeeded.
+    pub fn install_and_override(&self, project_path: &Path) -> Result<UpdateStatus> {
+        let status = try!(self.install_from_dist_if_not_installed());
+        try!(self.make_override(project_path));
+        Ok(status)
+    }
+    // Pruning the download cache is best-effort and must not fail an
+    // otherwise successful install
+    fn limit_download_dir(&self) {
+        if let Some(limit) = self.cfg.download_dir_limit {
+            if let Err(e) = self.cfg.prune_download_dir(None, Some(limit)) {
+                (self.cfg.notify_handler)(Notification::NonFatalError(&e));
+            }
+        }
+    }
+    // Hooks run only when something was actually installed. Like pruning,
+    // a failing hook is reported but doesn't fail the install.
+    fn run_post_install_hooks(&self, desc: &ToolchainDesc, status: UpdateStatus) {
+        if status == UpdateStatus::Unchanged {
+            return;
+        }
+        for hook in self.cfg.post_install_hooks(&desc.channel) {
+            (self.cfg.notify_handler)(Notification::RunningHook(&hook));
+            let result = Command::new(&hook)
+                .env("ELAN_TOOLCHAIN", &self.name)
+                .env("ELAN_TOOLCHAIN_DIR", &self.path)
+                .env("ELAN_CHANNEL", &desc.channel)
+                .status()
+                .chain_err(|| ErrorKind::HookFailed(hook.clone()))
+                .and_then(|s| if s.success() {
+                    Ok(())
+                } else {
+                    Err(ErrorKind::HookFailed(hook.clone()).into())
+                });
+            if let Err(e) = result {
+                (self.cfg.notify_handler)(Notification::NonFatalError(&e));
+            }
+        }
     }
     pub fn is_tracking(&self) -> bool {
-        ToolchainDesc::from_str(&self.name).ok().map(|d| d.is_tracking()) == Some(true)
+        matches!(self.classify(), ToolchainKind::TrackingChannel { .. })
     }
 
-    pub fn install_from_dir(&self, src: &Path, link: bool) -> Result<()> {
-        let mut pathbuf = PathBuf::from(src);
+    /// For tracking toolchains, looks up whether there is a newer release
+    /// than the installed one and returns its release page
+    pub fn check_for_update(&self) -> Result<Option<String>> {
+        if !self.exists() || !self.is_tracking() {
+            return Ok(None);
+        }
+        let url = try!(elan_dist::dist::resolve_toolchain_url(&try!(self.desc()),
+                                                                     self.cfg.include_prereleases,
+                                                                     &*self.dist_handler));
+        let installed = try!(self.update_hash())
+            .and_then(|hash_file| elan_dist::dist::read_update_hash(&hash_file));
+        Ok(if installed.as_ref() == Some(&url) { None } else { Some(url) })
+    }
 
-        pathbuf.push("bin");
-        try!(utils::assert_is_directory(&pathbuf));
-        pathbuf.push(format!("lean{}", EXE_SUFFIX));
-        try!(utils::assert_is_file(&pathbuf));
+    /// Resolves the toolchain's channel over the network and compares it
+    /// with the installed release. Unlike `available_version`, this never
+    /// relies on cached results.
+    pub fn is_up_to_date(&self) -> Result<UpdateAvailability> {
+        if !self.exists() {
+            return Err(ErrorKind::ToolchainNotInstalled(self.name.to_owned()).into());
+        }
+        if !self.is_tracking() {
+            return Ok(UpdateAvailability::NotTracking);
+        }
+        let latest = try!(elan_dist::dist::resolve_toolchain_url(&try!(self.desc()),
+                                                                 self.cfg.include_prereleases,
+                                                                 &*self.dist_handler));
+        let installed = try!(self.update_hash())
+            .and_then(|hash_file| elan_dist::dist::read_update_hash(&hash_file));
+        Ok(elan_dist::dist::update_availability(installed.as_ref().map(|s| &**s), &latest,
+                                                self.cfg.include_prereleases,
+                                                &|repo| Ok(try!(utils::fetch_release_tags(repo)))))
+    }
+
+    pub fn install_from_dir(&self, src: &Path, link: bool) -> Result<()> {
+        try!(assert_is_toolchain_dir(src));
 
         if link {
             try!(self.install(InstallMethod::Link(&try!(utils::to_absolute(src)))));
@@ -227,18 +805,90 @@ impl<'a> Toolchain<'a> {
         Ok(())
     }
 
+    /// Downloads the toolchain's archive into a bundle in `dir`, see
+    /// `elan_dist::bundle`
+    pub fn export_bundle(&self, dir: &Path) -> Result<Bundle> {
+        let desc = try!(self.desc());
+        Ok(try!(bundle::export(try!(self.download_cfg()), &desc, dir)))
+    }
+
+    /// Installs the toolchain from the bundle in `dir` without network
+    /// access. The bundle must be of this toolchain.
+    pub fn install_from_bundle(&self, dir: &Path) -> Result<UpdateStatus> {
+        let bundled = try!(Bundle::load(dir)).name;
+        if bundled != self.name {
+            return Err(format!("the bundle in '{}' is of '{}', not '{}'", dir.display(), bundled, self.name).into());
+        }
+        try!(self.check_writable(false));
+        let update_hash = try!(self.update_hash());
+        let status = try!(self.install(InstallMethod::Bundle(dir,
+                                                             update_hash.as_ref().map(|p| &**p),
+                                                             try!(self.download_cfg()))));
+        self.run_post_install_hooks(&try!(self.desc()), status);
+        Ok(status)
+    }
+
+    /// Points a linked toolchain at a different directory, e.g. after the
+    /// local build it refers to has moved
+    pub fn relink(&self, new_target: &Path) -> Result<()> {
+        if !self.exists() {
+            return Err(ErrorKind::ToolchainNotInstalled(self.name.to_owned()).into());
+        }
+        if !self.is_symlink() {
+            return Err(ErrorKind::ToolchainNotLinked(self.name.to_owned()).into());
+        }
+        try!(assert_is_toolchain_dir(new_target));
+
+        let new_target = try!(utils::to_absolute(new_target));
+        // Replaces the existing symlink, or junction on Windows
+        try!(utils::symlink_dir(&new_target, &self.path,
+                                &|n| (self.cfg.notify_handler)(n.into())));
+        Ok(())
+    }
+
+    /// Marks the toolchain as in use without running anything, so that it
+    /// isn't the first to be evicted when `ELAN_MAX_TOOLCHAINS` is reached.
+    /// Editors and other frontends that resolve a toolchain and keep it
+    /// around should call this when they activate it. Failing to record the
+    /// use is not an error.
+    pub fn touch(&self) -> Result<()> {
+        if !self.exists() {
+            return Err(ErrorKind::ToolchainNotInstalled(self.name.to_owned()).into());
+        }
+        self.cfg.record_toolchain_use(self);
+        Ok(())
+    }
+
     pub fn create_command<T: AsRef<OsStr>>(&self, binary: T) -> Result<Command> {
+        self.create_command_inner(binary, true)
+    }
+
+    /// Like `create_command`, but leaves `PATH` as it is, so that e.g. test
+    /// harnesses can run the toolchain without elan's proxies in the way.
+    /// The loader path, `ELAN_HOME` and `ELAN_TOOLCHAIN` are still set,
+    /// except when `PATH` is one of the loader path variables. On Windows
+    /// the toolchain's DLLs then have to be found some other way.
+    pub fn create_command_without_path<T: AsRef<OsStr>>(&self, binary: T) -> Result<Command> {
+        self.create_command_inner(binary, false)
+    }
+
+    fn create_command_inner<T: AsRef<OsStr>>(&self, binary: T, set_path: bool) -> Result<Command> {
         if !self.exists() {
             return Err(ErrorKind::ToolchainNotInstalled(self.name.to_owned()).into());
         }
+        try!(self.check_min_elan_version());
+        self.notify_deprecation();
+        self.cfg.record_toolchain_use(self);
 
         let bin_path = self.binary_file(&binary);
         let path = if utils::is_file(&bin_path) {
+            try!(self.check_arch(&bin_path));
             &bin_path
         } else {
             let recursion_count = env::var("LEAN_RECURSION_COUNT").ok()
                 .and_then(|s| s.parse().ok()).unwrap_or(0);
-            if recursion_count > env_var::LEAN_RECURSION_COUNT_MAX - 1 {
+            if recursion_count > env_var::LEAN_RECURSION_COUNT_MAX - 1 ||
+               try!(self.cfg.fallback_disabled()) {
                 return Err(ErrorKind::BinaryNotFound(self.name.clone(),
                                                      bin_path.to_str().unwrap().into())
                             .into())
@@ -252,108 +902,318 @@ impl<'a> Toolchain<'a> {
         } else {
             cmd = Command::new(&path);
         };
-        self.set_env(&mut cmd);
+        self.set_env(&mut cmd, set_path)?;
         Ok(cmd)
     }
 
-    fn set_env(&self, cmd: &mut Command) {
-        self.set_ldpath(cmd);
+    /// The files of the toolchain that were changed since it was installed,
+    /// relative to its directory. Empty if no digests were recorded, e.g.
+    /// for linked toolchains.
+    pub fn modified_files(&self) -> Result<Vec<PathBuf>> {
+        try!(self.verify());
+        let recorded = try!(toolchain_metadata::recorded_files(&self.path));
+        Ok(match recorded {
+            Some(recorded) => toolchain_metadata::modified_files(&self.path, &recorded),
+            None => vec![],
+        })
+    }
+
+    /// The components of the toolchain's release and whether they are
+    /// installed
+    pub fn list_components(&self) -> Result<Vec<ComponentStatus>> {
+        if !self.exists() {
+            return Err(ErrorKind::ToolchainNotInstalled(self.name.to_owned()).into());
+        }
+        let profile = try!(self.cfg.profile());
+        // All components are shipped in the same release archive
+        let installed_version = try!(self.installed_release());
+        let available_version = self.cfg
+            .cached_update_check(self, Duration::from_secs(update_check::DEFAULT_MAX_AGE_SECS))
+            .and_then(|check| check.latest.or_else(|| installed_version.clone()));
+        let mut result = vec![ComponentStatus {
+            component: Component { pkg: "lean".to_owned() },
+            required: true,
+            installed: true,
+            available: true,
+            in_profile: true,
+            installed_version: installed_version.clone(),
+            available_version: available_version.clone(),
+        }];
+        for &(pkg, path) in OPTIONAL_COMPONENTS.iter() {
+            let installed = utils::path_exists(self.path.join(path));
+            result.push(ComponentStatus {
+                component: Component { pkg: pkg.to_owned() },
+                required: false,
+                installed: installed,
+                available: !self.is_custom(),
+                in_profile: profile.includes(pkg),
+                installed_version: if installed { installed_version.clone() } else { None },
+                available_version: available_version.clone(),
+            });
+        }
+        Ok(result)
+    }
+
+    /// Whether `component`, `lean` or one of `OPTIONAL_COMPONENTS`, is
+    /// installed
+    pub fn has_component(&self, component: &str) -> bool {
+        match OPTIONAL_COMPONENTS.iter().find(|&&(c, _)| c == component) {
+            Some(&(_, path)) => utils::path_exists(self.path.join(path)),
+            None => component == "lean" && self.exists(),
+        }
+    }
+
+    /// Installs those of `components` that are missing. Releases ship all
+    /// components in one archive, so this reinstalls the release with
+    /// everything included. Custom and read-only toolchains can't get new
+    /// components, and components the release doesn't ship are only
+    /// warned about.
+    pub fn add_components(&self, components: &[String]) -> Result<UpdateStatus> {
+        let missing = |t: &Toolchain| -> String {
+            components.iter().filter(|c| !t.has_component(c)).map(|c| &**c).collect::<Vec<_>>().join(", ")
+        };
+        let before = missing(self);
+        if before.is_empty() {
+            return Ok(UpdateStatus::Unchanged);
+        }
+        if self.is_custom() || self.is_readonly() {
+            (self.cfg.notify_handler)(Notification::ComponentsUnavailable(&self.name, &before));
+            return Ok(UpdateStatus::Unchanged);
+        }
+        (self.cfg.notify_handler)(Notification::AddingComponents(&self.name, &before));
+
+        // Otherwise the installed release is considered up to date. The
+        // hash is put back if the reinstall fails.
+        let update_hash = try!(self.update_hash());
+        let old_hash = update_hash.as_ref().and_then(|h| elan_dist::dist::read_update_hash(h));
+        try!(self.reset_update_hash());
+        let desc = try!(self.desc());
+        let mut dlcfg = try!(self.download_cfg());
+        dlcfg.profile = Profile::Complete;
+        let result = self.install(InstallMethod::Dist {
+            toolchain: &desc,
+            update_hash: update_hash.as_ref().map(|p| &**p),
+            dl_cfg: dlcfg,
+            force_update: false,
+            dry_run: false,
+        });
+        if let (&Err(_), Some(hash_file), Some(old_hash)) = (&result, update_hash.as_ref(), old_hash.as_ref()) {
+            let _ = utils::write_file_atomic("update hash", hash_file, old_hash);
+        }
+        let status = try!(result);
+        self.limit_download_dir();
+
+        let after = missing(self);
+        if !after.is_empty() {
+            (self.cfg.notify_handler)(Notification::ComponentsUnavailable(&self.name, &after));
+        }
+        Ok(status)
+    }
+
+    /// The release tag of the installed release, as recorded at install
+    /// time. Custom toolchains don't have one.
+    pub fn installed_release(&self) -> Result<Option<String>> {
+        Ok(try!(self.update_hash())
+           .and_then(|hash_file| elan_dist::dist::read_update_hash(&hash_file))
+           .and_then(|url| elan_dist::dist::release_tag(&url).map(|t| t.to_owned())))
+    }
+
+    /// Runs `binary` from the toolchain with `args`, returning as soon as it
+    /// is started. Its stdin, stdout and stderr are piped so that callers
+    /// can feed it input and consume its output as it is produced.
+    pub fn spawn_with_args<T: AsRef<OsStr>, S: AsRef<OsStr>>(&self, binary: T, args: &[S]) -> Result<Child> {
+        let mut cmd = try!(self.create_command(&binary));
+        cmd.args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        Ok(try!(cmd.spawn().chain_err(|| elan_utils::ErrorKind::RunningCommand {
+            name: binary.as_ref().to_owned(),
+        })))
+    }
+
+    fn set_env(&self, cmd: &mut Command, set_path: bool) -> Result<()> {
+        let elan_home = self.elan_home()?;
+        if set_path {
+            self.set_ldpath_with_home(cmd, elan_home.as_ref().map(|p| &**p));
+        } else {
+            let new_path = self.lib_dir();
+            for var in utils::without_path_var(&self.cfg.loader_path_vars) {
+                env_var::prepend_path(var, vec![new_path.clone()], cmd);
+            }
+        }
+        self.set_lean_path(cmd);
 
         // Because elan and leanpkg use slightly different
         // definitions of leanpkg home (elan doesn't read HOME on
         // windows), we must set it here to ensure leanpkg and
         // elan agree.
-        if let Ok(elan_home) = utils::elan_home() {
-            cmd.env("ELAN_HOME", &elan_home);
+        if let Some(ref elan_home) = elan_home {
+            cmd.env("ELAN_HOME", elan_home);
         }
 
         env_var::inc("LEAN_RECURSION_COUNT", cmd);
 
         cmd.env("ELAN_TOOLCHAIN", &self.name);
         cmd.env("ELAN_HOME", &self.cfg.elan_dir);
+        Ok(())
     }
 
-    pub fn set_ldpath(&self, cmd: &mut Command) {
-        let new_path = self.path.join("lib");
-
-        #[cfg(not(target_os = "macos"))]
-        mod sysenv {
-            pub const LOADER_PATH: &'static str = "LD_LIBRARY_PATH";
-        }
-        #[cfg(target_os = "macos")]
-        mod sysenv {
-            pub const LOADER_PATH: &'static str = "DYLD_LIBRARY_PATH";
+    // Without an elan home the proxy bins can't be put on the PATH, so warn
+    // about it (or fail in strict mode) instead of silently running whatever
+    // `lean` happens to be found first.
+    fn elan_home(&self) -> Result<Option<PathBuf>> {
+        match utils::elan_home() {
+            Ok(elan_home) => Ok(Some(elan_home)),
+            Err(e) => {
+                if self.cfg.strict_home {
+                    Err(e).chain_err(|| ErrorKind::ElanHomeUnavailable)
+                } else {
+                    (self.cfg.notify_handler)(Notification::ElanHomeUnavailable);
+                    Ok(None)
+                }
+            }
         }
-        env_var::prepend_path(sysenv::LOADER_PATH, vec![new_path.clone()], cmd);
+    }
+
+    pub fn set_ldpath(&self, cmd: &mut Command) -> Result<()> {
+        let elan_home = self.elan_home()?;
+        self.set_ldpath_with_home(cmd, elan_home.as_ref().map(|p| &**p));
+        Ok(())
+    }
 
-        // Prepend ELAN_HOME/bin to the PATH variable so that we're sure to run
-        // leanpkg/lean via the proxy bins. There is no fallback case for if the
-        // proxy bins don't exist. We'll just be running whatever happens to
-        // be on the PATH.
+    /// The directories `set_ldpath` prepends to `PATH`, in order, for
+    /// callers that build their own `Command`. On all platforms this is
+    /// `ELAN_HOME/bin`, so that `lean` and `leanpkg` resolve to the proxy
+    /// bins. On Windows it is followed by the toolchain's own `bin`
+    /// directory, where the loader looks for the toolchain's DLLs; Unix
+    /// finds shared libraries through the loader path variables instead.
+    pub fn env_path(&self) -> Result<Vec<PathBuf>> {
+        let elan_home = self.elan_home()?;
+        Ok(self.env_path_with_home(elan_home.as_ref().map(|p| &**p)))
+    }
+
+    fn env_path_with_home(&self, elan_home: Option<&Path>) -> Vec<PathBuf> {
+        // There is no fallback case for if the proxy bins don't exist. We'll
+        // just be running whatever happens to be on the PATH.
         let mut path_entries = vec![];
-        if let Ok(elan_home) = utils::elan_home() {
+        if let Some(elan_home) = elan_home {
             path_entries.push(elan_home.join("bin").to_path_buf());
         }
 
         if cfg!(target_os = "windows") {
-            path_entries.push(self.path.join("bin"));
+            path_entries.push(self.bin_dir());
         }
 
-        env_var::prepend_path("PATH", path_entries, cmd);
+        path_entries
+    }
+
+    fn set_ldpath_with_home(&self, cmd: &mut Command, elan_home: Option<&Path>) {
+        self.set_loader_path(cmd);
+        env_var::prepend_path("PATH", self.env_path_with_home(elan_home), cmd);
+    }
+
+    fn set_loader_path(&self, cmd: &mut Command) {
+        let new_path = self.lib_dir();
+
+        for var in &self.cfg.loader_path_vars {
+            env_var::prepend_path(var, vec![new_path.clone()], cmd);
+        }
+    }
+
+    // Projects that vendor libraries declare them in their
+    // `lean-toolchain.toml`. `LEAN_PATH` is then made up of the toolchain's
+    // library directories, followed by the project's and finally those
+    // inherited from the parent process, each listed once. Without such a
+    // declaration it is left alone.
+    fn set_lean_path(&self, cmd: &mut Command) {
+        let project_dir = match self.project_dir {
+            Some(ref dir) => dir,
+            None => return,
+        };
+        let project: Vec<PathBuf> = match ToolchainConfig::load(project_dir) {
+            Ok(Some(config)) => config.lean_path.iter().map(|p| project_dir.join(p)).collect(),
+            _ => vec![],
+        };
+        if project.is_empty() {
+            return;
+        }
+        let mut dirs: Vec<PathBuf> = self.prefix().core_library_dirs()
+            .into_iter()
+            .filter(|d| utils::is_directory(d))
+            .collect();
+        dirs.extend(project);
+        let inherited = env::var_os("LEAN_PATH");
+        let dirs = utils::compose_search_path(dirs, inherited.as_ref().map(|v| &**v));
+        if let Ok(lean_path) = env::join_paths(dirs) {
+            cmd.env("LEAN_PATH", lean_path);
+        }
     }
 
-    pub fn doc_path(&self, relative: &str) -> Result<PathBuf> {
+    /// Resolves `relative` in the HTML documentation of `component`
+    /// (usually "lean") shipped by the toolchain
+    pub fn doc_path(&self, component: &str, relative: &str) -> Result<PathBuf> {
         try!(self.verify());
 
-        let parts = vec!["share", "doc", "lean", "html"];
-        let mut doc_dir = self.path.clone();
-        for part in parts {
-            doc_dir.push(part);
+        let docs_dir = self.doc_dir();
+        let mut doc_dir = docs_dir.join(component).join("html");
+        if !utils::is_directory(&doc_dir) {
+            let mut available: Vec<String> = match docs_dir.read_dir() {
+                Ok(entries) => entries.filter_map(|e| e.ok())
+                    .filter(|e| utils::is_directory(&e.path().join("html")))
+                    .filter_map(|e| e.file_name().into_string().ok())
+                    .collect(),
+                Err(_) => Vec::new(),
+            };
+            available.sort();
+            return Err(ErrorKind::DocComponentNotFound(self.name.clone(),
+                                                       component.to_owned(),
+                                                       available).into());
         }
         doc_dir.push(relative);
 
         Ok(doc_dir)
     }
-    pub fn open_docs(&self, relative: &str) -> Result<()> {
+    pub fn open_docs(&self, component: &str, relative: &str) -> Result<()> {
         try!(self.verify());
 
-        Ok(try!(utils::open_browser(&try!(self.doc_path(relative)))))
+        Ok(try!(utils::open_browser(&try!(self.doc_path(component, relative)))))
     }
 
     pub fn make_default(&self) -> Result<()> {
         self.cfg.set_default(&self.name)
     }
     pub fn make_override(&self, path: &Path) -> Result<()> {
-        Ok(try!(self.cfg.settings_file.with_mut(|s| {
+        try!(self.cfg.settings_file.with_mut(|s| {
             s.add_override(path, self.name.clone(), self.cfg.notify_handler.as_ref());
             Ok(())
-        })))
+        }));
+        self.cfg.warn_if_ephemeral();
+        Ok(())
     }
 
     pub fn binary_file<T: AsRef<OsStr>>(&self, binary: T) -> PathBuf {
-        let binary = if let Some(binary_str) = binary.as_ref().to_str() {
-            let binary_str = binary_str.to_lowercase();
-            let path = Path::new(&binary_str);
-            if path.extension().is_some() {
-                binary.as_ref().to_owned()
-            } else {
-                let mut ext = EXE_SUFFIX;
-                OsString::from(format!("{}{}", binary_str, ext))
-            }
-        } else {
-            // Very weird case. Non-unicode command.
-            binary.as_ref().to_owned()
-        };
+        // On Windows, `leanpkg.bat` and `leanc` (a shell script) are found
+        // as well
+        self.prefix().find_binary(binary.as_ref(), &self.extra_bin_dirs(), EXE_SUFFIX, cfg!(windows))
+    }
 
-        let path = self.path.join("bin").join(&binary);
-        if cfg!(windows) && !path.exists() && path.with_extension("bat").exists() {
-            // leanpkg.bat
-            path.with_extension("bat")
-        } else if cfg!(windows) && !path.exists() && path.with_extension("").exists() {
-            // leanc (sh script)
-            path.with_extension("")
-        } else {
-            path
-        }
+    /// The directories besides `bin` that executables are looked for in,
+    /// as listed in the toolchain's metadata or else `DEFAULT_EXTRA_BIN_DIRS`
+    fn extra_bin_dirs(&self) -> Vec<String> {
+        self.metadata().ok()
+            .and_then(|m| m.as_ref().as_ref().and_then(|m| m.bin_dirs.clone()))
+            .unwrap_or_else(|| toolchain_metadata::DEFAULT_EXTRA_BIN_DIRS.iter().map(|&d| d.to_owned()).collect())
     }
+
+    /// Names of the executables in the toolchain's `bin` directory, without
+    /// `EXE_SUFFIX` or `.bat`, sorted
+    pub fn list_binaries(&self) -> Result<Vec<String>> {
+        Ok(try!(utils::list_binaries(&self.bin_dir(), &binary_suffixes())))
+    }
+}
+
+/// What `list_binaries` strips from the names of executables
+pub(crate) fn binary_suffixes() -> Vec<&'static str> {
+    if cfg!(windows) { vec![EXE_SUFFIX, ".bat"] } else { vec![] }
 }
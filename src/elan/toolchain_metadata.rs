@@ -0,0 +1,152 @@
+//! Optional `elan-toolchain.json` metadata shipped at the root of a toolchain
+
+use errors::*;
+use elan_utils::utils;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use elan_dist;
+use elan_dist::version::Version;
+use json;
+use serde_json;
+use sha2::{Digest, Sha256};
+
+pub use elan_dist::METADATA_FILE;
+
+/// Where the digests of a toolchain's files are recorded at install time,
+/// in the format of `sha256sum`. Kept apart from the metadata, which is
+/// read whenever the toolchain is run.
+pub const FILE_DIGESTS_FILE: &'static str = ".elan-files.sha256";
+
+/// Where executables are looked for after `bin` in toolchains whose
+/// metadata doesn't say
+pub const DEFAULT_EXTRA_BIN_DIRS: [&'static str; 1] = ["libexec"];
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct ToolchainMetadata {
+    /// Lean version the toolchain was built from, for toolchains whose
+    /// name doesn't say
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Repository the toolchain's sources came from, as `owner/repo`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    /// Oldest elan release the toolchain is known to work with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_elan_version: Option<String>,
+    /// Directories besides `bin`, relative to the toolchain root, that
+    /// hold executables like `lake`, in the order they are searched.
+    /// Recorded by elan at install time unless the toolchain ships a list.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bin_dirs: Option<Vec<String>>,
+    /// Why the origin advises against the installed release, recorded by
+    /// elan at install time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deprecation: Option<ToolchainDeprecation>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ToolchainDeprecation {
+    pub yanked: bool,
+    pub reason: String,
+}
+
+impl ToolchainMetadata {
+    /// Reads the metadata of the toolchain at `toolchain_dir`, if it has any
+    pub fn load(toolchain_dir: &Path) -> Result<Option<ToolchainMetadata>> {
+        let path = toolchain_dir.join(METADATA_FILE);
+        if !utils::is_file(&path) {
+            return Ok(None);
+        }
+        let contents = try!(utils::read_file("toolchain metadata", &path));
+        let metadata = try!(serde_json::from_str(&contents)
+                            .chain_err(|| ErrorKind::InvalidToolchainMetadata(path.clone())));
+        Ok(Some(metadata))
+    }
+
+}
+
+/// Records which of `DEFAULT_EXTRA_BIN_DIRS` the toolchain in
+/// `toolchain_dir` has as its `bin_dirs`, unless it ships its own list
+pub fn record_bin_dirs(toolchain_dir: &Path) -> Result<()> {
+    if try!(ToolchainMetadata::load(toolchain_dir)).map_or(false, |m| m.bin_dirs.is_some()) {
+        return Ok(());
+    }
+    let found: Vec<json::JsonValue> = DEFAULT_EXTRA_BIN_DIRS.iter()
+        .filter(|dir| utils::is_directory(toolchain_dir.join(dir)))
+        .map(|&dir| dir.into())
+        .collect();
+    Ok(try!(elan_dist::record_metadata(toolchain_dir, "bin_dirs", json::JsonValue::Array(found))))
+}
+
+/// Records the digests of all files currently in `toolchain_dir`
+pub fn record_files(toolchain_dir: &Path) -> Result<()> {
+    let list: String = try!(file_digests(toolchain_dir)).iter()
+        .map(|(path, digest)| format!("{}  {}\n", digest, path))
+        .collect();
+    Ok(try!(utils::write_file_atomic("file digests", &toolchain_dir.join(FILE_DIGESTS_FILE), &list)))
+}
+
+/// The digests recorded by `record_files`, if any
+pub fn recorded_files(toolchain_dir: &Path) -> Result<Option<BTreeMap<String, String>>> {
+    let path = toolchain_dir.join(FILE_DIGESTS_FILE);
+    if !utils::is_file(&path) {
+        return Ok(None);
+    }
+    let list = try!(utils::read_file("file digests", &path));
+    Ok(Some(try!(elan_dist::delta::parse_digests(&list, &path.to_string_lossy()))))
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    try!(io::copy(&mut try!(File::open(path)), &mut hasher));
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Digests of the regular files below `dir`, not following links and
+/// excluding the metadata elan writes to
+pub fn file_digests(dir: &Path) -> Result<BTreeMap<String, String>> {
+    fn walk(root: &Path, dir: &Path, digests: &mut BTreeMap<String, String>) -> io::Result<()> {
+        for entry in try!(fs::read_dir(dir)) {
+            let entry = try!(entry);
+            let path = entry.path();
+            let file_type = try!(entry.file_type());
+            if file_type.is_dir() {
+                try!(walk(root, &path, digests));
+            } else if file_type.is_file() {
+                let relative = path.strip_prefix(root).unwrap().to_string_lossy().replace('\\', "/");
+                if relative != METADATA_FILE && relative != FILE_DIGESTS_FILE {
+                    digests.insert(relative, try!(hash_file(&path)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    let mut digests = BTreeMap::new();
+    try!(walk(dir, dir, &mut digests).chain_err(|| ErrorKind::ComputingFileDigests(dir.to_owned())));
+    Ok(digests)
+}
+
+/// The files whose digests in `recorded` don't match the files below
+/// `dir`, including ones that were deleted, as relative paths
+pub fn modified_files(dir: &Path, recorded: &BTreeMap<String, String>) -> Vec<PathBuf> {
+    recorded.iter()
+        .filter(|&(relative, digest)| {
+            hash_file(&dir.join(relative)).map(|d| d != *digest).unwrap_or(true)
+        })
+        .map(|(relative, _)| PathBuf::from(relative))
+        .collect()
+}
+
+/// Whether `current` is at least `required`. Unparsable requirements are
+/// treated as satisfied.
+pub fn version_satisfies(current: &str, required: &str) -> bool {
+    match (Version::parse_lenient(current), Version::parse_lenient(required)) {
+        (Some(current), Some(required)) => current >= required,
+        _ => true,
+    }
+}
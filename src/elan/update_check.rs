@@ -0,0 +1,37 @@
+//! Cached results of checking tracking toolchains for updates, so that
+//! shell prompts and editors can show them without network access
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long a background check may run before it gives up
+pub const TIMEOUT_SECS: u64 = 30;
+
+/// How old a cached result may be before it is checked again
+pub const DEFAULT_MAX_AGE_SECS: u64 = 60 * 60;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UpdateCheck {
+    pub toolchain: String,
+    /// Seconds since the Unix epoch
+    pub checked_at: u64,
+    /// The newer release, if there is one
+    pub latest: Option<String>,
+}
+
+impl UpdateCheck {
+    pub fn new(toolchain: &str, latest: Option<String>) -> Self {
+        UpdateCheck {
+            toolchain: toolchain.to_owned(),
+            checked_at: now(),
+            latest: latest,
+        }
+    }
+
+    pub fn is_fresh(&self, max_staleness: Duration) -> bool {
+        now().saturating_sub(self.checked_at) <= max_staleness.as_secs()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}